@@ -0,0 +1,284 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Forwarding the local ssh-agent to the commands run on nodes, like
+//! `ssh -A`, with `--forward-agent`.
+//!
+//! Forwarding is off unless asked for, since anyone with root on a node can
+//! use a forwarded agent to log in as you while the command runs. Each
+//! command's channel asks sshd to forward the agent, and sshd opens a channel
+//! back for every connection the command makes to it. libssh2 hands those
+//! channels to a callback, which queues them, and they're relayed to the local
+//! agent's socket while the command's output is read, so they need no
+//! threads of their own.
+
+use crate::ssh;
+use libssh2_sys::{LIBSSH2_CHANNEL, LIBSSH2_ERROR_EAGAIN, LIBSSH2_SESSION};
+use ssh2::{Channel, Session};
+use std::env;
+use std::ffi::{c_char, c_int, c_void};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// libssh2's callback for channels sshd opens to reach the agent.
+const LIBSSH2_CALLBACK_AUTHAGENT: c_int = 7;
+
+/// How much is relayed from one side of an agent connection at a time.
+const RELAY_BUFFER: usize = 16 * 1024;
+
+/// How long to wait for sshd to close an agent channel.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether `--forward-agent` was given.
+static FORWARD: AtomicBool = AtomicBool::new(false);
+
+/// The agent channels sshd has opened that aren't being relayed yet, each
+/// with the session it belongs to, as addresses.
+static OPENED: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+extern "C" {
+    fn libssh2_session_callback_set2(
+        session: *mut LIBSSH2_SESSION,
+        cbtype: c_int,
+        callback: *const c_void,
+    ) -> *const c_void;
+}
+
+/// Forwards the local agent to every command run after this.
+///
+/// # Returns
+///
+/// Returns an error if there's no agent to forward.
+pub fn use_forwarding() -> Result<(), String> {
+    if !ssh::agent_available() {
+        return Err(
+            "--forward-agent needs a running ssh-agent, but SSH_AUTH_SOCK isn't set".to_string(),
+        );
+    }
+    FORWARD.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns true if the agent is forwarded.
+pub fn is_forwarded() -> bool {
+    FORWARD.load(Ordering::Relaxed)
+}
+
+/// Asks sshd to forward the agent to the command a channel is about to run,
+/// if forwarding is on.
+pub fn request(sess: &Session, channel: &mut Channel) -> Result<(), ssh2::Error> {
+    if !is_forwarded() {
+        return Ok(());
+    }
+    {
+        let mut raw = sess.raw();
+        let raw: *mut LIBSSH2_SESSION = &mut *raw;
+        // SAFETY: the session is locked, and the callback has the signature
+        // libssh2 calls it with
+        unsafe {
+            libssh2_session_callback_set2(raw, LIBSSH2_CALLBACK_AUTHAGENT, opened as *const c_void);
+        }
+    }
+    channel.request_auth_agent_forwarding()
+}
+
+/// Queues an agent channel sshd has opened, for `Relays::pump` to pick up.
+extern "C" fn opened(
+    session: *mut LIBSSH2_SESSION,
+    channel: *mut LIBSSH2_CHANNEL,
+    _abstract: *mut *mut c_void,
+) {
+    if let Ok(mut opened) = OPENED.lock() {
+        opened.push((session as usize, channel as usize));
+    }
+}
+
+/// The agent connections of one session being relayed.
+#[derive(Default)]
+pub struct Relays {
+    relays: Vec<Relay>,
+    /// The channels that have closed, which are freed once the session is
+    /// blocking again.
+    finished: Vec<*mut LIBSSH2_CHANNEL>,
+}
+
+/// An agent channel and the connection to the local agent it's relayed to.
+struct Relay {
+    channel: *mut LIBSSH2_CHANNEL,
+    agent: UnixStream,
+    /// Data read from the channel that the agent hasn't taken yet.
+    to_agent: Vec<u8>,
+    /// Data read from the agent that the channel hasn't taken yet.
+    to_channel: Vec<u8>,
+}
+
+impl Relays {
+    /// Starts relaying the agent channels opened on a session, and moves data
+    /// along on the others. The session must be non-blocking.
+    ///
+    /// # Returns
+    ///
+    /// Returns true if any data moved.
+    pub fn pump(&mut self, sess: &Session) -> bool {
+        if !is_forwarded() {
+            return false;
+        }
+        let mut raw = sess.raw();
+        let raw: *mut LIBSSH2_SESSION = &mut *raw;
+
+        // Step 1: Connect the channels opened since the last pump to the agent
+        for channel in take_opened(raw) {
+            match connect_agent() {
+                Ok(agent) => self.relays.push(Relay {
+                    channel,
+                    agent,
+                    to_agent: Vec::new(),
+                    to_channel: Vec::new(),
+                }),
+                Err(_) => self.finished.push(channel),
+            }
+        }
+
+        // Step 2: Relay each connection until either side closes
+        let mut moved = false;
+        let mut finished = Vec::new();
+        self.relays.retain_mut(|relay| match relay.pump() {
+            Ok(progressed) => {
+                moved |= progressed;
+                true
+            }
+            Err(_) => {
+                finished.push(relay.channel);
+                false
+            }
+        });
+        self.finished.extend(finished);
+        moved
+    }
+
+    /// Closes and frees the agent channels, once the command is done. The
+    /// session must be blocking.
+    pub fn close(self, sess: &Session) {
+        if !is_forwarded() {
+            return;
+        }
+        sess.set_timeout(CLOSE_TIMEOUT.as_millis() as u32);
+        let mut locked = sess.raw();
+        let raw: *mut LIBSSH2_SESSION = &mut *locked;
+        let mut channels = self.finished;
+        channels.extend(self.relays.iter().map(|relay| relay.channel));
+        channels.extend(take_opened(raw));
+        for channel in channels {
+            // SAFETY: the session is locked and the channel is no longer used
+            unsafe {
+                libssh2_sys::libssh2_channel_close(channel);
+                libssh2_sys::libssh2_channel_free(channel);
+            }
+        }
+        drop(locked);
+        sess.set_timeout(0);
+    }
+}
+
+/// Takes the agent channels opened on a session off the queue.
+fn take_opened(session: *mut LIBSSH2_SESSION) -> Vec<*mut LIBSSH2_CHANNEL> {
+    let Ok(mut opened) = OPENED.lock() else {
+        return Vec::new();
+    };
+    let mut taken = Vec::new();
+    opened.retain(|&(owner, channel)| {
+        if owner != session as usize {
+            return true;
+        }
+        taken.push(channel as *mut LIBSSH2_CHANNEL);
+        false
+    });
+    taken
+}
+
+impl Relay {
+    /// Moves data both ways between the channel and the agent.
+    ///
+    /// # Returns
+    ///
+    /// Returns true if any data moved, or an error once either side closes.
+    fn pump(&mut self) -> io::Result<bool> {
+        let mut moved = false;
+        let mut buf = [0; RELAY_BUFFER];
+
+        // From the node to the agent
+        if self.to_agent.is_empty() {
+            // SAFETY: the channel is open, and the buffer's length is passed
+            let n = unsafe {
+                libssh2_sys::libssh2_channel_read_ex(
+                    self.channel,
+                    0,
+                    buf.as_mut_ptr().cast::<c_char>(),
+                    buf.len(),
+                )
+            };
+            match n {
+                0 if self.channel_eof() => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n if n > 0 => self.to_agent.extend_from_slice(&buf[..n as usize]),
+                n if n == LIBSSH2_ERROR_EAGAIN as isize || n == 0 => {}
+                _ => return Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+        if !self.to_agent.is_empty() {
+            match self.agent.write(&self.to_agent) {
+                Ok(n) => {
+                    self.to_agent.drain(..n);
+                    moved = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        // From the agent back to the node
+        if self.to_channel.is_empty() {
+            match self.agent.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.to_channel.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.to_channel.is_empty() {
+            // SAFETY: the channel is open, and the data's length is passed
+            let n = unsafe {
+                libssh2_sys::libssh2_channel_write_ex(
+                    self.channel,
+                    0,
+                    self.to_channel.as_ptr().cast::<c_char>(),
+                    self.to_channel.len(),
+                )
+            };
+            match n {
+                n if n > 0 => {
+                    self.to_channel.drain(..n as usize);
+                    moved = true;
+                }
+                n if n == LIBSSH2_ERROR_EAGAIN as isize || n == 0 => {}
+                _ => return Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+        Ok(moved)
+    }
+
+    fn channel_eof(&self) -> bool {
+        // SAFETY: the channel is open
+        unsafe { libssh2_sys::libssh2_channel_eof(self.channel) != 0 }
+    }
+}
+
+/// Opens a non-blocking connection to the local agent.
+fn connect_agent() -> io::Result<UnixStream> {
+    let path = env::var_os("SSH_AUTH_SOCK").ok_or(io::ErrorKind::NotFound)?;
+    let agent = UnixStream::connect(path)?;
+    agent.set_nonblocking(true)?;
+    Ok(agent)
+}
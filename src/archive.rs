@@ -0,0 +1,397 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Sending a tree's changes as a tar stream, for `sync --tar`.
+//!
+//! Over SFTP every file costs several round trips to open, write, close and
+//! set its attributes, which for tens of thousands of small files takes far
+//! longer than sending their bytes. With `--tar`, a node's copy is listed by
+//! a single `find`, and the changes are put in a tar archive that's piped
+//! through `tar -x` on the node by a single command, compressed with gzip or
+//! zstd if asked. The archive is built in memory, as input given with
+//! `--stdin` is, so this suits many small files rather than a few large
+//! ones, which SFTP sends just as quickly. Nodes whose copies need the same
+//! changes share one archive, and a node that needs more than
+//! `MAX_ARCHIVE` bytes of files is refused rather than held in memory.
+
+use crate::cancel::CancelToken;
+use crate::cli::Compression;
+use crate::shell;
+use crate::sync::{self, Kind, Options, Remote, Step, Synced, Tree};
+use crate::transport::Session;
+use flate2::write::GzEncoder;
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// The most bytes of files sent to a node in one archive.
+const MAX_ARCHIVE: u64 = 1 << 30;
+
+/// The archives being sent, by the paths they create, so the nodes whose
+/// changes are the same share one rather than each holding their own.
+#[derive(Default)]
+pub struct Archives {
+    built: tokio::sync::Mutex<Vec<Built>>,
+}
+
+/// The paths an archive creates, and the archive while a node holds it.
+type Built = (Vec<PathBuf>, Weak<Vec<u8>>);
+
+impl Archives {
+    /// Returns the archive of what the changes create, building it unless
+    /// another node is sending the same one.
+    async fn get(
+        &self,
+        tree: &Tree,
+        steps: &[Step],
+        compression: Option<Compression>,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        let created: Vec<PathBuf> = steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::CreateDir { path, .. }
+                | Step::Upload { path, .. }
+                | Step::Symlink { path, .. } => Some(path.clone()),
+                Step::Remove { .. } => None,
+            })
+            .collect();
+
+        // Holding the lock while building keeps the nodes that need the
+        // same archive from building it too
+        let mut built = self.built.lock().await;
+        built.retain(|(_, archive)| archive.strong_count() > 0);
+        let shared = built
+            .iter()
+            .find(|(paths, _)| *paths == created)
+            .and_then(|(_, archive)| archive.upgrade());
+        if let Some(archive) = shared {
+            return Ok(archive);
+        }
+        let archive = Arc::new(build(tree, steps, compression)?);
+        built.push((created, Arc::downgrade(&archive)));
+        Ok(archive)
+    }
+}
+
+/// Brings a directory on the node in line with a local tree, as
+/// `transfer::sync` does, but listing it with `find` and sending the
+/// changes through `tar`.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `tree` - The local tree to mirror.
+/// * `remote` - The directory on the node to mirror it to.
+/// * `options` - Whether to delete extra entries, and the node's digests.
+/// * `archives` - The archives other nodes are being sent.
+/// * `compression` - How to compress the archive, if at all.
+/// * `timeout` - The limit on each command.
+/// * `cancel` - Abandons the commands when the run is cancelled.
+///
+/// # Returns
+///
+/// Returns the totals of what was changed, with the bytes sent being the
+/// archive's size, or an error if there are too many bytes to send in one
+/// archive, or listing or extracting fails.
+#[allow(clippy::too_many_arguments)]
+pub async fn sync(
+    sess: &dyn Session,
+    tree: &Tree,
+    remote: &Path,
+    options: &Options,
+    archives: &Archives,
+    compression: Option<Compression>,
+    timeout: Option<Duration>,
+    cancel: &CancelToken,
+) -> Result<Synced, Box<dyn std::error::Error + Send + Sync>> {
+    let listing = sess
+        .exec(&list_command(remote), None, timeout, cancel, &mut |_, _| {})
+        .await?;
+    let steps = sync::plan(tree, &parse_listing(&listing.stdout), options);
+    let mut synced = Synced::default();
+    if steps.is_empty() {
+        return Ok(synced);
+    }
+
+    let bytes = sync::bytes_to_send(&steps);
+    if bytes > MAX_ARCHIVE {
+        return Err(format!(
+            "--tar would send {} bytes of files in one archive, more than the {} it holds in memory; sync without --tar to send them over SFTP",
+            bytes, MAX_ARCHIVE
+        )
+        .into());
+    }
+
+    // Removing things needs nothing sent, so no archive is extracted
+    let creates = steps
+        .iter()
+        .any(|step| !matches!(step, Step::Remove { .. }));
+    let archive = if creates {
+        archives.get(tree, &steps, compression).await?
+    } else {
+        Arc::default()
+    };
+    let command = extract_command(remote, &steps, creates.then_some(compression));
+    let stdin = creates.then_some(archive.as_slice());
+    let result = sess
+        .exec(&command, stdin, timeout, cancel, &mut |_, _| {})
+        .await?;
+    if result.exit_code != 0 {
+        return Err(format!(
+            "extracting failed with exit status {}: {}",
+            result.exit_code,
+            result.stderr.trim()
+        )
+        .into());
+    }
+    for step in &steps {
+        match step {
+            Step::Remove { .. } => synced.removed += 1,
+            Step::Upload { path, .. } => {
+                synced.files += 1;
+                synced.sent.push(path.clone());
+            }
+            Step::Symlink { .. } => synced.files += 1,
+            Step::CreateDir { .. } => {}
+        }
+    }
+    synced.bytes = archive.len() as u64;
+    Ok(synced)
+}
+
+/// Returns the command that lists everything in a node's copy, as a type,
+/// size, modification time and path, then the symlink target, each ended by
+/// a NUL. A copy that doesn't exist yet lists nothing.
+fn list_command(remote: &Path) -> String {
+    format!(
+        "cd {} 2>/dev/null && find . -mindepth 1 -printf '%y %s %T@ %P\\0%l\\0' || true",
+        shell::quote(&remote.to_string_lossy())
+    )
+}
+
+/// Parses the output of the listing command.
+fn parse_listing(output: &str) -> Vec<Remote> {
+    let mut fields = output.split('\0');
+    let mut listing = Vec::new();
+    while let (Some(entry), Some(target)) = (fields.next(), fields.next()) {
+        let mut parts = entry.splitn(4, ' ');
+        let (Some(kind), Some(size), Some(mtime), Some(path)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let kind = match kind {
+            "d" => Kind::Dir,
+            "f" => Kind::File,
+            "l" => Kind::Symlink(PathBuf::from(target)),
+            _ => Kind::Other,
+        };
+        let mtime = mtime.split('.').next().unwrap_or_default();
+        listing.push(Remote {
+            path: PathBuf::from(path),
+            kind,
+            size: size.parse().unwrap_or_default(),
+            mtime: mtime.parse().unwrap_or_default(),
+        });
+    }
+    listing
+}
+
+/// Builds the archive of the directories, files and symlinks the changes
+/// create, taken from the local tree with their modes and modification times.
+fn build(tree: &Tree, steps: &[Step], compression: Option<Compression>) -> io::Result<Vec<u8>> {
+    match compression {
+        None => append(tree, steps, Vec::new()),
+        Some(Compression::Gzip) => {
+            let encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            append(tree, steps, encoder)?.finish()
+        }
+        Some(Compression::Zstd) => {
+            append(tree, steps, zstd::Encoder::new(Vec::new(), 0)?)?.finish()
+        }
+    }
+}
+
+/// Writes the entries the changes create to a tar stream, and returns what
+/// it was written to.
+fn append<W: Write>(tree: &Tree, steps: &[Step], out: W) -> io::Result<W> {
+    let mut builder = tar::Builder::new(out);
+    builder.follow_symlinks(false);
+    for step in steps {
+        match step {
+            Step::CreateDir { path, .. }
+            | Step::Upload { path, .. }
+            | Step::Symlink { path, .. } => {
+                builder.append_path_with_name(tree.root.join(path), path)?
+            }
+            Step::Remove { .. } => {}
+        }
+    }
+    builder.into_inner()
+}
+
+/// Returns the command that removes what the changes remove, then extracts
+/// the archive from its input into the node's copy, creating the copy if
+/// it doesn't exist yet.
+///
+/// # Arguments
+///
+/// * `remote` - The node's copy.
+/// * `steps` - The changes to make.
+/// * `extract` - How the archive is compressed, or None if there's no
+///   archive to extract.
+fn extract_command(remote: &Path, steps: &[Step], extract: Option<Option<Compression>>) -> String {
+    let mut command = format!(
+        "mkdir -p -- {0} && cd -- {0}",
+        shell::quote(&remote.to_string_lossy())
+    );
+
+    // Removing a directory removes what's in it, so only the outermost of
+    // what's removed is named
+    let removed: HashSet<&Path> = steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Remove { path, .. } => Some(path.as_path()),
+            _ => None,
+        })
+        .collect();
+    let outermost: Vec<String> = steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Remove { path, .. }
+                if !path.ancestors().skip(1).any(|dir| removed.contains(dir)) =>
+            {
+                Some(shell::quote(&path.to_string_lossy()))
+            }
+            _ => None,
+        })
+        .collect();
+    if !outermost.is_empty() {
+        command.push_str(&format!(" && rm -rf -- {}", outermost.join(" ")));
+    }
+
+    if let Some(compression) = extract {
+        let decompress = match compression {
+            None => "",
+            Some(Compression::Gzip) => " -z",
+            Some(Compression::Zstd) => " --zstd",
+        };
+        command.push_str(&format!(" && tar -x -p --no-same-owner{} -f -", decompress));
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn remove(path: &str, dir: bool) -> Step {
+        Step::Remove {
+            path: PathBuf::from(path),
+            dir,
+        }
+    }
+
+    fn upload(path: &str) -> Step {
+        Step::Upload {
+            path: PathBuf::from(path),
+            size: 5,
+            mode: 0o644,
+            mtime: 0,
+        }
+    }
+
+    #[test]
+    fn parses_listings() {
+        let listing = parse_listing(
+            "d 4096 1700000000.1234567890 data\0\0\
+             f 5 1700000001.5 data/a b\0\0\
+             l 3 1700000002.0 link\0a b\0\
+             p 0 1700000003.0 fifo\0\0\
+             truncated\0",
+        );
+        let entries: Vec<(&Path, &Kind, u64, u64)> = listing
+            .iter()
+            .map(|entry| (entry.path.as_path(), &entry.kind, entry.size, entry.mtime))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (Path::new("data"), &Kind::Dir, 4096, 1700000000),
+                (Path::new("data/a b"), &Kind::File, 5, 1700000001),
+                (
+                    Path::new("link"),
+                    &Kind::Symlink(PathBuf::from("a b")),
+                    3,
+                    1700000002
+                ),
+                (Path::new("fifo"), &Kind::Other, 0, 1700000003),
+            ]
+        );
+        assert!(parse_listing("").is_empty());
+    }
+
+    #[test]
+    fn removes_only_the_outermost_of_what_is_removed() {
+        let steps = [
+            remove("a/b/c", false),
+            remove("a/b", true),
+            remove("a", true),
+            remove("ab", false),
+            remove("x y", false),
+            upload("a"),
+        ];
+        assert_eq!(
+            extract_command(Path::new("/srv/my data"), &steps, Some(None)),
+            "mkdir -p -- '/srv/my data' && cd -- '/srv/my data' \
+             && rm -rf -- a ab 'x y' && tar -x -p --no-same-owner -f -"
+        );
+        // Nothing to extract when only removing
+        assert_eq!(
+            extract_command(Path::new("/srv"), &steps[..2], None),
+            "mkdir -p -- /srv && cd -- /srv && rm -rf -- a/b"
+        );
+        assert_eq!(
+            extract_command(
+                Path::new("/srv"),
+                &[upload("a")],
+                Some(Some(Compression::Zstd))
+            ),
+            "mkdir -p -- /srv && cd -- /srv && tar -x -p --no-same-owner --zstd -f -"
+        );
+    }
+
+    #[tokio::test]
+    async fn shares_archives_between_nodes_with_the_same_changes() {
+        let root = env::temp_dir().join(format!("cluster_run-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a"), "hello").unwrap();
+        fs::write(root.join("b"), "world").unwrap();
+        let tree = Tree {
+            root: root.clone(),
+            entries: Vec::new(),
+        };
+
+        let archives = Archives::default();
+        let first = archives.get(&tree, &[upload("a")], None).await.unwrap();
+        // Removals aren't in the archive, so they don't keep it from being shared
+        let steps = [remove("old", false), upload("a")];
+        let second = archives.get(&tree, &steps, None).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        let other = archives.get(&tree, &[upload("b")], None).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &other));
+
+        // Once no node holds an archive it's built afresh
+        let len = first.len();
+        drop((first, second));
+        let again = archives.get(&tree, &[upload("a")], None).await.unwrap();
+        assert_eq!(again.len(), len);
+        assert_eq!(archives.built.lock().await.len(), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Limiting how fast data is sent to and received from the nodes, for
+//! `--bwlimit` and `--bwlimit-per-node`.
+//!
+//! Distributing a large dataset can otherwise fill the management network
+//! that monitoring and other tools depend on. `--bwlimit` caps the bytes a
+//! second moved by every node's transfers together, and `--bwlimit-per-node`
+//! caps each node's. The limits cover the files that `push`, `pull`, `sync`
+//! and `script` copy, and input sent to commands, such as with `--stdin` or
+//! `sync --tar`. Each transfer waits after every chunk until its bytes are
+//! within the limits, so the rates hold on average over a chunk or two.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The most bytes a second moved by all the nodes together, from `--bwlimit`.
+static TOTAL: OnceLock<u64> = OnceLock::new();
+
+/// The most bytes a second moved by each node, from `--bwlimit-per-node`.
+static PER_NODE: OnceLock<u64> = OnceLock::new();
+
+/// When the total limit lets the next bytes be moved.
+static NEXT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Moves at most `bytes_per_second` across all the nodes from now on.
+pub fn use_limit(bytes_per_second: u64) {
+    let _ = TOTAL.set(bytes_per_second);
+}
+
+/// Moves at most `bytes_per_second` to or from each node from now on.
+pub fn use_per_node_limit(bytes_per_second: u64) {
+    let _ = PER_NODE.set(bytes_per_second);
+}
+
+/// The pace of one transfer with a node.
+#[derive(Default)]
+pub struct Pace {
+    /// When the per-node limit lets the transfer's next bytes be moved.
+    next: Option<Instant>,
+}
+
+impl Pace {
+    /// Notes that bytes were just moved.
+    ///
+    /// # Returns
+    ///
+    /// Returns when the next bytes may be moved, or None if there's no limit.
+    pub fn after(&mut self, bytes: usize) -> Option<Instant> {
+        let now = Instant::now();
+        let mut ready = None;
+
+        // Step 1: The node's own bytes follow on from its last ones
+        if let Some(&rate) = PER_NODE.get() {
+            let next = self.next.map_or(now, |next| next.max(now)) + time_for(bytes, rate);
+            self.next = Some(next);
+            ready = Some(next);
+        }
+
+        // Step 2: Every node's bytes take their turn within the total
+        if let Some(&rate) = TOTAL.get() {
+            let mut total = NEXT.lock().unwrap();
+            let next = total.map_or(now, |next| next.max(now)) + time_for(bytes, rate);
+            *total = Some(next);
+            ready = Some(ready.map_or(next, |ready: Instant| ready.max(next)));
+        }
+        ready
+    }
+
+    /// Notes that bytes were just moved, and blocks the thread until the
+    /// next may be.
+    pub fn pause(&mut self, bytes: usize) {
+        if let Some(ready) = self.after(bytes) {
+            std::thread::sleep(ready.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    /// Notes that bytes were just moved, and waits until the next may be.
+    pub async fn wait(&mut self, bytes: usize) {
+        if let Some(ready) = self.after(bytes) {
+            tokio::time::sleep_until(ready.into()).await;
+        }
+    }
+}
+
+/// Returns how long moving the bytes at the rate takes.
+fn time_for(bytes: usize, bytes_per_second: u64) -> Duration {
+    Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64)
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Cooperative cancellation of a run.
+//!
+//! A CancelToken is shared by everything working on a run. Once it's
+//! cancelled, no new nodes are started, retries stop, and commands that are
+//! already running have their channels closed the next time their output is
+//! polled.
+//!
+//! The token for a whole invocation is cancelled by SIGINT or SIGTERM, so
+//! interrupting cluster_run stops the remote commands it started instead of
+//! leaving them running.
+
+use std::fmt;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often a cancellable sleep checks whether it's been cancelled.
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+/// The exit status used when cluster_run is interrupted by a signal.
+pub const INTERRUPTED_EXIT: u8 = 130;
+
+/// A shared flag used to stop a run early.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Cancels the run. Every clone of the token observes the cancellation.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once the run has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Cancels the token when the process receives SIGINT or SIGTERM.
+    ///
+    /// A second signal exits immediately, in case cleaning up is itself stuck.
+    pub fn cancel_on_signal(&self) -> Result<(), ctrlc::Error> {
+        let token = self.clone();
+        ctrlc::set_handler(move || {
+            if token.is_cancelled() {
+                process::exit(INTERRUPTED_EXIT.into());
+            }
+            tracing::warn!(
+                "Interrupted, cancelling in-flight commands (interrupt again to exit now)"
+            );
+            token.cancel();
+        })
+    }
+
+    /// Sleeps for the given duration, waking early if the run is cancelled.
+    ///
+    /// # Returns
+    ///
+    /// Returns false if the sleep was cut short by cancellation.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_cancelled() {
+                return false;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return true;
+            }
+            thread::sleep(remaining.min(SLEEP_SLICE));
+        }
+    }
+
+    /// Sleeps for the given duration, as `sleep` does, without blocking the
+    /// thread.
+    ///
+    /// # Returns
+    ///
+    /// Returns false if the sleep was cut short by cancellation.
+    pub async fn pause(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => !self.is_cancelled(),
+            _ = self.cancelled() => false,
+        }
+    }
+
+    /// Waits until the run is cancelled.
+    pub async fn cancelled(&self) {
+        while !self.is_cancelled() {
+            tokio::time::sleep(SLEEP_SLICE).await;
+        }
+    }
+}
+
+/// The error returned for work that was abandoned because the run was cancelled.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
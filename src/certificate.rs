@@ -0,0 +1,196 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! OpenSSH user certificates, which a CA signs to let a key log in for a
+//! limited time as a limited set of users.
+//!
+//! A key's certificate sits beside it as `<key>-cert.pub`, such as
+//! `~/.ssh/id_ed25519-cert.pub`, and is offered along with the key as OpenSSH
+//! offers it. Servers don't say why they turn a certificate down, so its
+//! validity and principals are checked before it's offered, to give a clear
+//! error when it has expired or wasn't issued for the user.
+
+use crate::output;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The suffix that turns a private key's path into its certificate's.
+const SUFFIX: &str = "-cert.pub";
+
+/// The `valid_before` of a certificate that never expires.
+const FOREVER: u64 = u64::MAX;
+
+/// The parts of a certificate that decide whom it can log in as, and when.
+pub struct Certificate {
+    pub path: PathBuf,
+    /// The users the certificate can log in as; empty means any user.
+    pub principals: Vec<String>,
+    /// The Unix time the certificate becomes valid.
+    pub valid_after: u64,
+    /// The Unix time the certificate stops being valid.
+    pub valid_before: u64,
+}
+
+/// Returns the path of a private key's certificate, if it has one.
+pub fn of_key(privkey: &Path) -> Option<PathBuf> {
+    let mut path = privkey.as_os_str().to_owned();
+    path.push(SUFFIX);
+    let path = PathBuf::from(path);
+    path.exists().then_some(path)
+}
+
+/// Returns true if a public key file is a certificate.
+pub fn is_certificate(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(SUFFIX)
+}
+
+impl Certificate {
+    /// Reads a certificate file, as `ssh-keygen -s` writes it.
+    ///
+    /// # Returns
+    ///
+    /// Returns the certificate, or an error if the file can't be read or
+    /// isn't a user certificate.
+    pub fn read(path: &Path) -> Result<Certificate, String> {
+        let invalid = |e: &str| format!("certificate {} is invalid: {}", path.display(), e);
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("unable to read certificate {}: {}", path.display(), e))?;
+        let mut fields = content.split_whitespace();
+        let (Some(cert_type), Some(body)) = (fields.next(), fields.next()) else {
+            return Err(invalid(
+                "it should be a key type followed by the certificate",
+            ));
+        };
+        let blob = STANDARD
+            .decode(body)
+            .map_err(|_| invalid("the certificate isn't base64"))?;
+
+        // Step 1: Skip the type, the nonce and the key, whose fields depend
+        // on the type of key
+        let key_fields = match cert_type {
+            "ssh-ed25519-cert-v01@openssh.com" => 1,
+            "ecdsa-sha2-nistp256-cert-v01@openssh.com"
+            | "ecdsa-sha2-nistp384-cert-v01@openssh.com"
+            | "ecdsa-sha2-nistp521-cert-v01@openssh.com"
+            | "ssh-rsa-cert-v01@openssh.com"
+            | "sk-ssh-ed25519-cert-v01@openssh.com" => 2,
+            "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com" => 3,
+            "ssh-dss-cert-v01@openssh.com" => 4,
+            _ => return Err(invalid(&format!("{} isn't a certificate type", cert_type))),
+        };
+        let mut reader = Reader(&blob);
+        let header = || invalid("the certificate is truncated");
+        for _ in 0..2 + key_fields {
+            reader.string().ok_or_else(header)?;
+        }
+
+        // Step 2: Read the serial, type, key ID, principals and validity
+        reader.u64().ok_or_else(header)?;
+        if reader.u32().ok_or_else(header)? != 1 {
+            return Err(invalid("it's a host certificate, not a user certificate"));
+        }
+        reader.string().ok_or_else(header)?;
+        let mut packed = Reader(reader.string().ok_or_else(header)?);
+        let mut principals = Vec::new();
+        while !packed.0.is_empty() {
+            let principal = packed.string().ok_or_else(header)?;
+            principals.push(String::from_utf8_lossy(principal).into_owned());
+        }
+        Ok(Certificate {
+            path: path.to_path_buf(),
+            principals,
+            valid_after: reader.u64().ok_or_else(header)?,
+            valid_before: reader.u64().ok_or_else(header)?,
+        })
+    }
+
+    /// Checks that the certificate is valid now and can log in as a user.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error saying why the certificate would be turned down.
+    pub fn check(&self, user: &str) -> Result<(), String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        if now >= self.valid_before {
+            return Err(format!(
+                "certificate {} expired at {}; ask the CA for a new one",
+                self.path.display(),
+                format_time(self.valid_before)
+            ));
+        }
+        if now < self.valid_after {
+            return Err(format!(
+                "certificate {} isn't valid until {}",
+                self.path.display(),
+                format_time(self.valid_after)
+            ));
+        }
+        if !self.principals.is_empty() && !self.principals.iter().any(|p| p == user) {
+            return Err(format!(
+                "certificate {} can't log in as {}, only as {}",
+                self.path.display(),
+                user,
+                self.principals.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the error for a node that turned the certificate down, though
+    /// it was valid for the user.
+    pub fn turned_down(&self, host: &str) -> String {
+        format!(
+            "{} turned down certificate {}; check that it trusts the CA that signed it",
+            host,
+            self.path.display()
+        )
+    }
+
+    /// Returns when the certificate expires, or None if it never does.
+    pub fn expires(&self) -> Option<String> {
+        (self.valid_before != FOREVER).then(|| format_time(self.valid_before))
+    }
+}
+
+/// Reads the big-endian fields of a certificate.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    /// Reads a length-prefixed string, or an mpint, which is written the same.
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(usize::try_from(len).ok()?)
+    }
+}
+
+/// Formats a Unix time as a UTC date and time.
+fn format_time(secs: u64) -> String {
+    let time = output::Utc::from_secs(secs);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        time.year, time.month, time.day, time.hour, time.minute
+    )
+}
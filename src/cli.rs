@@ -0,0 +1,1266 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Command-line interface definitions.
+//!
+//! Every operation is a subcommand. Options that control how nodes are
+//! connected to are global, so they may be given before or after the
+//! subcommand name. Remote commands are taken verbatim from the end of the
+//! command line, optionally after a `--` separator, so their flags are never
+//! mistaken for ours.
+
+use crate::config::{self, ClusterConfig, Source};
+use crate::history::Wrapping;
+use crate::host_key::Checking;
+use crate::inventory::NodeSource;
+use crate::notify::Webhook;
+use crate::redact;
+use crate::retry::RetryPolicy;
+use crate::select::Selection;
+use crate::shell::{self, Sudo};
+use crate::spill;
+use crate::ssh::Timeouts;
+use crate::transport;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use regex::Regex;
+use std::env;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Run commands across the nodes of a cluster over SSH.
+#[derive(Parser)]
+#[command(name = "cluster_run", version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub source: SourceArgs,
+
+    #[command(flatten)]
+    pub connection: ConnectionArgs,
+
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+/// The operations cluster_run can perform.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Execute a command on every node
+    Exec(ExecArgs),
+    /// Upload a local script to every node, run it, and remove it afterwards
+    Script(ScriptArgs),
+    /// List the nodes in the cluster
+    List,
+    /// Check which nodes complete an SSH handshake, and how quickly
+    Ping,
+    /// Upload a file to every node
+    Push(PushArgs),
+    /// Download a file or directory from every node into per-node directories
+    Pull(PullArgs),
+    /// Mirror a local directory to every node, sending only the files that changed
+    Sync(SyncArgs),
+    /// Re-run a command on every node periodically and show the latest output
+    Watch(WatchArgs),
+    /// Execute a command on just one of the nodes
+    One(OneArgs),
+    /// Work with the configuration file
+    Config(ConfigArgs),
+    /// Keep nodes out of every run, e.g. while they await repair
+    Quarantine(QuarantineArgs),
+    /// Work with the encrypted secrets in the configuration file
+    Secret(SecretArgs),
+    /// List, show and compare past runs
+    History(HistoryArgs),
+    /// Run the command of a past run again on the same nodes
+    Rerun(RerunArgs),
+}
+
+/// Options choosing where the configuration and nodes come from, shared by
+/// all subcommands.
+#[derive(Args)]
+#[command(next_help_heading = "Configuration")]
+pub struct SourceArgs {
+    /// Read the cluster configuration from PATH [env: CLUSTER_RUN_CONFIG]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Use the cluster with this name, for configs that describe several.
+    /// Give it more than once to run on each cluster in turn
+    #[arg(long, global = true, value_name = "NAME")]
+    pub cluster: Vec<String>,
+
+    /// Run on every cluster in the config in turn
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["cluster", "inventory", "hosts", "hosts_file"]
+    )]
+    pub all_clusters: bool,
+
+    /// Take the nodes and groups from an Ansible inventory file in INI or YAML
+    /// format instead of the config, or from a Kubernetes cluster's nodes if `k8s`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub inventory: Option<PathBuf>,
+
+    /// The kubeconfig context to list nodes from with `--inventory k8s`
+    #[arg(long, global = true, value_name = "CONTEXT", requires = "inventory")]
+    pub context: Option<String>,
+
+    /// Only list Kubernetes nodes matching this label selector, e.g. `gpu=true`
+    #[arg(long, global = true, value_name = "SELECTOR", requires = "inventory")]
+    pub label_selector: Option<String>,
+
+    /// Work on these comma-separated nodes instead of the config's, or on the
+    /// newline-separated nodes on standard input if `-`
+    #[arg(
+        long,
+        global = true,
+        value_name = "NODES",
+        value_delimiter = ',',
+        conflicts_with_all = ["inventory", "hosts_file"]
+    )]
+    pub hosts: Vec<String>,
+
+    /// Work on the newline-separated nodes in PATH instead of the config's
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "inventory")]
+    pub hosts_file: Option<PathBuf>,
+
+    /// Set the config variable NAME, used as `{{ NAME }}`, overriding `[vars]`
+    #[arg(
+        long = "var",
+        short = 'e',
+        global = true,
+        value_name = "NAME=VALUE",
+        value_parser = parse_var
+    )]
+    pub vars: Vec<(String, String)>,
+}
+
+impl SourceArgs {
+    /// Returns the source described by the options, for one of the clusters
+    /// to run on.
+    ///
+    /// # Arguments
+    ///
+    /// * `cluster` - The cluster, one of those returned by `clusters`.
+    pub fn source<'a>(&'a self, cluster: Option<&'a str>) -> Source<'a> {
+        Source {
+            config: self.config.as_deref(),
+            cluster,
+            nodes: self.node_source(),
+            vars: &self.vars,
+        }
+    }
+
+    /// Returns the clusters to run on, in turn.
+    ///
+    /// # Returns
+    ///
+    /// Returns the clusters named with `--cluster` or, with `--all-clusters`,
+    /// every cluster in the config, or a single None for the config's default
+    /// cluster. Returns an error if several clusters would share the nodes
+    /// given on the command line, or the config can't be read.
+    pub fn clusters(&self) -> Result<Vec<Option<String>>, String> {
+        if self.all_clusters {
+            let names = config::cluster_names(self.config.as_deref())?;
+            return Ok(names.into_iter().map(Some).collect());
+        }
+        if self.cluster.len() > 1 && self.node_source().is_some() {
+            return Err(
+                "Several clusters can't share the nodes given with --hosts or --inventory".into(),
+            );
+        }
+        if self.cluster.is_empty() {
+            return Ok(vec![None]);
+        }
+        Ok(self.cluster.iter().cloned().map(Some).collect())
+    }
+
+    /// Returns where the nodes come from, if not from the config.
+    pub fn node_source(&self) -> Option<NodeSource<'_>> {
+        if self.inventory.as_deref() == Some(Path::new("k8s")) {
+            Some(NodeSource::Kubernetes {
+                context: self.context.as_deref(),
+                label_selector: self.label_selector.as_deref(),
+            })
+        } else if let Some(path) = &self.inventory {
+            Some(NodeSource::Inventory(path))
+        } else if let Some(path) = &self.hosts_file {
+            Some(NodeSource::HostsFile(path))
+        } else if !self.hosts.is_empty() {
+            Some(NodeSource::Hosts(&self.hosts))
+        } else {
+            None
+        }
+    }
+}
+
+impl Command {
+    /// Returns true if the subcommand reads standard input to forward it.
+    pub fn forwards_stdin(&self) -> bool {
+        match self {
+            Command::Exec(args) => args.run.stdin,
+            Command::Script(args) => args.run.stdin,
+            Command::One(args) => args.exec.run.stdin,
+            Command::Rerun(args) => args.run.stdin,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the subcommand logs in to the nodes, rather than only
+    /// listing them or checking that they're reachable.
+    pub fn logs_in(&self) -> bool {
+        !matches!(
+            self,
+            Command::List
+                | Command::Ping
+                | Command::Config(_)
+                | Command::Quarantine(_)
+                | Command::Secret(_)
+                | Command::History(_)
+        )
+    }
+}
+
+/// Options controlling how nodes are connected to, shared by all subcommands.
+#[derive(Args)]
+#[command(next_help_heading = "Connection Options")]
+pub struct ConnectionArgs {
+    /// Work on at most N nodes at once [default: all nodes]
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 0,
+        hide_default_value = true
+    )]
+    pub parallel: usize,
+
+    /// Give up connecting to a node after SECS seconds, overriding the config
+    #[arg(long, global = true, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+
+    /// Start at most N new connections a second, given as N or N/s, so
+    /// sshd's MaxStartups isn't hit; commands still run side by side
+    #[arg(long, global = true, value_name = "N/s", value_parser = parse_rate)]
+    pub connect_rate: Option<f64>,
+
+    /// Move at most RATE bytes a second to and from all the nodes together,
+    /// such as 50MB/s, when copying files or sending command input
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_bandwidth)]
+    pub bwlimit: Option<u64>,
+
+    /// Move at most RATE bytes a second to and from each node, which can't
+    /// be more than --bwlimit
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_bandwidth)]
+    pub bwlimit_per_node: Option<u64>,
+
+    /// Hold each new connection back by a random time of up to SECS seconds
+    #[arg(long, global = true, value_name = "SECS", value_parser = parse_secs)]
+    pub connect_jitter: Option<Duration>,
+
+    /// Abandon a command that runs longer than SECS seconds, overriding the config
+    #[arg(long, global = true, value_name = "SECS")]
+    pub command_timeout: Option<u64>,
+
+    /// Authenticate with the private key in PATH, in place of the config's
+    /// keys and the default ones in ~/.ssh
+    #[arg(long, short = 'i', global = true, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Reach every node through these jump hosts, given as [user@]host[:port]
+    /// separated by commas, overriding the config; `none` connects directly
+    #[arg(long, global = true, value_name = "HOSTS")]
+    pub jump: Option<String>,
+
+    /// Read the passphrase of encrypted keys from environment variable VAR,
+    /// rather than asking for each key's passphrase
+    #[arg(long, global = true, value_name = "VAR")]
+    pub passphrase_env: Option<String>,
+
+    /// Ask for a password once, and log in with it to nodes that turn down
+    /// every key, by password or keyboard-interactive authentication
+    #[arg(long, global = true)]
+    pub ask_pass: bool,
+
+    /// Let commands on the nodes use the local ssh-agent, as `ssh -A` does;
+    /// anyone with root on a node can use it while the command runs
+    #[arg(long, global = true)]
+    pub forward_agent: bool,
+
+    /// Whether to connect to nodes that aren't in ~/.ssh/known_hosts, adding
+    /// them with accept-new; `no` skips checking host keys at all. Nodes
+    /// copying from each other for `push --fanout` check their peers the same
+    /// way
+    #[arg(long, global = true, value_name = "MODE", value_enum, default_value_t = Checking::AcceptNew)]
+    pub strict_host_key_checking: Checking,
+
+    /// What to open sessions with nodes over: russh, with every node on a few
+    /// threads, libssh2, with a thread per busy node, or openssh, running the
+    /// system's ssh with its own config
+    #[arg(long, global = true, value_name = "TRANSPORT", value_enum, default_value_t = transport::Kind::Russh)]
+    pub transport: transport::Kind,
+
+    /// Send a keepalive every SECS seconds while waiting on a node, and treat
+    /// the connection as lost once three go unacknowledged
+    #[arg(long, global = true, value_name = "SECS", value_parser = parse_secs)]
+    pub keepalive: Option<Duration>,
+
+    /// Retry a node up to N times if connecting or copying files fails
+    #[arg(long, global = true, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// Also retry a node whose command failed after it started, such as by
+    /// timing out, which runs the command again; only for commands that are
+    /// safe to repeat
+    #[arg(long, global = true, requires = "retries")]
+    pub retry_started: bool,
+
+    /// Wait SECS seconds before the first retry, doubling for each retry after it
+    #[arg(long, global = true, value_name = "SECS", default_value = "1", value_parser = parse_secs)]
+    pub retry_backoff: Duration,
+
+    /// Print how each node was connected to and logged into, and how long
+    /// each step took; give it twice to also print the SSH libraries' messages
+    #[arg(long, short = 'v', global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Print only failures and the summary
+    #[arg(long, short = 'q', global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Whether to color the output
+    #[arg(long, global = true, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Mask text matching the regular expression PATTERN, such as a token's
+    /// format, in the output, logs and history; may be given more than once
+    #[arg(long, global = true, value_name = "PATTERN")]
+    pub redact: Vec<String>,
+
+    /// Mask the value of the local environment variable VAR in the output,
+    /// logs and history; may be given more than once
+    #[arg(long, global = true, value_name = "VAR")]
+    pub redact_env: Vec<String>,
+
+    /// Keep at most SIZE of each command's stdout and of its stderr in
+    /// memory, such as 512K or 1G, writing all of it to a temporary file past
+    /// that; 0 keeps everything [default: 10M]
+    #[arg(
+        long,
+        global = true,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value_t = spill::DEFAULT_MAX_OUTPUT,
+        hide_default_value = true
+    )]
+    pub max_output: usize,
+
+    /// Print how each node would be connected to and what would run on it, without connecting
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+/// Options narrowing which nodes are worked on, shared by all subcommands.
+#[derive(Args)]
+#[command(next_help_heading = "Node Selection")]
+pub struct SelectionArgs {
+    /// Only work on nodes in this group, or in the result of combining groups
+    /// with `+` (either), `&` (both) and `-` (but not), e.g. `gpu - head`
+    #[arg(long, global = true, value_name = "GROUPS")]
+    pub group: Option<String>,
+
+    /// Only work on nodes whose labels match every comma-separated condition:
+    /// `key=value`, `key!=value`, `key` (has the label) or `!key` (lacks it)
+    #[arg(long, global = true, value_name = "LABELS", value_delimiter = ',')]
+    pub selector: Vec<String>,
+
+    /// Only work on nodes matching one of these comma-separated names,
+    /// ranges or globs, e.g. `gpu-*` or `node[001-064]`
+    #[arg(long, global = true, value_name = "NODES")]
+    pub limit: Vec<String>,
+
+    /// Skip nodes matching any of these comma-separated names, ranges or globs
+    #[arg(long, global = true, value_name = "NODES")]
+    pub exclude: Vec<String>,
+
+    /// Work on quarantined nodes too, rather than skipping them
+    #[arg(long, global = true)]
+    pub include_quarantined: bool,
+}
+
+impl SelectionArgs {
+    /// Returns the selection described by the options.
+    pub fn selection(&self) -> Selection<'_> {
+        Selection {
+            group: self.group.as_deref(),
+            selector: &self.selector,
+            limit: &self.limit,
+            exclude: &self.exclude,
+        }
+    }
+}
+
+/// Options for reporting output and deciding success, shared by the
+/// subcommands that run something on each node.
+#[derive(Args, Clone)]
+pub struct RunArgs {
+    /// Print output line by line as it arrives, prefixed with the node name
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Only print the lines of output matching PATTERN, a regular
+    /// expression, or any of them if given more than once; the logs, JSON
+    /// results and history keep everything
+    #[arg(long, value_name = "PATTERN", value_parser = parse_regex)]
+    pub grep: Vec<Regex>,
+
+    /// Don't print the lines of output matching PATTERN; may be given more
+    /// than once
+    #[arg(long, value_name = "PATTERN", value_parser = parse_regex)]
+    pub grep_v: Vec<Regex>,
+
+    /// Start each streamed line, and each line in the --log-dir files, with
+    /// the time it arrived, to the microsecond
+    #[arg(long)]
+    pub timestamps: bool,
+
+    /// Give the --timestamps in UTC rather than the local time
+    #[arg(long, requires = "timestamps")]
+    pub utc: bool,
+
+    /// Show a live progress bar of how many nodes have finished, are running
+    /// and failed, and which have been running longest, if standard error
+    /// is a terminal
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Print how long each node took to connect and run, slowest first
+    #[arg(long)]
+    pub timing: bool,
+
+    /// How to print each node's result
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Also write each node's stdout and stderr, and a summary.json, to a
+    /// timestamped directory in DIR
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Also write the exact bytes of each node's stdout and stderr to
+    /// <node>.stdout and <node>.stderr in DIR, for binary output; nothing
+    /// in them is masked by --redact
+    #[arg(long, value_name = "DIR")]
+    pub raw: Option<PathBuf>,
+
+    /// Once every node has finished, print how each node's output differs
+    /// from a baseline node's as a unified diff, to find the nodes that drifted
+    #[arg(long, conflicts_with_all = ["stream", "output"])]
+    pub diff: bool,
+
+    /// Compare the nodes against NODE with --diff, rather than against the
+    /// output most nodes share
+    #[arg(long, value_name = "NODE", requires = "diff")]
+    pub baseline: Option<String>,
+
+    /// Don't record the run in the history
+    #[arg(long)]
+    pub no_history: bool,
+
+    /// Write an event for each node starting, each chunk of output, each
+    /// node finishing and the run's summary as it happens, as ndjson to
+    /// standard output in place of the usual output, or as ndjson=PATH to a
+    /// file or named pipe
+    #[arg(long, value_name = "FORMAT[=PATH]", value_parser = parse_events)]
+    pub events: Option<(EventFormat, Option<PathBuf>)>,
+
+    /// Log the run's start, each node's result and the summary to journald,
+    /// or to syslog where journald isn't running, with structured fields
+    #[arg(long)]
+    pub syslog: bool,
+
+    /// Write a report treating each node as a test case, as junit=PATH for
+    /// JUnit XML or tap=PATH for TAP; may be given more than once
+    #[arg(long, value_name = "FORMAT=PATH", value_parser = parse_report)]
+    pub report: Vec<(ReportFormat, PathBuf)>,
+
+    /// Post the run's summary to a webhook once it's over, given as
+    /// slack://..., discord://... or an http(s) URL for JSON; may be given
+    /// more than once, adding to the config's [notify] webhooks
+    #[arg(long, value_name = "URL", value_parser = Webhook::parse)]
+    pub notify: Vec<Webhook>,
+
+    /// Only notify once N nodes have failed, as soon as they have and again
+    /// with the summary
+    #[arg(long, value_name = "N")]
+    pub notify_min_failures: Option<usize>,
+
+    /// Succeed as long as at least one node succeeds
+    #[arg(long)]
+    pub ok_if_any: bool,
+
+    /// Tolerate up to N failed nodes before exiting non-zero
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_failures: usize,
+
+    /// Run the command in DIR on each node
+    #[arg(long, value_name = "DIR")]
+    pub cwd: Option<String>,
+
+    /// Set an environment variable for the command; may be given more than once
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
+    /// Run the command as root with `sudo -n`, or `sudo -A` if a password is given
+    #[arg(long)]
+    pub sudo: bool,
+
+    /// Read the sudo password from environment variable VAR; implies --sudo
+    #[arg(long, value_name = "VAR", conflicts_with = "ask_sudo_password")]
+    pub sudo_password_env: Option<String>,
+
+    /// Prompt for the sudo password before the run starts; implies --sudo
+    #[arg(long)]
+    pub ask_sudo_password: bool,
+
+    /// Read all of standard input and give a copy of it to the command on every node
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Run on the first N nodes alone, then ask before continuing to the rest
+    #[arg(long, value_name = "N", default_value_t = 0, hide_default_value = true)]
+    pub canary: usize,
+
+    /// Continue past the canary nodes without asking
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
+    /// Run on N nodes at a time, waiting for each batch to finish before starting the next
+    #[arg(long, value_name = "N", default_value_t = 0, hide_default_value = true)]
+    pub batch_size: usize,
+
+    /// Wait SECS seconds between batches
+    #[arg(long, value_name = "SECS", default_value = "0", value_parser = parse_secs)]
+    pub batch_pause: Duration,
+
+    /// Skip the remaining batches once a batch has any failed nodes
+    #[arg(long)]
+    pub abort_on_batch_failure: bool,
+
+    /// Cancel in-flight and pending nodes as soon as any node fails
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Cancel in-flight and pending nodes once more than PERCENT of the nodes have failed
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub max_failure_percent: Option<f64>,
+}
+
+/// When to color the output, from `--color`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// When printing to a terminal, unless NO_COLOR is set
+    Auto,
+    /// Always, even when the output is piped
+    Always,
+    /// Never
+    Never,
+}
+
+/// The formats `--report` writes, for CI systems' test views.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// JUnit XML, as GitLab and Jenkins read
+    Junit,
+    /// The Test Anything Protocol, version 13
+    Tap,
+}
+
+/// The formats `--events` writes.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line for each event
+    Ndjson,
+}
+
+/// How the results of a run are printed.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Each node's output under a header, then a summary table
+    Text,
+    /// One JSON object per line for each node's result, then one for the
+    /// summary, with everything else on standard error
+    Json,
+    /// Each distinct output once, under the nodes that gave it, after every
+    /// node has finished
+    Grouped,
+    /// Each node's output under a header, as with text, but in the order of
+    /// the node list, each held back until the nodes before it have finished
+    Ordered,
+    /// A row for each node, with its exit code, duration and first line of
+    /// output, as comma-separated values
+    Csv,
+    /// The same rows as csv, separated by tabs
+    Tsv,
+}
+
+/// Options for the exec subcommand.
+#[derive(Args)]
+pub struct ExecArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Keep the command running on the node if the connection is lost, and
+    /// reconnect up to N times to pick its output up where it stopped
+    #[arg(long, value_name = "N", default_value_t = 0, hide_default_value = true)]
+    pub reconnect: u32,
+
+    /// The command to execute, e.g. `-- ls -l /var/log`; `{host}` and `{index}` are filled in per node
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// Options for the one subcommand.
+#[derive(Args)]
+pub struct OneArgs {
+    /// How to choose the node to run on
+    #[arg(long, value_enum, default_value_t = Pick::First)]
+    pub pick: Pick,
+
+    #[command(flatten)]
+    pub exec: ExecArgs,
+}
+
+/// How the one subcommand chooses its node.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Pick {
+    /// The first selected node
+    First,
+    /// A node chosen at random
+    Random,
+    /// The node that was least recently picked by `one`
+    Lru,
+}
+
+/// Options for the script subcommand.
+#[derive(Args)]
+pub struct ScriptArgs {
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// The local script to run on each node
+    pub script: PathBuf,
+
+    /// Arguments to pass to the script
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+/// Options for the push subcommand.
+#[derive(Args)]
+pub struct PushArgs {
+    /// The local file to upload
+    pub local: PathBuf,
+
+    /// Where to put the file on each node, which may use placeholders; a path ending in `/` keeps the local file name
+    pub remote: String,
+
+    /// Give the uploaded file these permissions, in octal [default: those of the local file]
+    #[arg(long, value_parser = parse_mode)]
+    pub mode: Option<u32>,
+
+    /// Change the owner of the uploaded file, as USER or USER:GROUP
+    #[arg(long)]
+    pub owner: Option<String>,
+
+    /// Upload by way of a partial file beside the destination, so an
+    /// interrupted push picks up where each node left off when run again
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Upload to only the first N nodes, and have every node that has the
+    /// file copy it on to another over SSH, so the local uplink carries it
+    /// N times rather than once per node
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub fanout: Option<usize>,
+
+    /// The address nodes copy the file from each other at, which may use
+    /// placeholders, such as their interconnect's
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = "{host}",
+        requires = "fanout"
+    )]
+    pub peer_address: String,
+
+    /// Check the uploaded file's digest on each node against the local file's
+    #[arg(long, value_name = "ALGORITHM")]
+    pub verify: Option<ChecksumAlgorithm>,
+}
+
+/// Options for the pull subcommand.
+#[derive(Args)]
+pub struct PullArgs {
+    /// The file or directory to download from each node
+    pub remote: String,
+
+    /// The directory to download into, as `<local-dir>/<node>/...` unless it uses placeholders
+    pub local_dir: PathBuf,
+}
+
+/// Options for the sync subcommand.
+#[derive(Args)]
+pub struct SyncArgs {
+    /// The local directory to mirror
+    pub local: PathBuf,
+
+    /// The directory to mirror it to on each node, which may use placeholders
+    pub remote: String,
+
+    /// Remove whatever on the node isn't in the local directory
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Compare files by their SHA-256 digest rather than their modification time
+    #[arg(long, short = 'c')]
+    pub checksum: bool,
+
+    /// Send the changes as a tar stream through `tar -x` on each node, which
+    /// is much faster than SFTP for many small files
+    #[arg(long)]
+    pub tar: bool,
+
+    /// Compress the tar stream
+    #[arg(long, value_name = "ALGORITHM", requires = "tar")]
+    pub compress: Option<Compression>,
+
+    /// Check the digests of the files sent on each node against the local files'
+    #[arg(long, value_name = "ALGORITHM")]
+    pub verify: Option<ChecksumAlgorithm>,
+}
+
+/// How `--verify` checks the files sent.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    /// SHA-256, worked out on the nodes with sha256sum
+    Sha256,
+}
+
+/// How `sync --tar` compresses its tar stream.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// gzip, which every tar can decompress
+    Gzip,
+    /// zstd, which is faster, but needs GNU tar 1.31 or later and zstd on the nodes
+    Zstd,
+}
+
+/// Options for the watch subcommand.
+#[derive(Args)]
+pub struct WatchArgs {
+    /// Wait SECS seconds between the start of one run and the next
+    #[arg(long, short = 'n', value_name = "SECS", default_value = "2", value_parser = parse_secs)]
+    pub interval: Duration,
+
+    /// Stop after N runs instead of running until interrupted
+    #[arg(long, value_name = "N")]
+    pub count: Option<u32>,
+
+    /// The command to run, e.g. `-- nvidia-smi`
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<String>,
+}
+
+/// Options for the config subcommand.
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+/// The operations on the configuration file.
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Check the config for mistakes, including in clusters other than the
+    /// one a run would use, and resolve every node's address
+    Validate,
+    /// Rewrite the config and the files it includes in the current version of
+    /// the format, keeping each original with a .bak suffix
+    Migrate,
+    /// Print the selected nodes as an OpenSSH config fragment or an
+    /// /etc/hosts block, so plain `ssh` reaches them the same way
+    Export {
+        /// What to print the nodes as
+        #[arg(long, value_enum)]
+        format: ExportFormat,
+    },
+}
+
+/// The formats `config export` can print the nodes in.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// `Host` entries for ~/.ssh/config, with each node's address, user,
+    /// port and key
+    SshConfig,
+    /// Lines for /etc/hosts mapping each node's IP address to its names
+    Hosts,
+}
+
+/// Options for the quarantine subcommand.
+#[derive(Args)]
+pub struct QuarantineArgs {
+    #[command(subcommand)]
+    pub command: QuarantineCommand,
+}
+
+/// The operations on the quarantine list.
+#[derive(Subcommand)]
+pub enum QuarantineCommand {
+    /// Quarantine nodes, so that every run skips them
+    Add {
+        /// The addresses of the nodes
+        #[arg(required = true)]
+        nodes: Vec<String>,
+
+        /// Why the nodes are quarantined, shown by `quarantine list`
+        #[arg(long, value_name = "TEXT")]
+        reason: Option<String>,
+    },
+    /// Release nodes from quarantine
+    Remove {
+        /// The addresses of the nodes
+        #[arg(required = true)]
+        nodes: Vec<String>,
+    },
+    /// List the quarantined nodes
+    List,
+}
+
+/// Options for the history subcommand.
+#[derive(Args)]
+pub struct HistoryArgs {
+    #[command(subcommand)]
+    pub command: HistoryCommand,
+}
+
+/// The operations on the history of past runs.
+#[derive(Subcommand)]
+pub enum HistoryCommand {
+    /// List the most recent runs, newest first
+    List {
+        /// List only the last N runs
+        #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+        last: usize,
+    },
+    /// Show how each node did in a run
+    Show {
+        /// The run's ID, as `history list` shows it
+        run: i64,
+
+        /// Print each node's full output, rather than its first line
+        #[arg(long)]
+        full: bool,
+    },
+    /// Compare each node's result in one run with its result in another
+    Diff {
+        /// The ID of the earlier run
+        old: i64,
+        /// The ID of the later run
+        new: i64,
+    },
+}
+
+/// Options for the rerun subcommand.
+#[derive(Args)]
+pub struct RerunArgs {
+    /// The ID of the run to repeat, as `history list` shows it
+    #[arg(value_name = "RUN")]
+    pub id: i64,
+
+    /// Run only on the nodes that didn't succeed, including any the run
+    /// never got to
+    #[arg(long)]
+    pub only_failed: bool,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    /// Keep the command running on the node if the connection is lost, and
+    /// reconnect up to N times to pick its output up where it stopped
+    #[arg(long, value_name = "N", default_value_t = 0, hide_default_value = true)]
+    pub reconnect: u32,
+}
+
+/// Options for the secret subcommand.
+#[derive(Args)]
+pub struct SecretArgs {
+    #[command(subcommand)]
+    pub command: SecretCommand,
+}
+
+/// The operations on secrets.
+#[derive(Subcommand)]
+pub enum SecretCommand {
+    /// Encrypt a value read from a prompt or standard input with age, and
+    /// print it as it should be written in the config
+    Encrypt {
+        /// Encrypt for this age recipient instead of the identity file's own
+        /// public key; may be given more than once
+        #[arg(long, value_name = "RECIPIENT")]
+        recipient: Vec<String>,
+    },
+}
+
+impl ConnectionArgs {
+    /// Resolves the timeouts, with command-line values taking precedence over the config.
+    pub fn timeouts(&self, cluster: &ClusterConfig) -> Timeouts {
+        Timeouts {
+            connect: self
+                .connect_timeout
+                .or(cluster.connect_timeout)
+                .map(Duration::from_secs),
+            command: self
+                .command_timeout
+                .or(cluster.command_timeout)
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Returns the retry policy described by the retry options.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            backoff: self.retry_backoff,
+            started: self.retry_started,
+        }
+    }
+}
+
+impl RunArgs {
+    /// Returns how commands are wrapped by these options, for the history.
+    pub fn wrapping(&self) -> Wrapping {
+        Wrapping {
+            cwd: self.cwd.clone(),
+            env: self.env.clone(),
+            sudo: self.sudo,
+            sudo_password_env: self.sudo_password_env.clone(),
+            ask_sudo_password: self.ask_sudo_password,
+        }
+    }
+
+    /// Wraps commands as a past run's were, as well as as these options ask.
+    /// The directory given here takes the past run's place, and the
+    /// variables given here are set after the past run's, so they win.
+    pub fn rewrap(&mut self, past: &Wrapping) {
+        self.cwd = self.cwd.take().or_else(|| past.cwd.clone());
+        self.env.splice(0..0, past.env.iter().cloned());
+        self.sudo |= past.sudo;
+        if !self.ask_sudo_password {
+            self.sudo_password_env = self
+                .sudo_password_env
+                .take()
+                .or_else(|| past.sudo_password_env.clone());
+        }
+        self.ask_sudo_password |= past.ask_sudo_password && self.sudo_password_env.is_none();
+    }
+
+    /// Wraps a command so it runs with the requested directory, environment and privileges.
+    pub fn wrap(&self, command: &str, cluster: &ClusterConfig) -> String {
+        let command = shell::with_env(command, self.cwd.as_deref(), &self.env);
+        self.sudo(cluster).wrap(&command)
+    }
+
+    /// Returns how commands are elevated, from the options and the config.
+    pub fn sudo(&self, cluster: &ClusterConfig) -> Sudo {
+        if self.sudo_password_env.is_some()
+            || self.ask_sudo_password
+            || cluster.sudo_password.is_some()
+        {
+            Sudo::Password
+        } else if self.sudo || cluster.sudo {
+            Sudo::Passwordless
+        } else {
+            Sudo::Off
+        }
+    }
+
+    /// Reads the sudo password from the environment or a prompt, if one was
+    /// requested, or else from the config, and masks it from then on.
+    pub fn sudo_password(
+        &self,
+        cluster: &ClusterConfig,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let password = if let Some(var) = &self.sudo_password_env {
+            env::var(var).map_err(|_| format!("environment variable {} is not set", var))?
+        } else if self.ask_sudo_password {
+            rpassword::prompt_password("Sudo password: ")?
+        } else {
+            match &cluster.sudo_password {
+                Some(password) => password.reveal()?,
+                None => return Ok(None),
+            }
+        };
+        redact::use_secret(&password);
+        Ok(Some(password))
+    }
+
+    /// Builds the input for each node's command.
+    ///
+    /// Standard input is read to the end if `--stdin` was given. It's buffered
+    /// so it can be replayed to every node, including to nodes that are
+    /// retried. A sudo password is sent ahead of it, for the sudo wrapper to read.
+    pub fn read_stdin(&self, sudo_password: Option<&str>) -> io::Result<Option<Vec<u8>>> {
+        let mut input = Vec::new();
+        if let Some(password) = sudo_password {
+            input.extend_from_slice(password.as_bytes());
+            input.push(b'\n');
+        }
+        if self.stdin {
+            io::stdin().read_to_end(&mut input)?;
+        } else if sudo_password.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(input))
+    }
+}
+
+/// Parses a possibly fractional number of seconds into a Duration.
+fn parse_secs(value: &str) -> Result<Duration, String> {
+    let secs: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid number of seconds: {}", value))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string())
+}
+
+/// Parses a rate of connections a second, such as "20" or "20/s".
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.strip_suffix("/s").unwrap_or(value).parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("invalid rate of connections a second: {}", value)),
+    }
+}
+
+/// Parses a rate of bytes a second, such as "50MB/s" or "512K", where K, M
+/// and G count in 1024s as they do for sizes.
+fn parse_bandwidth(value: &str) -> Result<u64, String> {
+    match parse_size(value.trim().strip_suffix("/s").unwrap_or(value)) {
+        Ok(0) => Err(format!("invalid rate of bytes a second: {}", value)),
+        Ok(rate) => Ok(rate as u64),
+        Err(_) => Err(format!("invalid rate of bytes a second: {}", value)),
+    }
+}
+
+/// Parses a size in bytes such as "512K" or "10MB", where K, M and G count
+/// in 1024s.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid size: {}", value);
+    let upper = value.trim().to_ascii_uppercase();
+    let number = upper
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+        .trim_end();
+    let (digits, scale) = match number.char_indices().last() {
+        Some((at, 'K')) => (&number[..at], 1 << 10),
+        Some((at, 'M')) => (&number[..at], 1 << 20),
+        Some((at, 'G')) => (&number[..at], 1 << 30),
+        _ => (number, 1),
+    };
+    let number: f64 = digits.trim().parse().map_err(|_| invalid())?;
+    match number * scale as f64 {
+        bytes if bytes >= 0.0 && bytes < usize::MAX as f64 => Ok(bytes as usize),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a variable assignment such as "registry=registry.internal:5000".
+fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected NAME=VALUE, not {}", value)),
+    }
+}
+
+/// Parses octal permission bits such as "644" or "0755".
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {}", value)),
+    }
+}
+
+/// Parses a percentage between 0 and 100.
+fn parse_percent(value: &str) -> Result<f64, String> {
+    match value.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("invalid percentage: {}", value)),
+    }
+}
+
+/// Parses a regular expression.
+fn parse_regex(value: &str) -> Result<Regex, String> {
+    Regex::new(value).map_err(|e| format!("invalid pattern {}: {}", value, e))
+}
+
+/// Parses a report to write, such as "junit=report.xml".
+fn parse_report(value: &str) -> Result<(ReportFormat, PathBuf), String> {
+    let (format, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected FORMAT=PATH, such as junit=report.xml: {}", value))?;
+    if path.is_empty() {
+        return Err(format!("no path given for the {} report", format));
+    }
+    let format = ReportFormat::from_str(format, true)
+        .map_err(|_| format!("unknown report format {}; use junit or tap", format))?;
+    Ok((format, PathBuf::from(path)))
+}
+
+/// Parses an event stream to write, such as "ndjson" or "ndjson=events.pipe".
+fn parse_events(value: &str) -> Result<(EventFormat, Option<PathBuf>), String> {
+    let (format, path) = match value.split_once('=') {
+        Some((_, "")) => return Err(format!("no path given for the events: {}", value)),
+        Some((format, path)) => (format, Some(PathBuf::from(path))),
+        None => (value, None),
+    };
+    let format = EventFormat::from_str(format, true)
+        .map_err(|_| format!("unknown event format {}; use ndjson", format))?;
+    Ok((format, path))
+}
+
+/// Parses a KEY=VALUE environment variable assignment.
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE: {}", value))?;
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    if name.is_empty()
+        || name.starts_with(|c: char| c.is_ascii_digit())
+        || !name.chars().all(is_name_char)
+    {
+        return Err(format!("invalid environment variable name: {}", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct Run {
+        #[command(flatten)]
+        run: RunArgs,
+    }
+
+    fn run_args(args: &[&str]) -> RunArgs {
+        Run::parse_from(std::iter::once("cluster_run").chain(args.iter().copied())).run
+    }
+
+    #[derive(Parser)]
+    struct Push {
+        #[command(flatten)]
+        push: PushArgs,
+    }
+
+    #[test]
+    fn rewraps_as_the_past_run_did() {
+        let past = run_args(&[
+            "--cwd",
+            "/srv",
+            "--env",
+            "A=1",
+            "--sudo-password-env",
+            "PASS",
+        ]);
+        let mut run = run_args(&[]);
+        run.rewrap(&past.wrapping());
+        assert_eq!(run.wrapping(), past.wrapping());
+
+        let cluster = ClusterConfig::default();
+        assert_eq!(run.wrap("make", &cluster), past.wrap("make", &cluster));
+    }
+
+    #[test]
+    fn rewrap_lets_the_new_options_win() {
+        let past = run_args(&["--cwd", "/srv", "--env", "A=1", "--ask-sudo-password"]);
+        let mut run = run_args(&[
+            "--cwd",
+            "/tmp",
+            "--env",
+            "A=2",
+            "--sudo-password-env",
+            "PASS",
+        ]);
+        run.rewrap(&past.wrapping());
+        assert_eq!(run.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(
+            run.env,
+            vec![
+                ("A".to_string(), "1".to_string()),
+                ("A".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(run.sudo_password_env.as_deref(), Some("PASS"));
+        assert!(!run.ask_sudo_password);
+    }
+
+    #[test]
+    fn parses_sizes_with_suffixes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("10MB"), Ok(10 << 20));
+        assert_eq!(parse_size("10mib"), Ok(10 << 20));
+        assert_eq!(parse_size(" 2 G "), Ok(2 << 30));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for value in ["", "K", "ten", "10T", "-1K", "nan", "inf", "99999999999G"] {
+            assert_eq!(
+                parse_size(value),
+                Err(format!("invalid size: {}", value)),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn parses_bandwidths() {
+        assert_eq!(parse_bandwidth("50MB/s"), Ok(50 << 20));
+        assert_eq!(parse_bandwidth("512K"), Ok(512 << 10));
+        assert_eq!(parse_bandwidth("100/s"), Ok(100));
+        for value in ["0", "0/s", "0.1", "fast", "10MB/m", "99999999999G/s"] {
+            assert_eq!(
+                parse_bandwidth(value),
+                Err(format!("invalid rate of bytes a second: {}", value)),
+                "{:?}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn fans_out_from_at_least_one_node() {
+        let push = |fanout: &str| {
+            Push::try_parse_from(["cluster_run", "a.pt", "/data/", "--fanout", fanout])
+                .map(|push| push.push.fanout)
+        };
+        assert_eq!(push("1").unwrap(), Some(1));
+        assert_eq!(push("4").unwrap(), Some(4));
+        assert!(push("0").is_err());
+        assert!(push("-1").is_err());
+    }
+}
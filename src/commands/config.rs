@@ -0,0 +1,262 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `config` subcommand, which works with the configuration file itself
+//! rather than running anything on the nodes it describes.
+//!
+//! Validation has to cope with a configuration that doesn't load, so it runs
+//! before the configuration is loaded; the other operations work on the
+//! loaded and selected nodes.
+
+use crate::cancel::{CancelToken, INTERRUPTED_EXIT};
+use crate::cli::{ConfigArgs, ConfigCommand, ConnectionArgs, ExportFormat, SourceArgs};
+use crate::config::{self, Config, Node, Report, Severity};
+use crate::executor;
+use crate::proxy;
+use crate::resolve;
+use crate::ssh;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::process::ExitCode;
+
+/// Runs the config subcommands that work on the loaded configuration.
+pub fn run(config: &Config, args: &ConfigArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    match args.command {
+        ConfigCommand::Validate | ConfigCommand::Migrate => {
+            unreachable!("these run before the config is loaded")
+        }
+        ConfigCommand::Export { format } => Ok(export(&config.cluster.nodes, format)),
+    }
+}
+
+/// Prints the nodes in a format other tools read.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if an /etc/hosts line couldn't be written for
+/// a node because its address didn't resolve.
+fn export(nodes: &[Node], format: ExportFormat) -> ExitCode {
+    match format {
+        ExportFormat::SshConfig => {
+            for node in nodes {
+                println!("Host {}", node.name());
+                if node.name() != node.address() {
+                    println!("    HostName {}", node.address());
+                }
+                println!("    User {}", node.user());
+                if node.port() != ssh::PORT {
+                    println!("    Port {}", node.port());
+                }
+                if let Some(key) = &node.key {
+                    println!("    IdentityFile {}", key.display());
+                }
+                if node.compresses() {
+                    println!("    Compression yes");
+                }
+                if let Some(jump) = &node.jump {
+                    println!("    ProxyJump {}", jump);
+                } else if let Some(Ok(proxy)) = node.proxy.as_deref().map(proxy::parse) {
+                    println!("    ProxyCommand {}", proxy.proxy_command());
+                }
+                println!();
+            }
+            ExitCode::SUCCESS
+        }
+        ExportFormat::Hosts => {
+            let mut unresolved = false;
+            println!("# BEGIN cluster_run");
+            for node in nodes {
+                // A node known only by its IP address has no name to map
+                let is_ip = node.host.parse::<IpAddr>().is_ok();
+                let names: Vec<&str> = node
+                    .alias
+                    .as_deref()
+                    .into_iter()
+                    .chain((!is_ip).then_some(node.host.as_str()))
+                    .collect();
+                if names.is_empty() {
+                    continue;
+                }
+                match resolve(node.address()) {
+                    Some(ip) => println!("{}\t{}", ip, names.join(" ")),
+                    None => {
+                        eprintln!("Unable to resolve {}", node.address());
+                        unresolved = true;
+                    }
+                }
+            }
+            println!("# END cluster_run");
+            if unresolved {
+                return ExitCode::FAILURE;
+            }
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Returns the IP address of a host, preferring IPv4.
+fn resolve(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse() {
+        return Some(ip);
+    }
+    let addresses: Vec<IpAddr> = resolve::lookup(host, 0)
+        .ok()?
+        .iter()
+        .map(|a| a.ip())
+        .collect();
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+/// Rewrites the configuration files in the current version of the format.
+pub fn migrate(source: &SourceArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    for (path, backup) in config::upgrade_files(source.config.as_deref())? {
+        match backup {
+            Some(backup) => println!(
+                "Migrated {} to version {}; the original is in {}",
+                path.display(),
+                config::VERSION,
+                backup.display()
+            ),
+            None => println!("{} is already version {}", path.display(), config::VERSION),
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Validates the configuration file and prints what's wrong with it.
+///
+/// This function performs the following steps:
+/// 1. Parses the file and checks each of its clusters.
+/// 2. Resolves the address of every node in parallel.
+/// 3. Prints each cluster and the problems found.
+///
+/// # Returns
+///
+/// Returns success if no errors were found, even if there were warnings.
+pub fn validate(
+    source: &SourceArgs,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // Step 1: Check the file
+    let mut report = config::validate(source.config.as_deref(), &source.vars)?;
+
+    // Step 2: Resolve the addresses, once for each distinct one
+    let addresses = addresses(
+        report
+            .clusters
+            .iter()
+            .filter_map(|(_, config)| config.as_ref())
+            .flat_map(|config| &config.cluster.nodes),
+    );
+    let mut unresolved = Vec::new();
+    executor::run_parallel(
+        &addresses,
+        connection.parallel,
+        cancel,
+        |_, &(host, port)| resolve::lookup(host, port).err(),
+        |index, error| {
+            if let Some(e) = error {
+                unresolved.push(format!("unable to resolve {}: {}", addresses[index].0, e));
+            }
+        },
+    );
+    unresolved.sort();
+    for message in unresolved {
+        report.add(Severity::Error, None, message);
+    }
+    if cancel.is_cancelled() {
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+
+    // Step 3: Print the report
+    print(&report);
+    if report.count(Severity::Error) > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Returns the distinct addresses of the nodes that are connected to
+/// directly, to be resolved. Nodes reached through jump hosts or a proxy
+/// are left out, since their names are looked up at the other end.
+fn addresses<'a>(nodes: impl Iterator<Item = &'a Node>) -> Vec<(&'a str, u16)> {
+    let addresses: BTreeSet<(&str, u16)> = nodes
+        .filter(|node| resolve::is_direct(node))
+        .map(|node| (node.address(), node.port()))
+        .collect();
+    addresses.into_iter().collect()
+}
+
+/// Prints the clusters in the report, then its problems and their counts.
+fn print(report: &Report) {
+    println!("Checked {}", report.path.display());
+    for (section, config) in &report.clusters {
+        match config {
+            Some(config) => {
+                println!(
+                    "  {}: {} nodes in {} groups",
+                    section,
+                    config.cluster.nodes.len(),
+                    config.cluster.groups.len()
+                );
+            }
+            None => println!(
+                "  {}: nodes are listed by a provider, so weren't checked",
+                section
+            ),
+        }
+    }
+    for problem in &report.problems {
+        let severity = match problem.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match problem.location {
+            Some((line, column)) => println!(
+                "{}: {}:{}:{}: {}",
+                severity,
+                report.path.display(),
+                line,
+                column,
+                problem.message
+            ),
+            None => println!("{}: {}", severity, problem.message),
+        }
+    }
+    println!(
+        "{} errors, {} warnings",
+        report.count(Severity::Error),
+        report.count(Severity::Warning)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_only_direct_nodes() {
+        let mut jumped = Node::from("gpu-01.internal");
+        jumped.jump = Some("bastion.example.com".to_string());
+        let mut proxied = Node::from("gpu-02.internal");
+        proxied.proxy = Some("socks5://127.0.0.1:1080".to_string());
+        let mut other_port = Node::from("gpu-03.example.com");
+        other_port.port = Some(2222);
+        let nodes = [
+            Node::from("gpu-03.example.com"),
+            jumped,
+            proxied,
+            other_port,
+            Node::from("gpu-03.example.com"),
+        ];
+        assert_eq!(
+            addresses(nodes.iter()),
+            vec![("gpu-03.example.com", 22), ("gpu-03.example.com", 2222)]
+        );
+    }
+}
@@ -0,0 +1,989 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `exec` subcommand, which runs a command on every node.
+
+use crate::cancel::{CancelToken, Cancelled, INTERRUPTED_EXIT};
+use crate::cli::{ConnectionArgs, ExecArgs, OutputFormat, ReportFormat, RunArgs};
+use crate::config::{Config, Node};
+use crate::diff::Drift;
+use crate::events::Events;
+use crate::executor;
+use crate::filter::Filter;
+use crate::history;
+use crate::logdir::{NodeLog, RunLog};
+use crate::logging;
+use crate::notify::Notifier;
+use crate::output::{self, Clock, Delimited, Grouped, LinePrinter, Ordered};
+use crate::plan;
+use crate::policy::FailurePolicy;
+use crate::pool;
+use crate::progress::{self, Progress};
+use crate::prompt;
+use crate::redact;
+use crate::report::{self, NodeResult, Status};
+use crate::shell::Sudo;
+use crate::ssh::{self, CommandResult, OutputStream, TimedOut};
+use crate::summary::{Summary, Timing};
+use crate::syslog::Syslog;
+use crate::template::Vars;
+use crate::transport::Session;
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Instant, SystemTime};
+use tracing::Instrument;
+
+/// Executes a command on the nodes of the cluster concurrently.
+///
+/// Variables from the config, used as `{{ name }}`, are filled into the
+/// command, and placeholders, such as `{host}` and `{index}`, are filled in
+/// separately for each node. The command runs in the directory and with the
+/// environment given by `--cwd` and `--env`.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the failures violate the failure policy.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &ExecArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    run_command(
+        config,
+        connection,
+        cancel,
+        &args.run,
+        args.reconnect,
+        &args.command.join(" "),
+    )
+}
+
+/// Executes a command, as given on the command line or recorded in the
+/// history, on the nodes of the cluster concurrently.
+///
+/// # Arguments
+///
+/// * `config` - The cluster configuration.
+/// * `connection` - The options for connecting to nodes.
+/// * `cancel` - Cancelled when the user interrupts the run.
+/// * `run` - The options for reporting output and deciding success.
+/// * `reconnect` - How many times to reconnect to a node that's lost, picking
+///   the command's output up where it stopped.
+/// * `description` - The command, which may contain placeholders.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the failures violate the failure policy.
+pub fn run_command(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    run: &RunArgs,
+    reconnect: u32,
+    description: &str,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let description = &config.fill(description);
+    let command = run.wrap(description, &config.cluster);
+    if connection.dry_run {
+        plan::print(&config.cluster.nodes, |index, node| {
+            let command = Vars::for_node(index, node).render(&command);
+            let mut steps = vec![format!("$ {}", command)];
+            if run.sudo(&config.cluster) == Sudo::Password {
+                steps.push("with the sudo password on standard input".to_string());
+            }
+            if run.stdin {
+                steps.push("with a copy of local standard input".to_string());
+            } else if reconnect > 0 {
+                steps.push(format!(
+                    "in the background, reconnecting up to {} times if the connection is lost",
+                    reconnect
+                ));
+            }
+            steps
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeouts = connection.timeouts(&config.cluster);
+    let password = run.sudo_password(&config.cluster)?;
+    let stdin = run.read_stdin(password.as_deref())?;
+    if reconnect > 0 && stdin.is_some() {
+        tracing::warn!(
+            "--reconnect can't resume a command that reads standard input, so it won't reconnect"
+        );
+    }
+    execute(
+        config,
+        connection,
+        run,
+        cancel,
+        "exec",
+        description,
+        async |sess, node, vars, on_output| {
+            let command = vars.render(&command);
+            if reconnect > 0 && stdin.is_none() {
+                let resume = ssh::Reconnect {
+                    node,
+                    timeout: timeouts.connect,
+                    attempts: reconnect,
+                };
+                return sess
+                    .exec_resumable(&command, timeouts.command, cancel, &resume, on_output)
+                    .await;
+            }
+            sess.exec(
+                &command,
+                stdin.as_deref(),
+                timeouts.command,
+                cancel,
+                on_output,
+            )
+            .await
+        },
+    )
+}
+
+/// Runs a task that executes something on each node, and reports the results.
+///
+/// This function performs the following steps:
+/// 1. Connects to the nodes and runs the task, a batch at a time if
+///    `--batch-size` is given, with at most `--parallel` nodes at once.
+/// 2. Prints the output or any errors encountered as each node finishes.
+/// 3. With `--canary`, runs on the canary nodes first and asks before continuing.
+/// 4. Cancels the rest of the run if the failures reach the abort threshold,
+///    or skips the remaining batches if a batch had failures and
+///    `--abort-on-batch-failure` is given.
+/// 5. Reports which nodes needed retries, then prints a summary of the run.
+///
+/// With `--output json`, each node's result and the summary are printed as
+/// JSON objects instead, and with `--output csv` or `--output tsv` each node
+/// gets a row of a table printed at the end, in place of the summary. With
+/// `--output ordered`, each node's output is held back until the nodes
+/// before it in the node list have finished, and printed in their order.
+/// `--grep` and `--grep-v` filter the lines printed, but nothing else. With
+/// `--diff`, each node's output is printed at the end as a diff against the
+/// baseline's, and the run fails if any node's differs. With
+/// `--log-dir`, each node's output is also written to its own files as it
+/// arrives, timestamped line by line with `--timestamps` as streamed lines
+/// are, and the results to a `summary.json` once the run is over,
+/// `--raw` writes the exact bytes of each node's output to its own files, and
+/// `--report` writes the results as JUnit XML or TAP for CI. `--events`
+/// writes an event for each node starting, each chunk of output, each node
+/// finishing and the summary as they happen, and `--syslog` logs the run
+/// and each node's result to the system log. With `--quiet`,
+/// only the output of commands that failed is printed, and with `--progress`
+/// a bar of the nodes finished, running and failed is drawn beneath it.
+/// Anything given to be redacted is masked in the command and its output
+/// before any of it is printed or written, other than to the `--raw` files.
+/// Unless `--no-history` is given,
+/// the run is recorded in the history once it's over, even if it was
+/// interrupted, and the webhooks to notify are posted its summary.
+///
+/// # Arguments
+///
+/// * `config` - The cluster configuration.
+/// * `connection` - The options for connecting to nodes.
+/// * `run` - The options for reporting output and deciding success.
+/// * `cancel` - The token the task should pass on so in-flight work can be cancelled.
+/// * `subcommand` - The subcommand doing the run, such as `exec`, for the history.
+/// * `description` - What is being run, for the output headers. It may contain placeholders.
+/// * `task` - Runs the work over a node's session, given the node and its
+///   template variables, passing output to the callback as it arrives.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the failures violate the failure policy or
+/// the run was cancelled.
+pub fn execute<F>(
+    config: &Config,
+    connection: &ConnectionArgs,
+    run: &RunArgs,
+    cancel: &CancelToken,
+    subcommand: &str,
+    description: &str,
+    task: F,
+) -> Result<ExitCode, Box<dyn std::error::Error>>
+where
+    F: AsyncFn(
+        &dyn Session,
+        &Node,
+        &Vars,
+        &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>,
+{
+    let json = run.output == OutputFormat::Json;
+    // The command itself may hold a secret, such as a token passed to a script
+    let description = redact::text(description);
+    let description = description.as_ref();
+    if run.stream && run.output != OutputFormat::Text {
+        return Err(
+            "--stream can only be used with --output text, as the other formats print each node's output whole"
+                .into(),
+        );
+    }
+    let events_on_stdout = matches!(&run.events, Some((_, None)));
+    if events_on_stdout && (run.output != OutputFormat::Text || run.stream || run.diff) {
+        return Err(
+            "--events without a path takes standard output over, so it can't be combined with --output, --stream or --diff; give it a path such as ndjson=events.pipe"
+                .into(),
+        );
+    }
+    let baseline = match &run.baseline {
+        Some(name) => {
+            let index = config
+                .cluster
+                .nodes
+                .iter()
+                .position(|node| node.is_named(name))
+                .ok_or_else(|| {
+                    format!("the baseline node {} isn't one of the selected nodes", name)
+                })?;
+            Some((index, config.cluster.nodes[index].to_string()))
+        }
+        None => None,
+    };
+    tracing::info!("Welcome to cluster_run!");
+    let log = match &run.log_dir {
+        Some(dir) => {
+            let log = RunLog::create(dir).map_err(|e| {
+                format!(
+                    "unable to create a log directory in {}: {}",
+                    dir.display(),
+                    e
+                )
+            })?;
+            tracing::info!("Logging to {}", log.dir().display());
+            Some(log)
+        }
+        None => None,
+    };
+    let events = match &run.events {
+        Some((_, path)) => Some(Events::open(path.as_deref())?),
+        None => None,
+    };
+    let syslog = if run.syslog {
+        let syslog = Syslog::open()?;
+        syslog.run_started(description, config.cluster.nodes.len());
+        Some(syslog)
+    } else {
+        None
+    };
+    let raw = match &run.raw {
+        Some(dir) => Some(
+            RunLog::open(dir)
+                .map_err(|e| format!("unable to create the directory {}: {}", dir.display(), e))?,
+        ),
+        None => None,
+    };
+
+    let nodes = &config.cluster.nodes;
+    let width = nodes
+        .iter()
+        .map(|node| node.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let timeouts = connection.timeouts(&config.cluster);
+    let retry = connection.retry_policy();
+    let policy = FailurePolicy {
+        ok_if_any: run.ok_if_any,
+        max_failures: run.max_failures,
+        fail_fast: run.fail_fast,
+        max_failure_percent: run.max_failure_percent,
+    };
+
+    let mut notifier = Notifier::new(&config.notify, &run.notify, run.notify_min_failures)?;
+    let filter = Filter::new(&run.grep, &run.grep_v);
+    let clock = match (run.timestamps, run.utc) {
+        (false, _) => None,
+        (true, false) => Some(Clock::Local),
+        (true, true) => Some(Clock::Utc),
+    };
+
+    // The bar takes the place of the messages about each node connecting
+    let progress = if run.progress {
+        Progress::start(nodes.len())
+    } else {
+        None
+    };
+
+    // Connects to a node, or reuses its session, and runs the task on it,
+    // retrying as configured, and times the last attempt
+    let run_node = async |index: usize, node: &Node| {
+        let vars = Vars::for_node(index, node);
+        let mut timing = Timing::default();
+        let outcome = retry
+            .run(
+                cancel,
+                async |_, started| {
+                    if progress.is_none() {
+                        tracing::info!("Connecting to node {}...", node);
+                    }
+                    timing = Timing {
+                        started: Some(SystemTime::now()),
+                        ..Timing::default()
+                    };
+                    let connecting = Instant::now();
+                    let sess = pool::checkout(node, timeouts.connect, cancel)
+                        .instrument(tracing::debug_span!("connect"))
+                        .await?;
+                    timing.connect = Some(connecting.elapsed());
+
+                    // Each attempt's log replaces the one before it
+                    let name = node.to_string();
+                    let mut streamed = Streamed {
+                        log: log.as_ref().map(|log| log.node(&name, clock)),
+                        raw: raw.as_ref().map(|raw| raw.node(&name, None)),
+                        events: events.as_ref(),
+                        printer: run
+                            .stream
+                            .then(|| LinePrinter::new(&name, width, clock, filter.clone())),
+                        name,
+                        masking: redact::Streams::default(),
+                    };
+                    // Once logged in, the command may be running whatever
+                    // goes wrong next
+                    started.mark();
+                    let running = Instant::now();
+                    let result = task(&*sess, node, &vars, &mut |stream, chunk| {
+                        streamed.output(stream, chunk)
+                    })
+                    .instrument(tracing::debug_span!("command"))
+                    .await
+                    .map_err(redact::error);
+                    streamed.finish();
+                    timing.command = Some(running.elapsed());
+                    if result.is_ok() {
+                        sess.release();
+                    }
+                    result
+                },
+                |attempt, e, delay| {
+                    tracing::warn!(
+                        "Attempt {} for node {} failed: {}; retrying in {:.2?}",
+                        attempt,
+                        node,
+                        e,
+                        delay
+                    )
+                },
+            )
+            .await;
+        (outcome, timing)
+    };
+
+    // Execute the command on the nodes in the cluster in batches, at most
+    // `parallel` at a time
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let mut summary = Summary::default();
+    let mut finished = 0;
+    let mut aborted = false;
+    let mut retried = Vec::new();
+    let mut printed = Printed::new(
+        run,
+        nodes,
+        description,
+        filter.clone(),
+        baseline,
+        events_on_stdout,
+    );
+    // The results are kept for the log, reports and history written at the end
+    let keep = log.is_some() || !run.report.is_empty() || !run.no_history;
+    let mut results = Vec::new();
+
+    // The canary nodes go first, on their own, ahead of the batches
+    let canary = run.canary.min(nodes.len());
+    let mut batches = Vec::new();
+    if canary > 0 {
+        batches.push(0..canary);
+    }
+    batches.extend(
+        executor::batches(nodes.len() - canary, run.batch_size)
+            .into_iter()
+            .map(|batch| batch.start + canary..batch.end + canary),
+    );
+    let first_batch = usize::from(canary > 0);
+    for (number, batch) in batches.iter().cloned().enumerate() {
+        if number > 0 && !cancel.sleep(run.batch_pause) {
+            break;
+        }
+        if number < first_batch {
+            tracing::info!("Canary: nodes {} to {}", batch.start + 1, batch.end);
+        } else if batches.len() - first_batch > 1 {
+            tracing::info!(
+                "Batch {} of {}: nodes {} to {}",
+                number + 1 - first_batch,
+                batches.len() - first_batch,
+                batch.start + 1,
+                batch.end
+            );
+        }
+        let failed_before = summary.failures();
+        executor::run_concurrent(
+            &nodes[batch.clone()],
+            connection.parallel,
+            cancel,
+            async |offset, node| {
+                if let Some(progress) = &progress {
+                    progress.started(batch.start + offset, &node.to_string());
+                }
+                if let Some(events) = &events {
+                    events.node_started(batch.start + offset, &node.to_string());
+                }
+                run_node(batch.start + offset, node)
+                    .instrument(tracing::debug_span!("node", host = %node))
+                    .await
+            },
+            |offset, ((result, attempts), timing)| {
+                let index = batch.start + offset;
+                let node = &nodes[index];
+                finished += 1;
+                if let Some(progress) = &progress {
+                    let succeeded = matches!(&result, Ok(result) if result.exit_code == 0);
+                    progress.finished(index, succeeded);
+                }
+                summary.record_timing(&node.to_string(), timing);
+                if attempts > 1 {
+                    retried.push((node, attempts, result.is_ok()));
+                }
+                let object = (json || keep || events.is_some() || syslog.is_some()).then(|| {
+                    let command = Vars::for_node(index, node).render(description);
+                    node_json(node, &command, &result, timing, attempts)
+                });
+                printed.add(index, &result, timing, object.as_ref());
+                if let Some(object) = object {
+                    if let Some(events) = &events {
+                        events.node_finished(&object);
+                    }
+                    if let Some(syslog) = &syslog {
+                        syslog.node_finished(&object);
+                    }
+                    if keep {
+                        results.push((index, object));
+                    }
+                }
+                tally(&mut summary, node, &result);
+
+                if let Some(notifier) = &mut notifier {
+                    notifier.failures(description, &summary, nodes.len());
+                }
+
+                // Stop the rest of the run once too many nodes have failed
+                let failed = summary.failures();
+                if !cancel.is_cancelled() && policy.should_abort(failed, nodes.len()) {
+                    tracing::error!("Aborting after {} of {} nodes failed", failed, nodes.len());
+                    aborted = true;
+                    cancel.cancel();
+                }
+            },
+        );
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        // Only roll out past the canary once it's succeeded and been confirmed
+        let batch_failed = summary.failures() - failed_before;
+        let remaining = nodes.len() - batch.end;
+        if number < first_batch && remaining > 0 {
+            if batch_failed > 0 {
+                tracing::error!(
+                    "Canary had {} failed nodes; not continuing to the remaining {} nodes",
+                    batch_failed,
+                    remaining
+                );
+                aborted = true;
+                break;
+            }
+            if !run.yes {
+                let question = format!("Continue to the remaining {} nodes?", remaining);
+                match progress::suspend(|| prompt::confirm(&question)) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        tracing::info!("Stopping after the canary");
+                        aborted = true;
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Stopping after the canary: {}; pass --yes to continue without asking",
+                            e
+                        );
+                        aborted = true;
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+
+        // Leave the remaining batches alone if this one didn't go cleanly
+        if run.abort_on_batch_failure && batch_failed > 0 && batch.end < nodes.len() {
+            tracing::error!(
+                "Batch {} had {} failed nodes; skipping the remaining batches",
+                number + 1 - first_batch,
+                batch_failed
+            );
+            aborted = true;
+            break;
+        }
+    }
+
+    drop(progress);
+
+    // Report which nodes needed more than one attempt
+    for (node, attempts, ok) in &retried {
+        if *ok {
+            tracing::info!("Node {} succeeded on attempt {}", node, attempts);
+        } else {
+            tracing::info!("Node {} failed after {} attempts", node, attempts);
+        }
+    }
+
+    summary.not_started = nodes.len() - finished;
+    summary.wall_time = started.elapsed();
+    results.sort_by_key(|(index, _)| *index);
+    if let Some(log) = &log {
+        write_log_summary(log, &results, &summary);
+    }
+    if let Some(events) = &events {
+        events.run_summary(&report::summary_json(&summary));
+    }
+    let drifted = printed.finish(&summary);
+
+    let not_started: Vec<String> = (0..nodes.len())
+        .filter(|index| !results.iter().any(|(finished, _)| finished == index))
+        .map(|index| nodes[index].to_string())
+        .collect();
+    let results: Vec<Value> = results.into_iter().map(|(_, object)| object).collect();
+    let mut recorded = None;
+    if !run.no_history {
+        let selected: Vec<String> = nodes.iter().map(|node| node.to_string()).collect();
+        // The variables' values may hold secrets, as the command may
+        let mut wrapping = run.wrapping();
+        for (_, value) in &mut wrapping.env {
+            *value = redact::text(value).into_owned();
+        }
+        match history::record(
+            subcommand,
+            description,
+            started_at,
+            &selected,
+            &wrapping,
+            &summary,
+            &results,
+        ) {
+            Ok(id) => {
+                tracing::info!("Recorded as run {} in the history", id);
+                recorded = Some(id);
+            }
+            Err(e) => tracing::warn!("{}", e),
+        }
+    }
+    if let Some(syslog) = &syslog {
+        syslog.run_finished(description, &report::summary_json(&summary), recorded);
+    }
+    if let Some(notifier) = notifier {
+        let interrupted = cancel.is_cancelled() && !aborted;
+        notifier.finish(description, &summary, recorded, interrupted);
+    }
+    write_reports(
+        &run.report,
+        &report::Cases {
+            name: description,
+            results: &results,
+            not_started: &not_started,
+            started_at,
+            wall_time: summary.wall_time,
+        },
+    )?;
+
+    let succeeded = summary.succeeded.len();
+    let failed = summary.failures();
+
+    // An interrupted run fails no matter how the finished nodes did
+    if cancel.is_cancelled() && !aborted {
+        tracing::warn!(
+            "Interrupted with {} of {} nodes finished",
+            succeeded + failed,
+            nodes.len()
+        );
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+
+    // Fail the process if the failures are more than the policy allows
+    if aborted || !policy.is_satisfied(succeeded, failed) {
+        if failed > 0 {
+            tracing::error!("{} of {} nodes failed", failed, nodes.len());
+        }
+        return Ok(ExitCode::FAILURE);
+    }
+
+    // As with diff(1), differences fail the run
+    if drifted > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Where a node's output goes as it arrives: its `--log-dir` and `--raw`
+/// files, the events, and the terminal with `--stream`. The `--raw` files
+/// get the output byte for byte, and the rest get it masked.
+struct Streamed<'a> {
+    name: String,
+    log: Option<NodeLog>,
+    raw: Option<NodeLog>,
+    events: Option<&'a Events>,
+    printer: Option<LinePrinter>,
+    masking: redact::Streams,
+}
+
+impl Streamed<'_> {
+    /// Passes a chunk of output on to each of them.
+    fn output(&mut self, stream: OutputStream, chunk: &[u8]) {
+        if let Some(raw) = &mut self.raw {
+            raw.write(stream, chunk);
+        }
+        let (log, events, printer) = (&mut self.log, self.events, &mut self.printer);
+        let name = &self.name;
+        self.masking.push(stream, chunk, &mut |stream, masked| {
+            Self::masked(name, log, events, printer, stream, masked)
+        });
+    }
+
+    /// Passes a chunk of masked output on to those that get it masked.
+    fn masked(
+        name: &str,
+        log: &mut Option<NodeLog>,
+        events: Option<&Events>,
+        printer: &mut Option<LinePrinter>,
+        stream: OutputStream,
+        chunk: &[u8],
+    ) {
+        if let Some(log) = log {
+            log.write(stream, chunk);
+        }
+        if let Some(events) = events {
+            events.output(name, stream, chunk);
+        }
+        if let Some(printer) = printer {
+            printer.push(stream, chunk);
+        }
+    }
+
+    /// Passes on the output held back for masking, and prints what's left
+    /// of a streamed line, once the node has finished.
+    fn finish(&mut self) {
+        let (log, events, printer) = (&mut self.log, self.events, &mut self.printer);
+        let name = &self.name;
+        self.masking
+            .finish(&mut |stream, masked| Self::masked(name, log, events, printer, stream, masked));
+        if let Some(printer) = &mut self.printer {
+            printer.finish();
+        }
+    }
+}
+
+/// How each node's result is printed, for the `--output` format chosen:
+/// as it finishes, as a JSON object, grouped with the nodes whose output
+/// matches, in the order of the node list, as a diff against the
+/// baseline's, or as a row of a table. What's printed at the end is held
+/// here until then.
+struct Printed<'a> {
+    nodes: &'a [Node],
+    description: &'a str,
+    filter: Option<Filter>,
+    json: bool,
+    quiet: bool,
+    /// Whether each node's output is printed as it finishes, rather than
+    /// being streamed or printed at the end.
+    printing: bool,
+    /// Whether the events have standard output to themselves.
+    events_on_stdout: bool,
+    stream: bool,
+    timing: bool,
+    grouped: Option<Grouped>,
+    ordered: Option<Ordered<(usize, CommandResult)>>,
+    drift: Option<Drift>,
+    delimited: Option<Delimited>,
+}
+
+impl<'a> Printed<'a> {
+    /// Returns the printing chosen by the run's options.
+    fn new(
+        run: &RunArgs,
+        nodes: &'a [Node],
+        description: &'a str,
+        filter: Option<Filter>,
+        baseline: Option<(usize, String)>,
+        events_on_stdout: bool,
+    ) -> Self {
+        Printed {
+            nodes,
+            description,
+            filter,
+            json: run.output == OutputFormat::Json,
+            quiet: logging::is_quiet(),
+            printing: run.output == OutputFormat::Text && !run.stream && !events_on_stdout,
+            events_on_stdout,
+            stream: run.stream,
+            timing: run.timing,
+            grouped: (run.output == OutputFormat::Grouped).then(Grouped::default),
+            ordered: (run.output == OutputFormat::Ordered).then(Ordered::default),
+            drift: run.diff.then(|| Drift::new(baseline)),
+            delimited: match run.output {
+                OutputFormat::Csv => Some(Delimited::new(',')),
+                OutputFormat::Tsv => Some(Delimited::new('\t')),
+                _ => None,
+            },
+        }
+    }
+
+    /// Prints a node's result as it finishes, or holds it to print at the
+    /// end. `object` is the result as JSON, if it was needed.
+    fn add(
+        &mut self,
+        index: usize,
+        result: &Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>,
+        timing: Timing,
+        object: Option<&Value>,
+    ) {
+        let node = &self.nodes[index];
+        if let (true, Some(object)) = (self.json, object) {
+            progress::suspend(|| println!("{}", object));
+        }
+        if let Some(delimited) = &mut self.delimited {
+            let name = node.to_string();
+            match result {
+                Ok(result) => delimited.add(
+                    index,
+                    &name,
+                    Some(result.exit_code),
+                    result.duration,
+                    output::first_line(result),
+                ),
+                Err(e) => delimited.add(index, &name, None, timing.total(), &e.to_string()),
+            }
+        }
+        // With --quiet, only the output of failed commands is printed
+        // With --grep, only the matching lines are printed, and nodes
+        // without any aren't printed at all
+        let shown = match (result, &self.filter) {
+            (Ok(result), Some(filter)) => Some(filter.result(result)),
+            _ => None,
+        };
+        let unmatched =
+            matches!(&shown, Some(shown) if shown.stdout.is_empty() && shown.stderr.is_empty());
+        let hidden =
+            unmatched || self.quiet && matches!(result, Ok(result) if result.exit_code == 0);
+        // Ordered output waits for the nodes before it, even those with
+        // nothing to print
+        if let Some(ordered) = &mut self.ordered {
+            let block = match result {
+                Ok(result) if !hidden => {
+                    Some((index, shown.clone().unwrap_or_else(|| result.clone())))
+                }
+                _ => None,
+            };
+            for (index, result) in ordered.add(index, block) {
+                print_output(&self.nodes[index], index, self.description, &result);
+                progress::suspend(|| println!());
+            }
+        }
+        // Streamed output has already been printed as it arrived, and
+        // grouped output and diffs are printed at the end
+        if let Ok(result) = result {
+            let shown = shown.as_ref().unwrap_or(result);
+            if let Some(grouped) = &mut self.grouped {
+                if !unmatched {
+                    grouped.add(index, &node.to_string(), shown);
+                }
+            } else if let Some(drift) = &mut self.drift {
+                drift.add(index, &node.to_string(), result);
+            } else if self.printing && !hidden {
+                print_output(node, index, self.description, shown);
+            }
+        }
+        if self.printing && self.drift.is_none() && !hidden {
+            progress::suspend(|| println!());
+        }
+    }
+
+    /// Prints what was held back to the end, and the summary of the run.
+    ///
+    /// # Returns
+    ///
+    /// Returns how many nodes' output differed from the baseline's.
+    fn finish(&mut self, summary: &Summary) -> usize {
+        let mut drifted = 0;
+        if self.events_on_stdout {
+            // Standard output holds nothing but the events
+        } else if self.json {
+            report::print_summary(summary);
+        } else if let Some(delimited) = &mut self.delimited {
+            // Standard output holds nothing but the table
+            delimited.print();
+        } else {
+            if let Some(grouped) = &mut self.grouped {
+                grouped.print();
+            }
+            if let Some(ordered) = &mut self.ordered {
+                for (index, result) in ordered.finish() {
+                    print_output(&self.nodes[index], index, self.description, &result);
+                    println!();
+                }
+            }
+            if let Some(drift) = &mut self.drift {
+                match drift.print() {
+                    Ok(count) => drifted = count,
+                    Err(e) => tracing::error!("Not comparing the nodes: {}", e),
+                }
+            }
+            if self.stream || self.grouped.is_some() || self.drift.is_some() {
+                println!();
+            }
+            summary.print();
+            if self.timing {
+                println!();
+                summary.print_timing();
+            }
+        }
+        drifted
+    }
+}
+
+/// Adds a node's result to the summary, logging how it failed if it did.
+fn tally(
+    summary: &mut Summary,
+    node: &Node,
+    result: &Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>,
+) {
+    match result {
+        Ok(result) => {
+            if result.exit_code != 0 {
+                tracing::error!(
+                    "Command on {} exited with status {} after {:.2?}",
+                    node,
+                    result.exit_code,
+                    result.duration
+                );
+            }
+            summary.record_exit(
+                &node.to_string(),
+                result.exit_code,
+                result.duration,
+                &result.stderr,
+            );
+        }
+        Err(e) if e.is::<Cancelled>() => {
+            tracing::info!("Node {} was cancelled", node);
+            summary.cancelled.push(node.to_string());
+        }
+        Err(e) if e.is::<TimedOut>() => {
+            tracing::error!("Node {} timed out: {}", node, e);
+            summary.timed_out.push(node.to_string());
+        }
+        Err(e) => {
+            tracing::error!("Error for node {}: {}", node, e);
+            summary.errored.push((node.to_string(), e.to_string()));
+        }
+    }
+}
+
+/// Writes the results to the log's `summary.json`, leaving out the output,
+/// which the log keeps in each node's own files.
+fn write_log_summary(log: &RunLog, results: &[(usize, Value)], summary: &Summary) {
+    let logged: Vec<Value> = results
+        .iter()
+        .map(|(_, object)| {
+            let mut object = object.clone();
+            if let Value::Object(fields) = &mut object {
+                fields.remove("type");
+                fields.remove("stdout");
+                fields.remove("stderr");
+            }
+            object
+        })
+        .collect();
+    let mut object = report::summary_json(summary);
+    if let Value::Object(fields) = &mut object {
+        fields.remove("type");
+        fields.insert("nodes".to_string(), logged.into());
+    }
+    log.write_summary(&object);
+}
+
+/// Writes the results as each report given with `--report`.
+fn write_reports(
+    reports: &[(ReportFormat, PathBuf)],
+    cases: &report::Cases,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (format, path) in reports {
+        let contents = match format {
+            ReportFormat::Junit => report::junit(cases),
+            ReportFormat::Tap => report::tap(cases),
+        };
+        fs::write(path, contents)
+            .map_err(|e| format!("unable to write the report {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Prints a node's output under a header naming it and the command, with
+/// its stderr after, if it wrote any, on standard error.
+fn print_output(node: &Node, index: usize, description: &str, result: &CommandResult) {
+    let name = output::paint(
+        OutputStream::Stdout,
+        output::status_color(result.exit_code),
+        &node.to_string(),
+    );
+    progress::suspend(|| {
+        println!(
+            "Output from {} for command '{}': \n{}",
+            name,
+            Vars::for_node(index, node).render(description),
+            result.stdout
+        );
+        if !result.stderr.is_empty() {
+            eprintln!("Stderr from {}: \n{}", node, result.stderr);
+        }
+    });
+}
+
+/// Returns a node's result as a JSON object, for `--output json` and the log.
+fn node_json(
+    node: &Node,
+    command: &str,
+    result: &Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>,
+    timing: Timing,
+    attempts: u32,
+) -> Value {
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let status = match result {
+        Ok(result) => Status::Exited(result.exit_code),
+        Err(e) if e.is::<Cancelled>() => Status::Cancelled,
+        Err(e) if e.is::<TimedOut>() => Status::TimedOut(error.as_deref().unwrap_or_default()),
+        Err(_) => Status::Error(error.as_deref().unwrap_or_default()),
+    };
+    let (stdout, stderr, duration) = match result {
+        Ok(result) => (
+            result.stdout.as_str(),
+            result.stderr.as_str(),
+            result.duration,
+        ),
+        Err(_) => ("", "", timing.total()),
+    };
+    report::node_json(&NodeResult {
+        host: &node.to_string(),
+        command,
+        status,
+        stdout,
+        stderr,
+        started_at: timing.started,
+        duration,
+        attempts,
+    })
+}
@@ -0,0 +1,242 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `history` subcommand, which looks back on past runs.
+//!
+//! Runs are listed newest first, a run can be shown node by node, and two
+//! runs can be compared, pairing up each node's results by its name, to see
+//! what changed on the fleet between them.
+
+use crate::cli::{HistoryArgs, HistoryCommand};
+use crate::diff;
+use crate::history::{self, NodeRun, Run};
+use crate::inventory;
+use crate::output::{self, Color};
+use crate::ssh::OutputStream;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+
+/// Runs the history subcommand.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if `history diff` found nodes that changed.
+pub fn run(args: &HistoryArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    match &args.command {
+        HistoryCommand::List { last } => list(&history::runs(*last)?),
+        HistoryCommand::Show { run, full } => {
+            let (run, nodes) = history::run(*run)?;
+            show(&run, &nodes, *full);
+        }
+        HistoryCommand::Diff { old, new } => {
+            let old = history::run(*old)?;
+            let new = history::run(*new)?;
+            if compare(old, new) > 0 {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the runs as a table.
+fn list(runs: &[Run]) {
+    if runs.is_empty() {
+        println!("No runs have been recorded");
+        return;
+    }
+    let width = runs
+        .iter()
+        .map(|run| run.id.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(2);
+    println!(
+        "{:<width$}  {:<24}  {:>5}  {:>9}  {:>6}  {:>9}  COMMAND",
+        "ID", "STARTED", "NODES", "SUCCEEDED", "FAILED", "WALL"
+    );
+    for run in runs {
+        let failed = format!("{:>6}", run.failed);
+        let failed = match run.failed {
+            0 => failed,
+            _ => output::paint(OutputStream::Stdout, Color::Red, &failed),
+        };
+        println!(
+            "{:<width$}  {:<24}  {:>5}  {:>9}  {}  {:>8.2}s  {}",
+            run.id, run.started_at, run.nodes, run.succeeded, failed, run.wall_time, run.command
+        );
+    }
+}
+
+/// Prints a run and how each node did in it, with the first line of each
+/// node's output, or all of it with `--full`.
+fn show(run: &Run, nodes: &[NodeRun], full: bool) {
+    println!("Run {}: {}", run.id, run.command);
+    println!(
+        "Started {}, took {:.2}s; {} of {} nodes succeeded",
+        run.started_at, run.wall_time, run.succeeded, run.nodes
+    );
+    let not_started = run.nodes - nodes.len() as i64;
+    if not_started > 0 {
+        println!("{} nodes were not started", not_started);
+    }
+    println!();
+
+    let width = nodes
+        .iter()
+        .map(|node| node.host.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    if !full {
+        println!(
+            "{:<width$}  {:<11}  {:>9}  OUTPUT",
+            "NODE", "STATUS", "DURATION"
+        );
+    }
+    for node in nodes {
+        let color = match node.status.as_str() {
+            "succeeded" => Color::Green,
+            "cancelled" => Color::Yellow,
+            _ => Color::Red,
+        };
+        if full {
+            println!(
+                "{} ({}, {:.2}s):",
+                output::paint(OutputStream::Stdout, color, &node.host),
+                outcome(node),
+                node.duration
+            );
+            print!("{}", node.stdout);
+            if !node.stdout.is_empty() && !node.stdout.ends_with('\n') {
+                println!();
+            }
+            if !node.stderr.is_empty() {
+                println!("Stderr:");
+                print!("{}", node.stderr);
+                if !node.stderr.ends_with('\n') {
+                    println!();
+                }
+            }
+            println!();
+            continue;
+        }
+        let first = match &node.error {
+            Some(error) => error.as_str(),
+            None => node
+                .stdout
+                .lines()
+                .find(|line| !line.is_empty())
+                .or_else(|| node.stderr.lines().find(|line| !line.is_empty()))
+                .unwrap_or_default(),
+        };
+        println!(
+            "{:<width$}  {}  {:>8.2}s  {}",
+            node.host,
+            output::paint(
+                OutputStream::Stdout,
+                color,
+                &format!("{:<11}", outcome(node))
+            ),
+            node.duration,
+            first
+        );
+    }
+}
+
+/// Prints what changed on each node between two runs, with the nodes whose
+/// results changed in the same way together under a diff of their output.
+///
+/// # Returns
+///
+/// Returns how many nodes changed, including those in only one of the runs.
+fn compare(old: (Run, Vec<NodeRun>), new: (Run, Vec<NodeRun>)) -> usize {
+    let ((old_run, old_nodes), (new_run, new_nodes)) = (old, new);
+    println!(
+        "Comparing run {} ({}) with run {} ({})",
+        old_run.id, old_run.started_at, new_run.id, new_run.started_at
+    );
+    if old_run.command != new_run.command {
+        println!("Run {}: {}", old_run.id, old_run.command);
+        println!("Run {}: {}", new_run.id, new_run.command);
+    } else {
+        println!("Both ran: {}", old_run.command);
+    }
+
+    // Step 1: Pair up each node's results, in the order of the later run
+    let before: BTreeMap<&str, &NodeRun> = old_nodes
+        .iter()
+        .map(|node| (node.host.as_str(), node))
+        .collect();
+    let mut unchanged = Vec::new();
+    let mut added = Vec::new();
+    // The nodes that changed, by what they changed from and to
+    let mut changed: Vec<((&NodeRun, &NodeRun), Vec<String>)> = Vec::new();
+    for node in &new_nodes {
+        let Some(old) = before.get(node.host.as_str()) else {
+            added.push(node.host.clone());
+            continue;
+        };
+        if same(old, node) {
+            unchanged.push(node.host.clone());
+            continue;
+        }
+        match changed
+            .iter_mut()
+            .find(|((was, now), _)| same(was, old) && same(now, node))
+        {
+            Some((_, hosts)) => hosts.push(node.host.clone()),
+            None => changed.push(((old, node), vec![node.host.clone()])),
+        }
+    }
+    let removed: Vec<String> = old_nodes
+        .iter()
+        .filter(|old| !new_nodes.iter().any(|node| node.host == old.host))
+        .map(|old| old.host.clone())
+        .collect();
+
+    // Step 2: Print the nodes that stayed the same, came and went, then the
+    // changes
+    println!("Unchanged: {} nodes", unchanged.len());
+    if !removed.is_empty() {
+        println!("Only in run {}: {}", old_run.id, inventory::fold(&removed));
+    }
+    if !added.is_empty() {
+        println!("Only in run {}: {}", new_run.id, inventory::fold(&added));
+    }
+    let count: usize = changed.iter().map(|(_, hosts)| hosts.len()).sum();
+    if count > 0 {
+        println!(
+            "Changed: {} nodes",
+            output::paint(OutputStream::Stdout, Color::Red, &count.to_string())
+        );
+    }
+    for ((old, new), hosts) in &changed {
+        println!();
+        println!("{}", inventory::fold(hosts));
+        let old_label = format!("run {} ({})", old_run.id, outcome(old));
+        let new_label = format!("run {} ({})", new_run.id, outcome(new));
+        if !diff::print_unified(&old_label, &old.stdout, &new_label, &new.stdout) {
+            println!("The output is the same, but the command ended differently");
+        }
+    }
+    count + removed.len() + added.len()
+}
+
+/// Returns true if a node's results in two runs are the same.
+fn same(old: &NodeRun, new: &NodeRun) -> bool {
+    old.status == new.status
+        && old.exit_code == new.exit_code
+        && old.stdout == new.stdout
+        && old.error == new.error
+}
+
+/// Returns how a node's run ended, such as "exit 0" or "timed out".
+fn outcome(node: &NodeRun) -> String {
+    match (node.status.as_str(), node.exit_code) {
+        (_, Some(code)) => format!("exit {}", code),
+        ("timed_out", None) => "timed out".to_string(),
+        ("cancelled", None) => "cancelled".to_string(),
+        _ => "error".to_string(),
+    }
+}
@@ -0,0 +1,15 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `list` subcommand, which prints the configured nodes.
+
+use crate::config::Config;
+use std::process::ExitCode;
+
+/// Prints each node in the cluster on its own line.
+pub fn run(config: &Config) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    for node in &config.cluster.nodes {
+        println!("{}", node);
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,22 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Implementations of the cluster_run subcommands.
+//!
+//! Each subcommand takes the loaded configuration and its parsed arguments,
+//! and returns the exit code cluster_run should finish with.
+
+pub mod config;
+pub mod exec;
+pub mod history;
+pub mod list;
+pub mod one;
+pub mod ping;
+pub mod pull;
+pub mod push;
+pub mod quarantine;
+pub mod rerun;
+pub mod script;
+pub mod secret;
+pub mod sync;
+pub mod watch;
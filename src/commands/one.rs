@@ -0,0 +1,94 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `one` subcommand, which runs a command on a single node.
+//!
+//! This is for when any node in the cluster will do. The node can be the first
+//! one selected, a random one, or the one least recently picked, which spreads
+//! ad hoc work across the cluster. When each node was last picked is kept in
+//! a state file so least-recently-used picks work across invocations.
+
+use crate::cancel::CancelToken;
+use crate::cli::{ConnectionArgs, OneArgs, Pick};
+use crate::commands::exec;
+use crate::config::Config;
+use crate::state;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Picks a node and executes the command on it alone.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the command failed on the node.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &OneArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let nodes = &config.cluster.nodes;
+    let mut last_used = load_last_used();
+    let node = match args.pick {
+        Pick::First => nodes.first(),
+        // RandomState is seeded randomly for every instance
+        Pick::Random => nodes.get(RandomState::new().hash_one(0) as usize % nodes.len().max(1)),
+        // Nodes that were never picked sort first, as time zero
+        Pick::Lru => nodes
+            .iter()
+            .min_by_key(|node| last_used.get(&node.host).copied().unwrap_or(0)),
+    }
+    .ok_or("There are no nodes to pick from")?
+    .clone();
+    tracing::info!("Picked node {}", node);
+
+    // Recording the pick is best effort, so it never stops the command running
+    if !connection.dry_run {
+        last_used.insert(node.host.clone(), now());
+        if let Err(e) = save_last_used(&last_used) {
+            tracing::warn!("Couldn't record the picked node: {}", e);
+        }
+    }
+
+    let mut config = config.clone();
+    config.cluster.nodes = vec![node];
+    exec::run(&config, connection, cancel, &args.exec)
+}
+
+/// Returns the path of the file recording when each node was last picked.
+fn state_path() -> Option<PathBuf> {
+    state::path("last_used.toml")
+}
+
+/// Reads when each node was last picked, as seconds since the Unix epoch.
+///
+/// A missing or unreadable state file is treated as no node having been picked.
+fn load_last_used() -> BTreeMap<String, u64> {
+    state_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| toml::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Writes when each node was last picked.
+fn save_last_used(last_used: &BTreeMap<String, u64>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = state_path().ok_or("Unable to determine home directory")?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(last_used)?)?;
+    Ok(())
+}
+
+/// Returns the current time as seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
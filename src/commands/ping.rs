@@ -0,0 +1,106 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `ping` subcommand, which checks that every node's sshd is up.
+
+use crate::cancel::{CancelToken, INTERRUPTED_EXIT};
+use crate::cli::ConnectionArgs;
+use crate::config::Config;
+use crate::executor;
+use crate::output::{self, Color};
+use crate::plan;
+use crate::retry::Started;
+use crate::ssh::{Latency, OutputStream};
+use crate::throttle;
+use crate::transport;
+use std::process::ExitCode;
+
+/// Opens a TCP connection and completes an SSH handshake with every node, then
+/// prints a table of which nodes are reachable and how quickly they respond.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if any node couldn't be reached.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let nodes = &config.cluster.nodes;
+    if connection.dry_run {
+        plan::print(nodes, |_, _| {
+            vec!["open a TCP connection and complete an SSH handshake".to_string()]
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeouts = connection.timeouts(&config.cluster);
+    let retry = connection.retry_policy();
+
+    // Probe every node, keeping the results in node order for the table
+    let mut results: Vec<Option<Result<Latency, String>>> = nodes.iter().map(|_| None).collect();
+    executor::run_concurrent(
+        nodes,
+        connection.parallel,
+        cancel,
+        async |_, node| {
+            let probe = async |_, _: &Started| {
+                throttle::wait_turn(cancel).await?;
+                transport::current().probe(node, timeouts.connect).await
+            };
+            retry.run(cancel, probe, |_, _, _| {}).await.0
+        },
+        |index, result| results[index] = Some(result.map_err(|e| e.to_string())),
+    );
+
+    let width = nodes
+        .iter()
+        .map(|node| node.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:<width$}  {:<11}  {:>10}  {:>10}",
+        "NODE", "STATUS", "TCP", "SSH"
+    );
+    let mut unreachable = 0;
+    let mut finished = 0;
+    for (node, result) in nodes.iter().zip(&results) {
+        match result {
+            Some(Ok(latency)) => println!(
+                "{:<width$}  {}  {:>10}  {:>10}",
+                node,
+                output::paint(
+                    OutputStream::Stdout,
+                    Color::Green,
+                    &format!("{:<11}", "reachable")
+                ),
+                format!("{:.2?}", latency.connect),
+                format!("{:.2?}", latency.handshake)
+            ),
+            Some(Err(e)) => {
+                println!(
+                    "{:<width$}  {}  {:>10}  {:>10}  {}",
+                    node,
+                    output::paint(OutputStream::Stdout, Color::Red, "unreachable"),
+                    "-",
+                    "-",
+                    e
+                );
+                unreachable += 1;
+            }
+            None => continue,
+        }
+        finished += 1;
+    }
+
+    if cancel.is_cancelled() {
+        output::print_cancelled(&[], nodes.len() - finished);
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+    if unreachable > 0 {
+        tracing::error!("{} of {} nodes unreachable", unreachable, nodes.len());
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,126 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `pull` subcommand, which downloads a file or directory from every node.
+
+use crate::cancel::{CancelToken, Cancelled, INTERRUPTED_EXIT};
+use crate::cli::{ConnectionArgs, PullArgs};
+use crate::config::{Config, Node};
+use crate::executor;
+use crate::output::{self, format_bytes};
+use crate::plan;
+use crate::pool;
+use crate::template::Vars;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// Downloads a remote path from every node into `<local-dir>/<node>/`.
+///
+/// Each node gets its own directory, so identically named files from
+/// different nodes never overwrite each other. If the local directory contains
+/// a placeholder such as `{host}`, it's filled in per node and used instead.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the download failed on any node.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &PullArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let remote = Path::new(&args.remote);
+    let name = remote
+        .file_name()
+        .ok_or("Remote path must name a file or directory")?;
+
+    // A destination with placeholders is used as given, otherwise each node
+    // gets its own directory
+    let local_for = |index: usize, node: &Node| {
+        let vars = Vars::for_node(index, node);
+        let dir = args.local_dir.to_string_lossy();
+        if vars.is_templated(&dir) {
+            PathBuf::from(vars.render(&dir)).join(name)
+        } else {
+            let mut dir = args.local_dir.clone();
+            dir.extend(&node.cluster);
+            dir.join(node.name()).join(name)
+        }
+    };
+
+    let nodes = &config.cluster.nodes;
+    if connection.dry_run {
+        plan::print(nodes, |index, node| {
+            vec![format!(
+                "download {} into {}",
+                remote.display(),
+                local_for(index, node).display()
+            )]
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeouts = connection.timeouts(&config.cluster);
+    let retry = connection.retry_policy();
+
+    let mut failed = 0;
+    let mut cancelled = Vec::new();
+    let mut finished = 0;
+    executor::run_concurrent(
+        nodes,
+        connection.parallel,
+        cancel,
+        async |index, node| {
+            let local = local_for(index, node);
+            let pull = async || -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                let sess = pool::checkout(node, timeouts.connect, cancel).await?;
+                let totals = sess.download(remote, &local, cancel).await?;
+                sess.release();
+                Ok(totals)
+            };
+            let (result, _) = retry
+                .run(
+                    cancel,
+                    async |_, _| pull().await,
+                    |attempt, e, delay| {
+                        tracing::warn!(
+                            "Attempt {} for node {} failed: {}; retrying in {:.2?}",
+                            attempt,
+                            node,
+                            e,
+                            delay
+                        )
+                    },
+                )
+                .await;
+            (result, local)
+        },
+        |index, (result, local)| {
+            finished += 1;
+            match result {
+                Ok(totals) => tracing::info!(
+                    "Pulled {} files ({}) from {} into {}",
+                    totals.files,
+                    format_bytes(totals.bytes),
+                    nodes[index],
+                    local.display()
+                ),
+                Err(e) if e.is::<Cancelled>() => cancelled.push(nodes[index].to_string()),
+                Err(e) => {
+                    tracing::error!("Error for node {}: {}", nodes[index], e);
+                    failed += 1;
+                }
+            }
+        },
+    );
+
+    if cancel.is_cancelled() {
+        output::print_cancelled(&cancelled, nodes.len() - finished);
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+    if failed > 0 {
+        tracing::error!("{} of {} nodes failed", failed, nodes.len());
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
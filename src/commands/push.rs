@@ -0,0 +1,435 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `push` subcommand, which uploads a file to every node.
+
+use crate::cancel::{CancelToken, Cancelled, INTERRUPTED_EXIT};
+use crate::cli::{ConnectionArgs, PushArgs};
+use crate::config::{Config, Node};
+use crate::executor;
+use crate::host_key::{self, Checking};
+use crate::output::{self, format_bytes};
+use crate::plan;
+use crate::pool;
+use crate::shell;
+use crate::template::Vars;
+use crate::transfer::{self, Resumed};
+use crate::verify;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+/// Files smaller than this are uploaded without progress messages.
+const PROGRESS_THRESHOLD: u64 = 1024 * 1024;
+
+/// Uploads a local file to the same path on every node in parallel.
+///
+/// Placeholders in the remote path, such as `{host}`, are filled in per node.
+/// Progress is reported per node every quarter of the way through large files.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the upload failed on any node.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &PushArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let metadata = fs::metadata(&args.local)?;
+    if metadata.is_dir() {
+        return Err(format!("{} is a directory", args.local.display()).into());
+    }
+
+    // Placeholders are filled in per node, and a remote path ending in a slash
+    // is a directory to put the file into
+    let name = args
+        .local
+        .file_name()
+        .ok_or("Local path has no file name")?;
+    let remote_for = |index: usize, node: &Node| {
+        let remote = Vars::for_node(index, node).render(&args.remote);
+        if remote.ends_with('/') {
+            PathBuf::from(remote).join(name)
+        } else {
+            PathBuf::from(remote)
+        }
+    };
+    let mode = args.mode.unwrap_or(metadata.permissions().mode() & 0o7777);
+    let digest = match args.verify {
+        Some(_) => Some(verify::digest_file(&args.local)?),
+        None => None,
+    };
+
+    let nodes = &config.cluster.nodes;
+    if connection.dry_run {
+        plan::print(nodes, |index, node| {
+            plan_steps(args, index, &remote_for(index, node), &metadata, mode)
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let timeouts = connection.timeouts(&config.cluster);
+    let retry = connection.retry_policy();
+
+    let mut failed = 0;
+    let mut cancelled = Vec::new();
+    let mut finished = 0;
+    let task = async |index: usize, node: &Node, source: Option<usize>| {
+        let remote = remote_for(index, node);
+        let push = async || -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+            let started = Instant::now();
+            let sess = pool::checkout(node, timeouts.connect, cancel).await?;
+
+            // A node in a cascade copies the file from another node rather
+            // than have it uploaded, and may take far longer than a command
+            let resumed = match source {
+                Some(source) => {
+                    let peer = &nodes[source];
+                    let command = copy_command(
+                        host_key::checking(),
+                        peer,
+                        &Vars::for_node(source, peer).render(&args.peer_address),
+                        &remote_for(source, peer),
+                        &transfer::partial_path(&remote, &metadata),
+                        &remote,
+                        mode,
+                    );
+                    let result = sess
+                        .exec(&command, None, None, cancel, &mut |_, _| {})
+                        .await?;
+                    if result.exit_code != 0 {
+                        return Err(format!(
+                            "copying from {} failed with exit status {}: {}",
+                            peer,
+                            result.exit_code,
+                            result.stderr.trim()
+                        )
+                        .into());
+                    }
+                    Resumed {
+                        kept: 0,
+                        sent: metadata.len(),
+                    }
+                }
+                None => {
+                    let mut reported = 0;
+                    let mut on_progress = |sent: u64, total: u64| {
+                        if total < PROGRESS_THRESHOLD {
+                            return;
+                        }
+                        let quarter = sent * 4 / total;
+                        if quarter > reported && quarter < 4 {
+                            reported = quarter;
+                            tracing::info!(
+                                "[{}] {}% of {}",
+                                node,
+                                quarter * 25,
+                                format_bytes(total)
+                            );
+                        }
+                    };
+                    if args.resume {
+                        sess.resume_upload(&args.local, &remote, mode, cancel, &mut on_progress)
+                            .await?
+                    } else {
+                        let sent = sess
+                            .upload(&args.local, &remote, mode, cancel, &mut on_progress)
+                            .await?;
+                        Resumed { kept: 0, sent }
+                    }
+                }
+            };
+
+            if let Some(owner) = &args.owner {
+                let command = chown_command(owner, &remote);
+                let result = sess
+                    .exec(&command, None, timeouts.command, cancel, &mut |_, _| {})
+                    .await?;
+                if result.exit_code != 0 {
+                    return Err(format!("chown failed: {}", result.stderr.trim()).into());
+                }
+            }
+            if let (Some(algorithm), Some(digest)) = (args.verify, &digest) {
+                let files = [(remote.clone(), digest.clone())];
+                verify::check(&*sess, algorithm, &files, timeouts.command, cancel).await?;
+            }
+            sess.release();
+
+            match source {
+                Some(source) => tracing::info!(
+                    "Copied {} to {}:{} from {} in {:.2?}",
+                    format_bytes(resumed.sent),
+                    node,
+                    remote.display(),
+                    nodes[source],
+                    started.elapsed()
+                ),
+                None if resumed.kept > 0 => tracing::info!(
+                    "Pushed {} to {}:{} in {:.2?}, resuming after the {} sent before",
+                    format_bytes(resumed.sent),
+                    node,
+                    remote.display(),
+                    started.elapsed(),
+                    format_bytes(resumed.kept)
+                ),
+                None => tracing::info!(
+                    "Pushed {} to {}:{} in {:.2?}",
+                    format_bytes(resumed.sent),
+                    node,
+                    remote.display(),
+                    started.elapsed()
+                ),
+            }
+            Ok(resumed.sent)
+        };
+        retry
+            .run(
+                cancel,
+                async |_, _| push().await,
+                |attempt, e, delay| {
+                    tracing::warn!(
+                        "Attempt {} for node {} failed: {}; retrying in {:.2?}",
+                        attempt,
+                        node,
+                        e,
+                        delay
+                    )
+                },
+            )
+            .await
+    };
+    let mut on_result =
+        |index: usize, result: Result<u64, Box<dyn std::error::Error + Send + Sync>>| {
+            finished += 1;
+            match result {
+                Ok(_) => return true,
+                Err(e) if e.is::<Cancelled>() => cancelled.push(nodes[index].to_string()),
+                Err(e) => {
+                    tracing::error!("Error for node {}: {}", nodes[index], e);
+                    failed += 1;
+                }
+            }
+            false
+        };
+    match args.fanout {
+        Some(seeds) => executor::run_cascade(
+            nodes,
+            seeds,
+            connection.parallel,
+            cancel,
+            task,
+            |index, (result, _)| on_result(index, result),
+        ),
+        None => executor::run_concurrent(
+            nodes,
+            connection.parallel,
+            cancel,
+            async |index, node| task(index, node, None).await,
+            |index, (result, _)| {
+                on_result(index, result);
+            },
+        ),
+    }
+
+    if cancel.is_cancelled() {
+        output::print_cancelled(&cancelled, nodes.len() - finished);
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+    if failed > 0 {
+        tracing::error!("{} of {} nodes failed", failed, nodes.len());
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Returns what pushing does on the node at `index`, for `--dry-run`: nodes
+/// past the first `--fanout` seeds copy the file from one that has it.
+fn plan_steps(
+    args: &PushArgs,
+    index: usize,
+    remote: &Path,
+    metadata: &fs::Metadata,
+    mode: u32,
+) -> Vec<String> {
+    let mut steps = Vec::new();
+    if args.fanout.is_some_and(|seeds| index >= seeds) {
+        steps.push(format!(
+            "copy {} over ssh from a node that has it, to {} with mode {:o}",
+            args.local.display(),
+            remote.display(),
+            mode
+        ));
+    } else if args.resume {
+        let partial = transfer::partial_path(remote, metadata);
+        steps.push(format!(
+            "upload {} to {} with mode {:o}, keeping what's there already",
+            args.local.display(),
+            partial.display(),
+            mode
+        ));
+        steps.push(format!(
+            "move {} to {}",
+            partial.display(),
+            remote.display()
+        ));
+    } else {
+        steps.push(format!(
+            "upload {} to {} with mode {:o}",
+            args.local.display(),
+            remote.display(),
+            mode
+        ));
+    }
+    if let Some(owner) = &args.owner {
+        steps.push(format!("$ {}", chown_command(owner, remote)));
+    }
+    if args.verify.is_some() {
+        steps.push(format!("check the digest of {}", remote.display()));
+    }
+    steps
+}
+
+/// Returns the command that copies the file over SSH from a node that has
+/// it, at `address`, to a partial file and then into place with `mode`,
+/// removing the partial file if the copy fails. The peer's host key is
+/// checked as `--strict-host-key-checking` says.
+fn copy_command(
+    checking: Checking,
+    peer: &Node,
+    address: &str,
+    from: &Path,
+    partial: &Path,
+    to: &Path,
+    mode: u32,
+) -> String {
+    let cat = format!("cat -- {}", shell::quote(&from.to_string_lossy()));
+    let partial = shell::quote(&partial.to_string_lossy());
+    format!(
+        "ssh -o BatchMode=yes {} -p {} -l {} -- {} {} > {partial} && chmod {:o} {partial} \
+         && mv -f -- {partial} {} || {{ status=$?; rm -f -- {partial}; exit $status; }}",
+        checking.ssh_options().join(" "),
+        peer.port(),
+        shell::quote(peer.user()),
+        shell::quote(address),
+        shell::quote(&cat),
+        mode,
+        shell::quote(&to.to_string_lossy())
+    )
+}
+
+/// Returns the command that gives the uploaded file its new owner.
+fn chown_command(owner: &str, remote: &Path) -> String {
+    format!(
+        "chown {} {}",
+        shell::quote(owner),
+        shell::quote(&remote.to_string_lossy())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::env;
+
+    #[derive(Parser)]
+    struct Push {
+        #[command(flatten)]
+        push: PushArgs,
+    }
+
+    fn copy_from_gpu_01(checking: Checking) -> String {
+        let mut peer = Node::from("gpu-01");
+        peer.user = Some("ops".to_string());
+        peer.port = Some(2222);
+        copy_command(
+            checking,
+            &peer,
+            "gpu-01-ib",
+            Path::new("/data/model's.pt"),
+            Path::new("/data/.model.pt.partial"),
+            Path::new("/data/model.pt"),
+            0o640,
+        )
+    }
+
+    #[test]
+    fn copies_from_a_peer_without_a_prompt() {
+        assert_eq!(
+            copy_from_gpu_01(Checking::AcceptNew),
+            "ssh -o BatchMode=yes -o StrictHostKeyChecking=accept-new -p 2222 -l ops -- \
+             gpu-01-ib 'cat -- '\\''/data/model'\\''\\'\\'''\\''s.pt'\\''' \
+             > /data/.model.pt.partial && chmod 640 /data/.model.pt.partial \
+             && mv -f -- /data/.model.pt.partial /data/model.pt \
+             || { status=$?; rm -f -- /data/.model.pt.partial; exit $status; }"
+        );
+    }
+
+    #[test]
+    fn checks_the_peer_as_the_flag_says() {
+        let options = |checking| {
+            let command = copy_from_gpu_01(checking);
+            command[..command.find(" -p ").unwrap()].to_string()
+        };
+        assert_eq!(
+            options(Checking::Yes),
+            "ssh -o BatchMode=yes -o StrictHostKeyChecking=yes"
+        );
+        assert_eq!(
+            options(Checking::No),
+            "ssh -o BatchMode=yes -o StrictHostKeyChecking=no \
+             -o UserKnownHostsFile=/dev/null -o GlobalKnownHostsFile=/dev/null"
+        );
+    }
+
+    #[test]
+    fn plans_uploads_to_seeds_and_copies_to_the_rest() {
+        let path = env::temp_dir().join(format!("cluster_run-push-{}", std::process::id()));
+        fs::write(&path, "weights").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let args = |flags: &[&str]| {
+            let cli = ["cluster_run", "a.pt", "/data/a.pt"];
+            Push::parse_from(cli.iter().chain(flags)).push
+        };
+        let steps = |args: &PushArgs, index| {
+            plan_steps(args, index, Path::new("/data/a.pt"), &metadata, 0o644)
+        };
+        let upload = ["upload a.pt to /data/a.pt with mode 644".to_string()];
+        let copy = [
+            "copy a.pt over ssh from a node that has it, to /data/a.pt with mode 644".to_string(),
+        ];
+
+        let plain = args(&[]);
+        assert_eq!(steps(&plain, 0), upload);
+        assert_eq!(steps(&plain, 5), upload);
+
+        let fanout = args(&["--fanout", "2"]);
+        assert_eq!(steps(&fanout, 0), upload);
+        assert_eq!(steps(&fanout, 1), upload);
+        assert_eq!(steps(&fanout, 2), copy);
+        assert_eq!(steps(&fanout, 5), copy);
+
+        // Only the seeds are uploaded to, so only they resume
+        let resume = args(&["--fanout", "1", "--resume", "--owner", "ops"]);
+        let partial = transfer::partial_path(Path::new("/data/a.pt"), &metadata);
+        assert_eq!(
+            steps(&resume, 0),
+            [
+                format!(
+                    "upload a.pt to {} with mode 644, keeping what's there already",
+                    partial.display()
+                ),
+                format!("move {} to /data/a.pt", partial.display()),
+                "$ chown ops /data/a.pt".to_string(),
+            ]
+        );
+        assert_eq!(
+            steps(&resume, 1),
+            [copy[0].clone(), "$ chown ops /data/a.pt".to_string()]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `quarantine` subcommand, which maintains the list of nodes skipped by
+//! every run.
+
+use crate::cli::{QuarantineArgs, QuarantineCommand};
+use crate::quarantine::{self, Entry};
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Runs the quarantine subcommand.
+pub fn run(args: &QuarantineArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut quarantined = quarantine::load()?;
+    match &args.command {
+        QuarantineCommand::Add { nodes, reason } => {
+            for node in nodes {
+                quarantined.insert(node.clone(), Entry::new(reason.clone()));
+                println!("Quarantined {}", node);
+            }
+            quarantine::save(&quarantined)?;
+        }
+        QuarantineCommand::Remove { nodes } => {
+            let mut missing = false;
+            for node in nodes {
+                if quarantined.remove(node).is_some() {
+                    println!("Released {}", node);
+                } else {
+                    eprintln!("{} is not quarantined", node);
+                    missing = true;
+                }
+            }
+            quarantine::save(&quarantined)?;
+            if missing {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        QuarantineCommand::List => list(&quarantined),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the quarantined nodes with how long ago, by whom and why.
+fn list(quarantined: &BTreeMap<String, Entry>) {
+    if quarantined.is_empty() {
+        println!("No nodes are quarantined");
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let width = quarantined
+        .keys()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!("{:<width$}  {:>8}  {:<12}  REASON", "NODE", "SINCE", "BY");
+    for (node, entry) in quarantined {
+        println!(
+            "{:<width$}  {:>8}  {:<12}  {}",
+            node,
+            age(now.saturating_sub(entry.since)),
+            entry.by.as_deref().unwrap_or("-"),
+            entry.reason.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+/// Formats a number of seconds in its largest whole unit, e.g. "3d ago".
+fn age(secs: u64) -> String {
+    let (count, unit) = match secs {
+        0..60 => (secs, "s"),
+        60..3600 => (secs / 60, "m"),
+        3600..86400 => (secs / 3600, "h"),
+        _ => (secs / 86400, "d"),
+    };
+    format!("{}{} ago", count, unit)
+}
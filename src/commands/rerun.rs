@@ -0,0 +1,141 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `rerun` subcommand, which runs a past run's command again.
+//!
+//! The command is taken from the history and run on the nodes the past run
+//! was on, or with `--only-failed` on just those that didn't succeed, so a
+//! rollout that failed part way can be finished without piecing the command
+//! and the node list back together by hand. The nodes still have to be in
+//! the config, and the usual `--limit`, `--exclude` and quarantine apply.
+//! The command runs in the directory, with the environment and as the user
+//! the past run gave it, along with any `--cwd`, `--env` or sudo options
+//! given to `rerun`.
+
+use crate::cancel::CancelToken;
+use crate::cli::{ConnectionArgs, RerunArgs};
+use crate::commands::exec;
+use crate::config::Config;
+use crate::history;
+use crate::inventory;
+use crate::redact;
+use std::process::ExitCode;
+
+/// Runs a past run's command again on its nodes.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the failures violate the failure policy, or
+/// an error if the run can't be found or wasn't an `exec` run.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &RerunArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // Step 1: Look the run up, and make sure it ran a command
+    let (past, results) = history::run(args.id)?;
+    match past.subcommand.as_deref() {
+        Some("exec") => {}
+        Some(subcommand) => {
+            return Err(format!(
+                "run {} was a {} run, and only exec runs can be rerun",
+                past.id, subcommand
+            )
+            .into())
+        }
+        None => {
+            return Err(format!(
+                "run {} was recorded before the history kept what ran it, so it can't be rerun",
+                past.id
+            )
+            .into())
+        }
+    }
+
+    if past.command.contains(redact::MASK) {
+        return Err(format!(
+            "a secret was masked in the command of run {}, so it can't be rerun",
+            past.id
+        )
+        .into());
+    }
+    let mut run = args.run.clone();
+    match &past.wrapping {
+        Some(wrapping) if wrapping.env.iter().any(|(_, value)| value.contains(redact::MASK)) => {
+            return Err(format!(
+                "a secret was masked in the --env of run {}, so it can't be rerun",
+                past.id
+            )
+            .into())
+        }
+        Some(wrapping) => run.rewrap(wrapping),
+        None => tracing::warn!(
+            "Run {} was recorded before the history kept its --cwd, --env and sudo options, so it's rerun without them",
+            past.id
+        ),
+    }
+
+    // Step 2: Work out which nodes to run on
+    let mut wanted = past
+        .selected
+        .clone()
+        .unwrap_or_else(|| results.iter().map(|node| node.host.clone()).collect());
+    if args.only_failed {
+        wanted.retain(|name| {
+            !results
+                .iter()
+                .any(|node| &node.host == name && node.status == "succeeded")
+        });
+        if wanted.is_empty() {
+            tracing::info!(
+                "Every node succeeded in run {}, so there's nothing to rerun",
+                past.id
+            );
+            return Ok(ExitCode::SUCCESS);
+        }
+    }
+    let mut config = config.clone();
+    config
+        .cluster
+        .nodes
+        .retain(|node| wanted.contains(&node.to_string()));
+    let missing: Vec<String> = wanted
+        .iter()
+        .filter(|name| {
+            !config
+                .cluster
+                .nodes
+                .iter()
+                .any(|node| &node.to_string() == *name)
+        })
+        .cloned()
+        .collect();
+    if config.cluster.nodes.is_empty() {
+        return Err(format!("none of the nodes of run {} are selected", past.id).into());
+    }
+    if !missing.is_empty() {
+        tracing::warn!(
+            "Skipping {} nodes of run {} that aren't selected: {}",
+            missing.len(),
+            past.id,
+            inventory::fold(&missing)
+        );
+    }
+
+    // Step 3: Run the command again
+    tracing::info!(
+        "Rerunning run {} on {} nodes: {}",
+        past.id,
+        config.cluster.nodes.len(),
+        past.command
+    );
+    exec::run_command(
+        &config,
+        connection,
+        cancel,
+        &run,
+        args.reconnect,
+        &past.command,
+    )
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `script` subcommand, which runs a local script on every node.
+//!
+//! The script is uploaded to a fresh temporary file on each node, made
+//! executable, run with the given arguments, and removed afterwards whether
+//! or not it succeeded. This avoids quoting multi-step operations into a
+//! single command line.
+
+use crate::cancel::CancelToken;
+use crate::cli::{ConnectionArgs, ScriptArgs};
+use crate::commands::exec;
+use crate::config::Config;
+use crate::plan;
+use crate::shell::{self, Sudo};
+use crate::template::Vars;
+use std::path::Path;
+use std::process::ExitCode;
+
+/// The command that reserves the temporary file on each node.
+const MKTEMP: &str = "mktemp /tmp/cluster_run.XXXXXX";
+
+/// Runs a local script on the nodes of the cluster concurrently.
+///
+/// Placeholders in the script's arguments are filled in separately for each node.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the failures violate the failure policy.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &ScriptArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if !args.script.is_file() {
+        return Err(format!("{} is not a file", args.script.display()).into());
+    }
+    let quoted_args = |vars: &Vars| -> Vec<String> {
+        args.args
+            .iter()
+            .map(|arg| shell::quote(&vars.render(arg)))
+            .collect()
+    };
+    let description = format!("{} {}", args.script.display(), args.args.join(" "));
+    if connection.dry_run {
+        plan::print(&config.cluster.nodes, |index, node| {
+            let quoted_args = quoted_args(&Vars::for_node(index, node));
+            let mut steps = vec![
+                format!("$ {}", MKTEMP),
+                format!("upload {} to the temporary file", args.script.display()),
+                format!(
+                    "$ {}",
+                    args.run.wrap(
+                        &format!("<temporary file> {}", quoted_args.join(" ")),
+                        &config.cluster
+                    )
+                ),
+            ];
+            if args.run.sudo(&config.cluster) == Sudo::Password {
+                steps.push("with the sudo password on standard input".to_string());
+            }
+            if args.run.stdin {
+                steps.push("with a copy of local standard input".to_string());
+            }
+            steps.push("$ rm -f <temporary file>".to_string());
+            steps
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+    let timeouts = connection.timeouts(&config.cluster);
+    let password = args.run.sudo_password(&config.cluster)?;
+    let stdin = args.run.read_stdin(password.as_deref())?;
+
+    exec::execute(
+        config,
+        connection,
+        &args.run,
+        cancel,
+        "script",
+        description.trim_end(),
+        async |sess, _, vars, on_output| {
+            // Reserve a unique path on the node for the script
+            let mktemp = sess
+                .exec(MKTEMP, None, timeouts.command, cancel, &mut |_, _| {})
+                .await?;
+            if mktemp.exit_code != 0 {
+                return Err(format!("mktemp failed: {}", mktemp.stderr.trim()).into());
+            }
+            let remote = mktemp.stdout.trim().to_string();
+
+            // Upload and run the script, then clean up regardless of the outcome
+            let uploaded = sess
+                .upload(
+                    &args.script,
+                    Path::new(&remote),
+                    0o700,
+                    cancel,
+                    &mut |_, _| {},
+                )
+                .await;
+            let result = match uploaded {
+                Ok(_) => {
+                    let mut command = shell::quote(&remote);
+                    for arg in quoted_args(vars) {
+                        command.push(' ');
+                        command.push_str(&arg);
+                    }
+                    sess.exec(
+                        &args.run.wrap(&command, &config.cluster),
+                        stdin.as_deref(),
+                        timeouts.command,
+                        cancel,
+                        on_output,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            let cleanup = format!("rm -f {}", shell::quote(&remote));
+            // Clean up even when cancelled, so use a token that is never cancelled
+            let no_cancel = CancelToken::default();
+            let _ = sess
+                .exec(&cleanup, None, timeouts.command, &no_cancel, &mut |_, _| {})
+                .await;
+            result
+        },
+    )
+}
@@ -0,0 +1,28 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `secret` subcommand, which prepares encrypted values for the
+//! configuration file.
+
+use crate::cli::{SecretArgs, SecretCommand};
+use crate::secrets;
+use std::io::{self, IsTerminal, Read};
+use std::process::ExitCode;
+
+/// Runs the secret subcommand.
+pub fn run(args: &SecretArgs) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    match &args.command {
+        SecretCommand::Encrypt { recipient } => {
+            // Prompt without echoing, unless the value is piped in
+            let value = if io::stdin().is_terminal() {
+                rpassword::prompt_password("Value to encrypt: ")?
+            } else {
+                let mut value = String::new();
+                io::stdin().read_to_string(&mut value)?;
+                value.trim_end_matches(['\r', '\n']).to_string()
+            };
+            println!("{}", secrets::encrypt(&value, recipient)?);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,203 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `sync` subcommand, which mirrors a local directory to every node.
+
+use crate::archive::{self, Archives};
+use crate::cancel::{CancelToken, Cancelled, INTERRUPTED_EXIT};
+use crate::cli::{ConnectionArgs, SyncArgs};
+use crate::config::{Config, Node};
+use crate::executor;
+use crate::output::{self, format_bytes};
+use crate::plan;
+use crate::pool;
+use crate::sync::{self, Options, Synced, Tree};
+use crate::template::Vars;
+use crate::verify;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+/// Changes smaller than this are sent without progress messages.
+const PROGRESS_THRESHOLD: u64 = 1024 * 1024;
+
+/// Mirrors a local directory to a directory on every node in parallel,
+/// sending only the files that changed.
+///
+/// Placeholders in the remote path, such as `{host}`, are filled in per node.
+/// Progress is reported per node every quarter of the way through large
+/// changes.
+///
+/// # Returns
+///
+/// Returns a failing ExitCode if the sync failed on any node.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &SyncArgs,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let tree = Tree::walk(&args.local, args.checksum || args.verify.is_some())?;
+    let digests: HashMap<&Path, &str> = tree
+        .entries
+        .iter()
+        .filter_map(|entry| Some((entry.path.as_path(), entry.digest.as_deref()?)))
+        .collect();
+    let (files, bytes) = tree.totals();
+    let remote_for =
+        |index: usize, node: &Node| PathBuf::from(Vars::for_node(index, node).render(&args.remote));
+
+    let nodes = &config.cluster.nodes;
+    if connection.dry_run {
+        plan::print(nodes, |index, node| {
+            let remote = remote_for(index, node);
+            let mut steps = Vec::new();
+            if args.checksum {
+                steps.push(format!("$ {}", sync::digest_command(&remote)));
+            }
+            steps.push(format!(
+                "send whichever of the {} files ({}) in {} differ from those in {}",
+                files,
+                format_bytes(bytes),
+                args.local.display(),
+                remote.display()
+            ));
+            if args.delete {
+                steps.push(format!("remove whatever else is in {}", remote.display()));
+            }
+            if args.verify.is_some() {
+                steps.push("check the digests of the files sent".to_string());
+            }
+            steps
+        });
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let report = |node: &Node, remote: &Path, started: Instant, synced: &Synced| {
+        tracing::info!(
+            "Synced {} to {}:{} in {:.2?}: sent {} files ({}), removed {}, {} up to date",
+            args.local.display(),
+            node,
+            remote.display(),
+            started.elapsed(),
+            synced.files,
+            format_bytes(synced.bytes),
+            synced.removed,
+            files as u64 - synced.files
+        )
+    };
+
+    let timeouts = connection.timeouts(&config.cluster);
+    let retry = connection.retry_policy();
+
+    let archives = Archives::default();
+    let mut failed = 0;
+    let mut cancelled = Vec::new();
+    let mut finished = 0;
+    executor::run_concurrent(
+        nodes,
+        connection.parallel,
+        cancel,
+        async |index, node| {
+            let remote = remote_for(index, node);
+            let sync = async || -> Result<Synced, Box<dyn std::error::Error + Send + Sync>> {
+                let started = Instant::now();
+                let sess = pool::checkout(node, timeouts.connect, cancel).await?;
+
+                // The node works out its own digests, so its files aren't
+                // read over the network to compare them
+                let mut options = Options {
+                    delete: args.delete,
+                    digests: None,
+                };
+                if args.checksum {
+                    let command = sync::digest_command(&remote);
+                    let result = sess
+                        .exec(&command, None, timeouts.command, cancel, &mut |_, _| {})
+                        .await?;
+                    options.digests = Some(sync::parse_digests(&result.stdout));
+                }
+
+                let synced = if args.tar {
+                    archive::sync(
+                        &*sess,
+                        &tree,
+                        &remote,
+                        &options,
+                        &archives,
+                        args.compress,
+                        timeouts.command,
+                        cancel,
+                    )
+                    .await?
+                } else {
+                    let mut reported = 0;
+                    sess.sync(&tree, &remote, &options, cancel, &mut |sent, total| {
+                        if total < PROGRESS_THRESHOLD {
+                            return;
+                        }
+                        let quarter = sent * 4 / total;
+                        if quarter > reported && quarter < 4 {
+                            reported = quarter;
+                            tracing::info!(
+                                "[{}] {}% of {}",
+                                node,
+                                quarter * 25,
+                                format_bytes(total)
+                            );
+                        }
+                    })
+                    .await?
+                };
+                if let Some(algorithm) = args.verify {
+                    let files: Vec<(PathBuf, String)> = synced
+                        .sent
+                        .iter()
+                        .map(|path| (remote.join(path), digests[path.as_path()].to_string()))
+                        .collect();
+                    verify::check(&*sess, algorithm, &files, timeouts.command, cancel).await?;
+                }
+                sess.release();
+                report(node, &remote, started, &synced);
+                Ok(synced)
+            };
+            retry
+                .run(
+                    cancel,
+                    async |_, _| sync().await,
+                    |attempt, e, delay| {
+                        tracing::warn!(
+                            "Attempt {} for node {} failed: {}; retrying in {:.2?}",
+                            attempt,
+                            node,
+                            e,
+                            delay
+                        )
+                    },
+                )
+                .await
+        },
+        |index, (result, _)| {
+            finished += 1;
+            match result {
+                Ok(_) => {}
+                Err(e) if e.is::<Cancelled>() => cancelled.push(nodes[index].to_string()),
+                Err(e) => {
+                    tracing::error!("Error for node {}: {}", nodes[index], e);
+                    failed += 1;
+                }
+            }
+        },
+    );
+
+    if cancel.is_cancelled() {
+        output::print_cancelled(&cancelled, nodes.len() - finished);
+        return Ok(ExitCode::from(INTERRUPTED_EXIT));
+    }
+    if failed > 0 {
+        tracing::error!("{} of {} nodes failed", failed, nodes.len());
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
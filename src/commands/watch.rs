@@ -0,0 +1,155 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The `watch` subcommand, which re-runs a command on every node on a timer.
+//!
+//! Each node's session is kept in the pool between runs, so only the first
+//! run pays for connecting and authenticating. A node whose session fails is
+//! reconnected on the next run. The screen is redrawn after every run with
+//! the latest output from all nodes, in node order.
+//!
+//! The configuration is reloaded whenever its files change, so the nodes
+//! follow allocations that change while the watch runs. Nodes that stay and
+//! log in the same way keep their sessions.
+
+use crate::cancel::CancelToken;
+use crate::cli::{ConnectionArgs, WatchArgs};
+use crate::config::Config;
+use crate::executor;
+use crate::output::{self, Color};
+use crate::pool;
+use crate::redact;
+use crate::reload::{Load, Reloader};
+use crate::ssh::OutputStream;
+use crate::template::Vars;
+use std::process::ExitCode;
+use std::time::Instant;
+
+/// Clears the terminal and moves the cursor to the top left.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Runs a command on every node repeatedly until interrupted.
+///
+/// # Arguments
+///
+/// * `load` - Loads the configuration again when its files change.
+///
+/// # Returns
+///
+/// Returns a successful ExitCode once interrupted, or after `--count` runs.
+pub fn run(
+    config: &Config,
+    connection: &ConnectionArgs,
+    cancel: &CancelToken,
+    args: &WatchArgs,
+    load: &Load,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut config = config.clone();
+    let command = config.fill(&args.command.join(" "));
+    let mut reloader = Reloader::new(load, &config);
+    let mut last_change = None;
+
+    let mut runs = 0;
+    loop {
+        let started = Instant::now();
+        runs += 1;
+
+        // Pick up changes to the config
+        match reloader.poll(&config) {
+            None => {}
+            Some(Err(e)) => {
+                last_change = Some(format!(
+                    "Unable to reload the config before run {}, so kept the old one: {}",
+                    runs, e
+                ));
+            }
+            Some(Ok((reloaded, change))) => {
+                // Patterns that were added are masked too; none are unmasked
+                if let Err(e) = redact::use_settings(&reloaded.redact) {
+                    tracing::warn!("{}", e);
+                }
+                config = reloaded;
+                last_change = Some(format!(
+                    "Reloaded the config before run {}: added {}, removed {}",
+                    runs,
+                    names(&change.added),
+                    names(&change.removed)
+                ));
+            }
+        }
+        let nodes = &config.cluster.nodes;
+        let timeouts = connection.timeouts(&config.cluster);
+
+        // Run the command everywhere, connecting only nodes without a live session
+        let mut outputs: Vec<String> = vec![String::new(); nodes.len()];
+        executor::run_concurrent(
+            nodes,
+            connection.parallel,
+            cancel,
+            async |index, node| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                let sess = pool::checkout(node, timeouts.connect, cancel).await?;
+                let command = Vars::for_node(index, node).render(&command);
+                let result = sess
+                    .exec(&command, None, timeouts.command, cancel, &mut |_, _| {})
+                    .await?;
+                sess.release();
+                Ok(result)
+            },
+            |index, result| {
+                let output = match result {
+                    Ok(result) if result.exit_code == 0 => result.stdout,
+                    Ok(result) => format!(
+                        "{}{}(exited with status {})\n",
+                        result.stdout, result.stderr, result.exit_code
+                    ),
+                    Err(e) => format!("(error: {})\n", e),
+                };
+                outputs[index] = redact::text(&output).into_owned();
+            },
+        );
+        if cancel.is_cancelled() {
+            break;
+        }
+
+        // Redraw the consolidated view
+        print!("{}", CLEAR_SCREEN);
+        println!(
+            "Every {:.1?}: {}    (run {}, took {:.2?})",
+            args.interval,
+            redact::text(&command),
+            runs,
+            started.elapsed()
+        );
+        if let Some(change) = &last_change {
+            println!("{}", change);
+        }
+        for (node, output) in nodes.iter().zip(&outputs) {
+            println!();
+            println!(
+                "{}",
+                output::paint(OutputStream::Stdout, Color::Dim, &format!("[{}]", node))
+            );
+            print!("{}", output);
+            if !output.is_empty() && !output.ends_with('\n') {
+                println!();
+            }
+        }
+
+        if args.count.is_some_and(|count| runs >= count) {
+            break;
+        }
+        if !cancel.sleep(args.interval.saturating_sub(started.elapsed())) {
+            break;
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Lists node names for a reload notice, or "none".
+fn names(nodes: &[String]) -> String {
+    if nodes.is_empty() {
+        return "none".to_string();
+    }
+    nodes.join(", ")
+}
@@ -0,0 +1,134 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Configuration files that include other configuration files.
+//!
+//! A file may list other files in `include`, with relative paths taken from
+//! the including file's directory. The included files are read in order and
+//! merged, and then the including file is merged on top of them, so later
+//! files override earlier ones:
+//! - Tables are merged key by key.
+//! - Lists, such as node lists and group members, are concatenated.
+//! - Any other value replaces the earlier one.
+//!
+//! Included files may include further files, and may be in any of the
+//! supported formats.
+
+use super::loader::Format;
+use super::ConfigFile;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The key listing the files a configuration file includes.
+const INCLUDE: &str = "include";
+
+/// Reads a configuration file along with the files it includes.
+///
+/// # Returns
+///
+/// Returns the merged configuration, which records every file that was
+/// read, or an error naming the file that couldn't be read or parsed.
+pub fn read(path: &Path) -> Result<ConfigFile, String> {
+    let content = read_file(path)?;
+    let value: Value = parse(path, &content)?;
+    let mut files = vec![path.to_path_buf()];
+    // Files without includes are parsed directly, for errors with line numbers
+    let mut file: ConfigFile = if value.get(INCLUDE).is_none() {
+        parse(path, &content)?
+    } else {
+        let merged = resolve(path, value, &mut Vec::new(), &mut files)?;
+        serde_json::from_value(merged).map_err(|e| e.to_string())?
+    };
+    file.files = files;
+    Ok(file)
+}
+
+/// Parses the contents of a file in whichever format it's in.
+fn parse<T: DeserializeOwned>(path: &Path, content: &str) -> Result<T, String> {
+    match Format::of(path) {
+        Format::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        Format::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+    }
+}
+
+/// Reads a file as text.
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path.display(), e))
+}
+
+/// Merges the files a file includes, and then the file itself.
+///
+/// # Arguments
+///
+/// * `path` - The file's path, which relative includes are resolved against.
+/// * `value` - The file's contents.
+/// * `including` - The files that led to this one, to catch include cycles.
+/// * `files` - Every file read so far, which the included files are added to.
+fn resolve(
+    path: &Path,
+    mut value: Value,
+    including: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Value, String> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if including.contains(&canonical) {
+        return Err(format!("{} includes itself", path.display()));
+    }
+
+    let includes = match value
+        .as_object_mut()
+        .and_then(|table| table.remove(INCLUDE))
+    {
+        None => Vec::new(),
+        Some(Value::Array(includes)) => includes,
+        Some(_) => {
+            return Err(format!(
+                "{} in {} should be a list of paths",
+                INCLUDE,
+                path.display()
+            ))
+        }
+    };
+    including.push(canonical);
+    let mut merged = Value::Object(Default::default());
+    for include in includes {
+        let Value::String(include) = include else {
+            return Err(format!(
+                "{} in {} should be a list of paths",
+                INCLUDE,
+                path.display()
+            ));
+        };
+        let include = path.parent().unwrap_or(Path::new("")).join(include);
+        let content = read_file(&include)?;
+        let included = parse(&include, &content)
+            .map_err(|e| format!("in included {}: {}", include.display(), e))?;
+        files.push(include.clone());
+        merge(&mut merged, resolve(&include, included, including, files)?);
+    }
+    including.pop();
+
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Merges `overlay` into `base`, following the rules in the module docs.
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(base), Value::Array(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
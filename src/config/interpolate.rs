@@ -0,0 +1,183 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Variables in the configuration file.
+//!
+//! The `[vars]` table defines named values, which `-e name=value` on the
+//! command line overrides. Node addresses, aliases, resolve addresses, users,
+//! key paths, jump hosts, proxies and labels, group members and settings, and the
+//! login defaults may use them as `{{ name }}`, with or without the spaces.
+//! Variables are substituted before node ranges are expanded, so a variable
+//! may hold a range such as `gpu[01-08]`.
+//!
+//! Commands given to `exec` and `watch` may use them too. There, a `{{ }}`
+//! that doesn't name a variable is left as it is, since commands are full of
+//! other templates, such as `docker ps --format '{{.Names}}'`.
+//!
+//! The doubled braces keep config variables apart from the `{host}` style
+//! placeholders in commands and paths, which are filled in per node later.
+
+use super::{ClusterConfig, Defaults};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Substitutes variables into every templated value of a cluster.
+///
+/// # Arguments
+///
+/// * `cluster` - The cluster, whose nodes and groups are updated in place.
+/// * `defaults` - The login defaults, updated in place.
+/// * `vars` - The value of each variable, by name.
+///
+/// # Returns
+///
+/// Returns an error if a value uses a variable that isn't defined or has an
+/// unclosed `{{`.
+pub fn apply(
+    cluster: &mut ClusterConfig,
+    defaults: &mut Defaults,
+    vars: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let render_path = |path: &mut PathBuf| -> Result<(), String> {
+        if let Some(text) = path.to_str() {
+            *path = PathBuf::from(render(text, vars)?);
+        }
+        Ok(())
+    };
+    for node in &mut cluster.nodes {
+        node.host = render(&node.host, vars)?;
+        if let Some(alias) = &mut node.alias {
+            *alias = render(alias, vars)?;
+        }
+        if let Some(resolve) = &mut node.resolve {
+            *resolve = render(resolve, vars)?;
+        }
+        if let Some(user) = &mut node.user {
+            *user = render(user, vars)?;
+        }
+        if let Some(key) = &mut node.key {
+            render_path(key)?;
+        }
+        if let Some(jump) = &mut node.jump {
+            *jump = render(jump, vars)?;
+        }
+        if let Some(proxy) = &mut node.proxy {
+            *proxy = render(proxy, vars)?;
+        }
+        for value in node.labels.values_mut() {
+            *value = render(value, vars)?;
+        }
+    }
+    for member in cluster.groups.values_mut().flatten() {
+        *member = render(member, vars)?;
+    }
+    for setting in cluster
+        .group_settings
+        .values_mut()
+        .flat_map(|settings| [settings.jump.as_mut(), settings.proxy.as_mut()])
+        .flatten()
+    {
+        *setting = render(setting, vars)?;
+    }
+    if let Some(user) = &mut defaults.user {
+        *user = render(user, vars)?;
+    }
+    if let Some(key) = &mut defaults.key {
+        render_path(key)?;
+    }
+    if let Some(jump) = &mut defaults.jump {
+        *jump = render(jump, vars)?;
+    }
+    if let Some(proxy) = &mut defaults.proxy {
+        *proxy = render(proxy, vars)?;
+    }
+    Ok(())
+}
+
+/// Returns a copy of a command with each `{{ name }}` naming a variable
+/// replaced, and anything else left as it is.
+pub fn fill(command: &str, vars: &BTreeMap<String, String>) -> String {
+    let mut filled = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        match vars.get(rest[start + 2..start + end].trim()) {
+            Some(value) => {
+                filled.push_str(&rest[..start]);
+                filled.push_str(value);
+            }
+            None => filled.push_str(&rest[..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Returns a copy of `template` with every `{{ name }}` replaced.
+fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find("}}") else {
+            return Err(format!("{} has an unclosed {{{{", template));
+        };
+        let name = rest[start + 2..start + end].trim();
+        match vars.get(name) {
+            Some(value) => rendered.push_str(value),
+            None => {
+                return Err(format!(
+                    "{} uses the variable {}, which isn't defined",
+                    template, name
+                ))
+            }
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("registry".to_string(), "registry.example.com".to_string()),
+            ("tag".to_string(), "v1.2".to_string()),
+        ])
+    }
+
+    #[test]
+    fn fills_variables_into_commands() {
+        assert_eq!(
+            fill("docker pull {{ registry }}/app:{{tag}}", &vars()),
+            "docker pull registry.example.com/app:v1.2"
+        );
+    }
+
+    #[test]
+    fn leaves_other_templates_in_commands_alone() {
+        let vars = vars();
+        assert_eq!(
+            fill("docker ps --format '{{.Names}}' {{ tag }}", &vars),
+            "docker ps --format '{{.Names}}' v1.2"
+        );
+        assert_eq!(fill("echo {{host}}", &vars), "echo {{host}}");
+        assert_eq!(fill("echo {{ tag", &vars), "echo {{ tag");
+    }
+
+    #[test]
+    fn rejects_undefined_variables_in_the_config() {
+        assert_eq!(
+            render("{{ tag }}.example.com", &vars()).unwrap(),
+            "v1.2.example.com"
+        );
+        assert!(render("{{ env }}.example.com", &vars()).is_err());
+        assert!(render("{{ tag.example.com", &vars()).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Locating the configuration file.
+//!
+//! The file is looked for in these places, in order:
+//! 1. The path given with `--config`.
+//! 2. The path in the `CLUSTER_RUN_CONFIG` environment variable.
+//! 3. `config.toml` in the current directory.
+//! 4. `cluster_run.toml` in the current directory.
+//! 5. `cluster_run/config.toml` in the user's config directory, which is
+//!    `$XDG_CONFIG_HOME` or `~/.config`.
+//!
+//! Each of the last three may instead end in `.yaml`, `.yml` or `.json`, with
+//! TOML preferred when a directory has more than one.
+//!
+//! An explicitly given path must exist; the others are skipped if missing.
+//! The file is optional when the nodes come from elsewhere, such as an
+//! inventory file.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The formats a configuration file can be written in.
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Returns the format of a file, going by its extension and defaulting to TOML.
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+}
+
+/// The extensions looked for when discovering a file, in order of preference.
+const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// The environment variable that names the configuration file.
+const CONFIG_VAR: &str = "CLUSTER_RUN_CONFIG";
+
+/// Returns the path of the configuration file to use.
+///
+/// # Arguments
+///
+/// * `explicit` - The path given with `--config`.
+/// * `optional` - Whether it's fine for there to be no configuration file.
+///
+/// # Returns
+///
+/// Returns the first location that applies, None if nothing was found and
+/// the file is optional, or an error listing everywhere that was searched
+/// if a required file wasn't found.
+pub fn locate(explicit: Option<&Path>, optional: bool) -> Result<Option<PathBuf>, String> {
+    if let Some(path) = explicit {
+        return existing(path.to_path_buf(), "--config").map(Some);
+    }
+    if let Some(path) = env::var_os(CONFIG_VAR) {
+        return existing(PathBuf::from(path), CONFIG_VAR).map(Some);
+    }
+
+    let candidates = candidates();
+    if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+        return Ok(Some(path.clone()));
+    }
+    if optional {
+        return Ok(None);
+    }
+    let searched: Vec<String> = candidates
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    Err(format!(
+        "No config file found; searched {}. Use --config or {} to give its location",
+        searched.join(", "),
+        CONFIG_VAR
+    ))
+}
+
+/// Returns the places a configuration file is looked for when none is named.
+fn candidates() -> Vec<PathBuf> {
+    let mut stems = vec![PathBuf::from("config"), PathBuf::from("cluster_run")];
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    if let Some(dir) = config_dir {
+        stems.push(dir.join("cluster_run").join("config"));
+    }
+    stems
+        .iter()
+        .flat_map(|stem| {
+            EXTENSIONS
+                .iter()
+                .map(move |extension| stem.with_extension(extension))
+        })
+        .collect()
+}
+
+/// Returns the path if it names a file, or an error naming where it came from.
+fn existing(path: PathBuf, source: &str) -> Result<PathBuf, String> {
+    if path.is_file() {
+        Ok(path)
+    } else {
+        Err(format!(
+            "Config file {} from {} does not exist",
+            path.display(),
+            source
+        ))
+    }
+}
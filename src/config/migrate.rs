@@ -0,0 +1,247 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Versions of the configuration file format.
+//!
+//! A file says which version of the format it's written in with `version`,
+//! and a file without one is version 1. Older versions are upgraded in memory
+//! as they're read, so existing files keep working, and `config migrate`
+//! rewrites a file in the current version.
+//!
+//! The versions are:
+//! 1. The original format, where a file's single cluster is its `[cluster]`
+//!    section.
+//! 2. Every cluster is named, in `[clusters.<name>]`. The `[cluster]` section
+//!    of version 1 becomes `[clusters.default]`, which `default_cluster`
+//!    picks unless the file already picks another.
+
+use super::loader::Format;
+use super::ConfigFile;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The version of the format that files are upgraded to.
+pub const CURRENT: u32 = 2;
+
+/// The name the `[cluster]` section of a version 1 file is given.
+const DEFAULT: &str = "default";
+
+/// Upgrades a parsed file to the current version.
+///
+/// # Returns
+///
+/// Returns an error if the file is newer than this version of cluster_run
+/// understands, or doesn't follow its own version's format.
+pub fn upgrade(file: &mut ConfigFile) -> Result<(), String> {
+    match file.version.unwrap_or(1) {
+        1 => {
+            if let Some(cluster) = file.cluster.take() {
+                if file.clusters.contains_key(DEFAULT) {
+                    return Err(renamed_collision());
+                }
+                file.clusters.insert(DEFAULT.to_string(), cluster);
+                file.default_cluster
+                    .get_or_insert_with(|| DEFAULT.to_string());
+            }
+        }
+        CURRENT => {
+            if file.cluster.is_some() {
+                return Err(format!(
+                    "{}; `cluster_run config migrate` rewrites older files",
+                    unnamed_cluster()
+                ));
+            }
+        }
+        version => return Err(unsupported(version)),
+    }
+    file.version = Some(CURRENT);
+    Ok(())
+}
+
+/// Rewrites a file in the current version, keeping a copy of the original.
+///
+/// TOML files keep their comments and layout. YAML and JSON files are
+/// written out afresh, which loses YAML comments.
+///
+/// # Returns
+///
+/// Returns the path of the copy of the original, None if the file was
+/// already in the current version, or an error if it couldn't be rewritten.
+pub fn rewrite(path: &Path) -> Result<Option<PathBuf>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    let rewritten = match Format::of(path) {
+        Format::Toml => rewrite_toml(&content)?,
+        Format::Yaml => rewrite_yaml(&content)?,
+        Format::Json => rewrite_json(&content)?,
+    };
+    let Some(rewritten) = rewritten else {
+        return Ok(None);
+    };
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    fs::copy(path, &backup).map_err(|e| {
+        format!(
+            "unable to copy {} to {}: {}",
+            path.display(),
+            backup.display(),
+            e
+        )
+    })?;
+    fs::write(path, rewritten).map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+    Ok(Some(backup))
+}
+
+/// Upgrades a TOML file's text, or returns None if it's already current.
+fn rewrite_toml(content: &str) -> Result<Option<String>, String> {
+    let mut doc: toml_edit::DocumentMut = content.parse().map_err(|e| format!("{}", e))?;
+    let version = match doc.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_integer()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or("version should be a whole number")?,
+    };
+    match version {
+        1 => {}
+        CURRENT if doc.contains_key("cluster") => return Err(unnamed_cluster()),
+        CURRENT => return Ok(None),
+        version => return Err(unsupported(version)),
+    }
+
+    // Step 1: Move [cluster] to [clusters.default]
+    let root = doc.as_table_mut();
+    if let Some(cluster) = root.remove("cluster") {
+        let clusters = root
+            .entry("clusters")
+            .or_insert_with(|| {
+                let mut clusters = toml_edit::Table::new();
+                clusters.set_implicit(true);
+                toml_edit::Item::Table(clusters)
+            })
+            .as_table_like_mut()
+            .ok_or("clusters should be a table")?;
+        if clusters.contains_key(DEFAULT) {
+            return Err(renamed_collision());
+        }
+        clusters.insert(DEFAULT, cluster);
+        if !root.contains_key("default_cluster") {
+            root.insert("default_cluster", toml_edit::value(DEFAULT));
+        }
+    }
+
+    // Step 2: Put the version first, ahead of the other top-level values
+    let values: Vec<(toml_edit::Key, toml_edit::Item)> = root
+        .iter()
+        .filter(|(_, item)| item.is_value())
+        .map(|(key, _)| key.to_string())
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|key| root.remove_entry(&key))
+        .collect();
+    root.insert("version", toml_edit::value(i64::from(CURRENT)));
+    for (key, item) in values {
+        root.insert_formatted(&key, item);
+    }
+    Ok(Some(doc.to_string()))
+}
+
+/// Upgrades a YAML file's text, or returns None if it's already current.
+fn rewrite_yaml(content: &str) -> Result<Option<String>, String> {
+    use serde_yaml::{Mapping, Value};
+    let mut root: Mapping = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+    let version = match root.get("version").map(|version| version.as_u64()) {
+        None => 1,
+        Some(version) => version
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or("version should be a whole number")?,
+    };
+    match version {
+        1 => {}
+        CURRENT if root.contains_key("cluster") => return Err(unnamed_cluster()),
+        CURRENT => return Ok(None),
+        version => return Err(unsupported(version)),
+    }
+
+    let mut upgraded = Mapping::new();
+    upgraded.insert("version".into(), Value::from(CURRENT));
+    if let Some(cluster) = root.remove("cluster") {
+        let clusters = root
+            .entry("clusters".into())
+            .or_insert_with(|| Value::Mapping(Mapping::new()))
+            .as_mapping_mut()
+            .ok_or("clusters should be a mapping")?;
+        if clusters.contains_key(DEFAULT) {
+            return Err(renamed_collision());
+        }
+        clusters.insert(DEFAULT.into(), cluster);
+        if !root.contains_key("default_cluster") {
+            upgraded.insert("default_cluster".into(), DEFAULT.into());
+        }
+    }
+    upgraded.extend(root);
+    serde_yaml::to_string(&upgraded)
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// Upgrades a JSON file's text, or returns None if it's already current.
+fn rewrite_json(content: &str) -> Result<Option<String>, String> {
+    use serde_json::{Map, Value};
+    let mut root: Map<String, Value> = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let version = match root.get("version").map(Value::as_u64) {
+        None => 1,
+        Some(version) => version
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or("version should be a whole number")?,
+    };
+    match version {
+        1 => {}
+        CURRENT if root.contains_key("cluster") => return Err(unnamed_cluster()),
+        CURRENT => return Ok(None),
+        version => return Err(unsupported(version)),
+    }
+
+    root.insert("version".into(), CURRENT.into());
+    if let Some(cluster) = root.remove("cluster") {
+        let clusters = root
+            .entry("clusters")
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or("clusters should be an object")?;
+        if clusters.contains_key(DEFAULT) {
+            return Err(renamed_collision());
+        }
+        clusters.insert(DEFAULT.into(), cluster);
+        root.entry("default_cluster").or_insert(DEFAULT.into());
+    }
+    let mut rewritten = serde_json::to_string_pretty(&root).map_err(|e| e.to_string())?;
+    rewritten.push('\n');
+    Ok(Some(rewritten))
+}
+
+/// The error for a version 1 file whose `[cluster]` can't be renamed.
+fn renamed_collision() -> String {
+    format!(
+        "[cluster] can't become [clusters.{0}], since there is already a cluster named {0}",
+        DEFAULT
+    )
+}
+
+/// The error for a current file with a version 1 `[cluster]` section.
+fn unnamed_cluster() -> String {
+    format!(
+        "version {} configs name every cluster, so [cluster] should be [clusters.{}]",
+        CURRENT, DEFAULT
+    )
+}
+
+/// The error for a file in a version this cluster_run doesn't know.
+fn unsupported(version: u32) -> String {
+    format!(
+        "version {} is not supported; this cluster_run reads versions 1 to {}",
+        version, CURRENT
+    )
+}
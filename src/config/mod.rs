@@ -0,0 +1,749 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The cluster configuration file.
+//!
+//! The types here describe the file's contents, which may be written in TOML,
+//! YAML or JSON. The `loader` module works out which file to read, and the
+//! `include` module merges in the files it includes. A file describes either
+//! a single cluster in its `[cluster]` section, or several named ones in
+//! `[clusters.<name>]` sections of which one is used per run.
+
+mod include;
+mod interpolate;
+mod loader;
+mod migrate;
+mod validate;
+
+use crate::dial;
+use crate::inventory::{self, NodeSource};
+use crate::notify;
+use crate::redact;
+use crate::secrets::Secret;
+use crate::ssh;
+use crate::ssh_config;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub use validate::{validate, Report, Severity};
+
+/// The version of the configuration format that files are upgraded to.
+pub use migrate::CURRENT as VERSION;
+
+/// Represents the configuration for a run, with the cluster it covers.
+#[derive(Clone)]
+pub struct Config {
+    /// How to log in to nodes that don't say otherwise.
+    pub defaults: Defaults,
+    pub cluster: ClusterConfig,
+    /// How TCP connections are opened, from `[transport]`.
+    pub transport: dial::Settings,
+    /// What to mask in the output, from `[redact]`.
+    pub redact: redact::Settings,
+    /// Who to tell how runs went, from `[notify]`.
+    pub notify: notify::Settings,
+    /// The files the configuration and nodes were read from, so that changes
+    /// to them can be noticed.
+    pub files: Vec<PathBuf>,
+    /// The variables from `[vars]` and `-e`, for filling into commands.
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Where a run's configuration and nodes come from.
+pub struct Source<'a> {
+    /// The configuration file given with `--config`.
+    pub config: Option<&'a Path>,
+    /// The cluster named with `--cluster`.
+    pub cluster: Option<&'a str>,
+    /// Where to take the nodes from instead of the cluster's nodes and groups.
+    pub nodes: Option<NodeSource<'a>>,
+    /// The variables given with `-e`, which override the file's `[vars]`.
+    pub vars: &'a [(String, String)],
+}
+
+/// Represents the contents of the configuration file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    /// The version of the format the file is written in.
+    version: Option<u32>,
+    #[serde(default)]
+    defaults: Defaults,
+    /// How TCP connections are opened, for every cluster.
+    #[serde(default)]
+    transport: dial::Settings,
+    /// What to mask in the output, for every cluster.
+    #[serde(default)]
+    redact: redact::Settings,
+    /// Who to tell how runs went, for every cluster.
+    #[serde(default)]
+    notify: notify::Settings,
+    /// The unnamed cluster of version 1 files, which is named on upgrade.
+    cluster: Option<ClusterConfig>,
+    /// The named clusters, of which `--cluster` picks one.
+    #[serde(default)]
+    clusters: BTreeMap<String, ClusterConfig>,
+    /// The named cluster to use when `--cluster` isn't given.
+    default_cluster: Option<String>,
+    /// The values of the variables used as `{{ name }}` in the file.
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
+    /// The file and the files it includes.
+    #[serde(skip)]
+    files: Vec<PathBuf>,
+}
+
+/// The login settings used for every node that doesn't set its own.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub key: Option<PathBuf>,
+    /// The jump hosts to reach every node through.
+    pub jump: Option<String>,
+    /// The SOCKS5 or HTTP proxy to reach every node through.
+    pub proxy: Option<String>,
+    /// The ways to log in to every node, in the order to try them.
+    pub auth: Option<Vec<AuthMethod>>,
+    /// Whether to compress every node's session.
+    pub compression: Option<bool>,
+}
+
+/// Settings shared by the nodes of a group, from
+/// `[clusters.<name>.group_settings.<group>]`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupSettings {
+    /// The jump hosts to reach the group's nodes through.
+    pub jump: Option<String>,
+    /// The SOCKS5 or HTTP proxy to reach the group's nodes through.
+    pub proxy: Option<String>,
+    /// Whether to compress the group's nodes' sessions, for nodes over slow
+    /// links.
+    pub compression: Option<bool>,
+}
+
+/// Represents the cluster configuration, containing the list of nodes, the
+/// named groups of nodes, optional default timeouts in seconds, and whether
+/// commands run as root.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub nodes: Vec<Node>,
+    /// The provider to list the nodes from, instead of `nodes`.
+    pub source: Option<String>,
+    /// The settings for each provider.
+    #[serde(default)]
+    pub inventory: inventory::Providers,
+    /// The addresses of the nodes in each group, by group name.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// The settings shared by each group's nodes, by group name.
+    #[serde(default)]
+    pub group_settings: BTreeMap<String, GroupSettings>,
+    pub connect_timeout: Option<u64>,
+    pub command_timeout: Option<u64>,
+    #[serde(default)]
+    pub sudo: bool,
+    /// The password sudo asks for, which implies `sudo`.
+    pub sudo_password: Option<Secret>,
+    /// The passphrase of the encrypted keys the nodes are logged into with,
+    /// used in place of asking for it.
+    pub key_passphrase: Option<Secret>,
+}
+
+impl Config {
+    /// Finds, reads and parses the configuration file, and reads the nodes
+    /// from elsewhere if asked to.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The files, cluster and nodes given on the command line. A
+    ///   configuration file is optional when the nodes come from elsewhere.
+    pub fn load(source: &Source) -> Result<Config, Box<dyn std::error::Error>> {
+        // Step 1: Read the configuration file
+        let path = loader::locate(source.config, source.nodes.is_some())?;
+        let mut file = match &path {
+            Some(path) => include::read(path)
+                .map_err(|e| format!("Invalid config in {}: {}", path.display(), e))?,
+            None => ConfigFile::default(),
+        };
+
+        // Step 2: Pick the cluster, taking its nodes from elsewhere if asked to
+        let invalid_config = |e: String| match &path {
+            Some(path) => format!("Invalid config in {}: {}", path.display(), e),
+            None => e,
+        };
+        migrate::upgrade(&mut file).map_err(invalid_config)?;
+        let cluster = file.pick_cluster(source.cluster).map_err(invalid_config)?;
+        let cluster = match (cluster, &source.nodes) {
+            (cluster, Some(nodes)) => {
+                let inventory =
+                    inventory::load(nodes).map_err(|e| format!("Invalid {}: {}", nodes, e))?;
+                ClusterConfig {
+                    nodes: inventory.nodes,
+                    groups: inventory.groups,
+                    ..cluster.unwrap_or_default()
+                }
+            }
+            (Some(mut cluster), None) => {
+                let discovered = inventory::discover(&cluster, &file.defaults).map_err(|e| {
+                    format!(
+                        "Unable to list the nodes from {}: {}",
+                        cluster.source.as_deref().unwrap_or_default(),
+                        e
+                    )
+                })?;
+                if let Some(inventory) = discovered {
+                    cluster.nodes = inventory.nodes;
+                    cluster.groups = inventory.groups;
+                }
+                cluster
+            }
+            (None, None) => {
+                return Err(
+                    invalid_config("the config doesn't describe any clusters".into()).into(),
+                )
+            }
+        };
+
+        // Step 3: Resolve the nodes, dropping any listed twice under different names
+        let vars = file.vars(source.vars);
+        let mut config = Config::resolve(cluster, file.defaults, &vars).map_err(invalid_config)?;
+        config.cluster.nodes = inventory::dedupe(config.cluster.nodes);
+        config.transport = file.transport;
+        config.redact = file.redact;
+        config.notify = file.notify;
+        config.files = file.files;
+        if let Some(NodeSource::Inventory(path) | NodeSource::HostsFile(path)) = source.nodes {
+            config.files.push(path.to_path_buf());
+        }
+        Ok(config)
+    }
+
+    /// Returns a command with the variables it uses as `{{ name }}` filled in.
+    pub fn fill(&self, command: &str) -> String {
+        interpolate::fill(command, &self.vars)
+    }
+
+    /// Gives every node the key and jump hosts from `--identity` and
+    /// `--jump`, read as the same settings in the file are: a `~` at the
+    /// start of the key is the home directory, and a jump of `none` connects
+    /// directly.
+    pub fn override_logins(&mut self, key: Option<&Path>, jump: Option<&str>) {
+        for node in &mut self.cluster.nodes {
+            if let Some(key) = key {
+                node.key = Some(expand_home(key));
+            }
+            if let Some(jump) = jump {
+                node.jump = Some(jump.to_string()).filter(|jump| !is_no_jump(jump));
+            }
+        }
+    }
+
+    /// Substitutes variables into a cluster, expands its node lists and
+    /// fills in its nodes' groups and login settings.
+    ///
+    /// # Returns
+    ///
+    /// Returns the configuration for the cluster, or an error if a variable
+    /// is undefined, a node list is malformed, a group lists an unknown node,
+    /// or there are no nodes.
+    fn resolve(
+        mut cluster: ClusterConfig,
+        mut defaults: Defaults,
+        vars: &BTreeMap<String, String>,
+    ) -> Result<Config, String> {
+        interpolate::apply(&mut cluster, &mut defaults, vars)?;
+        inventory::expand(&mut cluster)?;
+        if cluster.nodes.is_empty() {
+            return Err("the cluster has no nodes".into());
+        }
+        let mut config = Config {
+            cluster,
+            defaults,
+            transport: dial::Settings::default(),
+            redact: redact::Settings::default(),
+            notify: notify::Settings::default(),
+            files: Vec::new(),
+            vars: vars.clone(),
+        };
+        config.assign_groups()?;
+        config.apply_group_settings()?;
+        config.apply_defaults()?;
+        Ok(config)
+    }
+
+    /// Records on each node which groups it belongs to. Groups may list nodes
+    /// by address or by alias.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if a group lists a node that isn't in the cluster.
+    fn assign_groups(&mut self) -> Result<(), String> {
+        let cluster = &mut self.cluster;
+        for (group, members) in &cluster.groups {
+            for member in members {
+                let mut found = false;
+                for node in cluster
+                    .nodes
+                    .iter_mut()
+                    .filter(|node| node.is_named(member))
+                {
+                    node.groups.push(group.clone());
+                    found = true;
+                }
+                if !found {
+                    return Err(format!(
+                        "group {} lists {}, which is not in the cluster's nodes",
+                        group, member
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in each node's unset settings from the settings of its groups.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if a node's groups disagree on a setting.
+    fn apply_group_settings(&mut self) -> Result<(), String> {
+        let cluster = &mut self.cluster;
+        for node in &mut cluster.nodes {
+            if node.jump.is_none() {
+                node.jump = group_setting(
+                    node,
+                    &cluster.group_settings,
+                    ("jump", "jump hosts"),
+                    |settings| settings.jump.as_ref(),
+                )?;
+            }
+            if node.proxy.is_none() {
+                node.proxy = group_setting(
+                    node,
+                    &cluster.group_settings,
+                    ("proxy", "proxies"),
+                    |settings| settings.proxy.as_ref(),
+                )?;
+            }
+            if node.compression.is_none() {
+                node.compression = group_setting(
+                    node,
+                    &cluster.group_settings,
+                    ("compression", "compression"),
+                    |settings| settings.compression.as_ref(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in each node's unset login settings from the user's OpenSSH
+    /// config, and then from `[defaults]`. A jump of `none` means the node
+    /// is connected to directly, whatever the settings after it say.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the OpenSSH config can't be read.
+    fn apply_defaults(&mut self) -> Result<(), String> {
+        for node in &mut self.cluster.nodes {
+            ssh_config::lookup(&node.host)?.apply(node);
+            self.defaults.apply(node);
+            node.jump = node.jump.take().filter(|jump| !is_no_jump(jump));
+            node.proxy = node.proxy.take().filter(|proxy| !is_no_proxy(proxy));
+        }
+        Ok(())
+    }
+}
+
+impl Defaults {
+    /// Fills in a node's unset login settings.
+    pub fn apply(&self, node: &mut Node) {
+        node.user = node.user.take().or_else(|| self.user.clone());
+        node.port = node.port.or(self.port);
+        node.key = node
+            .key
+            .take()
+            .or_else(|| self.key.clone())
+            .map(|key| expand_home(&key));
+        node.jump = node.jump.take().or_else(|| self.jump.clone());
+        node.proxy = node.proxy.take().or_else(|| self.proxy.clone());
+        node.auth = node.auth.take().or_else(|| self.auth.clone());
+        node.compression = node.compression.or(self.compression);
+    }
+}
+
+/// Returns the value a node's groups give one of their settings.
+///
+/// # Arguments
+///
+/// * `node` - The node, with its groups assigned.
+/// * `group_settings` - The settings of each group, by group name.
+/// * `what` - The setting's key, and what it is as a plural, for messages.
+/// * `setting` - Picks the setting out of a group's settings.
+///
+/// # Returns
+///
+/// Returns the value, None if none of the node's groups set it, or an error
+/// if two of them set it differently.
+fn group_setting<T: Clone + PartialEq>(
+    node: &Node,
+    group_settings: &BTreeMap<String, GroupSettings>,
+    (key, what): (&str, &str),
+    setting: impl Fn(&GroupSettings) -> Option<&T>,
+) -> Result<Option<T>, String> {
+    let mut value: Option<(&str, &T)> = None;
+    for group in &node.groups {
+        let Some(group_value) = group_settings.get(group).and_then(&setting) else {
+            continue;
+        };
+        match value {
+            Some((other, other_value)) if other_value != group_value => {
+                return Err(format!(
+                    "{} is in groups {} and {}, which set different {}; set the node's own {} to pick one",
+                    node.name(),
+                    other,
+                    group,
+                    what,
+                    key
+                ))
+            }
+            Some(_) => {}
+            None => value = Some((group, group_value)),
+        }
+    }
+    Ok(value.map(|(_, value)| value.clone()))
+}
+
+/// Returns true if a jump setting says to connect directly, as OpenSSH's
+/// `ProxyJump none` does.
+pub fn is_no_jump(jump: &str) -> bool {
+    jump.eq_ignore_ascii_case("none")
+}
+
+/// Returns true if a proxy setting says to connect without a proxy.
+fn is_no_proxy(proxy: &str) -> bool {
+    proxy.eq_ignore_ascii_case("none")
+}
+
+/// Rewrites the configuration file and the files it includes in the current
+/// version of the format.
+///
+/// # Arguments
+///
+/// * `explicit` - The file given with `--config`, if any.
+///
+/// # Returns
+///
+/// Returns each file with the path of the copy of its original, or None if
+/// it was already current, or an error if a file couldn't be rewritten.
+pub fn upgrade_files(explicit: Option<&Path>) -> Result<Vec<(PathBuf, Option<PathBuf>)>, String> {
+    let path = loader::locate(explicit, false)?.unwrap_or_default();
+    let file =
+        include::read(&path).map_err(|e| format!("Invalid config in {}: {}", path.display(), e))?;
+    file.files
+        .into_iter()
+        .map(|path| {
+            let backup = migrate::rewrite(&path)
+                .map_err(|e| format!("Unable to migrate {}: {}", path.display(), e))?;
+            Ok((path, backup))
+        })
+        .collect()
+}
+
+/// Lists the names of the clusters the configuration file describes.
+///
+/// # Arguments
+///
+/// * `explicit` - The file given with `--config`, if any.
+///
+/// # Returns
+///
+/// Returns the names in alphabetical order, or an error if the file can't
+/// be read or describes no named clusters.
+pub fn cluster_names(explicit: Option<&Path>) -> Result<Vec<String>, String> {
+    let path = loader::locate(explicit, false)?.unwrap_or_default();
+    let invalid = |e: String| format!("Invalid config in {}: {}", path.display(), e);
+    let mut file = include::read(&path).map_err(invalid)?;
+    migrate::upgrade(&mut file).map_err(invalid)?;
+    if file.clusters.is_empty() {
+        return Err(invalid("the config doesn't describe any clusters".into()));
+    }
+    Ok(file.clusters.into_keys().collect())
+}
+
+impl ConfigFile {
+    /// Returns the file's variables with the command line's overrides applied.
+    fn vars(&self, overrides: &[(String, String)]) -> BTreeMap<String, String> {
+        let mut vars = self.vars.clone();
+        vars.extend(overrides.iter().cloned());
+        vars
+    }
+
+    /// Takes the cluster a run should cover out of the file.
+    ///
+    /// A cluster named with `--cluster` is used if given, then the file's
+    /// `default_cluster`, and then its only cluster if it has just one. The
+    /// file must have been upgraded to the current version first.
+    ///
+    /// # Returns
+    ///
+    /// Returns the cluster, None if the file doesn't describe any, or an
+    /// error if the named cluster doesn't exist or there's no way to tell
+    /// which cluster to use.
+    fn pick_cluster(&mut self, name: Option<&str>) -> Result<Option<ClusterConfig>, String> {
+        let name = name.or(self.default_cluster.as_deref());
+        let names = || self.clusters.keys().cloned().collect::<Vec<_>>().join(", ");
+        if let Some(name) = name {
+            return match self.clusters.get(name) {
+                Some(cluster) => Ok(Some(cluster.clone())),
+                None if self.clusters.is_empty() => {
+                    Err(format!("there is no cluster named {}", name))
+                }
+                None => Err(format!(
+                    "there is no cluster named {}; the clusters are {}",
+                    name,
+                    names()
+                )),
+            };
+        }
+        match self.clusters.len() {
+            0 => Ok(None),
+            1 => Ok(self.clusters.values().next().cloned()),
+            _ => Err(format!(
+                "there are several clusters, so one must be picked with --cluster: {}",
+                names()
+            )),
+        }
+    }
+}
+
+/// A node in the cluster and how to log in to it.
+///
+/// In the config file a node is either a bare address or a table such as
+/// `{ host = "10.0.0.5", user = "root", port = 2222, key = "~/.ssh/id_ed25519" }`,
+/// which may also give the node labels with `labels = { rack = "r3" }`, a
+/// short name to show it by with `alias = "gpu-03"`, and the address to
+/// connect to in place of the host's own with `resolve = "10.0.0.7"`, and the
+/// jump hosts to reach it through with `jump = "bastion.example.com"`, or the
+/// proxy with `proxy = "socks5://127.0.0.1:1080"`. Its host key can be pinned
+/// with `host_key = "SHA256:..."`, the ways to log in to it ordered with
+/// `auth = ["key", "password"]`, and its session compressed with
+/// `compression = true`.
+#[derive(Clone, Deserialize)]
+#[serde(from = "NodeEntry")]
+pub struct Node {
+    /// The node's address.
+    pub host: String,
+    /// A short name for the node, shown in place of its address.
+    pub alias: Option<String>,
+    /// The address to connect to instead of `host`, for nodes known by a
+    /// name that doesn't resolve, or doesn't yet resolve, to the right machine.
+    pub resolve: Option<String>,
+    /// The user to log in as, if not the default.
+    pub user: Option<String>,
+    /// The port sshd listens on, if not the default.
+    pub port: Option<u16>,
+    /// The private key to authenticate with, if not the default.
+    pub key: Option<PathBuf>,
+    /// The hosts to reach the node through, as `[user@]host[:port]`
+    /// separated by commas, like OpenSSH's `ProxyJump`.
+    pub jump: Option<String>,
+    /// The SOCKS5 or HTTP CONNECT proxy to reach the node, or its first jump
+    /// host, through, as a `socks5://` or `http://` URL.
+    pub proxy: Option<String>,
+    /// The SHA256 fingerprint the node's host key must have, checked in
+    /// place of the known hosts files.
+    pub host_key: Option<String>,
+    /// The ways to log in to the node, in the order to try them, if not the
+    /// default order.
+    pub auth: Option<Vec<AuthMethod>>,
+    /// Whether to compress the session, if not the default of leaving it
+    /// uncompressed.
+    pub compression: Option<bool>,
+    /// The groups the node belongs to, in alphabetical order.
+    pub groups: Vec<String>,
+    /// Arbitrary attributes of the node, such as its rack, for selecting it by.
+    pub labels: BTreeMap<String, String>,
+    /// The cluster the node is shown as part of, when a run covers several.
+    pub cluster: Option<String>,
+}
+
+impl Node {
+    /// Returns the user to log in as.
+    pub fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or(ssh::USER)
+    }
+
+    /// Returns the port to connect to.
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(ssh::PORT)
+    }
+
+    /// Returns the ways to log in, in the order to try them.
+    ///
+    /// Unless the config says otherwise, that's the ssh-agent's keys, then
+    /// the node's key if it has one or the default keys if it doesn't, and
+    /// then the password from `--ask-pass`.
+    pub fn auth(&self) -> Vec<AuthMethod> {
+        if let Some(auth) = &self.auth {
+            return auth.clone();
+        }
+        let keys = match self.key {
+            Some(_) => AuthMethod::Key,
+            None => AuthMethod::DefaultKeys,
+        };
+        vec![AuthMethod::Agent, keys, AuthMethod::Password]
+    }
+
+    /// Returns true if the session is compressed.
+    pub fn compresses(&self) -> bool {
+        self.compression.unwrap_or(false)
+    }
+
+    /// Returns the address to connect to, which is `resolve` if it's set.
+    pub fn address(&self) -> &str {
+        self.resolve.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Returns the name the node is shown by, which is its alias if it has one.
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.host)
+    }
+
+    /// Returns true if `name` is the node's address or alias.
+    pub fn is_named(&self, name: &str) -> bool {
+        self.host == name || self.alias.as_deref() == Some(name)
+    }
+}
+
+impl From<&str> for Node {
+    fn from(host: &str) -> Self {
+        Node {
+            host: host.to_string(),
+            alias: None,
+            resolve: None,
+            user: None,
+            port: None,
+            key: None,
+            jump: None,
+            proxy: None,
+            host_key: None,
+            auth: None,
+            compression: None,
+            groups: Vec::new(),
+            labels: BTreeMap::new(),
+            cluster: None,
+        }
+    }
+}
+
+/// Nodes are shown by their alias, or their address if they have none,
+/// after the name of their cluster when a run covers several.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cluster {
+            Some(cluster) => f.pad(&format!("{}/{}", cluster, self.name())),
+            None => f.pad(self.name()),
+        }
+    }
+}
+
+/// The ways a node can be written in the config file.
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "expected a node address, or a table with a host and optional alias, resolve, user, port, key, jump, proxy, host_key, auth, compression and labels"
+)]
+enum NodeEntry {
+    Host(String),
+    Table(Box<NodeTable>),
+}
+
+/// A node written as a table, so a misspelt setting is an error.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeTable {
+    host: String,
+    alias: Option<String>,
+    resolve: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+    key: Option<PathBuf>,
+    jump: Option<String>,
+    proxy: Option<String>,
+    host_key: Option<String>,
+    auth: Option<Vec<AuthMethod>>,
+    compression: Option<bool>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl From<NodeEntry> for Node {
+    fn from(entry: NodeEntry) -> Self {
+        match entry {
+            NodeEntry::Host(host) => Node::from(host.as_str()),
+            NodeEntry::Table(table) => Node {
+                host: table.host,
+                alias: table.alias,
+                resolve: table.resolve,
+                user: table.user,
+                port: table.port,
+                key: table.key,
+                jump: table.jump,
+                proxy: table.proxy,
+                host_key: table.host_key,
+                auth: table.auth,
+                compression: table.compression,
+                groups: Vec::new(),
+                labels: table.labels,
+                cluster: None,
+            },
+        }
+    }
+}
+
+/// A way of logging in to a node, as listed in an `auth` setting.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// The keys held by the running ssh-agent.
+    Agent,
+    /// The node's own key, from its `key` or `[defaults]`.
+    Key,
+    /// The default keys in ~/.ssh.
+    DefaultKeys,
+    /// The password from `--ask-pass`, which is asked for up front for
+    /// nodes that list it.
+    Password,
+    /// Answers to the questions the node asks, such as for a one-time code,
+    /// given on the terminal as they're asked.
+    KeyboardInteractive,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthMethod::Agent => "agent",
+            AuthMethod::Key => "key",
+            AuthMethod::DefaultKeys => "default-keys",
+            AuthMethod::Password => "password",
+            AuthMethod::KeyboardInteractive => "keyboard-interactive",
+        })
+    }
+}
+
+/// Replaces a leading `~` in a path with the home directory.
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Checking a configuration file without running anything.
+//!
+//! Validation parses the file the same way a run would, which catches syntax
+//! errors and unknown keys, and then goes over every cluster it describes
+//! rather than just the one a run would pick. Problems that a run would stop
+//! at are errors; anything that is allowed but likely a mistake is a warning.
+
+use super::{include, loader, migrate, AuthMethod, ClusterConfig, Config, ConfigFile};
+use crate::certificate::{self, Certificate};
+use crate::dial;
+use crate::host_key;
+use crate::notify::Webhook;
+use crate::proxy;
+use crate::redact;
+use crate::secrets::Secret;
+use crate::ssh;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How serious a problem is.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// A run would fail because of it.
+    Error,
+    /// A run would go ahead, but probably not as intended.
+    Warning,
+}
+
+/// A problem found in a configuration file.
+pub struct Problem {
+    pub severity: Severity,
+    /// The line and column the problem is at, if it could be pinned down.
+    pub location: Option<(usize, usize)>,
+    pub message: String,
+}
+
+/// The outcome of validating a configuration file.
+pub struct Report {
+    /// The file that was validated.
+    pub path: PathBuf,
+    /// Each cluster in the file, by section name, with its nodes if they could
+    /// be resolved. Clusters whose nodes come from a provider aren't resolved.
+    pub clusters: Vec<(String, Option<Config>)>,
+    /// The problems found, in the order they were found.
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// Records a problem.
+    pub fn add(&mut self, severity: Severity, location: Option<(usize, usize)>, message: String) {
+        self.problems.push(Problem {
+            severity,
+            location,
+            message,
+        });
+    }
+
+    /// Returns the number of problems of the given severity.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.problems
+            .iter()
+            .filter(|problem| problem.severity == severity)
+            .count()
+    }
+}
+
+/// Validates the configuration file.
+///
+/// This function performs the following steps:
+/// 1. Finds the file the same way a run would.
+/// 2. Parses it, along with the files it includes.
+/// 3. Checks that the file's version is supported, `default_cluster`
+///    names a cluster, the `[transport]` settings can be used and the
+///    `[redact]` patterns are valid.
+/// 4. Checks each cluster's secrets, and resolves and checks its nodes and
+///    groups.
+///
+/// # Arguments
+///
+/// * `explicit` - The file given with `--config`, if any.
+/// * `overrides` - The variables given with `-e`.
+///
+/// # Returns
+///
+/// Returns the report, or an error if no configuration file could be found.
+pub fn validate(explicit: Option<&Path>, overrides: &[(String, String)]) -> Result<Report, String> {
+    // Step 1: Find the file
+    let path = loader::locate(explicit, false)?.unwrap_or_default();
+    let mut report = Report {
+        path,
+        clusters: Vec::new(),
+        problems: Vec::new(),
+    };
+
+    // Step 2: Parse it
+    let file = match include::read(&report.path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.add(Severity::Error, None, e);
+            return Ok(report);
+        }
+    };
+    // Only the file itself is searched for locations, not what it includes
+    let content = fs::read_to_string(&report.path).unwrap_or_default();
+
+    // Step 3: Check the version, the default cluster, the transport, the
+    // patterns to redact and the webhooks
+    if let Err(e) = migrate::upgrade(&mut file.clone()) {
+        report.add(Severity::Error, None, e);
+    }
+    if let Some(name) = &file.default_cluster {
+        if !file.clusters.contains_key(name) {
+            report.add(
+                Severity::Error,
+                locate(&content, find(&content, name, 0, 0)),
+                format!("default_cluster names {}, which isn't a cluster", name),
+            );
+        }
+    }
+
+    check_transport(&mut report, &content, &file.transport);
+    check_redact(&mut report, &content, &file.redact);
+    check_notify(&mut report, &content, &file.notify);
+
+    // Step 4: Check each cluster
+    let vars = file.vars(overrides);
+    let ConfigFile {
+        defaults,
+        cluster,
+        clusters,
+        ..
+    } = file;
+    let sections = cluster
+        .map(|cluster| ("[cluster]".to_string(), cluster))
+        .into_iter()
+        .chain(
+            clusters
+                .into_iter()
+                .map(|(name, cluster)| (format!("[clusters.{}]", name), cluster)),
+        );
+    let mut any = false;
+    for (section, cluster) in sections {
+        any = true;
+        check_groups(&mut report, &content, &section, &cluster);
+        check_secrets(&mut report, &section, &cluster);
+        if cluster.source.is_some() {
+            report.clusters.push((section, None));
+            continue;
+        }
+        match Config::resolve(cluster, defaults.clone(), &vars) {
+            Ok(config) => {
+                check_nodes(&mut report, &content, &section, &config);
+                report.clusters.push((section, Some(config)));
+            }
+            Err(e) => report.add(Severity::Error, None, format!("{}: {}", section, e)),
+        }
+    }
+    if !any {
+        report.add(
+            Severity::Error,
+            None,
+            "there is no [cluster] section".into(),
+        );
+    }
+    Ok(report)
+}
+
+/// Checks a cluster's group definitions, before they're resolved.
+///
+/// A group named `all` replaces the built-in one, a group may list a node
+/// more than once, a group may be empty, and `group_settings` may name a
+/// group that doesn't exist. None of these stop a run, so they're warnings.
+fn check_groups(report: &mut Report, content: &str, section: &str, cluster: &ClusterConfig) {
+    let section_start = find(content, section, 0, 0).unwrap_or(0);
+    for (group, members) in &cluster.groups {
+        let group_start = find(content, group, section_start, 0);
+        if group == "all" {
+            report.add(
+                Severity::Warning,
+                locate(content, group_start),
+                format!(
+                    "{}: group all replaces the built-in group of every node",
+                    section
+                ),
+            );
+        }
+        if members.is_empty() {
+            report.add(
+                Severity::Warning,
+                locate(content, group_start),
+                format!("{}: group {} has no nodes", section, group),
+            );
+        }
+        let mut seen = BTreeSet::new();
+        for member in members {
+            if !seen.insert(member) {
+                let repeat = find(content, member, group_start.unwrap_or(section_start), 1);
+                report.add(
+                    Severity::Warning,
+                    locate(content, repeat),
+                    format!(
+                        "{}: group {} lists {} more than once",
+                        section, group, member
+                    ),
+                );
+            }
+        }
+    }
+    for group in cluster.group_settings.keys() {
+        if !cluster.groups.contains_key(group) {
+            report.add(
+                Severity::Warning,
+                locate(content, find(content, group, section_start, 0)),
+                format!(
+                    "{}: group_settings has settings for {}, which isn't a group",
+                    section, group
+                ),
+            );
+        }
+    }
+}
+
+/// Checks that the `[transport]` settings can be applied to sockets. An
+/// interface this host doesn't have may be one the config's other users
+/// have, so it's a warning.
+fn check_transport(report: &mut Report, content: &str, settings: &dial::Settings) {
+    let section = find(content, "[transport]", 0, 0);
+    for (name, secs) in [
+        ("connect_timeout", settings.connect_timeout),
+        ("tcp_keepalive", settings.tcp_keepalive),
+    ] {
+        if secs == Some(0) {
+            report.add(
+                Severity::Error,
+                locate(
+                    content,
+                    section.and_then(|start| find(content, name, start, 0)),
+                ),
+                format!("[transport]: {} must be at least 1 second", name),
+            );
+        }
+    }
+    if let Some(interface) = &settings.bind_interface {
+        let location = locate(
+            content,
+            section.and_then(|start| find(content, interface, start, 0)),
+        );
+        if !cfg!(target_os = "linux") {
+            report.add(
+                Severity::Error,
+                location,
+                "[transport]: bind_interface is only supported on Linux".into(),
+            );
+        } else if !Path::new("/sys/class/net").join(interface).exists() {
+            report.add(
+                Severity::Warning,
+                location,
+                format!("[transport]: this host has no interface {}", interface),
+            );
+        }
+    }
+}
+
+/// Checks that the `[redact]` patterns are valid regular expressions, and
+/// warns of variables that aren't set here, which may be set where the
+/// config is used.
+fn check_redact(report: &mut Report, content: &str, settings: &redact::Settings) {
+    let section = find(content, "[redact]", 0, 0);
+    for pattern in &settings.patterns {
+        if let Err(e) = redact::check(pattern) {
+            report.add(
+                Severity::Error,
+                locate(
+                    content,
+                    section.and_then(|start| find(content, pattern, start, 0)),
+                ),
+                format!("[redact]: {}", e),
+            );
+        }
+    }
+    for var in &settings.env {
+        if env::var_os(var).is_none() {
+            report.add(
+                Severity::Warning,
+                locate(
+                    content,
+                    section.and_then(|start| find(content, var, start, 0)),
+                ),
+                format!("[redact]: environment variable {} isn't set", var),
+            );
+        }
+    }
+}
+
+/// Checks that the `[notify]` webhooks can be revealed, and are webhook URLs.
+fn check_notify(report: &mut Report, content: &str, settings: &crate::notify::Settings) {
+    let section = find(content, "[notify]", 0, 0);
+    for webhook in &settings.webhooks {
+        let checked = webhook
+            .reveal()
+            .and_then(|url| Webhook::parse(&url).map(|_| ()));
+        if let Err(e) = checked {
+            let position = webhook
+                .plain()
+                .and_then(|url| section.and_then(|start| find(content, url, start, 0)));
+            report.add(
+                Severity::Error,
+                locate(content, position),
+                format!("[notify]: {}", e),
+            );
+        }
+    }
+}
+
+/// Checks that a cluster's encrypted secrets can be decrypted, and those
+/// kept in secrets managers fetched.
+fn check_secrets(report: &mut Report, section: &str, cluster: &ClusterConfig) {
+    let secrets = [
+        ("sudo_password", cluster.sudo_password.as_ref()),
+        ("key_passphrase", cluster.key_passphrase.as_ref()),
+        (
+            "inventory.sfcompute.token",
+            cluster
+                .inventory
+                .sfcompute
+                .as_ref()
+                .and_then(|settings| settings.token.as_ref()),
+        ),
+    ];
+    for (name, secret) in secrets {
+        if let Some(Err(e)) = secret.map(Secret::reveal) {
+            report.add(
+                Severity::Error,
+                None,
+                format!("{}: {}: {}", section, name, e),
+            );
+        }
+    }
+}
+
+/// Checks a cluster's resolved nodes for duplicates, shared aliases,
+/// missing key files and malformed pins and proxies.
+fn check_nodes(report: &mut Report, content: &str, section: &str, config: &Config) {
+    // Runs skip the repeats of a node, but listing it twice is likely a slip
+    let section_start = find(content, section, 0, 0).unwrap_or(0);
+    let mut seen: BTreeMap<(&str, u16), usize> = BTreeMap::new();
+    for node in &config.cluster.nodes {
+        let count = seen.entry((node.address(), node.port())).or_default();
+        *count += 1;
+        if *count == 2 {
+            report.add(
+                Severity::Warning,
+                locate(content, find(content, &node.host, section_start, 1)),
+                format!(
+                    "{}: node {} port {} is listed more than once, so runs skip the repeats",
+                    section,
+                    node.address(),
+                    node.port()
+                ),
+            );
+        }
+    }
+
+    // An alias names one node, or runs and output can't tell them apart
+    let mut aliases: BTreeMap<&str, usize> = BTreeMap::new();
+    for alias in config
+        .cluster
+        .nodes
+        .iter()
+        .filter_map(|node| node.alias.as_deref())
+    {
+        let count = aliases.entry(alias).or_default();
+        *count += 1;
+        if *count == 2 {
+            report.add(
+                Severity::Error,
+                locate(content, find(content, alias, section_start, 1)),
+                format!("{}: alias {} is used by more than one node", section, alias),
+            );
+        }
+    }
+
+    // Many nodes usually share a key, so each missing key is reported once.
+    // Nodes using the default keys can do without them if there's an agent.
+    let mut missing = BTreeSet::new();
+    for node in &config.cluster.nodes {
+        let auth = node.auth();
+        let agent = auth.contains(&AuthMethod::Agent) && ssh::agent_available();
+        for &method in &auth {
+            if method == AuthMethod::DefaultKeys && agent {
+                continue;
+            }
+            if let Err(e) = ssh::method_keys(node, method) {
+                missing.insert(e.to_string());
+            }
+        }
+    }
+    for e in missing {
+        report.add(Severity::Error, None, format!("{}: {}", section, e));
+    }
+
+    // Expired certificates are worth knowing about before a run fails on them
+    let mut unusable = BTreeSet::new();
+    for node in &config.cluster.nodes {
+        let keys = node
+            .auth()
+            .into_iter()
+            .filter_map(|method| ssh::method_keys(node, method).ok())
+            .flatten();
+        for (pubkey, _) in keys {
+            let Some(cert) = pubkey.filter(|p| certificate::is_certificate(p)) else {
+                continue;
+            };
+            if let Err(e) = Certificate::read(&cert).and_then(|c| c.check(node.user())) {
+                unusable.insert(e);
+            }
+        }
+    }
+    for e in unusable {
+        report.add(Severity::Warning, None, format!("{}: {}", section, e));
+    }
+
+    for pinned in config
+        .cluster
+        .nodes
+        .iter()
+        .filter_map(|node| node.host_key.as_deref())
+    {
+        if let Err(e) = host_key::check_pin(pinned) {
+            report.add(
+                Severity::Error,
+                locate(content, find(content, pinned, section_start, 0)),
+                format!("{}: {}", section, e),
+            );
+        }
+    }
+
+    // Nodes usually share a proxy, so each malformed one is reported once
+    let proxies: BTreeSet<&str> = config
+        .cluster
+        .nodes
+        .iter()
+        .filter_map(|node| node.proxy.as_deref())
+        .collect();
+    for url in proxies {
+        if let Err(e) = proxy::parse(url) {
+            report.add(Severity::Error, None, format!("{}: {}", section, e));
+        }
+    }
+}
+
+/// Finds where a value is written in the file, for pointing at a problem.
+///
+/// # Arguments
+///
+/// * `content` - The contents of the file.
+/// * `text` - The value to look for, matched as a whole word.
+/// * `from` - The offset to start looking from, e.g. the cluster's section.
+/// * `skip` - The number of earlier mentions to skip, so that a repeated
+///   value points at the repeat rather than the first mention.
+///
+/// # Returns
+///
+/// Returns the offset of the mention, or None if the value isn't written in
+/// the file as is, e.g. because a range or include produced it.
+fn find(content: &str, text: &str, from: usize, skip: usize) -> Option<usize> {
+    let is_word = |c: char| c.is_alphanumeric() || matches!(c, '.' | '-' | '_');
+    let mut mentions = content[from..]
+        .match_indices(text)
+        .map(|(start, _)| from + start)
+        .filter(|start| {
+            let before = content[..*start].chars().next_back();
+            let after = content[start + text.len()..].chars().next();
+            !before.is_some_and(is_word) && !after.is_some_and(is_word)
+        });
+    mentions.nth(skip)
+}
+
+/// Converts an offset in the file to a line and column, both counted from one.
+fn locate(content: &str, offset: Option<usize>) -> Option<(usize, usize)> {
+    let offset = offset?;
+    let line = content[..offset].matches('\n').count() + 1;
+    let line_start = content[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let column = content[line_start..offset].chars().count() + 1;
+    Some((line, column))
+}
@@ -0,0 +1,314 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Opening TCP connections to hosts with several addresses, using Happy
+//! Eyeballs (RFC 8305).
+//!
+//! Nodes often have both IPv6 and IPv4 addresses, and one of them may be
+//! unroutable from where cluster_run runs. Trying the addresses one at a time
+//! would leave every connection waiting out the broken one first, so they're
+//! raced instead: the addresses are interleaved by family, an attempt is
+//! started on each in turn, a short delay apart or as soon as the one before
+//! fails, and the first to connect is used. Attempts that lose the race are
+//! left to finish on their own threads, and their sockets are closed.
+//!
+//! The same race is run on the runtime for transports that connect there,
+//! with attempts that lose it dropped rather than left to finish.
+//!
+//! Every socket is set up with the config's `[transport]` settings, which
+//! can limit how long connecting takes, turn on TCP keepalives and
+//! `TCP_NODELAY`, and pin the interface or address connections leave from,
+//! so traffic from an admin host with several networks goes over the right
+//! one. Only addresses of the source address's family are tried.
+
+use crate::resolve;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::task;
+
+/// How long an attempt gets before one is started on the next address, as
+/// RFC 8305 recommends.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The settings from the config's `[transport]` section.
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// How TCP connections to nodes, jump hosts and proxies are opened, from the
+/// config's `[transport]` section.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The limit in seconds on opening a TCP connection, within the
+    /// cluster's `connect_timeout`, which also covers logging in.
+    pub connect_timeout: Option<u64>,
+    /// Send TCP keepalives on a connection that's been idle for this many
+    /// seconds, and as often after that.
+    pub tcp_keepalive: Option<u64>,
+    /// Whether to turn off Nagle's algorithm with `TCP_NODELAY`.
+    #[serde(default)]
+    pub nodelay: bool,
+    /// The network interface connections leave from, such as `eth1`.
+    pub bind_interface: Option<String>,
+    /// The local address connections leave from.
+    pub source_address: Option<IpAddr>,
+}
+
+/// Opens every connection after this with `settings`.
+pub fn use_settings(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Returns the `[transport]` settings.
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Default::default)
+}
+
+/// Connects to a port on a host, racing its addresses.
+///
+/// # Arguments
+///
+/// * `host` - The host's name, or an IPv4 or IPv6 address.
+/// * `port` - The port to connect to.
+/// * `timeout` - The limit on the whole connection, across every address.
+///
+/// # Returns
+///
+/// Returns the first connection made, or the last attempt's error if none
+/// could be, which is of kind TimedOut if the timeout passed.
+pub fn connect(host: &str, port: u16, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let deadline = limit(timeout).map(|timeout| Instant::now() + timeout);
+    let mut addrs = interleave(usable(host, resolve::lookup(host, port)?)?);
+    if addrs.len() <= 1 {
+        let Some(addr) = addrs.pop_front() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve", host),
+            ));
+        };
+        return attempt(addr, deadline);
+    }
+
+    let (done, finished) = mpsc::channel();
+    let mut running = 0;
+    let mut last_error = None;
+    loop {
+        // Step 1: Start an attempt on the next address
+        if let Some(addr) = addrs.pop_front() {
+            let done = done.clone();
+            thread::spawn(move || {
+                let _ = done.send(attempt(addr, deadline));
+            });
+            running += 1;
+        }
+
+        // Step 2: Wait for an attempt to finish, or for the next one to be due
+        let started = Instant::now();
+        loop {
+            let result = if addrs.is_empty() {
+                // Every attempt gives up at the deadline, so with a timeout
+                // this can't wait for longer than it
+                finished.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                finished.recv_timeout(ATTEMPT_DELAY.saturating_sub(started.elapsed()))
+            };
+            match result {
+                Ok(Ok(tcp)) => return Ok(tcp),
+                Ok(Err(e)) => {
+                    running -= 1;
+                    last_error = Some(e);
+                    if !addrs.is_empty() {
+                        break;
+                    }
+                    if running == 0 {
+                        return Err(last_error.unwrap());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(last_error.unwrap_or_else(|| io::ErrorKind::TimedOut.into()))
+                }
+            }
+        }
+    }
+}
+
+/// Connects to a port on a host as `connect` does, on the runtime.
+///
+/// # Returns
+///
+/// Returns the first connection made, or the last attempt's error if none
+/// could be, which is of kind TimedOut if the timeout passed.
+pub async fn connect_async(
+    host: &str,
+    port: u16,
+    timeout: Option<Duration>,
+) -> io::Result<tokio::net::TcpStream> {
+    let race = async {
+        // Names are almost always looked up already, but may not be
+        let name = host.to_string();
+        let addrs = task::spawn_blocking(move || resolve::lookup(&name, port))
+            .await
+            .map_err(io::Error::other)??;
+        let mut addrs = interleave(usable(host, addrs)?);
+        let Some(first) = addrs.pop_front() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} did not resolve", host),
+            ));
+        };
+        let mut attempts = FuturesUnordered::new();
+        attempts.push(attempt_async(first));
+        loop {
+            // Start an attempt on the next address once one fails, or once
+            // the last attempt has had its delay
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(tcp) => return Ok(tcp),
+                    Err(e) => match addrs.pop_front() {
+                        Some(addr) => attempts.push(attempt_async(addr)),
+                        None if attempts.is_empty() => return Err(e),
+                        None => {}
+                    },
+                },
+                () = tokio::time::sleep(ATTEMPT_DELAY), if !addrs.is_empty() => {
+                    let addr = addrs.pop_front().unwrap();
+                    attempts.push(attempt_async(addr));
+                }
+            }
+        }
+    };
+    match limit(timeout) {
+        Some(timeout) => tokio::time::timeout(timeout, race)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => race.await,
+    }
+}
+
+/// Orders addresses so the families alternate, starting with the family of
+/// the first, which is the one the system prefers.
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return VecDeque::new();
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut ordered = VecDeque::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.pop_front(), other.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Returns the shorter of `timeout` and the `[transport]` connect timeout.
+fn limit(timeout: Option<Duration>) -> Option<Duration> {
+    let configured = settings().connect_timeout.map(Duration::from_secs);
+    match (timeout, configured) {
+        (Some(timeout), Some(configured)) => Some(timeout.min(configured)),
+        (timeout, configured) => timeout.or(configured),
+    }
+}
+
+/// Drops the addresses that can't be reached from the source address, which
+/// are those of the other family.
+///
+/// # Returns
+///
+/// Returns the addresses left, or an error if there are none.
+fn usable(host: &str, mut addrs: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+    let Some(source) = settings().source_address else {
+        return Ok(addrs);
+    };
+    let resolved = !addrs.is_empty();
+    addrs.retain(|addr| addr.is_ipv6() == source.is_ipv6());
+    if resolved && addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!(
+                "{} has no {} address to reach from the source address {}",
+                host,
+                if source.is_ipv6() { "IPv6" } else { "IPv4" },
+                source
+            ),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Makes a socket for connecting to `addr`, with the `[transport]` settings.
+fn socket(addr: SocketAddr) -> io::Result<Socket> {
+    let settings = settings();
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Some(interface) = &settings.bind_interface {
+        bind_interface(&socket, interface)?;
+    }
+    if let Some(source) = settings.source_address {
+        socket
+            .bind(&SocketAddr::new(source, 0).into())
+            .map_err(|e| io::Error::new(e.kind(), format!("can't bind to {}: {}", source, e)))?;
+    }
+    if let Some(secs) = settings.tcp_keepalive {
+        let idle = Duration::from_secs(secs);
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(idle))?;
+    }
+    if settings.nodelay {
+        socket.set_tcp_nodelay(true)?;
+    }
+    Ok(socket)
+}
+
+/// Sends a socket's traffic out of one network interface.
+#[cfg(target_os = "linux")]
+fn bind_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("can't bind to interface {}: {}", interface, e),
+        )
+    })
+}
+
+/// Sends a socket's traffic out of one network interface.
+#[cfg(not(target_os = "linux"))]
+fn bind_interface(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bind_interface is only supported on Linux; set source_address instead",
+    ))
+}
+
+/// Connects to one address, giving up at the deadline.
+fn attempt(addr: SocketAddr, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    let socket = socket(addr)?;
+    let Some(deadline) = deadline else {
+        socket.connect(&addr.into())?;
+        return Ok(socket.into());
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    socket.connect_timeout(&addr.into(), remaining)?;
+    Ok(socket.into())
+}
+
+/// Connects to one address on the runtime.
+async fn attempt_async(addr: SocketAddr) -> io::Result<tokio::net::TcpStream> {
+    let socket = socket(addr)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
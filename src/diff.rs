@@ -0,0 +1,200 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Comparing the nodes' output against a baseline, for `--diff`.
+//!
+//! Configuration drift shows up as a few nodes whose output differs from the
+//! rest. Once every node has finished, the nodes are grouped by their output
+//! and exit status, and each group other than the baseline's is printed once
+//! as a unified diff against it, under the nodes in it. The baseline is the
+//! node given with `--baseline`, or else the first node of the largest group,
+//! taking the output most nodes share to be how they should all be.
+
+use crate::inventory;
+use crate::output::{self, Color};
+use crate::ssh::{CommandResult, OutputStream};
+use similar::TextDiff;
+
+/// The nodes' output gathered for `--diff`, with the nodes that gave the
+/// same output and exit status kept together.
+pub struct Drift {
+    /// The place in the node list, and the name, of the node given with
+    /// `--baseline`.
+    baseline: Option<(usize, String)>,
+    groups: Vec<Group>,
+}
+
+/// A distinct output, with the nodes that gave it by their place in the
+/// node list.
+struct Group {
+    stdout: String,
+    exit_code: i32,
+    nodes: Vec<(usize, String)>,
+}
+
+impl Group {
+    /// Returns the group's nodes folded into ranges, with its exit status if
+    /// it isn't zero.
+    fn label(&self) -> String {
+        let names: Vec<String> = self.nodes.iter().map(|(_, name)| name.clone()).collect();
+        let mut label = inventory::fold(&names);
+        if self.exit_code != 0 {
+            label.push_str(&format!(" (exit {})", self.exit_code));
+        }
+        label
+    }
+}
+
+impl Drift {
+    /// Creates an empty comparison.
+    ///
+    /// # Arguments
+    ///
+    /// * `baseline` - The place in the node list and the name of the node to
+    ///   compare the others against, or None to use the output most nodes share.
+    pub fn new(baseline: Option<(usize, String)>) -> Self {
+        Drift {
+            baseline,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Adds a node's output, given its place in the node list.
+    pub fn add(&mut self, index: usize, node: &str, result: &CommandResult) {
+        let same = self
+            .groups
+            .iter_mut()
+            .find(|group| group.exit_code == result.exit_code && group.stdout == result.stdout);
+        let group = match same {
+            Some(group) => group,
+            None => {
+                self.groups.push(Group {
+                    stdout: result.stdout.clone(),
+                    exit_code: result.exit_code,
+                    nodes: Vec::new(),
+                });
+                self.groups.last_mut().unwrap()
+            }
+        };
+        group.nodes.push((index, node.to_string()));
+    }
+
+    /// Prints the baseline, then a diff against it for each group of nodes
+    /// whose output differs.
+    ///
+    /// # Returns
+    ///
+    /// Returns how many nodes drifted from the baseline, or an error if the
+    /// node given with `--baseline` couldn't be run on.
+    pub fn print(&mut self) -> Result<usize, String> {
+        // Step 1: Find the baseline's group
+        for group in &mut self.groups {
+            group.nodes.sort();
+        }
+        self.groups.sort_by_key(|group| group.nodes[0].0);
+        let baseline = match &self.baseline {
+            Some((index, name)) => self
+                .groups
+                .iter()
+                .position(|group| group.nodes.iter().any(|(i, _)| i == index))
+                .ok_or_else(|| {
+                    format!(
+                        "the baseline node {} didn't finish, so there's nothing to compare against",
+                        name
+                    )
+                })?,
+            // The first of the largest groups, in the order of the node list
+            None => match self
+                .groups
+                .iter()
+                .enumerate()
+                .rev()
+                .max_by_key(|(_, group)| group.nodes.len())
+            {
+                Some((i, _)) => i,
+                None => return Ok(0),
+            },
+        };
+        let reference = &self.groups[baseline];
+        let reference_name = match &self.baseline {
+            Some((_, name)) => name,
+            None => &reference.nodes[0].1,
+        };
+
+        // Step 2: Print the diff of each other group against it
+        let total: usize = self.groups.iter().map(|group| group.nodes.len()).sum();
+        let drifted = total - reference.nodes.len();
+        println!(
+            "Baseline {}, matched by {}",
+            reference_name,
+            output::paint(OutputStream::Stdout, Color::Green, &reference.label())
+        );
+        if drifted == 0 {
+            println!("No drift: all {} nodes match", total);
+            return Ok(0);
+        }
+        println!(
+            "{} of {} nodes drifted",
+            output::paint(OutputStream::Stdout, Color::Red, &drifted.to_string()),
+            total
+        );
+        for (i, group) in self.groups.iter().enumerate() {
+            if i == baseline {
+                continue;
+            }
+            println!();
+            let same = !print_unified(
+                reference_name,
+                &reference.stdout,
+                &group.label(),
+                &group.stdout,
+            );
+            if same {
+                println!(
+                    "The output is the same, but the command exited with status {} rather than {}",
+                    group.exit_code, reference.exit_code
+                );
+            }
+        }
+        Ok(drifted)
+    }
+}
+
+/// Prints a unified diff of two outputs, under a header naming where each
+/// came from.
+///
+/// # Returns
+///
+/// Returns false if the outputs are the same, when only the header is printed.
+pub fn print_unified(old_label: &str, old: &str, new_label: &str, new: &str) -> bool {
+    let diff = TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(old_label, new_label)
+        .to_string();
+    if diff.is_empty() {
+        print_diff_line(&format!("--- {}", old_label));
+        print_diff_line(&format!("+++ {}", new_label));
+        return false;
+    }
+    for line in diff.lines() {
+        print_diff_line(line);
+    }
+    true
+}
+
+/// Prints a line of a unified diff, colored by what it does.
+fn print_diff_line(line: &str) {
+    let color = if line.starts_with("---") || line.starts_with("+++") || line.starts_with("@@") {
+        Some(Color::Dim)
+    } else if line.starts_with('+') {
+        Some(Color::Green)
+    } else if line.starts_with('-') {
+        Some(Color::Red)
+    } else {
+        None
+    };
+    match color {
+        Some(color) => println!("{}", output::paint(OutputStream::Stdout, color, line)),
+        None => println!("{}", line),
+    }
+}
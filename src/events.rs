@@ -0,0 +1,133 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! A stream of what happens during a run as it happens, for `--events`.
+//!
+//! Each event is a JSON object on a line of its own, written and flushed as
+//! soon as it happens, so dashboards and wrappers can follow a run without
+//! parsing its other output. An event's `event` field says what it is:
+//!
+//! - `node_started` when a node is first connected to,
+//! - `stdout_chunk` and `stderr_chunk` for each piece of a command's output,
+//! - `node_finished` with the node's result, as `--output json` reports it
+//!   but without the output, which the chunks have already carried,
+//! - `run_summary` once the run is over, as `--output json` summarises it.
+//!
+//! Every event has the `time` it happened, and all but the summary have the
+//! `host` it's about. The events go to standard output, in place of the
+//! usual output there, or to a file or named pipe.
+
+use crate::output;
+use crate::ssh::OutputStream;
+use serde_json::{json, Value};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Where the events of a run are written.
+pub struct Events {
+    /// Where the events go, or None once writing them has failed.
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+    /// The file or named pipe the events go to, if not standard output.
+    path: Option<PathBuf>,
+}
+
+impl Events {
+    /// Opens where the events are to be written. A named pipe is opened for
+    /// writing, which waits until something opens it for reading.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file or named pipe to write to, or None for standard
+    ///   output.
+    ///
+    /// # Returns
+    ///
+    /// Returns the events, or an error if the file can't be opened.
+    pub fn open(path: Option<&Path>) -> Result<Self, String> {
+        let out: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .map_err(|e| format!("unable to open {}: {}", path.display(), e))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+        Ok(Events {
+            out: Mutex::new(Some(out)),
+            path: path.map(Path::to_path_buf),
+        })
+    }
+
+    /// Notes that a node has started.
+    pub fn node_started(&self, index: usize, host: &str) {
+        self.write(json!({ "event": "node_started", "host": host, "index": index }));
+    }
+
+    /// Notes a chunk of a node's output, which may end part way through a
+    /// line. Bytes that aren't valid UTF-8 are shown as replacement characters.
+    pub fn output(&self, host: &str, stream: OutputStream, chunk: &[u8]) {
+        let event = match stream {
+            OutputStream::Stdout => "stdout_chunk",
+            OutputStream::Stderr => "stderr_chunk",
+        };
+        self.write(json!({
+            "event": event,
+            "host": host,
+            "data": String::from_utf8_lossy(chunk),
+        }));
+    }
+
+    /// Notes that a node has finished, given its result object.
+    pub fn node_finished(&self, result: &Value) {
+        self.write(rename(result, "node_finished", &["stdout", "stderr"]));
+    }
+
+    /// Notes that the run is over, given its summary object.
+    pub fn run_summary(&self, summary: &Value) {
+        self.write(rename(summary, "run_summary", &[]));
+    }
+
+    /// Writes an event, with the time it happened, as a line of its own.
+    /// Once writing fails, such as when the reader of a pipe goes away, the
+    /// rest of the events are dropped with a warning.
+    fn write(&self, mut event: Value) {
+        if let Value::Object(fields) = &mut event {
+            fields.insert(
+                "time".to_string(),
+                output::timestamp(SystemTime::now()).into(),
+            );
+        }
+        let mut out = self.out.lock().unwrap();
+        let Some(writer) = out.as_mut() else {
+            return;
+        };
+        if let Err(e) = writeln!(writer, "{}", event).and_then(|_| writer.flush()) {
+            let target = match &self.path {
+                Some(path) => path.display().to_string(),
+                None => "standard output".to_string(),
+            };
+            tracing::warn!("Not writing any more events to {}: {}", target, e);
+            *out = None;
+        }
+    }
+}
+
+/// Returns a copy of a result or summary object as an event, with its
+/// `type` replaced by the event's name and the given fields left out.
+fn rename(object: &Value, event: &str, without: &[&str]) -> Value {
+    let mut object = object.clone();
+    if let Value::Object(fields) = &mut object {
+        fields.remove("type");
+        for field in without {
+            fields.remove(*field);
+        }
+        fields.insert("event".to_string(), event.into());
+    }
+    object
+}
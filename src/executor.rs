@@ -0,0 +1,213 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Concurrent execution of a task across the nodes of a cluster.
+//!
+//! Work that talks to nodes is run as futures, driven concurrently on the
+//! calling thread by the transports' runtime, so a node waiting on the
+//! network costs no thread of its own. Other work is spread over a fixed
+//! number of scoped worker threads. Each worker pulls the next node index
+//! from a shared counter, runs the task, and sends the result back to the
+//! calling thread. Either way results are handled in the order they
+//! complete, and once the run is cancelled no more nodes are started.
+//!
+//! For rolling changes the nodes can also be split into batches, which are
+//! run one after another, and for spreading a large file they can be run as
+//! a cascade, each node getting the file from one that already has it.
+
+use crate::cancel::CancelToken;
+use crate::transport;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Runs `task` on every node with at most `parallel` tasks in flight at once.
+///
+/// # Arguments
+///
+/// * `nodes` - The nodes to run the task on.
+/// * `parallel` - The maximum number of concurrent tasks. Zero means one task per node.
+/// * `cancel` - Stops nodes that haven't started yet from being run.
+/// * `task` - The work to perform for a single node, given its index and the node.
+/// * `on_result` - Called on the calling thread with the node index and result
+///   as each task completes.
+pub fn run_parallel<N, T, F, R>(
+    nodes: &[N],
+    parallel: usize,
+    cancel: &CancelToken,
+    task: F,
+    mut on_result: R,
+) where
+    N: Sync,
+    T: Send,
+    F: Fn(usize, &N) -> T + Sync,
+    R: FnMut(usize, T),
+{
+    let workers = match parallel {
+        0 => nodes.len(),
+        n => n.min(nodes.len()),
+    };
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            let task = &task;
+            scope.spawn(move || loop {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(node) = nodes.get(index) else {
+                    break;
+                };
+                if tx.send((index, task(index, node))).is_err() {
+                    break;
+                }
+            });
+        }
+        // Drop our sender so the receiver finishes once every worker exits
+        drop(tx);
+
+        for (index, result) in rx {
+            on_result(index, result);
+        }
+    });
+}
+
+/// Runs `task` on every node with at most `parallel` tasks in flight at
+/// once, as `run_parallel` does, with the tasks driven concurrently on the
+/// calling thread rather than each taking a thread of its own.
+///
+/// # Arguments
+///
+/// * `nodes` - The nodes to run the task on.
+/// * `parallel` - The maximum number of concurrent tasks. Zero means one task per node.
+/// * `cancel` - Stops nodes that haven't started yet from being run.
+/// * `task` - The work to perform for a single node, given its index and the node.
+/// * `on_result` - Called with the node index and result as each task completes.
+pub fn run_concurrent<N, T, F, R>(
+    nodes: &[N],
+    parallel: usize,
+    cancel: &CancelToken,
+    task: F,
+    mut on_result: R,
+) where
+    F: AsyncFn(usize, &N) -> T,
+    R: FnMut(usize, T),
+{
+    let limit = match parallel {
+        0 => nodes.len(),
+        n => n.min(nodes.len()),
+    };
+    transport::block_on(async {
+        let mut running = FuturesUnordered::new();
+        let mut next = 0;
+        loop {
+            // Start nodes until the limit is reached, unless the run is cancelled
+            while running.len() < limit && next < nodes.len() && !cancel.is_cancelled() {
+                let index = next;
+                let task = &task;
+                running.push(async move { (index, task(index, &nodes[index]).await) });
+                next += 1;
+            }
+            match running.next().await {
+                Some((index, result)) => on_result(index, result),
+                None => break,
+            }
+        }
+    });
+}
+
+/// Runs `task` on every node as a cascade, where each node gets what it
+/// needs from one that already has it.
+///
+/// The first `seeds` nodes are given no source, so the task gets what they
+/// need from the local machine. Every node after them waits for a source:
+/// a node whose task succeeded, which serves one node at a time, so the
+/// nodes with what's needed roughly double with every round. If no node has
+/// it and none are on their way to having it, as when every seed failed, the
+/// next node is given no source and so seeded from the local machine.
+///
+/// # Arguments
+///
+/// * `nodes` - The nodes to run the task on.
+/// * `seeds` - How many nodes to seed from the local machine.
+/// * `parallel` - The maximum number of concurrent tasks. Zero means one task per node.
+/// * `cancel` - Stops nodes that haven't started yet from being run.
+/// * `task` - The work to perform for a single node, given its index, the
+///   node and the index of the node to get what it needs from, if any.
+/// * `on_result` - Called with the node index and result as each task
+///   completes, returning true if the node can now be a source for others.
+pub fn run_cascade<N, T, F, R>(
+    nodes: &[N],
+    seeds: usize,
+    parallel: usize,
+    cancel: &CancelToken,
+    task: F,
+    mut on_result: R,
+) where
+    F: AsyncFn(usize, &N, Option<usize>) -> T,
+    R: FnMut(usize, T) -> bool,
+{
+    let limit = match parallel {
+        0 => nodes.len(),
+        n => n.min(nodes.len()),
+    };
+    transport::block_on(async {
+        let mut running = FuturesUnordered::new();
+        let mut sources = Vec::new();
+        let mut seeded = 0;
+        let mut next = 0;
+        loop {
+            // Start nodes while there are sources for them, until the limit
+            // is reached, unless the run is cancelled
+            while running.len() < limit && next < nodes.len() && !cancel.is_cancelled() {
+                let source = if seeded < seeds || (sources.is_empty() && running.is_empty()) {
+                    seeded += 1;
+                    None
+                } else if let Some(source) = sources.pop() {
+                    Some(source)
+                } else {
+                    break;
+                };
+                let index = next;
+                let task = &task;
+                running
+                    .push(async move { (index, source, task(index, &nodes[index], source).await) });
+                next += 1;
+            }
+            match running.next().await {
+                Some((index, source, result)) => {
+                    sources.extend(source);
+                    if on_result(index, result) {
+                        sources.push(index);
+                    }
+                }
+                None => break,
+            }
+        }
+    });
+}
+
+/// Splits `count` nodes into consecutive batches of at most `size` nodes.
+///
+/// A size of zero puts every node in a single batch.
+///
+/// # Returns
+///
+/// Returns the range of node indices in each batch, in order.
+pub fn batches(count: usize, size: usize) -> Vec<Range<usize>> {
+    let size = match size {
+        0 => count.max(1),
+        n => n,
+    };
+    (0..count)
+        .step_by(size)
+        .map(|start| start..(start + size).min(count))
+        .collect()
+}
@@ -0,0 +1,60 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Showing only the lines of output that matter, for `--grep` and `--grep-v`.
+//!
+//! A search across the fleet, such as for the nodes whose kernel log has Xid
+//! errors, can run `dmesg` everywhere and filter what comes back here,
+//! without quoting a remote grep for every shell it passes through. Only
+//! what's printed is filtered: the `--log-dir` and `--raw` files, the JSON
+//! results, reports, events and history keep all of the output.
+
+use crate::ssh::CommandResult;
+use regex::Regex;
+
+/// Which lines of output are shown.
+#[derive(Clone)]
+pub struct Filter {
+    /// The patterns a line must match one of, if any are given.
+    grep: Vec<Regex>,
+    /// The patterns a line mustn't match any of.
+    grep_v: Vec<Regex>,
+}
+
+impl Filter {
+    /// Returns the filter for `--grep` and `--grep-v`, or None if neither
+    /// was given and every line is shown.
+    pub fn new(grep: &[Regex], grep_v: &[Regex]) -> Option<Self> {
+        if grep.is_empty() && grep_v.is_empty() {
+            return None;
+        }
+        Some(Filter {
+            grep: grep.to_vec(),
+            grep_v: grep_v.to_vec(),
+        })
+    }
+
+    /// Returns true if a line, without its line ending, is shown.
+    pub fn keeps(&self, line: &str) -> bool {
+        (self.grep.is_empty() || self.grep.iter().any(|regex| regex.is_match(line)))
+            && !self.grep_v.iter().any(|regex| regex.is_match(line))
+    }
+
+    /// Returns the lines of the text that are shown.
+    pub fn text(&self, text: &str) -> String {
+        text.split_inclusive('\n')
+            .filter(|line| self.keeps(line.trim_end_matches(['\n', '\r'])))
+            .collect()
+    }
+
+    /// Returns a command's result with only the lines of its output that are
+    /// shown.
+    pub fn result(&self, result: &CommandResult) -> CommandResult {
+        CommandResult {
+            stdout: self.text(&result.stdout),
+            stderr: self.text(&result.stderr),
+            exit_code: result.exit_code,
+            duration: result.duration,
+        }
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The history of past runs, kept in a SQLite database.
+//!
+//! Every run of a command on the nodes is recorded once it's over, with the
+//! command, how the run went, and each node's result, output and timing, so
+//! runs can be looked back on and compared with `history`, such as today's
+//! state of the fleet with last week's. The database is `history.sqlite3` in
+//! the state directory, or the file named by `CLUSTER_RUN_HISTORY`.
+
+use crate::output;
+use crate::state;
+use crate::summary::Summary;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// The environment variable naming the history database, for keeping it
+/// somewhere other than the state directory.
+const PATH_VAR: &str = "CLUSTER_RUN_HISTORY";
+
+/// How long to wait for another cluster_run that's writing to the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The changes that bring the database up to date, the first creating the
+/// tables. The database's `user_version` is how many have been applied, so
+/// a database made by an older cluster_run gains the newer columns.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        started_at TEXT NOT NULL,
+        command TEXT NOT NULL,
+        nodes INTEGER NOT NULL,
+        succeeded INTEGER NOT NULL,
+        failed INTEGER NOT NULL,
+        wall_time REAL NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS results (
+        run_id INTEGER NOT NULL REFERENCES runs (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        host TEXT NOT NULL,
+        status TEXT NOT NULL,
+        exit_code INTEGER,
+        stdout TEXT NOT NULL,
+        stderr TEXT NOT NULL,
+        started_at TEXT,
+        duration REAL NOT NULL,
+        attempts INTEGER NOT NULL,
+        error TEXT,
+        PRIMARY KEY (run_id, position)
+    );
+    ",
+    // What was run and where, for `rerun`
+    "
+    ALTER TABLE runs ADD COLUMN subcommand TEXT;
+    ALTER TABLE runs ADD COLUMN selected TEXT;
+    ",
+    // How the command was wrapped, for `rerun`
+    "
+    ALTER TABLE runs ADD COLUMN wrapping TEXT;
+    ",
+];
+
+/// A run, as the history lists it.
+pub struct Run {
+    pub id: i64,
+    /// When the run started, as an RFC 3339 time.
+    pub started_at: String,
+    /// What was run, as it was given, with any placeholders.
+    pub command: String,
+    /// How many nodes were selected, including those the run never got to.
+    pub nodes: i64,
+    pub succeeded: i64,
+    /// How many nodes failed, timed out or couldn't be run on.
+    pub failed: i64,
+    /// How long the run took, in seconds.
+    pub wall_time: f64,
+    /// The subcommand that was run, such as `exec`, or None for runs
+    /// recorded before it was kept.
+    pub subcommand: Option<String>,
+    /// The names of the selected nodes, in order, or None for runs recorded
+    /// before they were kept.
+    pub selected: Option<Vec<String>>,
+    /// How the command was wrapped, or None for runs recorded before it was
+    /// kept.
+    pub wrapping: Option<Wrapping>,
+}
+
+/// How a run's command was wrapped by `--cwd`, `--env` and the sudo
+/// options, so `rerun` can wrap it the same way. The sudo password itself
+/// is never kept, only where it came from.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Wrapping {
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// The variables set, with anything secret in their values masked.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+    #[serde(default)]
+    pub sudo: bool,
+    #[serde(default)]
+    pub sudo_password_env: Option<String>,
+    #[serde(default)]
+    pub ask_sudo_password: bool,
+}
+
+/// A node's result in a past run.
+pub struct NodeRun {
+    pub host: String,
+    /// `succeeded`, `failed`, `timed_out`, `error` or `cancelled`, as for
+    /// `--output json`.
+    pub status: String,
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    /// How long the command ran, in seconds.
+    pub duration: f64,
+    pub error: Option<String>,
+}
+
+/// Returns the path of the history database.
+pub fn path() -> Result<PathBuf, String> {
+    match env::var_os(PATH_VAR) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => state::path("history.sqlite3")
+            .ok_or_else(|| "Unable to determine home directory".into()),
+    }
+}
+
+/// Opens the history database, creating it if it doesn't exist.
+fn open() -> Result<Connection, String> {
+    let path = path()?;
+    let invalid = |e: &dyn std::fmt::Display| {
+        format!("Unable to open the history in {}: {}", path.display(), e)
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| invalid(&e))?;
+    }
+    let mut db = Connection::open(&path).map_err(|e| invalid(&e))?;
+    db.busy_timeout(BUSY_TIMEOUT).map_err(|e| invalid(&e))?;
+    let version: i64 = db
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| invalid(&e))?;
+    let version = version as usize;
+    if version < MIGRATIONS.len() {
+        let transaction = db.transaction().map_err(|e| invalid(&e))?;
+        for migration in &MIGRATIONS[version..] {
+            transaction
+                .execute_batch(migration)
+                .map_err(|e| invalid(&e))?;
+        }
+        transaction
+            .pragma_update(None, "user_version", MIGRATIONS.len() as i64)
+            .map_err(|e| invalid(&e))?;
+        transaction.commit().map_err(|e| invalid(&e))?;
+    }
+    Ok(db)
+}
+
+/// Records a run that's over.
+///
+/// # Arguments
+///
+/// * `subcommand` - The subcommand that was run, such as `exec`.
+/// * `command` - What was run, as it was given.
+/// * `started_at` - When the run started.
+/// * `selected` - The names of the selected nodes, in order.
+/// * `wrapping` - How the command was wrapped.
+/// * `summary` - How the run went.
+/// * `results` - The result objects of the nodes that finished, as
+///   `report::node_json` returns them, in the order of the node list.
+///
+/// # Returns
+///
+/// Returns the run's ID in the history.
+pub fn record(
+    subcommand: &str,
+    command: &str,
+    started_at: SystemTime,
+    selected: &[String],
+    wrapping: &Wrapping,
+    summary: &Summary,
+    results: &[Value],
+) -> Result<i64, String> {
+    let mut db = open()?;
+    let failed = |e: rusqlite::Error| format!("Unable to record the run: {}", e);
+    let transaction = db.transaction().map_err(failed)?;
+    transaction
+        .execute(
+            "INSERT INTO runs (started_at, command, nodes, succeeded, failed, wall_time,
+                 subcommand, selected, wrapping)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                output::timestamp(started_at),
+                command,
+                selected.len() as i64,
+                summary.succeeded.len() as i64,
+                summary.failures() as i64,
+                summary.wall_time.as_secs_f64(),
+                subcommand,
+                Value::from(selected).to_string(),
+                serde_json::to_string(wrapping).map_err(|e| e.to_string())?,
+            ],
+        )
+        .map_err(failed)?;
+    let id = transaction.last_insert_rowid();
+    for (position, result) in results.iter().enumerate() {
+        let text = |field: &str| result[field].as_str().map(str::to_string);
+        transaction
+            .execute(
+                "INSERT INTO results (run_id, position, host, status, exit_code, stdout,
+                     stderr, started_at, duration, attempts, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    id,
+                    position as i64,
+                    text("host").unwrap_or_default(),
+                    text("status").unwrap_or_default(),
+                    result["exit_code"].as_i64(),
+                    text("stdout").unwrap_or_default(),
+                    text("stderr").unwrap_or_default(),
+                    text("started_at"),
+                    result["duration"].as_f64().unwrap_or_default(),
+                    result["attempts"].as_i64().unwrap_or_default(),
+                    text("error"),
+                ],
+            )
+            .map_err(failed)?;
+    }
+    transaction.commit().map_err(failed)?;
+    Ok(id)
+}
+
+/// Returns the most recent runs, newest first.
+pub fn runs(limit: usize) -> Result<Vec<Run>, String> {
+    let db = open()?;
+    let failed = |e: rusqlite::Error| format!("Unable to read the history: {}", e);
+    let mut statement = db
+        .prepare(
+            "SELECT id, started_at, command, nodes, succeeded, failed, wall_time,
+                 subcommand, selected, wrapping
+             FROM runs ORDER BY id DESC LIMIT ?1",
+        )
+        .map_err(failed)?;
+    let runs = statement
+        .query_map([limit as i64], read_run)
+        .map_err(failed)?
+        .collect::<Result<_, _>>()
+        .map_err(failed)?;
+    Ok(runs)
+}
+
+/// Returns a run, with its nodes' results in the order of the node list.
+///
+/// # Returns
+///
+/// Returns the run, or an error if there's no run with the ID.
+pub fn run(id: i64) -> Result<(Run, Vec<NodeRun>), String> {
+    let db = open()?;
+    let failed = |e: rusqlite::Error| format!("Unable to read the history: {}", e);
+    let run = db
+        .query_row(
+            "SELECT id, started_at, command, nodes, succeeded, failed, wall_time,
+                 subcommand, selected, wrapping
+             FROM runs WHERE id = ?1",
+            [id],
+            read_run,
+        )
+        .optional()
+        .map_err(failed)?
+        .ok_or_else(|| format!("There's no run {} in the history", id))?;
+    let mut statement = db
+        .prepare(
+            "SELECT host, status, exit_code, stdout, stderr, duration, error
+             FROM results WHERE run_id = ?1 ORDER BY position",
+        )
+        .map_err(failed)?;
+    let nodes = statement
+        .query_map([id], |row| {
+            Ok(NodeRun {
+                host: row.get(0)?,
+                status: row.get(1)?,
+                exit_code: row.get(2)?,
+                stdout: row.get(3)?,
+                stderr: row.get(4)?,
+                duration: row.get(5)?,
+                error: row.get(6)?,
+            })
+        })
+        .map_err(failed)?
+        .collect::<Result<_, _>>()
+        .map_err(failed)?;
+    Ok((run, nodes))
+}
+
+/// Reads a run from a row of the runs table.
+fn read_run(row: &rusqlite::Row) -> rusqlite::Result<Run> {
+    Ok(Run {
+        id: row.get(0)?,
+        started_at: row.get(1)?,
+        command: row.get(2)?,
+        nodes: row.get(3)?,
+        succeeded: row.get(4)?,
+        failed: row.get(5)?,
+        wall_time: row.get(6)?,
+        subcommand: row.get(7)?,
+        selected: row
+            .get::<_, Option<String>>(8)?
+            .and_then(|selected| serde_json::from_str(&selected).ok()),
+        wrapping: row
+            .get::<_, Option<String>>(9)?
+            .and_then(|wrapping| serde_json::from_str(&wrapping).ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_how_the_command_was_wrapped() {
+        let dir = env::temp_dir().join(format!("cluster_run-history-{}", std::process::id()));
+        env::set_var(PATH_VAR, dir.join("history.sqlite3"));
+        let wrapping = Wrapping {
+            cwd: Some("/srv/app".to_string()),
+            env: vec![
+                ("RUST_LOG".to_string(), "debug".to_string()),
+                ("MODE".to_string(), "a b".to_string()),
+            ],
+            sudo: true,
+            sudo_password_env: Some("SUDO_PASS".to_string()),
+            ask_sudo_password: false,
+        };
+        let selected = vec!["gpu-01".to_string(), "gpu-02".to_string()];
+        let id = record(
+            "exec",
+            "systemctl restart app",
+            SystemTime::now(),
+            &selected,
+            &wrapping,
+            &Summary::default(),
+            &[],
+        )
+        .unwrap();
+
+        let (past, results) = run(id).unwrap();
+        assert_eq!(past.command, "systemctl restart app");
+        assert_eq!(past.subcommand.as_deref(), Some("exec"));
+        assert_eq!(past.selected, Some(selected));
+        assert_eq!(past.wrapping, Some(wrapping));
+        assert!(results.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Verifying the host keys of nodes, so that connections can't be
+//! intercepted.
+//!
+//! Host keys are checked against `~/.ssh/known_hosts` (or the file named by
+//! `CLUSTER_RUN_KNOWN_HOSTS`) and `/etc/ssh/ssh_known_hosts`, hashed host
+//! names included, as OpenSSH checks them. `--strict-host-key-checking`
+//! decides what happens to a host that isn't listed: `accept-new`, the
+//! default, adds its key to the user's file, `yes` refuses to connect, and
+//! `no` skips checking altogether. A key that differs from the listed one, or
+//! is marked `@revoked`, is refused unless checking is off.
+//!
+//! A node can instead pin its host key's SHA256 fingerprint in the config,
+//! as `ssh-keygen -lf` prints it, and is then checked against that alone.
+//!
+//! A host may be listed with only some types of key, while its sshd offers
+//! others. The listed types are asked for first in the handshake, as OpenSSH
+//! does, so that the host isn't mistaken for a new or changed one.
+
+use crate::config::Node;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use clap::ValueEnum;
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, MethodType, Session};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// The environment variable naming the user's known hosts file.
+const PATH_VAR: &str = "CLUSTER_RUN_KNOWN_HOSTS";
+
+/// The known hosts file shared by every user, which is only read.
+const GLOBAL_PATH: &str = "/etc/ssh/ssh_known_hosts";
+
+/// The host key algorithms libssh2 supports, in the order it prefers them.
+const ALGORITHMS: [&str; 8] = [
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+    "ssh-rsa",
+    "ssh-dss",
+];
+
+/// The prefix of a pinned fingerprint.
+const SHA256: &str = "SHA256:";
+
+/// How host keys are checked, set once per run.
+static CHECKING: OnceLock<Checking> = OnceLock::new();
+
+/// The known hosts, read once per run and added to as new hosts are accepted.
+static KNOWN: OnceLock<Result<Mutex<KnownHosts>, String>> = OnceLock::new();
+
+/// What to do with a host that isn't in the known hosts files.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Checking {
+    /// Refuse to connect
+    Yes,
+    /// Connect without checking any host key
+    No,
+    /// Add the host's key to ~/.ssh/known_hosts and connect
+    #[default]
+    AcceptNew,
+}
+
+impl Checking {
+    /// Returns the OpenSSH options that check host keys this way.
+    pub fn ssh_options(self) -> &'static [&'static str] {
+        match self {
+            Checking::Yes => &["-o", "StrictHostKeyChecking=yes"],
+            Checking::AcceptNew => &["-o", "StrictHostKeyChecking=accept-new"],
+            Checking::No => &[
+                "-o",
+                "StrictHostKeyChecking=no",
+                "-o",
+                "UserKnownHostsFile=/dev/null",
+                "-o",
+                "GlobalKnownHostsFile=/dev/null",
+            ],
+        }
+    }
+}
+
+/// The entries of the known hosts files.
+struct KnownHosts {
+    /// The file new hosts are added to.
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+/// A line of a known hosts file.
+struct Entry {
+    /// The key type, such as `ssh-ed25519`.
+    key_type: String,
+    /// The key, base64-encoded.
+    key: String,
+    /// The line without its marker, as libssh2 parses it.
+    line: String,
+    /// Whether the line is marked `@revoked`.
+    revoked: bool,
+}
+
+/// Sets how host keys are checked for the rest of the run.
+pub fn use_checking(checking: Checking) {
+    let _ = CHECKING.set(checking);
+}
+
+/// Asks for the types of key a node is listed with ahead of the others, so
+/// that the handshake gets a key that can be checked. Must be called before
+/// the handshake.
+///
+/// # Returns
+///
+/// Returns an error if the known hosts files can't be read.
+pub fn prefer_known(sess: &Session, node: &Node) -> Result<(), String> {
+    let listed = listed_types(node)?;
+    if listed.is_empty() {
+        return Ok(());
+    }
+    let (mut preferred, others): (Vec<&str>, Vec<&str>) = ALGORITHMS
+        .iter()
+        .partition(|algorithm| key_type_of(algorithm).is_some_and(|t| listed.contains(&t)));
+    preferred.extend(others);
+    sess.method_pref(MethodType::HostKey, &preferred.join(","))
+        .map_err(|e| format!("unable to choose the host key types: {}", e))
+}
+
+/// Returns the types of key a node is listed with in the known hosts files,
+/// in the order libssh2 prefers them, which the handshake should ask for
+/// ahead of the others.
+///
+/// # Returns
+///
+/// Returns no types if the node's key isn't checked against the files, or
+/// an error if they can't be read.
+pub fn listed_types(node: &Node) -> Result<Vec<&'static str>, String> {
+    if checking() == Checking::No || node.host_key.is_some() {
+        return Ok(Vec::new());
+    }
+    let known = known_hosts()?.lock().unwrap();
+    let mut listed: Vec<&str> = Vec::new();
+    for key_type in ALGORITHMS.iter().filter_map(|a| key_type_of(a)) {
+        if !listed.contains(&key_type) && known.lists(node, key_type)? {
+            listed.push(key_type);
+        }
+    }
+    Ok(listed)
+}
+
+/// Checks the host key a node sent in the handshake.
+///
+/// # Arguments
+///
+/// * `sess` - The session, once the handshake is done.
+/// * `node` - The node, with its pinned fingerprint if it has one.
+///
+/// # Returns
+///
+/// Returns an error if the key doesn't match the pinned fingerprint or the
+/// known hosts files, is revoked, or is new and new hosts aren't accepted.
+pub fn verify(sess: &Session, node: &Node) -> Result<(), String> {
+    if checking() == Checking::No {
+        return Ok(());
+    }
+    let (key, kind) = sess
+        .host_key()
+        .ok_or_else(|| format!("{} didn't send a host key", node.address()))?;
+    let key_type = key_type_name(kind).ok_or_else(|| {
+        format!(
+            "{} sent a type of host key that can't be checked",
+            node.address()
+        )
+    })?;
+    check(node, key_type, key, &fingerprint(sess))
+}
+
+/// Checks a host key a node sent, as `verify` does, for transports that
+/// handshake without libssh2.
+///
+/// # Arguments
+///
+/// * `node` - The node, with its pinned fingerprint if it has one.
+/// * `key_type` - The type of the key, as known hosts files write it.
+/// * `key` - The key, in the SSH wire format.
+/// * `fingerprint` - The key's SHA256 fingerprint, as `ssh-keygen -l` prints it.
+pub fn check(node: &Node, key_type: &str, key: &[u8], fingerprint: &str) -> Result<(), String> {
+    // Step 1: Check the key against the pinned fingerprint, if there is one
+    let checking = checking();
+    if checking == Checking::No {
+        return Ok(());
+    }
+    if let Some(pinned) = &node.host_key {
+        let pinned = pinned
+            .strip_prefix(SHA256)
+            .ok_or_else(|| pin_error(pinned))?
+            .trim_end_matches('=');
+        if fingerprint.strip_prefix(SHA256) == Some(pinned) {
+            tracing::debug!("[{}] Host key {} is the pinned one", node, fingerprint);
+            return Ok(());
+        }
+        return Err(format!(
+            "the host key of {} is {}, not the one pinned in the config; someone may be intercepting the connection, or the host's key was changed",
+            node.address(),
+            fingerprint
+        ));
+    }
+
+    // Step 2: Check it against the known hosts files
+    let mut known = known_hosts()?.lock().unwrap();
+    let encoded = STANDARD.encode(key);
+    if known.entries.iter().any(|e| e.revoked && e.key == encoded) {
+        return Err(format!(
+            "the host key of {} ({}) is marked @revoked in the known hosts files",
+            node.address(),
+            fingerprint
+        ));
+    }
+    match known.check(node, key_type, key)? {
+        CheckResult::Match => {
+            tracing::debug!("[{}] Host key {} is a known one", node, fingerprint);
+            Ok(())
+        }
+        CheckResult::Mismatch => Err(format!(
+            "the host key of {} has changed to {}, which doesn't match {}; someone may be intercepting the connection, or the host was reinstalled and its old key should be removed with `ssh-keygen -R {}`",
+            node.address(),
+            fingerprint,
+            known.path.display(),
+            host_pattern(node)
+        )),
+        CheckResult::NotFound if checking == Checking::Yes => Err(format!(
+            "{} isn't in the known hosts files, and its host key is {}; add it, or use --strict-host-key-checking accept-new",
+            node.address(),
+            fingerprint
+        )),
+        // Step 3: Remember a new host
+        CheckResult::NotFound => {
+            known.add(node, key_type, encoded).map_err(|e| {
+                format!("unable to add {} to {}: {}", node.address(), known.path.display(), e)
+            })?;
+            tracing::info!(
+                "Added the host key of {} ({}) to {}",
+                node.address(),
+                fingerprint,
+                known.path.display()
+            );
+            Ok(())
+        }
+        CheckResult::Failure => Err(format!(
+            "unable to check the host key of {}",
+            node.address()
+        )),
+    }
+}
+
+/// Returns an error if a pinned fingerprint isn't in the SHA256 form that
+/// `ssh-keygen -l` prints.
+pub fn check_pin(pinned: &str) -> Result<(), String> {
+    let body = pinned.strip_prefix(SHA256).map(|b| b.trim_end_matches('='));
+    match body.map(|body| STANDARD_NO_PAD.decode(body)) {
+        Some(Ok(hash)) if hash.len() == 32 => Ok(()),
+        _ => Err(pin_error(pinned)),
+    }
+}
+
+/// The error for a malformed pinned fingerprint.
+fn pin_error(pinned: &str) -> String {
+    format!(
+        "host_key {} should be a SHA256 fingerprint, as `ssh-keygen -lf` prints it",
+        pinned
+    )
+}
+
+/// Returns how host keys are checked.
+pub fn checking() -> Checking {
+    CHECKING.get().copied().unwrap_or_default()
+}
+
+/// Returns the known hosts file named by `CLUSTER_RUN_KNOWN_HOSTS`, if it's
+/// set.
+pub fn path_override() -> Option<PathBuf> {
+    env::var_os(PATH_VAR).map(PathBuf::from)
+}
+
+/// Returns the SHA256 fingerprint of the session's host key.
+fn fingerprint(sess: &Session) -> String {
+    let hash = sess.host_key_hash(HashType::Sha256).unwrap_or_default();
+    format!("{}{}", SHA256, STANDARD_NO_PAD.encode(hash))
+}
+
+/// Returns the known hosts, reading the files the first time.
+fn known_hosts() -> Result<&'static Mutex<KnownHosts>, String> {
+    KNOWN
+        .get_or_init(|| KnownHosts::load().map(Mutex::new))
+        .as_ref()
+        .map_err(Clone::clone)
+}
+
+impl KnownHosts {
+    /// Reads the user's known hosts file and the global one, either of which
+    /// may be missing.
+    fn load() -> Result<KnownHosts, String> {
+        let path = match (env::var_os(PATH_VAR), env::var_os("HOME")) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Some(home)) => PathBuf::from(home).join(".ssh").join("known_hosts"),
+            (None, None) => return Err("Unable to determine home directory".into()),
+        };
+        let mut known = KnownHosts {
+            path,
+            entries: Vec::new(),
+        };
+        for path in [known.path.clone(), PathBuf::from(GLOBAL_PATH)] {
+            match fs::read_to_string(&path) {
+                Ok(content) => known
+                    .entries
+                    .extend(content.lines().filter_map(Entry::parse)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+            }
+        }
+        Ok(known)
+    }
+
+    /// Checks a key against the entries of its type for a node.
+    fn check(&self, node: &Node, key_type: &str, key: &[u8]) -> Result<CheckResult, String> {
+        // libssh2 compares keys of every type, so only this type is given to
+        // it, on a session of its own since no connection is needed
+        let sess = Session::new().map_err(|e| format!("unable to check host keys: {}", e))?;
+        let mut hosts = sess
+            .known_hosts()
+            .map_err(|e| format!("unable to check host keys: {}", e))?;
+        for entry in self
+            .entries
+            .iter()
+            .filter(|e| !e.revoked && e.key_type == key_type)
+        {
+            // Lines libssh2 can't parse are skipped, as OpenSSH skips them
+            let _ = hosts.read_str(&entry.line, KnownHostFileKind::OpenSSH);
+        }
+        Ok(hosts.check_port(node.address(), node.port(), key))
+    }
+
+    /// Returns true if a node is listed with a key of the given type.
+    fn lists(&self, node: &Node, key_type: &str) -> Result<bool, String> {
+        // A key that can't be any real one matches every listed key but none
+        let result = self.check(node, key_type, &[0])?;
+        Ok(matches!(result, CheckResult::Match | CheckResult::Mismatch))
+    }
+
+    /// Adds a node's key to the user's known hosts file.
+    fn add(&mut self, node: &Node, key_type: &str, key: String) -> io::Result<()> {
+        let line = format!("{} {} {}", host_pattern(node), key_type, key);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", line)?;
+        self.entries.push(Entry {
+            key_type: key_type.to_string(),
+            key,
+            line,
+            revoked: false,
+        });
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// Parses a line of a known hosts file, skipping blank lines, comments
+    /// and certificate authorities.
+    fn parse(line: &str) -> Option<Entry> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (revoked, line) = match line.split_once(char::is_whitespace) {
+            Some(("@revoked", rest)) => (true, rest.trim_start()),
+            Some((marker, _)) if marker.starts_with('@') => return None,
+            _ => (false, line),
+        };
+        let mut fields = line.split_whitespace();
+        let _hosts = fields.next()?;
+        let key_type = fields.next()?.to_string();
+        let key = fields.next()?.to_string();
+        Some(Entry {
+            key_type,
+            key,
+            line: line.to_string(),
+            revoked,
+        })
+    }
+}
+
+/// Returns how a node is written in a known hosts file, which includes the
+/// port if it isn't the default.
+fn host_pattern(node: &Node) -> String {
+    match node.port() {
+        crate::ssh::PORT => node.address().to_string(),
+        port => format!("[{}]:{}", node.address(), port),
+    }
+}
+
+/// Returns the name of a type of host key, as known hosts files write it.
+fn key_type_name(kind: HostKeyType) -> Option<&'static str> {
+    match kind {
+        HostKeyType::Rsa => Some("ssh-rsa"),
+        HostKeyType::Dss => Some("ssh-dss"),
+        HostKeyType::Ecdsa256 => Some("ecdsa-sha2-nistp256"),
+        HostKeyType::Ecdsa384 => Some("ecdsa-sha2-nistp384"),
+        HostKeyType::Ecdsa521 => Some("ecdsa-sha2-nistp521"),
+        HostKeyType::Ed255219 => Some("ssh-ed25519"),
+        HostKeyType::Unknown => None,
+    }
+}
+
+/// Returns the type of key a host key algorithm uses.
+pub fn key_type_of(algorithm: &str) -> Option<&'static str> {
+    match algorithm {
+        "rsa-sha2-512" | "rsa-sha2-256" | "ssh-rsa" => Some("ssh-rsa"),
+        "ssh-dss" => Some("ssh-dss"),
+        "ecdsa-sha2-nistp256" => Some("ecdsa-sha2-nistp256"),
+        "ecdsa-sha2-nistp384" => Some("ecdsa-sha2-nistp384"),
+        "ecdsa-sha2-nistp521" => Some("ecdsa-sha2-nistp521"),
+        "ssh-ed25519" => Some("ssh-ed25519"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A host key, and another of the same type.
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAINSxoa+U+Y6/G5hA7aQgh8xtav/mBGip8PjPnZOz8kma";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIIGra5EfYDMLAESUbVe+d4ftHif8NxhC+ALC7hZR8/D8";
+
+    /// KEY for gpu-01.example.com and [gpu-02.example.com]:2222, with the
+    /// names hashed by `ssh-keygen -H`.
+    const HASHED: [&str; 2] = [
+        "|1|GKzoDZRW3ejbXUEDs1g/dd90XAU=|m2ya2WK8jxnS2VL3bNJpbun5AJs= ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINSxoa+U+Y6/G5hA7aQgh8xtav/mBGip8PjPnZOz8kma",
+        "|1|XVE5sMwev+C91wKdwnM0RrDa0TI=|QJEs07phfEmprlel63suNJ0g0oI= ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAINSxoa+U+Y6/G5hA7aQgh8xtav/mBGip8PjPnZOz8kma",
+    ];
+
+    fn known(lines: &[&str]) -> KnownHosts {
+        KnownHosts {
+            path: PathBuf::new(),
+            entries: lines.iter().filter_map(|line| Entry::parse(line)).collect(),
+        }
+    }
+
+    fn node(host: &str, port: Option<u16>) -> Node {
+        let mut node = Node::from(host);
+        node.port = port;
+        node
+    }
+
+    fn check(known: &KnownHosts, node: &Node, key: &str) -> CheckResult {
+        let key = STANDARD.decode(key).unwrap();
+        known.check(node, "ssh-ed25519", &key).unwrap()
+    }
+
+    #[test]
+    fn parses_entries() {
+        let entry =
+            Entry::parse(&format!("gpu-01,10.0.0.1 ssh-ed25519 {} a comment", KEY)).unwrap();
+        assert_eq!(entry.key_type, "ssh-ed25519");
+        assert_eq!(entry.key, KEY);
+        assert!(!entry.revoked);
+
+        let entry = Entry::parse(&format!("[gpu-02]:2222 ssh-ed25519 {}", KEY)).unwrap();
+        assert_eq!(entry.line, format!("[gpu-02]:2222 ssh-ed25519 {}", KEY));
+
+        let entry = Entry::parse(HASHED[0]).unwrap();
+        assert_eq!(entry.key, KEY);
+        assert_eq!(entry.line, HASHED[0]);
+    }
+
+    #[test]
+    fn parses_markers() {
+        // The marker is kept out of the line libssh2 is given
+        let entry = Entry::parse(&format!("@revoked  * ssh-ed25519 {}", KEY)).unwrap();
+        assert!(entry.revoked);
+        assert_eq!(entry.line, format!("* ssh-ed25519 {}", KEY));
+
+        assert!(Entry::parse(&format!(
+            "@cert-authority *.example.com ssh-ed25519 {}",
+            KEY
+        ))
+        .is_none());
+        assert!(Entry::parse(&format!("@unknown gpu-01 ssh-ed25519 {}", KEY)).is_none());
+    }
+
+    #[test]
+    fn skips_blank_comment_and_short_lines() {
+        assert!(Entry::parse("").is_none());
+        assert!(Entry::parse("   ").is_none());
+        assert!(Entry::parse(&format!("# gpu-01 ssh-ed25519 {}", KEY)).is_none());
+        assert!(Entry::parse("gpu-01 ssh-ed25519").is_none());
+        assert!(Entry::parse("@revoked gpu-01").is_none());
+    }
+
+    #[test]
+    fn checks_hashed_names() {
+        let known = known(&HASHED);
+        let gpu = node("gpu-01.example.com", None);
+        assert!(matches!(check(&known, &gpu, KEY), CheckResult::Match));
+        assert!(matches!(
+            check(&known, &gpu, OTHER_KEY),
+            CheckResult::Mismatch
+        ));
+        let other = node("gpu-03.example.com", None);
+        assert!(matches!(check(&known, &other, KEY), CheckResult::NotFound));
+    }
+
+    #[test]
+    fn checks_ports() {
+        let known = known(&[HASHED[1], &format!("[10.0.0.2]:2222 ssh-ed25519 {}", KEY)]);
+        for host in ["gpu-02.example.com", "10.0.0.2"] {
+            let listed = node(host, Some(2222));
+            assert!(matches!(check(&known, &listed, KEY), CheckResult::Match));
+            // The same name on another port is another host
+            let default_port = node(host, None);
+            assert!(matches!(
+                check(&known, &default_port, KEY),
+                CheckResult::NotFound
+            ));
+        }
+        assert_eq!(
+            host_pattern(&node("10.0.0.2", Some(2222))),
+            "[10.0.0.2]:2222"
+        );
+        assert_eq!(host_pattern(&node("10.0.0.2", Some(22))), "10.0.0.2");
+    }
+
+    #[test]
+    fn leaves_revoked_keys_out_of_matching() {
+        let known = known(&[&format!("@revoked gpu-01 ssh-ed25519 {}", KEY)]);
+        assert!(matches!(
+            check(&known, &node("gpu-01", None), KEY),
+            CheckResult::NotFound
+        ));
+    }
+}
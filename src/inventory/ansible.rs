@@ -0,0 +1,590 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Ansible inventories, in both the INI and the YAML format.
+//!
+//! Both formats are read into the same groups of hosts, children and
+//! variables, which are then mapped onto nodes. A host's address is its
+//! `ansible_host` if set, in which case its inventory name becomes its
+//! alias, or its inventory name otherwise, and
+//! `ansible_user`, `ansible_port` and `ansible_ssh_private_key_file` set its
+//! login settings. Its other variables that don't start with `ansible_`
+//! become labels.
+//!
+//! Variables are merged the way Ansible merges them: those of `all` first,
+//! then those of each group the host is in from the outermost group to the
+//! innermost, with groups at the same depth in alphabetical order, and
+//! finally the host's own. The `all` and `ungrouped` groups aren't carried
+//! over, since every node is in the built-in `all` group.
+
+use super::range;
+use super::Inventory;
+use crate::config::Node;
+use serde_yaml::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The variables of a host or group.
+type Vars = BTreeMap<String, String>;
+
+/// A group as written in the inventory.
+#[derive(Default)]
+struct Group {
+    /// The hosts listed directly in the group.
+    hosts: Vec<String>,
+    /// The names of the groups nested in the group.
+    children: Vec<String>,
+    vars: Vars,
+}
+
+/// An inventory as written, before variables are merged.
+#[derive(Default)]
+struct Parsed {
+    /// Every host, in the order they first appear.
+    hosts: Vec<String>,
+    host_vars: BTreeMap<String, Vars>,
+    groups: BTreeMap<String, Group>,
+}
+
+impl Parsed {
+    /// Adds a host pattern to a group, expanding any `[01:50]` ranges in it.
+    fn add_hosts(&mut self, group: &str, pattern: &str, vars: Vars) -> Result<(), String> {
+        // Ansible writes ranges with a colon where cluster_run uses a dash
+        let mut in_range = false;
+        let pattern: String = pattern
+            .chars()
+            .map(|c| match c {
+                '[' | ']' => {
+                    in_range = c == '[';
+                    c
+                }
+                ':' if in_range => '-',
+                c => c,
+            })
+            .collect();
+
+        for host in range::expand(&pattern)? {
+            if !self.host_vars.contains_key(&host) {
+                self.hosts.push(host.clone());
+            }
+            self.host_vars
+                .entry(host.clone())
+                .or_default()
+                .extend(vars.clone());
+            self.groups
+                .entry(group.to_string())
+                .or_default()
+                .hosts
+                .push(host);
+        }
+        Ok(())
+    }
+
+    /// Returns the group with the given name, creating it if needed.
+    fn group(&mut self, name: &str) -> &mut Group {
+        self.groups.entry(name.to_string()).or_default()
+    }
+
+    /// Merges each host's variables and maps the hosts onto nodes.
+    fn resolve(self) -> Result<Inventory, String> {
+        // Step 1: Find every host in each group, including through its children
+        let mut members: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for name in self.groups.keys() {
+            let mut hosts = BTreeSet::new();
+            self.collect_hosts(name, &mut Vec::new(), &mut hosts)?;
+            members.insert(name, hosts);
+        }
+
+        // Step 2: Work out how deeply each group is nested, for variable precedence
+        let mut depth: BTreeMap<&str, usize> =
+            self.groups.keys().map(|name| (name.as_str(), 1)).collect();
+        for _ in 0..self.groups.len() {
+            for (name, group) in &self.groups {
+                let child_depth = depth[name.as_str()] + 1;
+                for child in &group.children {
+                    let depth = depth.get_mut(child.as_str()).unwrap();
+                    *depth = (*depth).max(child_depth);
+                }
+            }
+        }
+
+        // Step 3: Merge each host's variables and turn it into a node
+        let mut nodes = Vec::with_capacity(self.hosts.len());
+        let mut addresses = BTreeMap::new();
+        for host in &self.hosts {
+            let mut groups: Vec<&str> = members
+                .iter()
+                .filter(|(name, hosts)| **name != "all" && hosts.contains(host.as_str()))
+                .map(|(name, _)| *name)
+                .collect();
+            groups.sort_by_key(|name| (depth[name], *name));
+
+            let mut vars = Vars::new();
+            if let Some(all) = self.groups.get("all") {
+                vars.extend(all.vars.clone());
+            }
+            for group in &groups {
+                vars.extend(self.groups[*group].vars.clone());
+            }
+            vars.extend(self.host_vars[host].clone());
+
+            let node = node_from(host, vars)?;
+            addresses.insert(host.as_str(), node.host.clone());
+            nodes.push(node);
+        }
+
+        let groups = members
+            .iter()
+            .filter(|(name, _)| !matches!(**name, "all" | "ungrouped"))
+            .map(|(name, hosts)| {
+                let mut addresses: Vec<String> =
+                    hosts.iter().map(|host| addresses[host].clone()).collect();
+                addresses.sort();
+                addresses.dedup();
+                (name.to_string(), addresses)
+            })
+            .collect();
+        Ok(Inventory { nodes, groups })
+    }
+
+    /// Adds the hosts in a group and its children to `hosts`.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if a group is nested inside itself or a child group
+    /// doesn't exist.
+    fn collect_hosts<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        hosts: &mut BTreeSet<&'a str>,
+    ) -> Result<(), String> {
+        if path.contains(&name) {
+            return Err(format!("group {} contains itself", name));
+        }
+        let group = self
+            .groups
+            .get(name)
+            .ok_or_else(|| format!("group {} is listed as a child but never defined", name))?;
+        hosts.extend(group.hosts.iter().map(String::as_str));
+        path.push(name);
+        for child in &group.children {
+            self.collect_hosts(child, path, hosts)?;
+        }
+        path.pop();
+        Ok(())
+    }
+}
+
+/// Maps a host and its merged variables onto a node.
+fn node_from(host: &str, mut vars: Vars) -> Result<Node, String> {
+    let mut take = |names: &[&str]| {
+        let values: Vec<String> = names.iter().filter_map(|name| vars.remove(*name)).collect();
+        values.into_iter().next()
+    };
+    let address = take(&["ansible_host", "ansible_ssh_host"]);
+    let user = take(&["ansible_user", "ansible_ssh_user"]);
+    let port = take(&["ansible_port", "ansible_ssh_port"])
+        .map(|port| {
+            port.parse()
+                .map_err(|_| format!("host {} has an invalid ansible_port {}", host, port))
+        })
+        .transpose()?;
+    let key = take(&["ansible_ssh_private_key_file", "ansible_private_key_file"]);
+
+    let mut node = Node::from(address.as_deref().unwrap_or(host));
+    if node.host != host {
+        node.alias = Some(host.to_string());
+    }
+    node.user = user;
+    node.port = port;
+    node.key = key.map(Into::into);
+    node.labels = vars
+        .into_iter()
+        .filter(|(name, _)| !name.starts_with("ansible_"))
+        .collect();
+    Ok(node)
+}
+
+/// The kind of lines that follow a section header in an INI inventory.
+enum Section {
+    Hosts(String),
+    Vars(String),
+    Children(String),
+}
+
+/// Parses an INI inventory.
+///
+/// # Returns
+///
+/// Returns the inventory's nodes and groups, or an error naming the line
+/// that couldn't be parsed.
+pub fn parse_ini(content: &str) -> Result<Inventory, String> {
+    let mut parsed = Parsed::default();
+    let mut section = Section::Hosts("ungrouped".to_string());
+    for (index, line) in content.lines().enumerate() {
+        let at_line = |e: String| format!("line {}: {}", index + 1, e);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        // A header starts a section of hosts, variables or child groups
+        if let Some(header) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = match header.split_once(':') {
+                None => Section::Hosts(header.to_string()),
+                Some((group, "vars")) => Section::Vars(group.to_string()),
+                Some((group, "children")) => Section::Children(group.to_string()),
+                Some(_) => return Err(at_line(format!("unknown section [{}]", header))),
+            };
+            let (Section::Hosts(group) | Section::Vars(group) | Section::Children(group)) =
+                &section;
+            parsed.group(group);
+            continue;
+        }
+
+        match &section {
+            Section::Hosts(group) => {
+                let mut words = split_words(line).into_iter();
+                let Some(pattern) = words.next() else {
+                    return Err(at_line(format!("expected a host, found {}", line)));
+                };
+                let vars = words
+                    .map(|word| match word.split_once('=') {
+                        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+                        None => Err(at_line(format!("expected name=value, found {}", word))),
+                    })
+                    .collect::<Result<Vars, String>>()?;
+                parsed.add_hosts(group, &pattern, vars).map_err(at_line)?;
+            }
+            Section::Vars(group) => {
+                let (name, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| at_line(format!("expected name=value, found {}", line)))?;
+                let value = split_words(value).join(" ");
+                parsed
+                    .group(group)
+                    .vars
+                    .insert(name.trim().to_string(), value);
+            }
+            Section::Children(group) => {
+                parsed.group(group).children.push(line.to_string());
+            }
+        }
+    }
+    parsed.resolve()
+}
+
+/// Splits a line into words at whitespace outside of quotes, removing the
+/// quotes and stopping at a comment.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '#') if word.is_empty() => break,
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, c) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            (_, c) => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Parses a YAML inventory.
+///
+/// # Returns
+///
+/// Returns the inventory's nodes and groups, or an error if the file isn't
+/// YAML laid out as an inventory.
+pub fn parse_yaml(content: &str) -> Result<Inventory, String> {
+    let root: Value = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+    let mut parsed = Parsed::default();
+    for (name, body) in mapping(&root, "the inventory")? {
+        parse_yaml_group(&mut parsed, &key(name)?, body)?;
+    }
+    parsed.resolve()
+}
+
+/// Reads a YAML group, with its hosts, variables and children.
+fn parse_yaml_group(parsed: &mut Parsed, name: &str, body: &Value) -> Result<(), String> {
+    parsed.group(name);
+    for (field, value) in mapping(body, &format!("group {}", name))? {
+        match key(field)?.as_str() {
+            "hosts" => {
+                for (host, vars) in mapping(value, &format!("the hosts of {}", name))? {
+                    let vars = yaml_vars(vars, &format!("host {}", key(host)?))?;
+                    parsed.add_hosts(name, &key(host)?, vars)?;
+                }
+            }
+            "vars" => {
+                let vars = yaml_vars(value, &format!("the vars of {}", name))?;
+                parsed.group(name).vars.extend(vars);
+            }
+            "children" => {
+                for (child, body) in mapping(value, &format!("the children of {}", name))? {
+                    let child = key(child)?;
+                    parsed.group(name).children.push(child.clone());
+                    parse_yaml_group(parsed, &child, body)?;
+                }
+            }
+            other => return Err(format!("group {} has an unknown field {}", name, other)),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entries of a YAML mapping, treating an empty value as an empty
+/// mapping.
+fn mapping<'a>(value: &'a Value, what: &str) -> Result<Vec<(&'a Value, &'a Value)>, String> {
+    match value {
+        Value::Mapping(mapping) => Ok(mapping.iter().collect()),
+        Value::Null => Ok(Vec::new()),
+        _ => Err(format!("{} should be a mapping", what)),
+    }
+}
+
+/// Returns a YAML value used as a name.
+fn key(value: &Value) -> Result<String, String> {
+    scalar(value).ok_or_else(|| "names should be strings".to_string())
+}
+
+/// Reads a mapping of variables, skipping those whose values aren't scalars.
+fn yaml_vars(value: &Value, what: &str) -> Result<Vars, String> {
+    let mut vars = Vars::new();
+    for (name, value) in mapping(value, what)? {
+        if let Some(value) = scalar(value) {
+            vars.insert(key(name)?, value);
+        }
+    }
+    Ok(vars)
+}
+
+/// Returns a string, number or boolean as a string.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the node with an address or alias.
+    fn node<'a>(inventory: &'a Inventory, name: &str) -> &'a Node {
+        inventory
+            .nodes
+            .iter()
+            .find(|node| node.is_named(name))
+            .unwrap_or_else(|| panic!("no node {}", name))
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    const INI: &str = "
+# The bastion isn't in any group
+bastion.example.com ansible_user=admin
+
+[gpu]
+gpu[01:03].example.com rack=r1
+gpu04 ansible_host=10.0.0.4 ansible_port=2222 rack=\"r 2\"
+
+[gpu:vars]
+ansible_user=ubuntu
+rack = r0
+role=trainer ; the GPUs
+
+[storage]
+nas01 ansible_ssh_private_key_file=~/.ssh/nas
+
+[cluster:children]
+gpu
+storage
+
+[cluster:vars]
+role=worker
+site=sfo
+";
+
+    #[test]
+    fn parses_ini_groups_and_children() {
+        let inventory = parse_ini(INI).unwrap();
+        let hosts: Vec<&str> = inventory
+            .nodes
+            .iter()
+            .map(|node| node.host.as_str())
+            .collect();
+        assert_eq!(
+            hosts,
+            [
+                "bastion.example.com",
+                "gpu01.example.com",
+                "gpu02.example.com",
+                "gpu03.example.com",
+                "10.0.0.4",
+                "nas01",
+            ]
+        );
+        assert_eq!(
+            inventory.groups["gpu"],
+            names(&[
+                "10.0.0.4",
+                "gpu01.example.com",
+                "gpu02.example.com",
+                "gpu03.example.com"
+            ])
+        );
+        assert_eq!(inventory.groups["storage"], names(&["nas01"]));
+        assert_eq!(inventory.groups["cluster"].len(), 5);
+        assert!(!inventory.groups.contains_key("ungrouped"));
+    }
+
+    #[test]
+    fn merges_ini_vars_innermost_last() {
+        let inventory = parse_ini(INI).unwrap();
+
+        // The host's own variables win over its group's, whose win over
+        // the parent group's
+        let gpu = node(&inventory, "gpu01.example.com");
+        assert_eq!(gpu.user.as_deref(), Some("ubuntu"));
+        assert_eq!(gpu.labels["rack"], "r1");
+        assert_eq!(gpu.labels["role"], "trainer ; the GPUs");
+        assert_eq!(gpu.labels["site"], "sfo");
+
+        let renamed = node(&inventory, "gpu04");
+        assert_eq!(renamed.host, "10.0.0.4");
+        assert_eq!(renamed.alias.as_deref(), Some("gpu04"));
+        assert_eq!(renamed.port, Some(2222));
+        assert_eq!(renamed.labels["rack"], "r 2");
+
+        let nas = node(&inventory, "nas01");
+        assert_eq!(nas.key.as_deref(), Some(std::path::Path::new("~/.ssh/nas")));
+        assert_eq!(nas.labels["role"], "worker");
+        assert_eq!(nas.user, None);
+
+        let bastion = node(&inventory, "bastion.example.com");
+        assert_eq!(bastion.user.as_deref(), Some("admin"));
+        assert!(bastion.labels.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_ini_lines() {
+        let error = |content: &str| parse_ini(content).err().unwrap();
+        assert_eq!(error("[gpu]\n''\n"), "line 2: expected a host, found ''");
+        assert_eq!(
+            error("[gpu]\ngpu01 rack\n"),
+            "line 2: expected name=value, found rack"
+        );
+        assert_eq!(
+            error("[gpu:hosts]\n"),
+            "line 1: unknown section [gpu:hosts]"
+        );
+        assert_eq!(
+            error("[gpu:vars]\nrack\n"),
+            "line 2: expected name=value, found rack"
+        );
+        assert!(error("[gpu]\ngpu[03:01]\n").starts_with("line 2: "));
+        assert_eq!(
+            error("[a:children]\nb\n[b:children]\na\n"),
+            "group a contains itself"
+        );
+        assert_eq!(
+            error("[a:children]\nb\n"),
+            "group b is listed as a child but never defined"
+        );
+        assert_eq!(
+            error("[gpu]\ngpu01 ansible_port=ssh\n"),
+            "host gpu01 has an invalid ansible_port ssh"
+        );
+    }
+
+    #[test]
+    fn parses_yaml_inventories() {
+        let inventory = parse_yaml(
+            "
+all:
+  vars:
+    ansible_user: ubuntu
+    site: sfo
+  hosts:
+    bastion.example.com:
+  children:
+    cluster:
+      vars:
+        role: worker
+      children:
+        gpu:
+          hosts:
+            gpu[01:02]:
+              rack: r1
+            gpu03:
+              ansible_host: 10.0.0.3
+              ansible_port: 2222
+              rack: 2
+          vars:
+            role: trainer
+        storage:
+          hosts:
+            nas01:
+              ansible_user: root
+",
+        )
+        .unwrap();
+        assert_eq!(inventory.nodes.len(), 5);
+        assert_eq!(
+            inventory.groups["gpu"],
+            names(&["10.0.0.3", "gpu01", "gpu02"])
+        );
+        assert_eq!(
+            inventory.groups["cluster"],
+            names(&["10.0.0.3", "gpu01", "gpu02", "nas01"])
+        );
+        assert!(!inventory.groups.contains_key("all"));
+
+        let gpu = node(&inventory, "gpu02");
+        assert_eq!(gpu.user.as_deref(), Some("ubuntu"));
+        assert_eq!(gpu.labels["rack"], "r1");
+        assert_eq!(gpu.labels["role"], "trainer");
+        assert_eq!(gpu.labels["site"], "sfo");
+
+        let renamed = node(&inventory, "gpu03");
+        assert_eq!(renamed.host, "10.0.0.3");
+        assert_eq!(renamed.port, Some(2222));
+        assert_eq!(renamed.labels["rack"], "2");
+
+        let nas = node(&inventory, "nas01");
+        assert_eq!(nas.user.as_deref(), Some("root"));
+        assert_eq!(nas.labels["role"], "worker");
+    }
+
+    #[test]
+    fn rejects_malformed_yaml() {
+        let error = |content: &str| parse_yaml(content).err().unwrap();
+        assert_eq!(error("- gpu01\n"), "the inventory should be a mapping");
+        assert_eq!(
+            error("gpu:\n  members: [gpu01]\n"),
+            "group gpu has an unknown field members"
+        );
+        assert_eq!(
+            error("gpu:\n  hosts: [gpu01]\n"),
+            "the hosts of gpu should be a mapping"
+        );
+    }
+}
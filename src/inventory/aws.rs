@@ -0,0 +1,119 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Nodes read from AWS EC2.
+//!
+//! A cluster with `source = "aws"` takes its nodes from the running EC2
+//! instances matching its tag filters, listed afresh on every run with the
+//! AWS CLI so that its usual credentials and profiles apply. Each instance's
+//! private IP is its address unless the public one is asked for, and its
+//! tags, instance ID, type and availability zone become labels.
+
+use super::Inventory;
+use crate::config::Node;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// The `[cluster.inventory.aws]` section of the config.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The region to list instances in, if not the CLI's default.
+    pub region: Option<String>,
+    /// The AWS CLI profile to use, if not the default.
+    pub profile: Option<String>,
+    /// The tags an instance must have, with their values.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Which of an instance's addresses to connect to.
+    #[serde(default)]
+    pub address: Address,
+}
+
+/// Which of an instance's IP addresses is used as its address.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Address {
+    #[default]
+    Private,
+    Public,
+}
+
+/// Lists the running instances matching the configured filters.
+///
+/// # Returns
+///
+/// Returns the instances as nodes, or an error if the AWS CLI can't be run
+/// or fails.
+pub fn discover(settings: &Settings) -> Result<Inventory, String> {
+    // Step 1: Ask the AWS CLI for the matching instances
+    let mut command = Command::new("aws");
+    command.args(["ec2", "describe-instances", "--output", "json"]);
+    if let Some(region) = &settings.region {
+        command.args(["--region", region]);
+    }
+    if let Some(profile) = &settings.profile {
+        command.args(["--profile", profile]);
+    }
+    command.arg("--filters");
+    command.arg("Name=instance-state-name,Values=running");
+    for (tag, value) in &settings.tags {
+        command.arg(format!("Name=tag:{},Values={}", tag, value));
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("couldn't run the AWS CLI: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "the AWS CLI failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("the AWS CLI printed invalid JSON: {}", e))?;
+
+    // Step 2: Turn each instance into a node
+    let instances = response["Reservations"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|reservation| reservation["Instances"].as_array().into_iter().flatten());
+    let mut nodes = Vec::new();
+    for instance in instances {
+        let field = match settings.address {
+            Address::Private => "PrivateIpAddress",
+            Address::Public => "PublicIpAddress",
+        };
+        // An instance without the address can't be reached, so it's left out
+        let Some(address) = instance[field].as_str() else {
+            continue;
+        };
+
+        let mut node = Node::from(address);
+        for tag in instance["Tags"].as_array().into_iter().flatten() {
+            if let (Some(key), Some(value)) = (tag["Key"].as_str(), tag["Value"].as_str()) {
+                node.labels.insert(key.to_string(), value.to_string());
+            }
+        }
+        let details = [
+            ("instance_id", &instance["InstanceId"]),
+            ("instance_type", &instance["InstanceType"]),
+            (
+                "availability_zone",
+                &instance["Placement"]["AvailabilityZone"],
+            ),
+        ];
+        for (label, value) in details {
+            if let Some(value) = value.as_str() {
+                node.labels.insert(label.to_string(), value.to_string());
+            }
+        }
+        nodes.push(node);
+    }
+    Ok(Inventory {
+        nodes,
+        groups: BTreeMap::new(),
+    })
+}
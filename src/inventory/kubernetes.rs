@@ -0,0 +1,110 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Nodes read from a Kubernetes cluster.
+//!
+//! The machines underneath a Kubernetes cluster are listed with kubectl, so
+//! the kubeconfig and its contexts work as they do everywhere else. Each
+//! node's internal IP is its address unless another is asked for, its
+//! Kubernetes name is its alias, and its Kubernetes labels become labels.
+
+use super::Inventory;
+use crate::config::Node;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+/// The `[cluster.inventory.k8s]` section of the config, or the equivalent
+/// command line options.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The kubeconfig context to use, if not the current one.
+    pub context: Option<String>,
+    /// The Kubernetes label selector nodes must match, e.g. `gpu=true`.
+    pub label_selector: Option<String>,
+    /// Which of a node's addresses to connect to.
+    #[serde(default)]
+    pub address: Address,
+}
+
+/// Which of a node's addresses is used as its address.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Address {
+    #[default]
+    Internal,
+    External,
+    Hostname,
+}
+
+impl Address {
+    /// Returns the address type as Kubernetes names it.
+    fn kind(self) -> &'static str {
+        match self {
+            Address::Internal => "InternalIP",
+            Address::External => "ExternalIP",
+            Address::Hostname => "Hostname",
+        }
+    }
+}
+
+/// Lists the nodes of the Kubernetes cluster that match the label selector.
+///
+/// # Returns
+///
+/// Returns the machines as nodes, or an error if kubectl can't be run or
+/// fails.
+pub fn discover(settings: &Settings) -> Result<Inventory, String> {
+    // Step 1: Ask kubectl for the nodes
+    let mut command = Command::new("kubectl");
+    command.args(["get", "nodes", "--output", "json"]);
+    if let Some(context) = &settings.context {
+        command.args(["--context", context]);
+    }
+    if let Some(selector) = &settings.label_selector {
+        command.args(["--selector", selector]);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("couldn't run kubectl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "kubectl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("kubectl printed invalid JSON: {}", e))?;
+
+    // Step 2: Turn each machine into a node
+    let mut nodes = Vec::new();
+    for item in response["items"].as_array().into_iter().flatten() {
+        let name = item["metadata"]["name"].as_str().unwrap_or_default();
+        let address = item["status"]["addresses"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|address| address["type"] == settings.address.kind())
+            .and_then(|address| address["address"].as_str())
+            .ok_or_else(|| format!("node {} has no {} address", name, settings.address.kind()))?;
+
+        let mut node = Node::from(address);
+        if address != name {
+            node.alias = Some(name.to_string());
+        }
+        if let Some(labels) = item["metadata"]["labels"].as_object() {
+            for (key, value) in labels {
+                if let Some(value) = value.as_str() {
+                    node.labels.insert(key.clone(), value.to_string());
+                }
+            }
+        }
+        nodes.push(node);
+    }
+    Ok(Inventory {
+        nodes,
+        groups: BTreeMap::new(),
+    })
+}
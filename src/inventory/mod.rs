@@ -0,0 +1,474 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Turning the node lists written in the config into the nodes themselves.
+//!
+//! Large clusters have regular names and addresses, so node lists may use
+//! compact forms that stand for many nodes: hostname ranges such as
+//! `node[001-064].cluster.local` and IPv4 CIDR blocks such as `10.0.1.0/28`.
+//!
+//! The nodes can also come from an inventory file kept for another tool, or
+//! from a plain list of hosts produced by one, rather than from the config,
+//! so the same list isn't maintained twice. A cluster can instead name a
+//! provider as its `source`, which lists the nodes from wherever they are
+//! allocated each time cluster_run starts.
+
+mod ansible;
+mod aws;
+mod kubernetes;
+mod range;
+mod sfcompute;
+mod slurm;
+
+pub use range::{expand as expand_range, fold};
+
+use crate::config::{ClusterConfig, Defaults, Node};
+use crate::resolve;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// Where a run's nodes come from when not from the config.
+pub enum NodeSource<'a> {
+    /// An Ansible inventory file.
+    Inventory(&'a Path),
+    /// The nodes of a Kubernetes cluster.
+    Kubernetes {
+        context: Option<&'a str>,
+        label_selector: Option<&'a str>,
+    },
+    /// A file listing one host per line, or standard input if the path is `-`.
+    HostsFile(&'a Path),
+    /// Hosts given on the command line.
+    Hosts(&'a [String]),
+}
+
+impl NodeSource<'_> {
+    /// Returns true if the nodes are read from standard input.
+    pub fn reads_stdin(&self) -> bool {
+        match self {
+            NodeSource::HostsFile(path) => *path == Path::new("-"),
+            NodeSource::Hosts(hosts) => hosts == &["-"],
+            NodeSource::Inventory(_) | NodeSource::Kubernetes { .. } => false,
+        }
+    }
+}
+
+/// Describes the source in error messages.
+impl fmt::Display for NodeSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            _ if self.reads_stdin() => write!(f, "host list on standard input"),
+            NodeSource::Inventory(path) => write!(f, "inventory in {}", path.display()),
+            NodeSource::Kubernetes { .. } => write!(f, "Kubernetes nodes"),
+            NodeSource::HostsFile(path) => write!(f, "host list in {}", path.display()),
+            NodeSource::Hosts(_) => write!(f, "--hosts"),
+        }
+    }
+}
+
+/// The settings for each provider, from the cluster's `[cluster.inventory]` section.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Providers {
+    pub aws: Option<aws::Settings>,
+    pub k8s: Option<kubernetes::Settings>,
+    pub sfcompute: Option<sfcompute::Settings>,
+    pub slurm: Option<slurm::Settings>,
+}
+
+/// The nodes and groups read from an inventory file or provider.
+pub struct Inventory {
+    pub nodes: Vec<Node>,
+    /// The addresses of the nodes in each group, by group name.
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+/// Reads the nodes from a source other than the config.
+///
+/// The Kubernetes nodes are listed with kubectl.
+/// Ansible inventory files ending in `.yml` or `.yaml` are read as YAML
+/// inventories, and any other file as an INI inventory. Host lists have one
+/// host per line, ignoring blank lines and `#` comments, and have no groups.
+///
+/// # Returns
+///
+/// Returns the nodes and groups, or an error if the source can't be read or
+/// isn't valid.
+pub fn load(source: &NodeSource) -> Result<Inventory, String> {
+    let hosts: Vec<String> = match source {
+        NodeSource::Inventory(path) => {
+            let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+            return match path.extension().and_then(|extension| extension.to_str()) {
+                Some("yml" | "yaml") => ansible::parse_yaml(&content),
+                _ => ansible::parse_ini(&content),
+            };
+        }
+        _ if source.reads_stdin() => {
+            let mut content = String::new();
+            io::stdin()
+                .read_to_string(&mut content)
+                .map_err(|e| e.to_string())?;
+            host_lines(&content)
+        }
+        NodeSource::HostsFile(path) => {
+            host_lines(&fs::read_to_string(path).map_err(|e| e.to_string())?)
+        }
+        NodeSource::Hosts(hosts) => hosts.to_vec(),
+        NodeSource::Kubernetes {
+            context,
+            label_selector,
+        } => {
+            return kubernetes::discover(&kubernetes::Settings {
+                context: context.map(str::to_string),
+                label_selector: label_selector.map(str::to_string),
+                ..Default::default()
+            })
+        }
+    };
+    if hosts.is_empty() {
+        return Err("no hosts are listed".to_string());
+    }
+    Ok(Inventory {
+        nodes: hosts.iter().map(|host| Node::from(host.as_str())).collect(),
+        groups: BTreeMap::new(),
+    })
+}
+
+/// Lists the nodes of a cluster whose nodes come from a provider.
+///
+/// # Arguments
+///
+/// * `cluster` - The cluster, with its source and the source's settings.
+/// * `defaults` - How to log in to a host the provider is queried through.
+///
+/// # Returns
+///
+/// Returns None if the cluster's nodes are written in the config, the
+/// provider's nodes and groups otherwise, or an error if the provider isn't
+/// configured or fails.
+pub fn discover(cluster: &ClusterConfig, defaults: &Defaults) -> Result<Option<Inventory>, String> {
+    let Some(source) = &cluster.source else {
+        return Ok(None);
+    };
+    let missing = || {
+        format!(
+            "source {} needs a [cluster.inventory.{}] section",
+            source, source
+        )
+    };
+    let inventory = match source.as_str() {
+        "aws" => aws::discover(cluster.inventory.aws.as_ref().ok_or_else(missing)?)?,
+        "k8s" => kubernetes::discover(&cluster.inventory.k8s.clone().unwrap_or_default())?,
+        "sfcompute" => {
+            sfcompute::discover(cluster.inventory.sfcompute.as_ref().ok_or_else(missing)?)?
+        }
+        "slurm" => slurm::discover(
+            &cluster.inventory.slurm.clone().unwrap_or_default(),
+            defaults,
+            cluster.connect_timeout.map(Duration::from_secs),
+        )?,
+        other => {
+            return Err(format!(
+                "unknown source {}; the sources are aws, k8s, sfcompute and slurm",
+                other
+            ))
+        }
+    };
+    Ok(Some(inventory))
+}
+
+/// Returns the hosts in a newline-separated list.
+fn host_lines(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Expands the compact forms in the cluster's node and group lists.
+///
+/// A node table written with a range or block becomes one node per address,
+/// each with the table's settings. Its alias and resolve address may be
+/// ranges too, such as `gpu-[01-08]`, which are paired up with the addresses
+/// in order. A group member written as `@name` stands for every member of
+/// the group with that name.
+///
+/// # Returns
+///
+/// Returns an error if an entry is a malformed range or block, if an alias
+/// or resolve address doesn't expand to one value per address, or if a group
+/// includes a group that doesn't exist or includes itself.
+pub fn expand(cluster: &mut ClusterConfig) -> Result<(), String> {
+    let mut nodes = Vec::with_capacity(cluster.nodes.len());
+    for node in &cluster.nodes {
+        let hosts = range::expand(&node.host)?;
+        let aliases = paired("alias", node.alias.as_deref(), node, hosts.len())?;
+        let resolves = paired("resolve", node.resolve.as_deref(), node, hosts.len())?;
+        for ((host, alias), resolve) in hosts.into_iter().zip(aliases).zip(resolves) {
+            nodes.push(Node {
+                host,
+                alias,
+                resolve,
+                ..node.clone()
+            });
+        }
+    }
+    cluster.nodes = nodes;
+
+    for members in cluster.groups.values_mut() {
+        let mut expanded = Vec::with_capacity(members.len());
+        for member in members.iter() {
+            expanded.extend(range::expand(member)?);
+        }
+        *members = expanded;
+    }
+    include_groups(&mut cluster.groups)
+}
+
+/// Replaces the `@name` members of each group with the members of the
+/// group they name, which may include other groups in turn.
+///
+/// # Returns
+///
+/// Returns an error if a group includes a group that doesn't exist, or
+/// includes itself, directly or through other groups.
+fn include_groups(groups: &mut BTreeMap<String, Vec<String>>) -> Result<(), String> {
+    if !groups
+        .values()
+        .flatten()
+        .any(|member| member.starts_with('@'))
+    {
+        return Ok(());
+    }
+    let mut included = BTreeMap::new();
+    for group in groups.keys() {
+        members_of(group, groups, &mut included, &mut Vec::new())?;
+    }
+    *groups = included;
+    Ok(())
+}
+
+/// Returns the members of a group with its included groups replaced,
+/// remembering the result for the groups that include it.
+///
+/// # Arguments
+///
+/// * `group` - The group to list the members of.
+/// * `groups` - The groups as written, by name.
+/// * `included` - The groups whose members are already known, by name.
+/// * `path` - The groups being listed that led to this one, for finding cycles.
+fn members_of<'a>(
+    group: &'a str,
+    groups: &'a BTreeMap<String, Vec<String>>,
+    included: &mut BTreeMap<String, Vec<String>>,
+    path: &mut Vec<&'a str>,
+) -> Result<Vec<String>, String> {
+    if let Some(members) = included.get(group) {
+        return Ok(members.clone());
+    }
+    if let Some(start) = path.iter().position(|name| *name == group) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(group);
+        return Err(format!(
+            "group {} includes itself: {}",
+            group,
+            cycle.join(" -> ")
+        ));
+    }
+
+    path.push(group);
+    let mut members = Vec::new();
+    for member in &groups[group] {
+        match member.strip_prefix('@') {
+            Some(name) => match groups.get_key_value(name) {
+                Some((name, _)) => members.extend(members_of(name, groups, included, path)?),
+                None => {
+                    return Err(format!(
+                        "group {} includes @{}, which isn't a group",
+                        group, name
+                    ))
+                }
+            },
+            None => members.push(member.clone()),
+        }
+    }
+    path.pop();
+
+    // A node in several of the included groups is still one member
+    let mut seen = HashSet::new();
+    members.retain(|member| seen.insert(member.clone()));
+    included.insert(group.to_string(), members.clone());
+    Ok(members)
+}
+
+/// Expands a node setting that is paired up with the node's addresses.
+///
+/// # Returns
+///
+/// Returns one value per address, or an error if the setting is malformed
+/// or expands to a different number of values.
+fn paired(
+    setting: &str,
+    value: Option<&str>,
+    node: &Node,
+    count: usize,
+) -> Result<Vec<Option<String>>, String> {
+    let Some(value) = value else {
+        return Ok(vec![None; count]);
+    };
+    let values = range::expand(value)?;
+    if values.len() != count {
+        return Err(format!(
+            "{} {} expands to {} values, but host {} expands to {} addresses",
+            setting,
+            value,
+            values.len(),
+            node.host,
+            count
+        ));
+    }
+    Ok(values.into_iter().map(Some).collect())
+}
+
+/// Drops the nodes that are the same machine as an earlier node.
+///
+/// Nodes are the same machine when they connect to the same port on an
+/// address in common, which catches a machine listed under both its hostname
+/// and its IP address, or under two hostnames. A node whose address doesn't
+/// resolve is only the same as nodes with the same address, as is a node
+/// reached through jump hosts or a proxy, whose address means whatever it
+/// means to the last of them, among the nodes reached the same way. The earlier
+/// node joins the groups of the nodes dropped in its favour, and the dropped
+/// nodes are listed on stderr.
+///
+/// # Returns
+///
+/// Returns the remaining nodes, in their original order.
+pub fn dedupe(nodes: Vec<Node>) -> Vec<Node> {
+    let addresses = lookup_all(&nodes);
+    let mut kept: Vec<Node> = Vec::with_capacity(nodes.len());
+    let mut owners: HashMap<String, usize> = HashMap::new();
+    let mut skipped = Vec::new();
+    for (node, addresses) in nodes.into_iter().zip(addresses) {
+        match addresses.iter().find_map(|address| owners.get(address)) {
+            Some(&owner) => {
+                let same = &mut kept[owner];
+                skipped.push(format!("{} (as {})", node, same));
+                same.groups.extend(node.groups);
+                same.groups.sort();
+                same.groups.dedup();
+            }
+            None => {
+                for address in addresses {
+                    owners.insert(address, kept.len());
+                }
+                kept.push(node);
+            }
+        }
+    }
+    if !skipped.is_empty() {
+        tracing::warn!(
+            "Skipping {} nodes listed more than once: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+    kept
+}
+
+/// Looks up the socket addresses of every node, all at once.
+///
+/// # Returns
+///
+/// Returns each node's addresses, or its unresolved address and port if the
+/// lookup failed or the node is reached through jump hosts or a proxy.
+fn lookup_all(nodes: &[Node]) -> Vec<Vec<String>> {
+    resolve::all(nodes);
+    nodes
+        .iter()
+        .map(|node| {
+            let address = (node.address(), node.port());
+            if let Some(via) = node.jump.as_ref().or(node.proxy.as_ref()) {
+                return vec![format!("{}:{} via {}", address.0, address.1, via)];
+            }
+            match resolve::lookup(address.0, address.1) {
+                Ok(addrs) => addrs.iter().map(|addr| addr.to_string()).collect(),
+                Err(_) => vec![format!("{}:{}", address.0, address.1)],
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn groups(entries: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        entries
+            .iter()
+            .map(|(name, members)| {
+                let members = members.iter().map(|member| member.to_string()).collect();
+                (name.to_string(), members)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_nested_groups() {
+        let mut written = groups(&[
+            ("all", &["@gpu", "login-01"]),
+            ("gpu", &["@h100", "gpu-09"]),
+            ("h100", &["gpu-01", "gpu-02"]),
+        ]);
+        include_groups(&mut written).unwrap();
+        assert_eq!(written["all"], ["gpu-01", "gpu-02", "gpu-09", "login-01"]);
+        assert_eq!(written["gpu"], ["gpu-01", "gpu-02", "gpu-09"]);
+        assert_eq!(written["h100"], ["gpu-01", "gpu-02"]);
+    }
+
+    #[test]
+    fn includes_a_diamond_once() {
+        // top reaches base through both left and right, which isn't a cycle
+        let mut written = groups(&[
+            ("top", &["@left", "@right"]),
+            ("left", &["@base", "gpu-03"]),
+            ("right", &["gpu-04", "@base"]),
+            ("base", &["gpu-01", "gpu-02"]),
+        ]);
+        include_groups(&mut written).unwrap();
+        assert_eq!(written["top"], ["gpu-01", "gpu-02", "gpu-03", "gpu-04"]);
+        assert_eq!(written["left"], ["gpu-01", "gpu-02", "gpu-03"]);
+        assert_eq!(written["right"], ["gpu-04", "gpu-01", "gpu-02"]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut written = groups(&[("a", &["@b"]), ("b", &["@c", "gpu-01"]), ("c", &["@a"])]);
+        assert_eq!(
+            include_groups(&mut written).unwrap_err(),
+            "group a includes itself: a -> b -> c -> a"
+        );
+
+        let mut written = groups(&[("a", &["gpu-01", "@a"])]);
+        assert_eq!(
+            include_groups(&mut written).unwrap_err(),
+            "group a includes itself: a -> a"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_groups() {
+        let mut written = groups(&[("a", &["@missing"])]);
+        assert_eq!(
+            include_groups(&mut written).unwrap_err(),
+            "group a includes @missing, which isn't a group"
+        );
+    }
+}
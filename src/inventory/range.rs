@@ -0,0 +1,380 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Hostname ranges and CIDR blocks.
+//!
+//! A range is written in square brackets as comma-separated numbers and
+//! `start-end` spans, so `gpu[1-3,7]` is gpu1, gpu2, gpu3 and gpu7. Numbers
+//! are padded to the width of the start of their span, so `node[001-064]`
+//! runs from node001 to node064. A name with several ranges expands to every
+//! combination of them. A CIDR block expands to the host addresses in it,
+//! leaving out the network and broadcast addresses of blocks that have them.
+//!
+//! Lists of nodes are folded back into ranges for printing, so that
+//! gpu-01 to gpu-14 and gpu-16 are shown as `gpu-[01-14,16]`.
+
+use std::net::Ipv4Addr;
+
+/// The most addresses a single entry may expand to, to catch typos like `/8`.
+const MAX_EXPANSION: usize = 65536;
+
+/// Expands an entry in a node list into the addresses it stands for.
+///
+/// # Returns
+///
+/// Returns the addresses in order, which is just the entry itself if it's a
+/// plain address, or an error if it's a malformed range or block.
+pub fn expand(entry: &str) -> Result<Vec<String>, String> {
+    if entry.contains('[') {
+        expand_ranges(entry, entry)
+    } else if let Some((address, prefix)) = entry.split_once('/') {
+        expand_cidr(entry, address, prefix)
+    } else {
+        Ok(vec![entry.to_string()])
+    }
+}
+
+/// Folds a list of names into as few ranges as expand back to them, by the
+/// last number in each name, as `gpu-[01-14,16]`.
+///
+/// # Returns
+///
+/// Returns the ranges and names, comma-separated, in the order their first
+/// names appear.
+pub fn fold(hosts: &[String]) -> String {
+    // Step 1: Gather the numbers of the names that differ only in their
+    // last number
+    let mut groups: Vec<(Around, Vec<(u64, &str)>)> = Vec::new();
+    // The names without numbers, and None where each group goes
+    let mut order = Vec::new();
+    for host in hosts {
+        let Some(end) = host.rfind(|c: char| c.is_ascii_digit()) else {
+            order.push(Some(host.as_str()));
+            continue;
+        };
+        let start = host[..=end]
+            .rfind(|c: char| !c.is_ascii_digit())
+            .map_or(0, |i| i + 1);
+        let digits = &host[start..=end];
+        let Ok(number) = digits.parse::<u64>() else {
+            order.push(Some(host.as_str()));
+            continue;
+        };
+        let key = (&host[..start], &host[end + 1..]);
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, numbers)) => numbers.push((number, digits)),
+            None => {
+                order.push(None);
+                groups.push((key, vec![(number, digits)]));
+            }
+        }
+    }
+
+    // Step 2: Join runs of numbers into spans, each padded to the width of
+    // its start as `expand` reads them
+    let mut folded = groups.into_iter().map(|((prefix, suffix), mut numbers)| {
+        numbers.sort();
+        numbers.dedup();
+        if numbers.len() == 1 {
+            return format!("{}{}{}", prefix, numbers[0].1, suffix);
+        }
+        let mut spans: Vec<(u64, &str, u64)> = Vec::new();
+        for (number, digits) in numbers {
+            match spans.last_mut() {
+                Some((_, start, end))
+                    if number == *end + 1
+                        && format!("{:0width$}", number, width = start.len()) == digits =>
+                {
+                    *end = number;
+                }
+                _ => spans.push((number, digits, number)),
+            }
+        }
+        let spans: Vec<String> = spans
+            .iter()
+            .map(|&(first, start, end)| match first == end {
+                true => start.to_string(),
+                false => format!("{}-{:0width$}", start, end, width = start.len()),
+            })
+            .collect();
+        format!("{}[{}]{}", prefix, spans.join(","), suffix)
+    });
+
+    // Step 3: Put the groups where their first names were
+    let names: Vec<String> = order
+        .into_iter()
+        .map(|host| match host {
+            Some(host) => host.to_string(),
+            None => folded.next().unwrap_or_default(),
+        })
+        .collect();
+    names.join(",")
+}
+
+/// What comes before and after the last number in a name.
+type Around<'a> = (&'a str, &'a str);
+
+/// Expands every bracketed range in the part of an entry from its first
+/// range onwards, naming the whole entry in errors.
+fn expand_ranges(entry: &str, part: &str) -> Result<Vec<String>, String> {
+    let Some(open) = part.find('[') else {
+        if part.contains(']') {
+            return Err(format!("unmatched ']' in {}", entry));
+        }
+        return Ok(vec![part.to_string()]);
+    };
+    let close = part[open..]
+        .find(']')
+        .map(|offset| open + offset)
+        .ok_or_else(|| format!("unmatched '[' in {}", entry))?;
+    let (prefix, body, rest) = (&part[..open], &part[open + 1..close], &part[close + 1..]);
+    if prefix.contains(']') {
+        return Err(format!("unmatched ']' in {}", entry));
+    }
+
+    // Step 1: Expand the names that follow this range
+    let suffixes = expand_ranges(entry, rest)?;
+
+    // Step 2: Put each number in the range in front of each of them
+    let mut hosts = Vec::new();
+    for item in body.split(',') {
+        let (start, end) = item.split_once('-').unwrap_or((item, item));
+        let invalid = || format!("invalid range [{}] in {}", body, entry);
+        let width = start.len();
+        let start: u64 = parse_number(start).ok_or_else(invalid)?;
+        let end: u64 = parse_number(end).ok_or_else(invalid)?;
+        if start > end {
+            return Err(invalid());
+        }
+        // Count the names before making them, as the product of several
+        // ranges can be far too many to hold
+        let count = usize::try_from(end - start)
+            .ok()
+            .and_then(|span| span.checked_add(1))
+            .and_then(|span| span.checked_mul(suffixes.len()))
+            .and_then(|names| names.checked_add(hosts.len()));
+        if count.is_none_or(|count| count > MAX_EXPANSION) {
+            return Err(too_many(entry));
+        }
+        for number in start..=end {
+            for suffix in &suffixes {
+                hosts.push(format!("{}{:0width$}{}", prefix, number, suffix));
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+/// Parses a range bound, which must be made of digits only.
+fn parse_number(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// Expands an IPv4 CIDR block into its host addresses.
+fn expand_cidr(entry: &str, address: &str, prefix: &str) -> Result<Vec<String>, String> {
+    let invalid = || format!("invalid CIDR block {}", entry);
+    let address: Ipv4Addr = address.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    if prefix > 32 {
+        return Err(invalid());
+    }
+    let size = 1u64 << (32 - prefix);
+    if size as usize > MAX_EXPANSION {
+        return Err(too_many(entry));
+    }
+
+    let network = u32::from(address) & !((size - 1) as u32);
+    let range = match size {
+        // /31 and /32 blocks have no network or broadcast address
+        1 | 2 => 0..size,
+        _ => 1..size - 1,
+    };
+    Ok(range
+        .map(|offset| Ipv4Addr::from(network + offset as u32).to_string())
+        .collect())
+}
+
+/// The error for an entry that stands for more than MAX_EXPANSION addresses.
+fn too_many(entry: &str) -> String {
+    format!("{} expands to more than {} nodes", entry, MAX_EXPANSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_ranges() {
+        assert_eq!(expand("gpu01").unwrap(), names(&["gpu01"]));
+        assert_eq!(
+            expand("gpu[1-3,7]").unwrap(),
+            names(&["gpu1", "gpu2", "gpu3", "gpu7"])
+        );
+        assert_eq!(
+            expand("node[098-101].dc").unwrap(),
+            names(&["node098.dc", "node099.dc", "node100.dc", "node101.dc"])
+        );
+        // Each span is padded to the width of its own start
+        assert_eq!(
+            expand("gpu[08-09,10,1]").unwrap(),
+            names(&["gpu08", "gpu09", "gpu10", "gpu1"])
+        );
+    }
+
+    #[test]
+    fn expands_every_combination_of_several_ranges() {
+        assert_eq!(
+            expand("rack[1-2]-gpu[01-02]").unwrap(),
+            names(&["rack1-gpu01", "rack1-gpu02", "rack2-gpu01", "rack2-gpu02"])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        let error = |entry: &str| expand(entry).err().unwrap();
+        assert_eq!(error("gpu[3-1]"), "invalid range [3-1] in gpu[3-1]");
+        assert_eq!(error("gpu[a-c]"), "invalid range [a-c] in gpu[a-c]");
+        assert_eq!(error("gpu[]"), "invalid range [] in gpu[]");
+        assert_eq!(error("gpu[1-]"), "invalid range [1-] in gpu[1-]");
+        assert_eq!(error("gpu[-1]"), "invalid range [-1] in gpu[-1]");
+        assert_eq!(error("gpu[+1-2]"), "invalid range [+1-2] in gpu[+1-2]");
+        assert_eq!(error("gpu[1-2"), "unmatched '[' in gpu[1-2");
+        assert_eq!(error("gpu[1-2]]"), "unmatched ']' in gpu[1-2]]");
+        assert_eq!(error("gpu[1-2]x[3"), "unmatched '[' in gpu[1-2]x[3");
+        assert_eq!(error("gpu[1-2]-[a]"), "invalid range [a] in gpu[1-2]-[a]");
+        assert_eq!(error("gpu]1[2]"), "unmatched ']' in gpu]1[2]");
+    }
+
+    #[test]
+    fn limits_the_size_of_an_expansion() {
+        assert_eq!(expand("n[0-65535]").unwrap().len(), MAX_EXPANSION);
+        assert_eq!(
+            expand("n[0-65536]").err().unwrap(),
+            "n[0-65536] expands to more than 65536 nodes"
+        );
+        // The limit is on the product of the ranges as well
+        assert_eq!(
+            expand("n[1-300]-[1-300]").err().unwrap(),
+            "n[1-300]-[1-300] expands to more than 65536 nodes"
+        );
+        assert!(expand("n[1-256]-[1-256]").is_ok());
+        // and is checked before any names are made
+        assert_eq!(
+            expand("n[0-65535]-[0-65535]").err().unwrap(),
+            "n[0-65535]-[0-65535] expands to more than 65536 nodes"
+        );
+        assert_eq!(
+            expand("n[0-18446744073709551615]").err().unwrap(),
+            "n[0-18446744073709551615] expands to more than 65536 nodes"
+        );
+        assert_eq!(
+            expand("n[1-2,1-65535]").err().unwrap(),
+            "n[1-2,1-65535] expands to more than 65536 nodes"
+        );
+    }
+
+    #[test]
+    fn expands_cidr_blocks() {
+        assert_eq!(
+            expand("10.0.0.0/30").unwrap(),
+            names(&["10.0.0.1", "10.0.0.2"])
+        );
+        // The host bits of the address are ignored
+        assert_eq!(
+            expand("10.0.0.9/29").unwrap(),
+            names(&[
+                "10.0.0.9",
+                "10.0.0.10",
+                "10.0.0.11",
+                "10.0.0.12",
+                "10.0.0.13",
+                "10.0.0.14"
+            ])
+        );
+        // /31 and /32 blocks have no network or broadcast address to drop
+        assert_eq!(
+            expand("10.0.0.4/31").unwrap(),
+            names(&["10.0.0.4", "10.0.0.5"])
+        );
+        assert_eq!(expand("10.0.0.7/32").unwrap(), names(&["10.0.0.7"]));
+        assert_eq!(expand("10.1.0.0/16").unwrap().len(), 65534);
+    }
+
+    #[test]
+    fn rejects_malformed_cidr_blocks() {
+        let error = |entry: &str| expand(entry).err().unwrap();
+        assert_eq!(error("10.0.0.0/33"), "invalid CIDR block 10.0.0.0/33");
+        assert_eq!(error("10.0.0/24"), "invalid CIDR block 10.0.0/24");
+        assert_eq!(error("10.0.0.0/x"), "invalid CIDR block 10.0.0.0/x");
+        assert_eq!(error("fd00::/120"), "invalid CIDR block fd00::/120");
+        assert_eq!(
+            error("10.0.0.0/15"),
+            "10.0.0.0/15 expands to more than 65536 nodes"
+        );
+        assert_eq!(
+            error("0.0.0.0/0"),
+            "0.0.0.0/0 expands to more than 65536 nodes"
+        );
+    }
+
+    #[test]
+    fn folds_names_into_ranges() {
+        let fold = |hosts: &[&str]| fold(&names(hosts));
+        assert_eq!(
+            fold(&["gpu-01", "gpu-02", "gpu-03", "gpu-05", "gpu-04", "gpu-07"]),
+            "gpu-[01-05,07]"
+        );
+        assert_eq!(
+            fold(&["bastion", "gpu1", "gpu2", "cpu1.dc", "gpu3", "cpu2.dc"]),
+            "bastion,gpu[1-3],cpu[1-2].dc"
+        );
+        assert_eq!(fold(&["gpu7"]), "gpu7");
+        assert_eq!(fold(&["gpu7", "gpu7"]), "gpu7");
+        assert_eq!(fold(&[]), "");
+    }
+
+    /// Splits folded names at the commas outside of brackets.
+    fn entries(folded: &str) -> Vec<String> {
+        let mut entries = vec![String::new()];
+        let mut depth = 0;
+        for c in folded.chars() {
+            match c {
+                ',' if depth == 0 => entries.push(String::new()),
+                c => {
+                    depth += usize::from(c == '[');
+                    depth -= usize::from(c == ']');
+                    entries.last_mut().unwrap().push(c);
+                }
+            }
+        }
+        entries
+    }
+
+    #[test]
+    fn folds_back_to_what_expands_to_the_names() {
+        // A span only grows while the padding stays the same width
+        for hosts in [
+            names(&["gpu08", "gpu09", "gpu10", "gpu11"]),
+            names(&["gpu9", "gpu10", "gpu11"]),
+            names(&["gpu099", "gpu100", "gpu0101"]),
+            names(&["rack1-gpu1", "rack1-gpu2", "rack2-gpu1"]),
+        ] {
+            let folded = fold(&hosts);
+            let mut expanded: Vec<String> = entries(&folded)
+                .iter()
+                .flat_map(|entry| expand(entry).unwrap())
+                .collect();
+            let mut hosts = hosts.clone();
+            expanded.sort();
+            hosts.sort();
+            assert_eq!(expanded, hosts, "{} doesn't expand back", folded);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Nodes read from the SF Compute API.
+//!
+//! A cluster with `source = "sfcompute"` takes its nodes from the nodes
+//! attached to a contract or an order, listed afresh on every run so the node
+//! list always matches the allocation. Each node's address is its public IP
+//! if it has one, and its other scalar fields, such as its status, become
+//! labels.
+
+use super::Inventory;
+use crate::config::Node;
+use crate::secrets::Secret;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration;
+
+/// The API used when the config doesn't give another.
+const API_URL: &str = "https://api.sfcompute.com";
+
+/// The environment variable holding the API token when the config has none.
+const TOKEN_VAR: &str = "SF_API_KEY";
+
+/// How long to wait for the API to answer.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The fields that may hold a node's address, most preferred first.
+const ADDRESS_FIELDS: [&str; 4] = ["public_ip", "ip", "address", "hostname"];
+
+/// The `[cluster.inventory.sfcompute]` section of the config.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The contract whose nodes make up the cluster.
+    pub contract: Option<String>,
+    /// The order whose nodes make up the cluster.
+    pub order: Option<String>,
+    /// The API to query instead of the public one.
+    pub api_url: Option<String>,
+    /// The API token, if not in the `SF_API_KEY` environment variable.
+    pub token: Option<Secret>,
+}
+
+/// Lists the nodes attached to the configured contract or order.
+///
+/// # Returns
+///
+/// Returns the nodes, or an error if the settings are incomplete or the API
+/// request fails.
+pub fn discover(settings: &Settings) -> Result<Inventory, String> {
+    // Step 1: Work out what to ask for
+    let (parameter, id) = match (&settings.contract, &settings.order) {
+        (Some(contract), None) => ("contract_id", contract),
+        (None, Some(order)) => ("order_id", order),
+        _ => {
+            return Err("the sfcompute source needs exactly one of contract and order".to_string())
+        }
+    };
+    let token = match &settings.token {
+        Some(token) => token.reveal()?,
+        None => env::var(TOKEN_VAR).map_err(|_| {
+            format!(
+                "the sfcompute source needs a token or {} to be set",
+                TOKEN_VAR
+            )
+        })?,
+    };
+    let url = format!(
+        "{}/v0/nodes",
+        settings
+            .api_url
+            .as_deref()
+            .unwrap_or(API_URL)
+            .trim_end_matches('/')
+    );
+
+    // Step 2: Ask the API
+    let response: Value = ureq::AgentBuilder::new()
+        .timeout(TIMEOUT)
+        .build()
+        .get(&url)
+        .query(parameter, id)
+        .set("Authorization", &format!("Bearer {}", token))
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::Status(status, response) => {
+                let reason = response.status_text().to_string();
+                let body = response.into_string().unwrap_or_default();
+                format!(
+                    "the SF Compute API answered {} {}: {}",
+                    status,
+                    reason,
+                    body.trim()
+                )
+            }
+            e => format!("couldn't reach the SF Compute API: {}", e),
+        })?
+        .into_json()
+        .map_err(|e| format!("the SF Compute API sent an invalid response: {}", e))?;
+
+    // Step 3: Turn each listed node into one of ours
+    let entries = match &response {
+        Value::Array(entries) => entries,
+        response => response
+            .get("data")
+            .and_then(Value::as_array)
+            .ok_or("the SF Compute API response has no list of nodes")?,
+    };
+    let nodes = entries
+        .iter()
+        .map(node_from)
+        .collect::<Result<Vec<Node>, String>>()?;
+    Ok(Inventory {
+        nodes,
+        groups: BTreeMap::new(),
+    })
+}
+
+/// Maps a node listed by the API onto a node.
+fn node_from(entry: &Value) -> Result<Node, String> {
+    let fields = entry
+        .as_object()
+        .ok_or("the SF Compute API listed a node that isn't an object")?;
+    let address = ADDRESS_FIELDS
+        .iter()
+        .find_map(|field| fields.get(*field).and_then(Value::as_str))
+        .filter(|address| !address.is_empty())
+        .ok_or_else(|| {
+            format!(
+                "the SF Compute API listed a node with no address: {}",
+                entry
+            )
+        })?;
+
+    let mut node = Node::from(address);
+    node.labels = fields
+        .iter()
+        .filter(|(name, _)| !ADDRESS_FIELDS.contains(&name.as_str()))
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => return None,
+            };
+            Some((name.clone(), value))
+        })
+        .collect();
+    Ok(node)
+}
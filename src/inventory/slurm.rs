@@ -0,0 +1,127 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Nodes read from Slurm.
+//!
+//! A cluster with `source = "slurm"` takes its nodes from `sinfo`, run
+//! locally or on a head node over SSH, so the node list follows the Slurm
+//! configuration. Each node is reached at its `NodeAddr` and shown by its
+//! node name, and every Slurm partition becomes a group of the nodes in it.
+
+use super::Inventory;
+use crate::cancel::CancelToken;
+use crate::config::{Defaults, Node};
+use crate::shell;
+use crate::ssh;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::time::Duration;
+
+/// The `[cluster.inventory.slurm]` section of the config.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Only list nodes in these comma-separated partitions.
+    pub partition: Option<String>,
+    /// The host to run `sinfo` on, if not this one.
+    pub head_node: Option<String>,
+}
+
+/// Lists the nodes Slurm knows about, by partition.
+///
+/// # Arguments
+///
+/// * `settings` - The partitions to list and where to run `sinfo`.
+/// * `defaults` - How to log in to the head node.
+/// * `timeout` - The limit on connecting to the head node.
+///
+/// # Returns
+///
+/// Returns the nodes with a group for each partition, or an error if `sinfo`
+/// can't be run or fails.
+pub fn discover(
+    settings: &Settings,
+    defaults: &Defaults,
+    timeout: Option<Duration>,
+) -> Result<Inventory, String> {
+    // Step 1: List each node in each partition, one per line
+    let mut command = "sinfo --noheader --Node --format='%R %N %o'".to_string();
+    if let Some(partition) = &settings.partition {
+        command.push_str(" --partition=");
+        command.push_str(&shell::quote(partition));
+    }
+    let listing = match &settings.head_node {
+        Some(head_node) => run_on(head_node, &command, defaults, timeout)?,
+        None => run_locally(&command)?,
+    };
+
+    // Step 2: Collect the nodes, which are listed once for each of their partitions
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [partition, name, address] = fields[..] else {
+            return Err(format!("sinfo printed an unexpected line: {}", line));
+        };
+        // Slurm reports an address of N/A for nodes it hasn't heard from
+        let address = if address == "N/A" { name } else { address };
+        if !nodes.iter().any(|node| node.host == address) {
+            let mut node = Node::from(address);
+            if address != name {
+                node.alias = Some(name.to_string());
+            }
+            nodes.push(node);
+        }
+        groups
+            .entry(partition.to_string())
+            .or_default()
+            .push(address.to_string());
+    }
+    Ok(Inventory { nodes, groups })
+}
+
+/// Runs a command with the local shell and returns its output.
+fn run_locally(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .map_err(|e| format!("couldn't run sinfo: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "sinfo failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs a command on the head node and returns its output.
+fn run_on(
+    head_node: &str,
+    command: &str,
+    defaults: &Defaults,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let mut node = Node::from(head_node);
+    defaults.apply(&mut node);
+    let unreachable = |e| format!("couldn't run sinfo on {}: {}", head_node, e);
+    let sess = ssh::connect(&node, timeout).map_err(unreachable)?;
+    let result = ssh::exec(
+        &sess,
+        command,
+        None,
+        None,
+        &CancelToken::default(),
+        &mut |_, _| {},
+    )
+    .map_err(unreachable)?;
+    if result.exit_code != 0 {
+        return Err(format!(
+            "sinfo failed on {}: {}",
+            head_node,
+            result.stderr.trim()
+        ));
+    }
+    Ok(result.stdout)
+}
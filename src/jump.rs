@@ -0,0 +1,549 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Reaching nodes through jump hosts, like OpenSSH's `ProxyJump`.
+//!
+//! A node's jump hosts are written as `[user@]host[:port]`, separated by
+//! commas and connected to in order. Each hop logs in with the settings the
+//! user's OpenSSH config gives it, or as the default user with the default
+//! keys, and the first hop may itself be reached through the jump hosts its
+//! OpenSSH config names. The first hop is reached through the node's proxy, if
+//! it has one. Any questions a hop asks while logging in, such as for a
+//! one-time code, are asked on the terminal.
+//!
+//! The nodes behind the same chain of jump hosts share one login to them,
+//! kept in `Logins`, so a bastion sees one connection rather than one for
+//! every node, and asks for a one-time code once. Each node's connection is
+//! a direct-tcpip channel of the session with the last hop.
+//!
+//! libssh2 can only run a session over a socket, so the channels are relayed
+//! to socket pairs: the channel to each intermediate hop by a thread of its
+//! own, and the nodes' channels all by the one thread, a hub, that keeps the
+//! last hop's session to itself.
+
+use crate::config::{AuthMethod, Node};
+use crate::ssh;
+use crate::ssh_config;
+use ssh2::{Channel, ErrorCode, Session};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The most jump hosts on the way to a node, which stops hosts whose OpenSSH
+/// configs jump through each other from being followed forever.
+const MAX_HOPS: usize = 8;
+
+/// How long a relay first sleeps when neither side has data.
+const MIN_IDLE: Duration = Duration::from_millis(1);
+
+/// The longest a relay sleeps when neither side has data, which it backs
+/// off to while the connection stays idle.
+const MAX_IDLE: Duration = Duration::from_millis(20);
+
+/// How much a relay reads from one side at a time.
+const RELAY_BUFFER: usize = 32 * 1024;
+
+/// The libssh2 transport's hubs, one for each chain of jump hosts.
+static HUBS: Logins<Hub> = Logins::new();
+
+/// The logins to chains of jump hosts that the nodes behind them share, by
+/// the jump hosts, as written, and the proxy the first is reached through.
+///
+/// A chain is logged into by the first node to need it while the others
+/// wait, and if that fails the nodes that were waiting are given the same
+/// error rather than each trying again, which would ask for another code.
+pub struct Logins<T> {
+    chains: Mutex<BTreeMap<Chain, Arc<Login<T>>>>,
+}
+
+/// A node's jump hosts, as written, and its proxy.
+type Chain = (String, Option<String>);
+
+/// A chain's login, or why logging in last failed and when, held locked
+/// while it's logged into.
+type Login<T> = tokio::sync::Mutex<Option<Result<T, (String, Instant)>>>;
+
+impl<T: Clone> Logins<T> {
+    /// Returns an empty set of logins, for a static.
+    pub const fn new() -> Self {
+        Logins {
+            chains: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the login to a node's jump hosts, logging in with `log_in`
+    /// if no other node behind them has yet, or the login is no longer
+    /// `usable`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the login, or the error logging in failed with, now or while
+    /// waiting for another node to log in.
+    pub async fn get<F>(
+        &self,
+        jump: &str,
+        node: &Node,
+        usable: impl Fn(&T) -> bool,
+        log_in: impl FnOnce() -> F,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>>
+    where
+        F: Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+    {
+        let login = self.login(jump, node);
+        let waited = Instant::now();
+        let mut login = login.lock().await;
+        if let Some(result) = reuse(&login, waited, usable) {
+            return result;
+        }
+        let result = log_in().await;
+        *login = Some(remember(&result));
+        result
+    }
+
+    /// Returns the login to a node's jump hosts as `get` does, blocking the
+    /// thread while another node logs in.
+    pub fn get_blocking(
+        &self,
+        jump: &str,
+        node: &Node,
+        usable: impl Fn(&T) -> bool,
+        log_in: impl FnOnce() -> Result<T, Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+        let login = self.login(jump, node);
+        let waited = Instant::now();
+        let mut login = login.blocking_lock();
+        if let Some(result) = reuse(&login, waited, usable) {
+            return result;
+        }
+        let result = log_in();
+        *login = Some(remember(&result));
+        result
+    }
+
+    /// Returns the slot for the login to a node's jump hosts.
+    fn login(&self, jump: &str, node: &Node) -> Arc<Login<T>> {
+        let key = (jump.to_string(), node.proxy.clone());
+        self.chains.lock().unwrap().entry(key).or_default().clone()
+    }
+}
+
+/// Returns the earlier login if it can be used, or its error if it failed
+/// since the node started waiting, or None to log in again.
+fn reuse<T: Clone>(
+    login: &Option<Result<T, (String, Instant)>>,
+    waited: Instant,
+    usable: impl Fn(&T) -> bool,
+) -> Option<Result<T, Box<dyn std::error::Error + Send + Sync>>> {
+    match login {
+        Some(Ok(login)) if usable(login) => Some(Ok(login.clone())),
+        Some(Err((e, failed))) if *failed > waited => Some(Err(e.clone().into())),
+        _ => None,
+    }
+}
+
+/// Returns what to keep of a login for the nodes that come after.
+fn remember<T: Clone>(
+    result: &Result<T, Box<dyn std::error::Error + Send + Sync>>,
+) -> Result<T, (String, Instant)> {
+    match result {
+        Ok(login) => Ok(login.clone()),
+        Err(e) => Err((e.to_string(), Instant::now())),
+    }
+}
+
+/// Opens a connection to a node's sshd through its jump hosts.
+///
+/// # Arguments
+///
+/// * `jump` - The node's jump hosts.
+/// * `node` - The node to connect to.
+/// * `timeout` - The limit on connecting to, handshaking with and
+///   authenticating to each hop, and on opening the channel to the node.
+///
+/// # Returns
+///
+/// Returns a socket connected to the node's sshd, or an error if a hop
+/// can't be reached or won't open a channel to the next.
+pub fn tunnel(
+    jump: &str,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<UnixStream, Box<dyn std::error::Error + Send + Sync>> {
+    // Step 1: Log into the jump hosts, unless another node behind them has
+    let hops = hops(jump)?;
+    let hub = HUBS.get_blocking(
+        jump,
+        node,
+        |hub| hub.alive.load(Ordering::Relaxed),
+        || log_into_hops(hops.clone(), node, timeout),
+    )?;
+
+    // Step 2: Have the hub open a channel to the node
+    let (reply, replied) = mpsc::channel();
+    let open = Open {
+        host: node.address().to_string(),
+        port: node.port(),
+        reply,
+    };
+    let closed = || format!("the session with jump host {} closed", hops.last().unwrap());
+    hub.requests.send(open).map_err(|_| closed())?;
+    let result = match timeout {
+        Some(timeout) => replied.recv_timeout(timeout).map_err(|e| match e {
+            mpsc::RecvTimeoutError::Timeout => Box::new(io::Error::from(io::ErrorKind::TimedOut))
+                as Box<dyn std::error::Error + Send + Sync>,
+            mpsc::RecvTimeoutError::Disconnected => closed().into(),
+        })?,
+        None => replied.recv().map_err(|_| closed())?,
+    };
+    result.map_err(|e| {
+        format!(
+            "jump host {} can't connect to {} port {}: {}",
+            hops.last().unwrap(),
+            node.address(),
+            node.port(),
+            e
+        )
+        .into()
+    })
+}
+
+/// Logs into a chain of jump hosts, the first through the node's proxy,
+/// and starts a hub for the last hop's session.
+fn log_into_hops(
+    mut hops: Vec<Node>,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<Hub, Box<dyn std::error::Error + Send + Sync>> {
+    hops[0].proxy.clone_from(&node.proxy);
+    let mut sess = ssh::connect(&hops[0], timeout)
+        .map_err(|e| format!("unable to reach jump host {}: {}", hops[0], e))?;
+    for (hop, next) in hops.iter().zip(&hops[1..]) {
+        let stream = forward(sess, hop, next)?;
+        sess = ssh::connect_over(stream, next, timeout)
+            .map_err(|e| format!("unable to reach jump host {}: {}", next, e))?;
+    }
+    let (requests, received) = mpsc::channel();
+    let alive = Arc::new(AtomicBool::new(true));
+    let hub = Hub {
+        requests,
+        alive: alive.clone(),
+    };
+    thread::spawn(move || multiplex(sess, received, &alive));
+    Ok(hub)
+}
+
+/// Lists the hosts to connect through, in order, each without jump hosts of
+/// its own.
+///
+/// # Returns
+///
+/// Returns at least one host, or an error if a host is malformed, the
+/// OpenSSH config can't be read, or there are too many hosts.
+pub fn hops(jump: &str) -> Result<Vec<Node>, String> {
+    let mut hops: Vec<Node> = Vec::new();
+    let mut jump = jump.to_string();
+    loop {
+        let mut before: Vec<Node> = jump.split(',').map(hop).collect::<Result<_, _>>()?;
+        let first_jump = before[0].jump.take();
+        for hop in &mut before {
+            hop.jump = None;
+        }
+        before.append(&mut hops);
+        hops = before;
+        if hops.len() > MAX_HOPS {
+            return Err(format!(
+                "there are more than {} jump hosts on the way to the node; check the ProxyJump settings in the OpenSSH config for a loop",
+                MAX_HOPS
+            ));
+        }
+        match first_jump {
+            Some(first_jump) => jump = first_jump,
+            None => return Ok(hops),
+        }
+    }
+}
+
+/// Parses one jump host, filling in its settings from the OpenSSH config.
+fn hop(spec: &str) -> Result<Node, String> {
+    let spec = spec.trim();
+    let (user, address) = match spec.rsplit_once('@') {
+        Some((user, address)) => (Some(user), address),
+        None => (None, spec),
+    };
+    let (host, port) = match address.strip_prefix('[') {
+        // A bracketed IPv6 address
+        Some(rest) => match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            None => return Err(format!("jump host {} has an unclosed [", spec)),
+        },
+        None => match address.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (address, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("jump host {} has no address", spec));
+    }
+
+    let mut node = Node::from(host);
+    node.user = user.map(str::to_string);
+    node.port = match port {
+        Some(port) => Some(
+            port.parse()
+                .map_err(|_| format!("jump host {} has an invalid port", spec))?,
+        ),
+        None => None,
+    };
+    ssh_config::lookup(host)?.apply(&mut node);
+    // Bastions often ask for a one-time code, which is asked for on the
+    // terminal as the hop is only logged into once for the nodes behind it
+    let mut auth = node.auth();
+    auth.push(AuthMethod::KeyboardInteractive);
+    node.auth = Some(auth);
+    Ok(node)
+}
+
+/// Opens a channel from a hop to the next host and relays it to a socket.
+///
+/// # Returns
+///
+/// Returns the socket, or an error if the hop won't open the channel.
+fn forward(
+    sess: Session,
+    hop: &Node,
+    next: &Node,
+) -> Result<UnixStream, Box<dyn std::error::Error + Send + Sync>> {
+    let channel = sess
+        .channel_direct_tcpip(next.address(), next.port(), None)
+        .map_err(|e| {
+            format!(
+                "jump host {} can't connect to {} port {}: {}",
+                hop,
+                next.address(),
+                next.port(),
+                e
+            )
+        })?;
+    let (local, remote) = UnixStream::pair()?;
+    thread::spawn(move || relay(sess, channel, remote));
+    Ok(local)
+}
+
+/// Copies data both ways between a channel and a socket until either closes.
+///
+/// The session is polled rather than blocked on, since a blocking read of
+/// the channel would hold up writes to it. Data read from one side is kept
+/// until the other side takes all of it, so a full channel window doesn't
+/// stop data flowing the other way, which may be what reopens the window.
+fn relay(sess: Session, mut channel: Channel, mut socket: UnixStream) {
+    sess.set_blocking(false);
+    if socket.set_nonblocking(true).is_err() {
+        return;
+    }
+    let mut outbound = Pending::default();
+    let mut inbound = Pending::default();
+    let mut idle = MIN_IDLE;
+    loop {
+        // Toward the next host, then back from it
+        let sent = outbound.pump(&mut socket, &mut channel);
+        let received = inbound.pump(&mut channel, &mut socket);
+        let (Ok(sent), Ok(received)) = (sent, received) else {
+            break;
+        };
+        if inbound.closed && channel.eof() {
+            break;
+        }
+        if ssh::send_keepalive(&sess).is_err() {
+            break;
+        }
+
+        if sent || received {
+            idle = MIN_IDLE;
+        } else {
+            thread::sleep(idle);
+            idle = (idle * 2).min(MAX_IDLE);
+        }
+    }
+    let _ = channel.close();
+}
+
+/// The thread relaying the channels of the nodes behind a jump host, which
+/// runs for as long as the session with the jump host stays open.
+#[derive(Clone)]
+struct Hub {
+    requests: mpsc::Sender<Open>,
+    alive: Arc<AtomicBool>,
+}
+
+/// A request for a hub to open a channel to a node.
+struct Open {
+    host: String,
+    port: u16,
+    reply: mpsc::Sender<Result<UnixStream, ssh2::Error>>,
+}
+
+/// A node's channel through a hub, with the data each way that the other
+/// side hasn't taken yet.
+struct Relayed {
+    channel: Channel,
+    socket: UnixStream,
+    outbound: Pending,
+    inbound: Pending,
+}
+
+/// Opens the channels the nodes ask a hub for, one at a time as libssh2
+/// can only open one at a time, and relays all of them to their sockets as
+/// `relay` does a single channel, until the session closes.
+fn multiplex(sess: Session, requests: mpsc::Receiver<Open>, alive: &AtomicBool) {
+    sess.set_blocking(false);
+    let mut queued = VecDeque::new();
+    let mut relayed: Vec<Relayed> = Vec::new();
+    let mut idle = MIN_IDLE;
+    loop {
+        // Step 1: Open the first queued channel, which takes a few calls
+        queued.extend(requests.try_iter());
+        let mut moved = false;
+        if let Some(open) = queued.front() {
+            let opened = sess.channel_direct_tcpip(&open.host, open.port, None);
+            if !is_again(&opened) {
+                let open = queued.pop_front().unwrap();
+                moved = true;
+                match opened {
+                    Ok(channel) => {
+                        // A node that gave up waiting has dropped its reply
+                        if let Ok((local, socket)) = relay_pair() {
+                            if open.reply.send(Ok(local)).is_ok() {
+                                relayed.push(Relayed {
+                                    channel,
+                                    socket,
+                                    outbound: Pending::default(),
+                                    inbound: Pending::default(),
+                                });
+                            }
+                        }
+                    }
+                    // Only a refused channel leaves the session usable
+                    Err(e)
+                        if e.code()
+                            == ErrorCode::Session(libssh2_sys::LIBSSH2_ERROR_CHANNEL_FAILURE) =>
+                    {
+                        let _ = open.reply.send(Err(e));
+                    }
+                    Err(e) => {
+                        let _ = open.reply.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Step 2: Move data each way for every channel, dropping the closed
+        relayed.retain_mut(|relay| {
+            let sent = relay.outbound.pump(&mut relay.socket, &mut relay.channel);
+            let received = relay.inbound.pump(&mut relay.channel, &mut relay.socket);
+            match (sent, received) {
+                (Ok(sent), Ok(received)) if !(relay.inbound.closed && relay.channel.eof()) => {
+                    moved |= sent || received;
+                    true
+                }
+                _ => {
+                    let _ = relay.channel.close();
+                    false
+                }
+            }
+        });
+        if ssh::send_keepalive(&sess).is_err() {
+            break;
+        }
+
+        if moved {
+            idle = MIN_IDLE;
+        } else {
+            thread::sleep(idle);
+            idle = (idle * 2).min(MAX_IDLE);
+        }
+    }
+    // Nodes that ask from now on log in again, and those queued are told
+    // the session closed as their replies are dropped
+    alive.store(false, Ordering::Relaxed);
+    for relay in &mut relayed {
+        let _ = relay.channel.close();
+    }
+}
+
+/// Returns true if libssh2 needs to be called again to finish.
+fn is_again<T>(result: &Result<T, ssh2::Error>) -> bool {
+    matches!(result, Err(e) if e.code() == ErrorCode::Session(libssh2_sys::LIBSSH2_ERROR_EAGAIN))
+}
+
+/// Returns a socket pair for relaying a channel, with the side the relay
+/// uses not blocking.
+fn relay_pair() -> io::Result<(UnixStream, UnixStream)> {
+    let (local, remote) = UnixStream::pair()?;
+    remote.set_nonblocking(true)?;
+    Ok((local, remote))
+}
+
+/// Data read from one side of a relay that the other side hasn't taken yet.
+#[derive(Default)]
+struct Pending {
+    buf: Vec<u8>,
+    /// How much of the buffer has been written.
+    written: usize,
+    /// Whether the side being read from has closed.
+    closed: bool,
+}
+
+impl Pending {
+    /// Writes as much of the pending data as the writer takes, reading more
+    /// once it's all written.
+    ///
+    /// # Returns
+    ///
+    /// Returns true if any data moved, or an error if either side failed or
+    /// the reader closed with nothing left to write.
+    fn pump(&mut self, reader: &mut impl Read, writer: &mut impl Write) -> io::Result<bool> {
+        let mut moved = false;
+        if self.written == self.buf.len() {
+            if self.closed {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.resize(RELAY_BUFFER, 0);
+            self.written = 0;
+            match reader.read(&mut self.buf) {
+                Ok(0) => {
+                    self.buf.clear();
+                    self.closed = true;
+                    return Ok(false);
+                }
+                Ok(n) => {
+                    self.buf.truncate(n);
+                    moved = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.buf.clear();
+                    return Ok(false);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        while self.written < self.buf.len() {
+            match writer.write(&self.buf[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    moved = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(moved)
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Per-node log files, for `--log-dir`.
+//!
+//! Each run writes its logs to a subdirectory of the log directory named
+//! after the time it started, such as `run-logs/2024-05-01T12-34-56Z`, so
+//! runs never overwrite each other. Every node's stdout and stderr are
+//! written to `<node>.stdout` and `<node>.stderr` as they arrive, and when
+//! the run finishes `summary.json` records how each node did, so the logs
+//! of a run that's killed part way through are kept up to its end. With
+//! `--timestamps`, each line in the files starts with the time, to the
+//! microsecond, that it started arriving, so what happened across the nodes
+//! can be put back in order afterwards.
+//!
+//! `--raw` writes the same files, straight into the directory it's given
+//! and without a summary, so binary output can be picked up byte for byte.
+//! Nothing in them is masked, as masking would change the bytes.
+
+use crate::output::{self, Clock};
+use crate::ssh::OutputStream;
+use serde_json::Value;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The directory a run's logs are written to.
+pub struct RunLog {
+    dir: PathBuf,
+}
+
+impl RunLog {
+    /// Creates the directory for a run's logs in `base`, creating `base` too
+    /// if it doesn't exist.
+    ///
+    /// # Returns
+    ///
+    /// Returns the run's log, or an error if the directory can't be made.
+    pub fn create(base: &Path) -> io::Result<Self> {
+        fs::create_dir_all(base)?;
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let utc = output::Utc::from_secs(secs);
+        let name = format!(
+            "{:04}-{:02}-{:02}T{:02}-{:02}-{:02}Z",
+            utc.year, utc.month, utc.day, utc.hour, utc.minute, utc.second
+        );
+        // Runs started in the same second are told apart by a number
+        let mut dir = base.join(&name);
+        let mut number = 1;
+        loop {
+            match fs::create_dir(&dir) {
+                Ok(()) => return Ok(RunLog { dir }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    number += 1;
+                    dir = base.join(format!("{}-{}", name, number));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes a run's logs straight into `dir`, creating it if it doesn't
+    /// exist, for `--raw`.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(RunLog {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Returns the directory the run's logs are in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Starts a node's log files afresh, replacing those of any earlier
+    /// attempt, with each line timestamped by `clock` if it's given.
+    pub fn node(&self, node: &str, clock: Option<Clock>) -> NodeLog {
+        // Names can't hold a path separator, which IPv6 zones and aliases might
+        let name = node.replace('/', "_");
+        NodeLog {
+            stdout: Output::create(self.dir.join(format!("{}.stdout", name)), clock),
+            stderr: Output::create(self.dir.join(format!("{}.stderr", name)), clock),
+        }
+    }
+
+    /// Writes `summary.json`, reporting an error rather than failing the run.
+    pub fn write_summary(&self, summary: &Value) {
+        let path = self.dir.join("summary.json");
+        let json = serde_json::to_string_pretty(summary).unwrap_or_default() + "\n";
+        if let Err(e) = fs::write(&path, json) {
+            tracing::warn!("unable to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A node's stdout and stderr log files.
+pub struct NodeLog {
+    stdout: Output,
+    stderr: Output,
+}
+
+impl NodeLog {
+    /// Appends a chunk of the node's output to its file.
+    pub fn write(&mut self, stream: OutputStream, chunk: &[u8]) {
+        match stream {
+            OutputStream::Stdout => self.stdout.write(chunk),
+            OutputStream::Stderr => self.stderr.write(chunk),
+        }
+    }
+}
+
+/// One log file, which is given up on after the first error writing it,
+/// with a warning.
+struct Output {
+    path: PathBuf,
+    file: Option<File>,
+    clock: Option<Clock>,
+    /// Whether the next byte written starts a line.
+    line_start: bool,
+}
+
+impl Output {
+    /// Creates the file, or warns that it can't be.
+    fn create(path: PathBuf, clock: Option<Clock>) -> Self {
+        let file = match File::create(&path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                tracing::warn!("unable to create {}: {}", path.display(), e);
+                None
+            }
+        };
+        Output {
+            path,
+            file,
+            clock,
+            line_start: true,
+        }
+    }
+
+    fn write(&mut self, chunk: &[u8]) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let written = match self.clock {
+            Some(clock) => {
+                // Every line that starts in the chunk gets the time it arrived
+                let stamp = clock.stamp(SystemTime::now());
+                let mut stamped = Vec::with_capacity(chunk.len() + stamp.len() + 1);
+                for line in chunk.split_inclusive(|&b| b == b'\n') {
+                    if self.line_start {
+                        stamped.extend_from_slice(stamp.as_bytes());
+                        stamped.push(b' ');
+                    }
+                    stamped.extend_from_slice(line);
+                    self.line_start = line.ends_with(b"\n");
+                }
+                file.write_all(&stamped)
+            }
+            None => file.write_all(chunk),
+        };
+        if let Err(e) = written {
+            tracing::warn!("unable to write {}: {}", self.path.display(), e);
+            self.file = None;
+        }
+    }
+}
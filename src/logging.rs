@@ -0,0 +1,106 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Progress and diagnostic messages, at the level chosen with `-q` and `-v`.
+//!
+//! Everything cluster_run says about a run, as opposed to the results it
+//! reports, is logged with `tracing` and printed to standard error, with
+//! anything secret masked, so the results on standard output can be piped on
+//! their own. By default progress,
+//! warnings and failures are printed as plain lines. `--quiet` (`-q`) leaves
+//! only warnings and failures, `-v` adds how each node was connected to and
+//! logged into and how long each step took, each line with the time since
+//! the run started and the node it's about, and `-vv` adds everything down to
+//! the SSH libraries' own messages.
+
+use crate::output::{self, Color};
+use crate::redact;
+use crate::ssh::OutputStream;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::{FmtSpan, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Whether `--quiet` was given.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Starts printing messages at the level given on the command line.
+///
+/// # Arguments
+///
+/// * `quiet` - Whether `--quiet` was given.
+/// * `verbose` - How many times `--verbose` was given.
+pub fn init(quiet: bool, verbose: u8) {
+    QUIET.store(quiet, Ordering::Relaxed);
+    let (ours, theirs) = match (quiet, verbose) {
+        (true, _) => (LevelFilter::WARN, LevelFilter::OFF),
+        (false, 0) => (LevelFilter::INFO, LevelFilter::OFF),
+        (false, 1) => (LevelFilter::DEBUG, LevelFilter::OFF),
+        (false, _) => (LevelFilter::TRACE, LevelFilter::DEBUG),
+    };
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), ours)
+        .with_default(theirs);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(redact::Stderr::default)
+        .with_ansi(output::colored(OutputStream::Stderr));
+    let registry = tracing_subscriber::registry().with(filter);
+    if verbose > 0 && !quiet {
+        // Closing a span logs how long it was open for
+        registry
+            .with(
+                layer
+                    .with_target(false)
+                    .with_timer(tracing_subscriber::fmt::time::uptime())
+                    .with_span_events(FmtSpan::CLOSE),
+            )
+            .init();
+    } else {
+        registry.with(layer.event_format(Plain)).init();
+    }
+}
+
+/// Returns whether `--quiet` was given, so only failures are reported.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints each message alone on its line, with warnings marked as such and
+/// failures in red.
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let color = match *event.metadata().level() {
+            Level::ERROR => Some(Color::Red),
+            Level::WARN => Some(Color::Yellow),
+            _ => None,
+        }
+        .filter(|_| writer.has_ansi_escapes());
+        if let Some(color) = color {
+            write!(writer, "{}", output::start(color))?;
+        }
+        if *event.metadata().level() == Level::WARN {
+            write!(writer, "Warning: ")?;
+        }
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        if color.is_some() {
+            write!(writer, "{}", output::RESET)?;
+        }
+        writeln!(writer)
+    }
+}
@@ -4,135 +4,273 @@
 //! cluster_run: A Rust application for executing commands on multiple nodes in a cluster.
 //!
 //! This application reads a list of nodes from a TOML configuration file,
-//! takes a command as command-line arguments, and then executes that command on each node
-//! in the cluster using SSH. It uses public key authentication and assumes
-//! the 'ubuntu' user for connections.
+//! takes a subcommand and its arguments from the command line, and then performs
+//! it on each node in the cluster using SSH. It uses public key authentication
+//! and assumes the 'ubuntu' user for connections.
 
-use serde::Deserialize;
-use ssh2::Session;
-use std::env;
-use std::fs;
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::path::PathBuf;
-
-/// Represents the entire configuration structure.
-#[derive(Deserialize)]
-struct Config {
-    cluster: ClusterConfig,
-}
+mod agent;
+mod archive;
+mod bandwidth;
+mod cancel;
+mod certificate;
+mod cli;
+mod commands;
+mod config;
+mod dial;
+mod diff;
+mod events;
+mod executor;
+mod filter;
+mod history;
+mod host_key;
+mod inventory;
+mod jump;
+mod logdir;
+mod logging;
+mod notify;
+mod output;
+mod plan;
+mod policy;
+mod pool;
+mod progress;
+mod prompt;
+mod proxy;
+mod quarantine;
+mod redact;
+mod reload;
+mod report;
+mod resolve;
+mod retry;
+mod secrets;
+mod select;
+mod shell;
+mod spill;
+mod ssh;
+mod ssh_config;
+mod state;
+mod summary;
+mod sync;
+mod syslog;
+mod template;
+mod throttle;
+mod transfer;
+mod transport;
+mod verify;
 
-/// Represents the cluster configuration, containing a list of node addresses.
-#[derive(Deserialize)]
-struct ClusterConfig {
-    nodes: Vec<String>,
-}
+use cancel::CancelToken;
+use clap::Parser;
+use cli::{Cli, Command, ConfigArgs, ConfigCommand};
+use config::{AuthMethod, Config};
+use std::env;
+use std::process::ExitCode;
 
 /// The main function that drives the cluster_run application.
 ///
 /// This function performs the following steps:
-/// 1. Reads and parses the configuration file.
-/// 2. Collects the command from command-line arguments.
-/// 3. Iterates through each node in the cluster, executing the command.
-/// 4. Prints the output or any errors encountered during execution.
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Welcome to cluster_run!");
-
-    // Collect command-line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <command> [args...]", args[0]);
-        std::process::exit(1);
+/// 1. Parses the command-line arguments.
+/// 2. Finds, reads and parses the configuration file, and narrows the nodes down to
+///    those selected with `--group`, `--selector`, `--limit` and `--exclude`,
+///    less any that are quarantined.
+/// 3. Installs a SIGINT/SIGTERM handler that cancels in-flight work.
+/// 4. Runs the requested subcommand and exits with its exit code.
+///
+/// With several clusters, steps 2 and 4 are repeated for each cluster in
+/// turn, the nodes are shown after their cluster's name, and the exit code
+/// is that of the first cluster to fail.
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    output::use_color(cli.connection.color);
+    logging::init(cli.connection.quiet, cli.connection.verbose);
+    spill::use_max_output(cli.connection.max_output);
+    for pattern in &cli.connection.redact {
+        redact::use_pattern(pattern).map_err(|e| format!("--redact: {}", e))?;
+    }
+    for var in &cli.connection.redact_env {
+        redact::use_env(var);
+    }
+    let reads_stdin = cli
+        .source
+        .node_source()
+        .is_some_and(|nodes| nodes.reads_stdin());
+    if reads_stdin && cli.command.forwards_stdin() {
+        return Err("The host list and --stdin can't both be read from standard input".into());
+    }
+    // Checking and upgrading the config have to cope with configs that don't load
+    if let Command::Config(ConfigArgs { command }) = &cli.command {
+        match command {
+            ConfigCommand::Validate => {
+                let cancel = CancelToken::default();
+                cancel.cancel_on_signal()?;
+                return commands::config::validate(&cli.source, &cli.connection, &cancel);
+            }
+            ConfigCommand::Migrate => return commands::config::migrate(&cli.source),
+            ConfigCommand::Export { .. } => {}
+        }
+    }
+    if let Command::Quarantine(args) = &cli.command {
+        return commands::quarantine::run(args);
+    }
+    if let Command::Secret(args) = &cli.command {
+        return commands::secret::run(args);
+    }
+    if let Command::History(args) = &cli.command {
+        return commands::history::run(args);
+    }
+    if let Some(var) = &cli.connection.passphrase_env {
+        let passphrase =
+            env::var(var).map_err(|_| format!("environment variable {} is not set", var))?;
+        redact::use_secret(&passphrase);
+        ssh::use_passphrase(passphrase);
+    }
+    host_key::use_checking(cli.connection.strict_host_key_checking);
+    transport::use_transport(cli.connection.transport);
+    if let Some(interval) = cli.connection.keepalive {
+        ssh::use_keepalive(interval);
+    }
+    if let Some(per_second) = cli.connection.connect_rate {
+        throttle::use_rate(per_second);
+    }
+    if let Some(jitter) = cli.connection.connect_jitter {
+        throttle::use_jitter(jitter);
+    }
+    if let (Some(total), Some(per_node)) = (cli.connection.bwlimit, cli.connection.bwlimit_per_node)
+    {
+        if per_node > total {
+            return Err("--bwlimit-per-node can't be more than --bwlimit".into());
+        }
+    }
+    if let Some(rate) = cli.connection.bwlimit {
+        bandwidth::use_limit(rate);
+    }
+    if let Some(rate) = cli.connection.bwlimit_per_node {
+        bandwidth::use_per_node_limit(rate);
+    }
+    if cli.connection.forward_agent {
+        agent::use_forwarding()?;
+    }
+    let clusters = cli.source.clusters()?;
+    let several = clusters.len() > 1;
+    if several && matches!(cli.command, Command::Watch(_)) {
+        return Err("watch works on one cluster at a time".into());
     }
 
-    // Construct the command from arguments
-    let command = args[1..].join(" ");
-
-    // Read and parse the config file
-    let config_content = fs::read_to_string("config.toml")?;
-    let config: Config = toml::from_str(&config_content)?;
+    let cancel = CancelToken::default();
+    let mut handling_signals = false;
+    let mut code = ExitCode::SUCCESS;
+    let mut failed = Vec::new();
+    for cluster in &clusters {
+        let load = || -> Result<Config, Box<dyn std::error::Error>> {
+            let mut config = Config::load(&cli.source.source(cluster.as_deref()))?;
+            if several {
+                for node in &mut config.cluster.nodes {
+                    node.cluster = cluster.clone();
+                }
+            }
+            config.override_logins(
+                cli.connection.identity.as_deref(),
+                cli.connection.jump.as_deref(),
+            );
+            config.cluster.nodes = cli.selection.selection().apply(&config.cluster)?;
+            if !cli.selection.include_quarantined {
+                config.cluster.nodes = quarantine::skip(config.cluster.nodes)?;
+                config.files.extend(quarantine::path().ok());
+            }
+            Ok(config)
+        };
+        let result = load().and_then(|config| {
+            if !handling_signals {
+                cancel.cancel_on_signal()?;
+                handling_signals = true;
+            }
+            run(&cli, &config, &cancel, &load)
+        });
+        if !several {
+            return result;
+        }
 
-    // Execute the command on each node in the cluster
-    for node in &config.cluster.nodes {
-        println!("Connecting to node {}...", node);
-        match run_command(node, &command) {
-            Ok(output) => println!(
-                "Output from {} for command '{}': \n{}",
-                node, command, output
-            ),
-            Err(e) => eprintln!("Error for node {}: {}", node, e),
+        // Carry on with the other clusters if one fails
+        let name = cluster.as_deref().unwrap_or_default();
+        let result = result.unwrap_or_else(|e| {
+            tracing::error!("Error: {}: {}", name, e);
+            ExitCode::FAILURE
+        });
+        if result != ExitCode::SUCCESS {
+            if failed.is_empty() {
+                code = result;
+            }
+            failed.push(name);
+        }
+        if cancel.is_cancelled() {
+            break;
         }
-        println!();
     }
-
-    Ok(())
+    if failed.is_empty() {
+        tracing::info!("All {} clusters succeeded", clusters.len());
+    } else {
+        tracing::error!(
+            "{} of {} clusters failed: {}",
+            failed.len(),
+            clusters.len(),
+            failed.join(", ")
+        );
+    }
+    Ok(code)
 }
 
-/// Executes a command on a specified node using SSH.
-///
-/// This function performs the following steps:
-/// 1. Establishes an SSH connection to the node.
-/// 2. Authenticates using SSH key-based authentication.
-/// 3. Creates an SSH channel and executes the specified command.
-/// 4. Captures and returns the output of the command.
+/// Runs the requested subcommand on the selected nodes of a cluster.
 ///
 /// # Arguments
 ///
-/// * `node` - The address of the node to connect to.
-/// * `command` - The command to execute on the node.
-///
-/// # Returns
-///
-/// Returns a Result containing either the command output as a String,
-/// or an error if any step in the process fails.
-fn run_command(node: &str, command: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Connect to the node
-    let tcp = TcpStream::connect(format!("{}:22", node))?;
-    let mut sess = Session::new()?;
-    sess.set_tcp_stream(tcp);
-    sess.handshake()?;
-
-    // Get the path to the SSH key files
-    let (pubkey, privkey) = get_ssh_key_paths()?;
-
-    // Authenticate using the SSH key
-    sess.userauth_pubkey_file("ubuntu", Some(&pubkey), &privkey, None)?;
-
-    // Create a channel and execute the command
-    let mut channel = sess.channel_session()?;
-    channel.exec(command)?;
-
-    // Read the output
-    let mut output = String::new();
-    channel.read_to_string(&mut output)?;
-
-    // Wait for the command to finish
-    channel.wait_close()?;
-
-    // Return the output
-    Ok(output)
-}
-
-/// Retrieves the paths to the SSH public and private key files.
-///
-/// This function assumes the SSH keys are located in the default ~/.ssh directory
-/// and are named id_rsa.pub and id_rsa for the public and private keys respectively.
-///
-/// # Returns
-///
-/// Returns a Result containing a tuple of PathBuf for the public and private key files,
-/// or an error if the keys are not found in the expected location.
-fn get_ssh_key_paths() -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
-    let home = env::var("HOME").map_err(|_| "Unable to determine home directory")?;
-    let ssh_dir = PathBuf::from(home).join(".ssh");
-
-    let pubkey = ssh_dir.join("id_rsa.pub");
-    let privkey = ssh_dir.join("id_rsa");
-
-    if !pubkey.exists() || !privkey.exists() {
-        return Err("SSH key files not found in the default location".into());
+/// * `cli` - The parsed command line.
+/// * `config` - The configuration, with the nodes narrowed down.
+/// * `cancel` - Cancelled when the user interrupts the run.
+/// * `load` - Loads the configuration again, for subcommands that reload it.
+fn run(
+    cli: &Cli,
+    config: &Config,
+    cancel: &CancelToken,
+    load: &reload::Load,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let connection = &cli.connection;
+    // Ask for the password and key passphrases before any output, rather
+    // than part way through
+    if cli.command.logs_in() && !connection.dry_run {
+        // Nodes whose auth lists a password need one even without --ask-pass
+        let lists_password = config.cluster.nodes.iter().any(|node| {
+            node.auth
+                .as_ref()
+                .is_some_and(|auth| auth.contains(&AuthMethod::Password))
+        });
+        if connection.ask_pass || lists_password {
+            ssh::ask_password()?;
+        }
+        if let (None, Some(passphrase)) =
+            (&connection.passphrase_env, &config.cluster.key_passphrase)
+        {
+            ssh::use_passphrase(passphrase.reveal()?);
+        }
+        ssh::unlock_keys(&config.cluster.nodes)?;
+    }
+    dial::use_settings(config.transport.clone());
+    redact::use_settings(&config.redact)?;
+    if cli.command.logs_in() {
+        resolve::all(&config.cluster.nodes);
+        resolve::warn_unresolved(&config.cluster.nodes);
+    }
+    match &cli.command {
+        Command::Exec(args) => commands::exec::run(config, connection, cancel, args),
+        Command::Script(args) => commands::script::run(config, connection, cancel, args),
+        Command::List => commands::list::run(config),
+        Command::Ping => commands::ping::run(config, connection, cancel),
+        Command::Push(args) => commands::push::run(config, connection, cancel, args),
+        Command::Pull(args) => commands::pull::run(config, connection, cancel, args),
+        Command::Sync(args) => commands::sync::run(config, connection, cancel, args),
+        Command::Watch(args) => commands::watch::run(config, connection, cancel, args, load),
+        Command::One(args) => commands::one::run(config, connection, cancel, args),
+        Command::Config(args) => commands::config::run(config, args),
+        Command::Rerun(args) => commands::rerun::run(config, connection, cancel, args),
+        Command::Quarantine(_) | Command::Secret(_) | Command::History(_) => {
+            unreachable!("these subcommands run before the config is loaded")
+        }
     }
-
-    Ok((pubkey, privkey))
 }
-
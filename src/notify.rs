@@ -0,0 +1,291 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Telling a chat channel or another service how a run went, for `--notify`
+//! and the config's `[notify]` section.
+//!
+//! Each webhook is a URL: `slack://` followed by the rest of a Slack
+//! incoming webhook's URL, `discord://` for a Discord webhook, or an
+//! `http://` or `https://` URL, which is sent the summary as JSON. The
+//! summary is posted once the run is over, whether it finished or was
+//! interrupted, so a long rollout doesn't need a terminal watched. With a
+//! threshold, an alert is posted as soon as that many nodes have failed, and
+//! the summary only if the threshold was reached. Posting happens in the
+//! background, and a webhook that can't be reached is warned of without
+//! failing the run.
+
+use crate::inventory;
+use crate::report;
+use crate::secrets::Secret;
+use crate::summary::Summary;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a webhook is given to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Who to notify, from the config's `[notify]` section.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The webhooks to post to, which are secrets as they grant posting.
+    #[serde(default)]
+    pub webhooks: Vec<Secret>,
+    /// Only notify once this many nodes have failed.
+    pub min_failures: Option<usize>,
+}
+
+/// The kinds of webhook, which are sent different bodies.
+#[derive(Clone, Copy)]
+enum Kind {
+    Slack,
+    Discord,
+    /// Any other service, which is sent the summary as JSON.
+    Generic,
+}
+
+/// A webhook to post to.
+#[derive(Clone)]
+pub struct Webhook {
+    kind: Kind,
+    /// The URL posted to.
+    url: String,
+}
+
+impl Webhook {
+    /// Parses a webhook's URL, such as
+    /// "slack://hooks.slack.com/services/T000/B000/XXXX".
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (scheme, rest) = value.split_once("://").ok_or_else(|| {
+            format!(
+                "expected a URL such as slack://hooks.slack.com/...: {}",
+                value
+            )
+        })?;
+        let kind = match scheme {
+            "slack" => Kind::Slack,
+            "discord" => Kind::Discord,
+            "http" | "https" => Kind::Generic,
+            _ => {
+                return Err(format!(
+                    "unknown webhook scheme {}; use slack, discord, http or https",
+                    scheme
+                ))
+            }
+        };
+        if rest.is_empty() {
+            return Err(format!("no address given for the webhook: {}", value));
+        }
+        let url = match kind {
+            Kind::Generic => value.to_string(),
+            Kind::Slack | Kind::Discord => format!("https://{}", rest),
+        };
+        Ok(Webhook { kind, url })
+    }
+
+    /// Returns the host the webhook is on, to name it without giving away the
+    /// rest of the URL, which is the secret part.
+    fn host(&self) -> &str {
+        let rest = self.url.split_once("://").map_or("", |(_, rest)| rest);
+        rest.split('/').next().unwrap_or_default()
+    }
+
+    /// Posts a message to the webhook, with the details for services that
+    /// take JSON.
+    fn post(&self, text: &str, details: &Value) -> Result<(), String> {
+        let body = match self.kind {
+            Kind::Slack => json!({ "text": text }),
+            Kind::Discord => json!({ "content": text }),
+            Kind::Generic => {
+                let mut body = details.clone();
+                if let Value::Object(fields) = &mut body {
+                    fields.insert("text".to_string(), text.into());
+                }
+                body
+            }
+        };
+        ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .build()
+            .post(&self.url)
+            .send_json(body)
+            .map(|_| ())
+            .map_err(|e| match e {
+                ureq::Error::Status(status, response) => {
+                    format!("it answered {} {}", status, response.status_text())
+                }
+                ureq::Error::Transport(e) => e.kind().to_string(),
+            })
+    }
+}
+
+/// Notifies the webhooks of a run.
+pub struct Notifier {
+    webhooks: Vec<Webhook>,
+    /// How many nodes must fail before anyone is notified, if any must.
+    min_failures: Option<usize>,
+    /// Whether the alert that the threshold was reached has been posted.
+    alerted: bool,
+    /// The posts still being sent.
+    sending: Vec<JoinHandle<()>>,
+}
+
+impl Notifier {
+    /// Gathers the webhooks to notify of a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `settings` - The config's `[notify]` section.
+    /// * `webhooks` - The webhooks given with `--notify`.
+    /// * `min_failures` - The threshold given with `--notify-min-failures`,
+    ///   which replaces the config's.
+    ///
+    /// # Returns
+    ///
+    /// Returns the notifier, None if there's no one to notify, or an error if
+    /// a webhook in the config can't be revealed or isn't a webhook URL.
+    pub fn new(
+        settings: &Settings,
+        webhooks: &[Webhook],
+        min_failures: Option<usize>,
+    ) -> Result<Option<Self>, String> {
+        let mut all = webhooks.to_vec();
+        for webhook in &settings.webhooks {
+            let url = webhook.reveal().map_err(|e| format!("[notify]: {}", e))?;
+            all.push(Webhook::parse(&url).map_err(|e| format!("[notify]: {}", e))?);
+        }
+        if all.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Notifier {
+            webhooks: all,
+            min_failures: min_failures.or(settings.min_failures).filter(|&n| n > 0),
+            alerted: false,
+            sending: Vec::new(),
+        }))
+    }
+
+    /// Posts an alert the first time the failures reach the threshold.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - What's being run.
+    /// * `summary` - What's happened to the nodes so far.
+    /// * `total` - How many nodes the run is on.
+    pub fn failures(&mut self, command: &str, summary: &Summary, total: usize) {
+        let failed = summary.failures();
+        if self.alerted || self.min_failures.is_none_or(|min| failed < min) {
+            return;
+        }
+        self.alerted = true;
+        let text = format!(
+            "cluster_run: {} of {} nodes have failed so far running `{}`: {}",
+            failed,
+            total,
+            command,
+            inventory::fold(&failed_nodes(summary))
+        );
+        let details = json!({
+            "event": "failures",
+            "command": command,
+            "failed": failed,
+            "nodes": total,
+        });
+        self.post(text, details);
+    }
+
+    /// Posts the summary of a run that's over, unless there's a threshold
+    /// that wasn't reached, and waits for everything posted to be sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - What was run.
+    /// * `summary` - What happened to the nodes.
+    /// * `run` - The run's number in the history, if it was recorded.
+    /// * `interrupted` - Whether the run was interrupted before it finished.
+    pub fn finish(mut self, command: &str, summary: &Summary, run: Option<i64>, interrupted: bool) {
+        if self.min_failures.is_none() || self.alerted {
+            let text = message(command, summary, run, interrupted);
+            let mut details = json!({
+                "event": "run_finished",
+                "command": command,
+                "run": run,
+                "interrupted": interrupted,
+            });
+            if let Value::Object(fields) = &mut details {
+                fields.insert("summary".to_string(), report::summary_json(summary));
+            }
+            self.post(text, details);
+        }
+        for sending in self.sending {
+            let _ = sending.join();
+        }
+    }
+
+    /// Posts a message to every webhook in the background.
+    fn post(&mut self, text: String, details: Value) {
+        for webhook in self.webhooks.clone() {
+            let (text, details) = (text.clone(), details.clone());
+            self.sending.push(thread::spawn(move || {
+                if let Err(e) = webhook.post(&text, &details) {
+                    tracing::warn!("Unable to notify {}: {}", webhook.host(), e);
+                }
+            }));
+        }
+    }
+}
+
+/// Returns the nodes that failed, timed out or couldn't be run on.
+fn failed_nodes(summary: &Summary) -> Vec<String> {
+    let mut nodes: Vec<String> = summary
+        .failed
+        .iter()
+        .map(|(node, _)| node.clone())
+        .collect();
+    nodes.extend(summary.timed_out.iter().cloned());
+    nodes.extend(summary.errored.iter().map(|(node, _)| node.clone()));
+    nodes
+}
+
+/// Returns the message summarising a run that's over, such as "cluster_run
+/// finished `uptime` on 16 nodes in 2.1s: 15 succeeded, 1 failed".
+fn message(command: &str, summary: &Summary, run: Option<i64>, interrupted: bool) -> String {
+    let total = summary.succeeded.len()
+        + summary.failures()
+        + summary.cancelled.len()
+        + summary.not_started;
+    let mut counts = vec![format!("{} succeeded", summary.succeeded.len())];
+    for (count, what) in [
+        (summary.failed.len(), "failed"),
+        (summary.timed_out.len(), "timed out"),
+        (summary.errored.len(), "couldn't be run on"),
+        (summary.cancelled.len(), "cancelled"),
+        (summary.not_started, "not started"),
+    ] {
+        if count > 0 {
+            counts.push(format!("{} {}", count, what));
+        }
+    }
+    let mut text = format!(
+        "cluster_run {} `{}` on {} nodes in {:.2?}: {}",
+        if interrupted {
+            "was interrupted running"
+        } else {
+            "finished"
+        },
+        command,
+        total,
+        summary.wall_time,
+        counts.join(", ")
+    );
+    let failed = failed_nodes(summary);
+    if !failed.is_empty() {
+        text.push_str(&format!("\nFailed: {}", inventory::fold(&failed)));
+    }
+    if let Some(run) = run {
+        text.push_str(&format!("\nRecorded as run {}", run));
+    }
+    text
+}
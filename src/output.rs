@@ -0,0 +1,594 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Console output for remote commands.
+//!
+//! In streaming mode, output from many nodes is interleaved on the console as
+//! it arrives. Each complete line is printed with the node it came from, so
+//! lines from different nodes never run together.
+//!
+//! With `--output grouped`, nodes that gave the same output are printed
+//! together once the run finishes, in the manner of clush's `dshbak -c`, with
+//! `--output ordered` each node's output is printed in the order of the node
+//! list however the nodes finish, and
+//! with `--output csv` or `--output tsv` each node gets a row, ready to paste
+//! into a spreadsheet.
+//!
+//! Output to a terminal is colored, so succeeded and failed nodes stand out
+//! and the node prefixes recede, unless `--color` says otherwise.
+//!
+//! This module also holds small formatting helpers shared by the subcommands.
+
+use crate::cli::ColorChoice;
+use crate::filter::Filter;
+use crate::inventory;
+use crate::progress;
+use crate::ssh::{CommandResult, OutputStream};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::{self, IsTerminal};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Whether standard output and standard error are colored, from `--color`.
+static COLORED: OnceLock<(bool, bool)> = OnceLock::new();
+
+/// The colors output is painted in.
+#[derive(Clone, Copy)]
+pub enum Color {
+    /// For nodes that succeeded.
+    Green,
+    /// For nodes that failed.
+    Red,
+    /// For warnings, and nodes that were cancelled or never started.
+    Yellow,
+    /// For node prefixes and other framing.
+    Dim,
+}
+
+/// The escape code that ends a color.
+pub const RESET: &str = "\x1b[0m";
+
+/// Returns the escape code that starts a color.
+pub fn start(color: Color) -> &'static str {
+    match color {
+        Color::Green => "\x1b[32m",
+        Color::Red => "\x1b[31m",
+        Color::Yellow => "\x1b[33m",
+        Color::Dim => "\x1b[2m",
+    }
+}
+
+/// Colors standard output and standard error as `--color` asks.
+pub fn use_color(choice: ColorChoice) {
+    let colored = match choice {
+        ColorChoice::Always => (true, true),
+        ColorChoice::Never => (false, false),
+        ColorChoice::Auto => {
+            let allowed = env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+            (
+                allowed && io::stdout().is_terminal(),
+                allowed && io::stderr().is_terminal(),
+            )
+        }
+    };
+    let _ = COLORED.set(colored);
+}
+
+/// Returns true if what's printed to the stream is colored.
+pub fn colored(stream: OutputStream) -> bool {
+    let (stdout, stderr) = COLORED.get().copied().unwrap_or_default();
+    match stream {
+        OutputStream::Stdout => stdout,
+        OutputStream::Stderr => stderr,
+    }
+}
+
+/// Returns the text painted in the color if the stream it's printed to is
+/// colored, or as it is otherwise.
+///
+/// Text is padded before it's painted, so the escape codes don't count
+/// towards the width of a column.
+pub fn paint(stream: OutputStream, color: Color, text: &str) -> String {
+    match colored(stream) {
+        true => format!("{}{}{}", start(color), text, RESET),
+        false => text.to_string(),
+    }
+}
+
+/// Returns the color of a node whose command exited with the status.
+pub fn status_color(exit_code: i32) -> Color {
+    match exit_code {
+        0 => Color::Green,
+        _ => Color::Red,
+    }
+}
+
+/// How long a line that's held back for its newline can grow before it's
+/// passed on anyway, so output without newlines can't fill memory.
+pub const MAX_LINE: usize = 64 * 1024;
+
+/// Splits a node's output into lines and prints each as `[node] line`.
+///
+/// Partial lines are held back until their newline arrives, until the
+/// printer is finished, or until they reach `MAX_LINE` bytes. Stdout lines go to stdout and stderr lines to stderr.
+/// The prefixes are padded to the same width for every node, so the lines
+/// of interleaved nodes start in the same column. With `--timestamps`, each
+/// line is printed after the time its first byte arrived, and with `--grep`
+/// or `--grep-v` only the lines the filter keeps are printed.
+pub struct LinePrinter {
+    prefix: String,
+    clock: Option<Clock>,
+    filter: Option<Filter>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// When the lines held back in stdout and stderr started arriving.
+    began: [SystemTime; 2],
+}
+
+impl LinePrinter {
+    /// Creates a printer for output from the given node.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The name of the node.
+    /// * `width` - The length of the longest node name in the run.
+    /// * `clock` - The clock to timestamp each line by, if any.
+    /// * `filter` - Which lines to print, if not all of them.
+    pub fn new(node: &str, width: usize, clock: Option<Clock>, filter: Option<Filter>) -> Self {
+        LinePrinter {
+            prefix: format!("{:<width$}", format!("[{}]", node), width = width + 2),
+            clock,
+            filter,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            began: [UNIX_EPOCH; 2],
+        }
+    }
+
+    /// Buffers a chunk of output and prints any lines it completes.
+    pub fn push(&mut self, stream: OutputStream, chunk: &[u8]) {
+        let now = SystemTime::now();
+        let (buffer, began) = match stream {
+            OutputStream::Stdout => (&mut self.stdout, &mut self.began[0]),
+            OutputStream::Stderr => (&mut self.stderr, &mut self.began[1]),
+        };
+        if buffer.is_empty() {
+            *began = now;
+        }
+        buffer.extend_from_slice(chunk);
+        if buffer.len() >= MAX_LINE && !buffer.contains(&b'\n') {
+            let line = std::mem::take(buffer);
+            print_line(
+                &self.prefix,
+                self.clock,
+                self.filter.as_ref(),
+                *began,
+                stream,
+                &line,
+            );
+            return;
+        }
+
+        let Some(end) = buffer.iter().rposition(|&b| b == b'\n') else {
+            return;
+        };
+        let complete: Vec<u8> = buffer.drain(..=end).collect();
+        // Only the first line started arriving before this chunk
+        for (number, line) in complete[..end].split(|&b| b == b'\n').enumerate() {
+            let time = if number == 0 { *began } else { now };
+            print_line(
+                &self.prefix,
+                self.clock,
+                self.filter.as_ref(),
+                time,
+                stream,
+                line,
+            );
+        }
+        *began = now;
+    }
+
+    /// Prints whatever is left over from lines without a trailing newline.
+    pub fn finish(&mut self) {
+        for (stream, buffer, began) in [
+            (OutputStream::Stdout, &mut self.stdout, self.began[0]),
+            (OutputStream::Stderr, &mut self.stderr, self.began[1]),
+        ] {
+            if !buffer.is_empty() {
+                let line = std::mem::take(buffer);
+                print_line(
+                    &self.prefix,
+                    self.clock,
+                    self.filter.as_ref(),
+                    began,
+                    stream,
+                    &line,
+                );
+            }
+        }
+    }
+}
+
+/// Prints a single line of output after its node's prefix, and the time it
+/// arrived if there's a clock to stamp it by, unless the filter drops it.
+fn print_line(
+    prefix: &str,
+    clock: Option<Clock>,
+    filter: Option<&Filter>,
+    time: SystemTime,
+    stream: OutputStream,
+    line: &[u8],
+) {
+    let line = String::from_utf8_lossy(line);
+    let line = line.strip_suffix('\r').unwrap_or(&line);
+    if filter.is_some_and(|filter| !filter.keeps(line)) {
+        return;
+    }
+    let prefix = match clock {
+        Some(clock) => format!("{} {}", clock.stamp(time), prefix),
+        None => prefix.to_string(),
+    };
+    let prefix = paint(stream, Color::Dim, &prefix);
+    progress::suspend(|| match stream {
+        OutputStream::Stdout => println!("{} {}", prefix, line),
+        OutputStream::Stderr => eprintln!("{} {}", prefix, line),
+    })
+}
+
+/// The nodes' results held back for `--output ordered`, so each is passed on
+/// once every node before it in the node list has finished.
+pub struct Ordered<T> {
+    /// The place in the node list of the next node to pass on.
+    next: usize,
+    /// The nodes that finished ahead of their turn, with what they have to
+    /// print, if anything.
+    waiting: BTreeMap<usize, Option<T>>,
+}
+
+impl<T> Default for Ordered<T> {
+    fn default() -> Self {
+        Ordered {
+            next: 0,
+            waiting: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Ordered<T> {
+    /// Adds a node's result, given its place in the node list, or None if
+    /// the node has nothing to print.
+    ///
+    /// # Returns
+    ///
+    /// Returns the results whose turn has come, in the order of the node list.
+    pub fn add(&mut self, index: usize, result: Option<T>) -> Vec<T> {
+        self.waiting.insert(index, result);
+        let mut ready = Vec::new();
+        while let Some(result) = self.waiting.remove(&self.next) {
+            ready.extend(result);
+            self.next += 1;
+        }
+        ready
+    }
+
+    /// Returns the results still held back, in order, for the nodes after
+    /// those that never finished.
+    pub fn finish(&mut self) -> Vec<T> {
+        std::mem::take(&mut self.waiting)
+            .into_values()
+            .flatten()
+            .collect()
+    }
+}
+
+/// The nodes' results gathered for `--output grouped`, with the nodes that
+/// gave the same output, stderr and exit status kept together.
+#[derive(Default)]
+pub struct Grouped {
+    groups: Vec<Group>,
+    /// The groups with each hash of a result, to find a node's group.
+    by_hash: HashMap<u64, Vec<usize>>,
+}
+
+/// A distinct result, with the nodes that gave it by their place in the
+/// node list.
+struct Group {
+    stdout: String,
+    stderr: String,
+    exit_code: i32,
+    nodes: Vec<(usize, String)>,
+}
+
+impl Grouped {
+    /// Adds a node's result, given its place in the node list.
+    pub fn add(&mut self, index: usize, node: &str, result: &CommandResult) {
+        let mut hasher = DefaultHasher::new();
+        (&result.stdout, &result.stderr, result.exit_code).hash(&mut hasher);
+        let candidates = self.by_hash.entry(hasher.finish()).or_default();
+        let same = candidates.iter().copied().find(|&i| {
+            let group = &self.groups[i];
+            group.exit_code == result.exit_code
+                && group.stdout == result.stdout
+                && group.stderr == result.stderr
+        });
+        let group = match same {
+            Some(i) => &mut self.groups[i],
+            None => {
+                candidates.push(self.groups.len());
+                self.groups.push(Group {
+                    stdout: result.stdout.clone(),
+                    stderr: result.stderr.clone(),
+                    exit_code: result.exit_code,
+                    nodes: Vec::new(),
+                });
+                self.groups.last_mut().unwrap()
+            }
+        };
+        group.nodes.push((index, node.to_string()));
+    }
+
+    /// Prints each distinct result once under the nodes that gave it, folded
+    /// into ranges, in the order of each group's first node. A result of a
+    /// single line is printed on the same line as its nodes.
+    pub fn print(&mut self) {
+        for group in &mut self.groups {
+            group.nodes.sort();
+        }
+        self.groups.sort_by_key(|group| group.nodes[0].0);
+        for group in &self.groups {
+            let names: Vec<String> = group.nodes.iter().map(|(_, name)| name.clone()).collect();
+            let mut label = inventory::fold(&names);
+            if group.exit_code != 0 {
+                label.push_str(&format!(" (exit {})", group.exit_code));
+            }
+            let rule = "-".repeat(label.chars().count().clamp(16, 80));
+            let label = paint(OutputStream::Stdout, status_color(group.exit_code), &label);
+            let stdout = group.stdout.strip_suffix('\n').unwrap_or(&group.stdout);
+            if group.stderr.is_empty() && !stdout.contains('\n') {
+                match stdout.is_empty() {
+                    true => println!("{}: (no output)", label),
+                    false => println!("{}: {}", label, stdout),
+                }
+                continue;
+            }
+            let rule = paint(OutputStream::Stdout, Color::Dim, &rule);
+            println!("{}\n{}\n{}", rule, label, rule);
+            if !stdout.is_empty() {
+                println!("{}", stdout);
+            }
+            if !group.stderr.is_empty() {
+                eprint!("{}", group.stderr);
+            }
+        }
+    }
+}
+
+/// The nodes' results gathered for `--output csv` and `--output tsv`, with
+/// a row for each node.
+pub struct Delimited {
+    separator: char,
+    /// The rows by each node's place in the node list.
+    rows: Vec<(usize, [String; 4])>,
+}
+
+impl Delimited {
+    /// The first row, naming the columns.
+    const HEADER: [&'static str; 4] = ["node", "exit_code", "duration_ms", "first_line_of_output"];
+
+    /// Creates a table whose fields are separated by `separator`, which is a
+    /// comma for CSV or a tab for TSV.
+    pub fn new(separator: char) -> Self {
+        Delimited {
+            separator,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Adds a node's row, given its place in the node list.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The node's place in the node list.
+    /// * `node` - The name of the node.
+    /// * `exit_code` - The command's exit status, or None if it didn't finish.
+    /// * `duration` - How long the command, or the node's last attempt, took.
+    /// * `output` - The output to take the first line of, or the error if the
+    ///   command didn't finish.
+    pub fn add(
+        &mut self,
+        index: usize,
+        node: &str,
+        exit_code: Option<i32>,
+        duration: Duration,
+        output: &str,
+    ) {
+        let line = output.lines().next().unwrap_or_default();
+        self.rows.push((
+            index,
+            [
+                node.to_string(),
+                exit_code.map(|code| code.to_string()).unwrap_or_default(),
+                duration.as_millis().to_string(),
+                line.to_string(),
+            ],
+        ));
+    }
+
+    /// Prints the header and then the rows, in the order of the node list.
+    pub fn print(&mut self) {
+        self.rows.sort_by_key(|(index, _)| *index);
+        self.print_row(&Self::HEADER.map(String::from));
+        for (_, row) in &self.rows {
+            self.print_row(row);
+        }
+    }
+
+    /// Prints one row, quoting fields as CSV needs or, since TSV can't quote,
+    /// turning the tabs in fields into spaces.
+    fn print_row(&self, row: &[String; 4]) {
+        let fields: Vec<String> = row
+            .iter()
+            .map(|field| match self.separator {
+                '\t' => field.replace('\t', " "),
+                separator if field.contains([separator, '"', '\r', '\n']) => {
+                    format!("\"{}\"", field.replace('"', "\"\""))
+                }
+                _ => field.clone(),
+            })
+            .collect();
+        println!("{}", fields.join(&self.separator.to_string()));
+    }
+}
+
+/// Returns the first line a command printed, from its stdout, or its stderr
+/// if it printed nothing else.
+pub fn first_line(result: &CommandResult) -> &str {
+    let stdout = result.stdout.trim_start_matches(['\r', '\n']);
+    match stdout.is_empty() {
+        true => result.stderr.trim_start_matches(['\r', '\n']),
+        false => stdout,
+    }
+}
+
+/// Formats a byte count with a binary unit suffix, e.g. "12.3 MiB".
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// A Unix time as a UTC date and time of day.
+pub struct Utc {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+}
+
+impl Utc {
+    /// Returns the date and time a number of seconds after the Unix epoch.
+    pub fn from_secs(secs: u64) -> Self {
+        // The civil date of a day count, from Howard Hinnant's algorithm
+        let days = (secs / 86400) as i64;
+        let rest = secs % 86400;
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        Utc {
+            year: yoe + era * 400 + i64::from(month <= 2),
+            month,
+            day,
+            hour: rest / 3600,
+            minute: rest % 3600 / 60,
+            second: rest % 60,
+        }
+    }
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC, to the millisecond,
+/// e.g. "2024-05-01T12:34:56.789Z".
+pub fn timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let utc = Utc::from_secs(since.as_secs());
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        utc.year,
+        utc.month,
+        utc.day,
+        utc.hour,
+        utc.minute,
+        utc.second,
+        since.subsec_millis()
+    )
+}
+
+/// The clock lines of output are timestamped by, for `--timestamps`.
+#[derive(Clone, Copy)]
+pub enum Clock {
+    /// The local time, with its offset from UTC.
+    Local,
+    /// UTC, from `--utc`.
+    Utc,
+}
+
+impl Clock {
+    /// Formats a time as an RFC 3339 timestamp to the microsecond, so lines
+    /// from different nodes can be put back in order, e.g.
+    /// "2024-05-01T14:34:56.789012+02:00" or "2024-05-01T12:34:56.789012Z".
+    pub fn stamp(self, time: SystemTime) -> String {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let offset = match self {
+            Clock::Local => local_offset(since.as_secs()),
+            Clock::Utc => 0,
+        };
+        let civil = Utc::from_secs(since.as_secs().saturating_add_signed(offset));
+        let zone = match offset {
+            0 if matches!(self, Clock::Utc) => "Z".to_string(),
+            _ => format!(
+                "{}{:02}:{:02}",
+                if offset < 0 { '-' } else { '+' },
+                offset.unsigned_abs() / 3600,
+                offset.unsigned_abs() % 3600 / 60
+            ),
+        };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}{}",
+            civil.year,
+            civil.month,
+            civil.day,
+            civil.hour,
+            civil.minute,
+            civil.second,
+            since.subsec_micros(),
+            zone
+        )
+    }
+}
+
+/// Returns how many seconds the local time zone was ahead of UTC at a Unix
+/// time, or zero if it can't be told.
+fn local_offset(secs: u64) -> i64 {
+    let Ok(time) = libc::time_t::try_from(secs) else {
+        return 0;
+    };
+    // SAFETY: localtime_r only writes to the tm it's given, which is plain data
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        tm.tm_gmtoff as i64
+    }
+}
+
+/// Prints which nodes were cancelled part way through and how many never started.
+pub fn print_cancelled(cancelled: &[String], pending: usize) {
+    if !cancelled.is_empty() {
+        eprintln!(
+            "Cancelled on {} nodes: {}",
+            cancelled.len(),
+            cancelled.join(", ")
+        );
+    }
+    if pending > 0 {
+        eprintln!("{} nodes were not started", pending);
+    }
+}
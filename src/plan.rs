@@ -0,0 +1,105 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Dry runs, which print what a subcommand would do without doing it.
+//!
+//! The plan lists every node along with how it would be connected to and the
+//! exact steps that would be performed on it. No connections are opened, so
+//! a plan can be checked before running anything destructive.
+
+use crate::agent;
+use crate::certificate::{self, Certificate};
+use crate::config::{AuthMethod, Node};
+use crate::proxy;
+use crate::ssh;
+use std::path::Path;
+
+/// Prints the execution plan for a run across the given nodes.
+///
+/// # Arguments
+///
+/// * `nodes` - The nodes the run would cover.
+/// * `steps` - Given a node's index and the node, returns the steps that would be
+///   performed on it, in order.
+pub fn print(nodes: &[Node], steps: impl Fn(usize, &Node) -> Vec<String>) {
+    println!("Dry run; no connections will be opened.");
+    println!("{} nodes:", nodes.len());
+    for (index, node) in nodes.iter().enumerate() {
+        // The password is only asked for ahead of a dry run if auth lists it
+        let mut ways = Vec::new();
+        for method in node.auth() {
+            match method {
+                AuthMethod::Agent if ssh::agent_available() => {
+                    ways.push("the ssh-agent's keys".to_string())
+                }
+                AuthMethod::Key | AuthMethod::DefaultKeys => match ssh::method_keys(node, method) {
+                    Ok(keys) => ways.extend(keys.iter().map(|(pubkey, privkey)| match pubkey {
+                        Some(cert) if certificate::is_certificate(cert) => format!(
+                            "key {} with certificate {}",
+                            privkey.display(),
+                            describe(cert, node)
+                        ),
+                        _ => format!("key {}", privkey.display()),
+                    })),
+                    Err(e) => ways.push(format!("key none ({})", e)),
+                },
+                AuthMethod::Password if node.auth.is_some() => ways.push("a password".to_string()),
+                AuthMethod::KeyboardInteractive => {
+                    ways.push("answers to its questions".to_string())
+                }
+                AuthMethod::Agent | AuthMethod::Password => {}
+            }
+        }
+        let mut key = if ways.is_empty() {
+            "no way to log in".to_string()
+        } else {
+            ways.join(", then ")
+        };
+        if agent::is_forwarded() {
+            key.push_str(", forwarding the ssh-agent");
+        }
+        if node.compresses() {
+            key.push_str(", compressed");
+        }
+        let mut via = match &node.jump {
+            Some(jump) => format!(" via {}", jump),
+            None => String::new(),
+        };
+        if let Some(url) = &node.proxy {
+            match proxy::parse(url) {
+                Ok(proxy) => via.push_str(&format!(" through proxy {}", proxy)),
+                Err(e) => via.push_str(&format!(" through an invalid proxy ({})", e)),
+            }
+        }
+        // An IPv6 address is bracketed so the port can be told apart from it
+        let host = if node.host.contains(':') && !node.host.starts_with('[') {
+            format!("[{}]", node.host)
+        } else {
+            node.host.clone()
+        };
+        println!(
+            "{}: {}@{}:{}{} with {}",
+            node,
+            node.user(),
+            host,
+            node.port(),
+            via,
+            key
+        );
+        for step in steps(index, node) {
+            println!("    {}", step);
+        }
+    }
+}
+
+/// Describes a certificate with when it expires, or why it can't be used.
+fn describe(cert: &Path, node: &Node) -> String {
+    let checked = Certificate::read(cert).and_then(|c| c.check(node.user()).map(|()| c));
+    match checked {
+        Ok(c) => match c.expires() {
+            Some(expires) => format!("{} (expires {})", cert.display(), expires),
+            None => cert.display().to_string(),
+        },
+        Err(e) => format!("{} (unusable: {})", cert.display(), e),
+    }
+}
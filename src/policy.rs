@@ -0,0 +1,56 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Policies that decide whether a run as a whole succeeded.
+//!
+//! A node fails when the connection or command errors, or when the command
+//! exits with a non-zero status. The policy looks at how many nodes succeeded
+//! and failed and decides the exit code of cluster_run itself, which is what
+//! CI pipelines and scripts act on.
+//!
+//! A policy can also abort a run early, so a bad rollout stops as soon as
+//! enough nodes have failed rather than plowing ahead across the cluster.
+
+/// Decides whether a run succeeded given the per-node outcomes.
+#[derive(Default)]
+pub struct FailurePolicy {
+    /// Treat the run as successful if at least one node succeeded.
+    pub ok_if_any: bool,
+    /// The number of failed nodes to tolerate before the run fails.
+    pub max_failures: usize,
+    /// Abort the run as soon as any node fails.
+    pub fail_fast: bool,
+    /// Abort the run once more than this percentage of the nodes have failed.
+    pub max_failure_percent: Option<f64>,
+}
+
+impl FailurePolicy {
+    /// Returns true if a run with the given counts satisfies the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `succeeded` - The number of nodes that completed successfully.
+    /// * `failed` - The number of nodes that errored or exited non-zero.
+    pub fn is_satisfied(&self, succeeded: usize, failed: usize) -> bool {
+        if self.ok_if_any {
+            return succeeded > 0;
+        }
+        failed <= self.max_failures
+    }
+
+    /// Returns true if the run should be aborted given the failures so far.
+    ///
+    /// # Arguments
+    ///
+    /// * `failed` - The number of nodes that have failed so far.
+    /// * `total` - The number of nodes in the run.
+    pub fn should_abort(&self, failed: usize, total: usize) -> bool {
+        if self.fail_fast && failed > 0 {
+            return true;
+        }
+        match self.max_failure_percent {
+            Some(percent) => total > 0 && failed as f64 * 100.0 > percent * total as f64,
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Authenticated sessions kept open for the rest of the run, so nodes are
+//! connected to and logged into once rather than for every command.
+//!
+//! Sessions are pooled by the settings a node is logged into with, so a node
+//! whose address, port, user, key, auth, compression, jump hosts, proxy or
+//! pinned host key change, as they can when the configuration is reloaded, gets
+//! a new session. A session is only put back once what used it has finished
+//! cleanly; one that saw an error is closed, since it may be left with a
+//! half-finished channel. Servers close idle connections, so a session is
+//! checked to still be open before it's handed out again, and one the server
+//! has closed is replaced with a new connection.
+
+use crate::cancel::CancelToken;
+use crate::config::{AuthMethod, Node};
+use crate::throttle;
+use crate::transport::{self, Session};
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Idle sessions, by the login they were opened with.
+type Idle = HashMap<Login, Vec<Box<dyn Session>>>;
+
+/// The idle sessions.
+static POOL: OnceLock<Mutex<Idle>> = OnceLock::new();
+
+/// The settings that decide where and how a session is logged in.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Login {
+    address: String,
+    port: u16,
+    user: String,
+    key: Option<PathBuf>,
+    jump: Option<String>,
+    proxy: Option<String>,
+    host_key: Option<String>,
+    auth: Vec<AuthMethod>,
+    compression: bool,
+}
+
+/// A session taken from the pool, which is closed when dropped unless it's
+/// released back to the pool.
+pub struct Pooled {
+    login: Login,
+    sess: Option<Box<dyn Session>>,
+}
+
+/// Returns an authenticated session to a node, reusing an idle one that's
+/// still open, or connecting if there's none.
+///
+/// # Arguments
+///
+/// * `node` - The node to log into.
+/// * `timeout` - The limit on connecting, handshaking and authenticating.
+/// * `cancel` - Stops a new connection waiting for its turn.
+///
+/// # Returns
+///
+/// Returns the session, or an error if a new one had to be opened and the
+/// node couldn't be reached or logged into.
+pub async fn checkout(
+    node: &Node,
+    timeout: Option<Duration>,
+    cancel: &CancelToken,
+) -> Result<Pooled, Box<dyn std::error::Error + Send + Sync>> {
+    let login = Login::of(node);
+    let idle = {
+        let mut pool = pool().lock().unwrap();
+        let mut idle = None;
+        if let Some(sessions) = pool.get_mut(&login) {
+            while let Some(sess) = sessions.pop() {
+                if sess.is_open() {
+                    idle = Some(sess);
+                    break;
+                }
+            }
+        }
+        idle
+    };
+    let sess = match idle {
+        Some(sess) => sess,
+        None => {
+            throttle::wait_turn(cancel).await?;
+            transport::current().connect(node, timeout).await?
+        }
+    };
+    Ok(Pooled {
+        login,
+        sess: Some(sess),
+    })
+}
+
+impl Pooled {
+    /// Puts the session back in the pool for the next command on the node.
+    pub fn release(mut self) {
+        if let Some(sess) = self.sess.take() {
+            let mut pool = pool().lock().unwrap();
+            pool.entry(self.login.clone()).or_default().push(sess);
+        }
+    }
+}
+
+impl Deref for Pooled {
+    type Target = dyn Session;
+
+    fn deref(&self) -> &(dyn Session + 'static) {
+        self.sess
+            .as_deref()
+            .expect("the session is held until released")
+    }
+}
+
+impl Login {
+    fn of(node: &Node) -> Login {
+        Login {
+            address: node.address().to_string(),
+            port: node.port(),
+            user: node.user().to_string(),
+            key: node.key.clone(),
+            jump: node.jump.clone(),
+            proxy: node.proxy.clone(),
+            host_key: node.host_key.clone(),
+            auth: node.auth(),
+            compression: node.compresses(),
+        }
+    }
+}
+
+fn pool() -> &'static Mutex<Idle> {
+    POOL.get_or_init(Default::default)
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! A live progress bar for runs on many nodes, for `--progress`.
+//!
+//! The bar is drawn on standard error, and shows how many nodes have
+//! finished, how many are running and how many failed, along with the nodes
+//! that have been running the longest, as those are usually the ones holding
+//! the run up. Messages and output printed while it's drawn are printed above
+//! it. When standard error isn't a terminal there's nothing to draw on, so the
+//! usual progress messages are printed instead.
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the bar is redrawn, and the running nodes' times updated.
+const TICK: Duration = Duration::from_millis(200);
+
+/// How many of the longest-running nodes are shown.
+const SLOWEST: usize = 3;
+
+/// The bar being drawn, if any, which output is printed above.
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// The progress of a run, drawn as a bar until it's dropped.
+pub struct Progress {
+    bar: ProgressBar,
+    state: Arc<Mutex<State>>,
+}
+
+/// What the bar shows besides how many nodes have finished.
+#[derive(Default)]
+struct State {
+    /// The nodes running now, by their place in the node list, with when
+    /// they started.
+    running: BTreeMap<usize, (String, Instant)>,
+    failed: usize,
+    done: bool,
+}
+
+impl Progress {
+    /// Starts drawing the progress of a run on `total` nodes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the progress, or None if standard error isn't a terminal.
+    pub fn start(total: usize) -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            tracing::info!("Not showing progress, as standard error isn't a terminal");
+            return None;
+        }
+        let bar = ProgressBar::with_draw_target(Some(total as u64), ProgressDrawTarget::stderr());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{spinner} [{elapsed_precise}] {bar:30} {pos}/{len} finished, {msg}",
+            )
+            .expect("the template is valid")
+            .progress_chars("=> "),
+        );
+        *BAR.lock().unwrap() = Some(bar.clone());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        // The running nodes' times change even when no node starts or finishes
+        let ticking = (bar.clone(), state.clone());
+        thread::spawn(move || {
+            let (bar, state) = ticking;
+            loop {
+                let message = {
+                    let state = state.lock().unwrap();
+                    if state.done {
+                        break;
+                    }
+                    state.message()
+                };
+                bar.set_message(message);
+                thread::sleep(TICK);
+            }
+        });
+        Some(Progress { bar, state })
+    }
+
+    /// Notes that a node has started.
+    pub fn started(&self, index: usize, node: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .running
+            .insert(index, (node.to_string(), Instant::now()));
+        self.bar.set_message(state.message());
+    }
+
+    /// Notes that a node has finished, and whether it succeeded.
+    pub fn finished(&self, index: usize, succeeded: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.running.remove(&index);
+        if !succeeded {
+            state.failed += 1;
+        }
+        self.bar.set_message(state.message());
+        self.bar.inc(1);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().done = true;
+        *BAR.lock().unwrap() = None;
+        self.bar.finish_and_clear();
+    }
+}
+
+impl State {
+    /// Returns how many nodes are running and failed, and the slowest running.
+    fn message(&self) -> String {
+        let mut message = format!("{} running, {} failed", self.running.len(), self.failed);
+        let mut running: Vec<&(String, Instant)> = self.running.values().collect();
+        running.sort_by_key(|(_, started)| *started);
+        let slowest: Vec<String> = running
+            .iter()
+            .take(SLOWEST)
+            .map(|(node, started)| format!("{} ({}s)", node, started.elapsed().as_secs()))
+            .collect();
+        if !slowest.is_empty() {
+            message.push_str("; slowest: ");
+            message.push_str(&slowest.join(", "));
+        }
+        message
+    }
+}
+
+/// Runs `print` with the bar cleared out of the way, if one is drawn, so
+/// what it prints appears above the bar.
+pub fn suspend<R>(print: impl FnOnce() -> R) -> R {
+    let bar = BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(print),
+        None => print(),
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Interactive questions for the person running cluster_run.
+//!
+//! Questions are asked on the controlling terminal rather than standard input
+//! and output, so they still work when standard input is being forwarded to
+//! the nodes or output is piped elsewhere.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+/// Held while a server's questions are asked, so that the questions of
+/// hosts being logged into at the same time aren't mixed up.
+static ASKING: Mutex<()> = Mutex::new(());
+
+/// Asks a yes or no question, defaulting to no.
+///
+/// # Returns
+///
+/// Returns true if the answer was yes, or an error if there's no terminal to
+/// ask on.
+pub fn confirm(question: &str) -> io::Result<bool> {
+    let mut tty = open_tty()?;
+    write!(tty, "{} [y/N] ", question)?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks the questions a server sent during keyboard-interactive
+/// authentication, such as for a one-time code, after the name and
+/// instruction the server gave them.
+///
+/// # Arguments
+///
+/// * `server` - The host asking, which the questions are shown under.
+/// * `name` - The server's title for the questions, which may be empty.
+/// * `instruction` - What the server says to do, which may be empty.
+/// * `prompts` - Each question, and whether its answer may be shown as it's
+///   typed.
+///
+/// # Returns
+///
+/// Returns the answers in order, or an error if there's no terminal to ask on.
+pub fn challenge(
+    server: &str,
+    name: &str,
+    instruction: &str,
+    prompts: &[(String, bool)],
+) -> io::Result<Vec<String>> {
+    let _asking = ASKING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut tty = open_tty()?;
+    writeln!(tty, "{} asks:", server)?;
+    for line in [name, instruction] {
+        if !line.trim().is_empty() {
+            writeln!(tty, "{}", printable(line.trim_end()))?;
+        }
+    }
+
+    let mut answers = Vec::with_capacity(prompts.len());
+    for (prompt, echo) in prompts {
+        let prompt = printable(prompt);
+        let answer = if *echo {
+            write!(tty, "{}", prompt)?;
+            tty.flush()?;
+            let mut answer = String::new();
+            BufReader::new(&tty).read_line(&mut answer)?;
+            answer.trim_end_matches(['\r', '\n']).to_string()
+        } else {
+            rpassword::prompt_password(prompt)?
+        };
+        answers.push(answer);
+    }
+    Ok(answers)
+}
+
+/// Drops the control characters from a server's text, other than line
+/// breaks, so it can't move the cursor or change the terminal's settings.
+fn printable(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n')
+        .collect()
+}
+
+/// Opens the controlling terminal for reading and writing.
+fn open_tty() -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| io::Error::new(e.kind(), format!("no terminal to ask on: {}", e)))
+}
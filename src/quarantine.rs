@@ -0,0 +1,124 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Nodes kept out of every run.
+//!
+//! A quarantined node is skipped by every subcommand until it's removed from
+//! quarantine, which stops known-bad machines, such as GPU hosts awaiting
+//! repair, from failing runs. The list is kept in `quarantine.toml` in the
+//! state directory, or in the file named by `CLUSTER_RUN_QUARANTINE`, which
+//! can point at a shared location so that a whole team skips the same nodes.
+
+use crate::config::Node;
+use crate::state;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The environment variable naming the quarantine file.
+const PATH_VAR: &str = "CLUSTER_RUN_QUARANTINE";
+
+/// Why and when a node was quarantined.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Entry {
+    /// Why the node was quarantined, if given.
+    pub reason: Option<String>,
+    /// Who quarantined the node, if known.
+    pub by: Option<String>,
+    /// When the node was quarantined, in seconds since the Unix epoch.
+    pub since: u64,
+}
+
+impl Entry {
+    /// Creates an entry for a node being quarantined now by the current user.
+    pub fn new(reason: Option<String>) -> Self {
+        Entry {
+            reason,
+            by: env::var("USER").ok(),
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Returns the path of the quarantine file.
+pub fn path() -> Result<PathBuf, String> {
+    match env::var_os(PATH_VAR) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => state::path("quarantine.toml")
+            .ok_or_else(|| "Unable to determine home directory".into()),
+    }
+}
+
+/// Reads the quarantined nodes, by address or alias.
+///
+/// # Returns
+///
+/// Returns the nodes, which are none if the file doesn't exist, or an error
+/// if the file can't be read or parsed. A broken list is an error rather
+/// than being ignored, since ignoring it would run on the quarantined nodes.
+pub fn load() -> Result<BTreeMap<String, Entry>, String> {
+    let path = path()?;
+    let invalid = |e: String| {
+        format!(
+            "Unable to read the quarantine list in {}: {}",
+            path.display(),
+            e
+        )
+    };
+    match fs::read_to_string(&path) {
+        Ok(content) => toml::from_str(&content).map_err(|e| invalid(e.to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(invalid(e.to_string())),
+    }
+}
+
+/// Writes the quarantined nodes.
+pub fn save(nodes: &BTreeMap<String, Entry>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path()?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(nodes)?)?;
+    Ok(())
+}
+
+/// Removes the quarantined nodes from the nodes a run covers.
+///
+/// The skipped nodes are listed on stderr, so it's clear why they're missing.
+///
+/// # Returns
+///
+/// Returns the remaining nodes, or an error if the quarantine list can't be
+/// read or every node is quarantined.
+pub fn skip(nodes: Vec<Node>) -> Result<Vec<Node>, String> {
+    let quarantined = load()?;
+    if quarantined.is_empty() {
+        return Ok(nodes);
+    }
+    let (skipped, kept): (Vec<Node>, Vec<Node>) = nodes.into_iter().partition(|node| {
+        quarantined.contains_key(&node.host)
+            || node
+                .alias
+                .as_ref()
+                .is_some_and(|alias| quarantined.contains_key(alias))
+    });
+    if !skipped.is_empty() {
+        let names: Vec<String> = skipped.iter().map(Node::to_string).collect();
+        tracing::info!(
+            "Skipping {} quarantined nodes: {}",
+            skipped.len(),
+            names.join(", ")
+        );
+    }
+    if kept.is_empty() {
+        return Err("every selected node is quarantined".into());
+    }
+    Ok(kept)
+}
@@ -0,0 +1,328 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Masking secrets in everything cluster_run prints or writes down.
+//!
+//! Text matching any of the patterns given with `--redact` or in the config's
+//! `[redact]` section, or equal to the value of a variable given with
+//! `--redact-env` or listed in `[redact]`, is replaced by `[REDACTED]` before
+//! it reaches the terminal, the `--log-dir` files, the reports or the
+//! history. The passwords and passphrases cluster_run is given, such as the
+//! sudo password, are masked too once they've been revealed. Output that's streamed is held back a line
+//! at a time while anything is being masked, so a secret split across two
+//! chunks is still caught.
+
+use crate::cancel::Cancelled;
+use crate::output;
+use crate::progress;
+use crate::ssh::{OutputStream, TimedOut};
+use regex::{bytes, Regex};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::env;
+use std::io::{self, Write};
+use std::sync::RwLock;
+
+/// What masked text is replaced by.
+pub const MASK: &str = "[REDACTED]";
+
+/// What's masked, from the command line, the config and the secrets used.
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    patterns: Vec::new(),
+    regex: None,
+    bytes: None,
+});
+
+/// What to mask, from the config's `[redact]` section.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The regular expressions matching text to mask.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// The local environment variables whose values are masked.
+    #[serde(default)]
+    pub env: Vec<String>,
+}
+
+/// The patterns being masked, and the expression matching any of them,
+/// both in text and in output that may not be valid UTF-8.
+struct Filter {
+    patterns: Vec<String>,
+    regex: Option<Regex>,
+    bytes: Option<bytes::Regex>,
+}
+
+/// Masks text matching a regular expression from now on.
+///
+/// # Returns
+///
+/// Returns an error if the pattern isn't a valid regular expression.
+pub fn use_pattern(pattern: &str) -> Result<(), String> {
+    check(pattern)?;
+    add(pattern.to_string());
+    Ok(())
+}
+
+/// Masks the value of a local environment variable from now on, warning if
+/// it isn't set.
+pub fn use_env(var: &str) {
+    match env::var(var) {
+        Ok(value) => use_secret(&value),
+        Err(_) => tracing::warn!(
+            "environment variable {} isn't set, so there's nothing to redact for it",
+            var
+        ),
+    }
+}
+
+/// Masks what the config's `[redact]` section lists from now on.
+pub fn use_settings(settings: &Settings) -> Result<(), String> {
+    for pattern in &settings.patterns {
+        use_pattern(pattern).map_err(|e| format!("[redact]: {}", e))?;
+    }
+    for var in &settings.env {
+        use_env(var);
+    }
+    Ok(())
+}
+
+/// Masks a secret's value from now on.
+pub fn use_secret(value: &str) {
+    if !value.is_empty() {
+        add(regex::escape(value));
+    }
+}
+
+/// Returns an error if a pattern isn't a valid regular expression.
+pub fn check(pattern: &str) -> Result<(), String> {
+    Regex::new(pattern)
+        .map(|_| ())
+        .map_err(|e| format!("invalid pattern {}: {}", pattern, e))
+}
+
+/// Adds a pattern to the filter, unless it's there already.
+fn add(pattern: String) {
+    let mut filter = FILTER.write().unwrap();
+    if filter.patterns.contains(&pattern) {
+        return;
+    }
+    filter.patterns.push(pattern);
+    // Longer patterns go first, so a secret that contains another is masked whole
+    let mut patterns = filter.patterns.clone();
+    patterns.sort_by_key(|pattern| std::cmp::Reverse(pattern.len()));
+    let alternatives: Vec<String> = patterns
+        .iter()
+        .map(|pattern| format!("(?:{})", pattern))
+        .collect();
+    filter.regex = Regex::new(&alternatives.join("|")).ok();
+    filter.bytes = bytes::Regex::new(&alternatives.join("|")).ok();
+}
+
+/// Returns the expression matching anything to mask, if there's anything.
+fn regex() -> Option<Regex> {
+    FILTER.read().unwrap().regex.clone()
+}
+
+/// Returns the expression matching anything to mask in raw output, if
+/// there's anything.
+fn bytes_regex() -> Option<bytes::Regex> {
+    FILTER.read().unwrap().bytes.clone()
+}
+
+/// Returns the text with anything secret masked.
+pub fn text(text: &str) -> Cow<'_, str> {
+    match regex() {
+        Some(regex) => match regex.replace_all(text, MASK) {
+            Cow::Borrowed(_) => Cow::Borrowed(text),
+            Cow::Owned(masked) => Cow::Owned(masked),
+        },
+        None => Cow::Borrowed(text),
+    }
+}
+
+/// Masks anything secret in an error's message. Cancellations and timeouts
+/// are kept as they are, as they say nothing that could be secret and are
+/// told apart by their type.
+pub fn error(
+    e: Box<dyn std::error::Error + Send + Sync>,
+) -> Box<dyn std::error::Error + Send + Sync> {
+    if e.is::<Cancelled>() || e.is::<TimedOut>() {
+        return e;
+    }
+    match text(&e.to_string()) {
+        Cow::Borrowed(_) => e,
+        Cow::Owned(masked) => masked.into(),
+    }
+}
+
+/// Masks output as it arrives, a line at a time, passing it on once each
+/// line is complete.
+#[derive(Default)]
+pub struct Streams {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl Streams {
+    /// Takes a chunk of output, and passes on the lines it completes masked.
+    /// Nothing is held back if nothing is being masked.
+    pub fn push(
+        &mut self,
+        stream: OutputStream,
+        chunk: &[u8],
+        emit: &mut dyn FnMut(OutputStream, &[u8]),
+    ) {
+        let Some(regex) = bytes_regex() else {
+            emit(stream, chunk);
+            return;
+        };
+        let pending = match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+        pending.extend_from_slice(chunk);
+        // Progress bars end their lines with a carriage return, and a line
+        // that grows too long is passed on as it is
+        let end = match pending.iter().rposition(|&b| b == b'\n' || b == b'\r') {
+            Some(end) => end,
+            None if pending.len() >= output::MAX_LINE => pending.len() - 1,
+            None => return,
+        };
+        let lines: Vec<u8> = pending.drain(..=end).collect();
+        emit(stream, &mask_bytes(&regex, &lines));
+    }
+
+    /// Passes on whatever's left of the output, masked.
+    pub fn finish(&mut self, emit: &mut dyn FnMut(OutputStream, &[u8])) {
+        let Some(regex) = bytes_regex() else {
+            return;
+        };
+        for (stream, pending) in [
+            (OutputStream::Stdout, &mut self.stdout),
+            (OutputStream::Stderr, &mut self.stderr),
+        ] {
+            if !pending.is_empty() {
+                emit(stream, &mask_bytes(&regex, pending));
+                pending.clear();
+            }
+        }
+    }
+}
+
+/// Returns the output with anything secret masked, and every other byte as
+/// it was.
+fn mask_bytes(regex: &bytes::Regex, output: &[u8]) -> Vec<u8> {
+    regex.replace_all(output, MASK.as_bytes()).into_owned()
+}
+
+/// Standard error, with anything secret masked in each message written to
+/// it, for the log messages.
+#[derive(Default)]
+pub struct Stderr {
+    message: Vec<u8>,
+}
+
+impl Write for Stderr {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.message.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let message = std::mem::take(&mut self.message);
+        if message.is_empty() {
+            return Ok(());
+        }
+        let message = match bytes_regex() {
+            Some(regex) => mask_bytes(&regex, &message),
+            None => message,
+        };
+        progress::suspend(|| io::stderr().write_all(&message))
+    }
+}
+
+impl Drop for Stderr {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Passes a stream's chunks through masking, returning what comes out,
+    /// chunk by chunk.
+    fn stream(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut streams = Streams::default();
+        let mut emitted = Vec::new();
+        let mut emit = |stream: OutputStream, chunk: &[u8]| {
+            assert!(matches!(stream, OutputStream::Stdout));
+            emitted.push(chunk.to_vec());
+        };
+        for chunk in chunks {
+            streams.push(OutputStream::Stdout, chunk, &mut emit);
+        }
+        streams.finish(&mut emit);
+        emitted
+    }
+
+    // The filter is shared by the whole process, so each test masks
+    // something of its own
+
+    #[test]
+    fn masks_patterns() {
+        use_pattern(r"tok-[a-z]{4}\b").unwrap();
+        assert_eq!(
+            text("a tok-abcd and tok-abcde"),
+            "a [REDACTED] and tok-abcde"
+        );
+        assert!(matches!(text("nothing here"), Cow::Borrowed(_)));
+        assert_eq!(
+            use_pattern("tok-(").unwrap_err().lines().next().unwrap(),
+            "invalid pattern tok-(: regex parse error:"
+        );
+    }
+
+    #[test]
+    fn masks_environment_values() {
+        env::set_var("CLUSTER_RUN_REDACT_TEST", "s3cr3t.value");
+        use_env("CLUSTER_RUN_REDACT_TEST");
+        // The value is masked as it is, not as a pattern
+        assert_eq!(text("got s3cr3t.value"), "got [REDACTED]");
+        assert_eq!(text("got s3cr3tXvalue"), "got s3cr3tXvalue");
+    }
+
+    #[test]
+    fn masks_the_longest_secret_first() {
+        use_secret("ovl-pass");
+        use_secret("ovl-passphrase");
+        assert_eq!(text("ovl-passphrase ovl-pass"), "[REDACTED] [REDACTED]");
+    }
+
+    #[test]
+    fn masks_a_secret_split_across_chunks() {
+        use_secret("spl-it-secret");
+        let emitted = stream(&[b"key: spl-", b"it-secret\nnext ", b"line"]);
+        assert_eq!(
+            emitted,
+            [b"key: [REDACTED]\n".to_vec(), b"next line".to_vec()]
+        );
+
+        // A progress bar's carriage returns end lines too
+        let emitted = stream(&[b"50%\rspl-it", b"-secret\r", b"\xff"]);
+        assert_eq!(
+            emitted,
+            [b"50%\r".to_vec(), b"[REDACTED]\r".to_vec(), vec![0xff]]
+        );
+    }
+
+    #[test]
+    fn masks_errors_but_keeps_their_kind() {
+        use_secret("err-secret");
+        let masked = error("failed with err-secret".into());
+        assert_eq!(masked.to_string(), "failed with [REDACTED]");
+        assert!(error(Box::new(Cancelled)).is::<Cancelled>());
+    }
+}
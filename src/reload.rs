@@ -0,0 +1,111 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Reloading the configuration while a long-running subcommand runs.
+//!
+//! The files the configuration was read from are checked for changes to
+//! their modification times, which is cheap enough to do before every run
+//! and works the same on every platform. When one changes the configuration
+//! is loaded again, and the nodes that were added or removed are reported.
+//! A configuration that no longer loads is reported and the old one is kept.
+
+use crate::config::{Config, Node};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Loads the configuration, with the nodes narrowed down to the selected ones.
+pub type Load<'a> = dyn Fn() -> Result<Config, Box<dyn std::error::Error>> + 'a;
+
+/// Watches the files a configuration was read from.
+pub struct Reloader<'a> {
+    load: &'a Load<'a>,
+    /// Each file with its modification time when last checked, or None if
+    /// it didn't exist.
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+/// How the nodes changed when the configuration was reloaded.
+pub struct Change {
+    /// The nodes that are new, by name.
+    pub added: Vec<String>,
+    /// The nodes that are gone, by name.
+    pub removed: Vec<String>,
+}
+
+impl<'a> Reloader<'a> {
+    /// Starts watching the files the configuration was read from.
+    ///
+    /// # Arguments
+    ///
+    /// * `load` - Loads the configuration again once a file has changed.
+    /// * `config` - The configuration as first loaded.
+    pub fn new(load: &'a Load<'a>, config: &Config) -> Self {
+        let mut reloader = Reloader {
+            load,
+            files: Vec::new(),
+        };
+        reloader.watch(config);
+        reloader
+    }
+
+    /// Reloads the configuration if any of its files has changed.
+    ///
+    /// # Returns
+    ///
+    /// Returns None if no file changed, and otherwise the new configuration
+    /// and how its nodes changed, or the error if it no longer loads.
+    pub fn poll(&mut self, current: &Config) -> Option<Result<(Config, Change), String>> {
+        if !self
+            .files
+            .iter()
+            .any(|(path, modified)| modified_time(path) != *modified)
+        {
+            return None;
+        }
+
+        match (self.load)() {
+            Ok(config) => {
+                self.watch(&config);
+                let change = Change {
+                    added: missing_from(&config.cluster.nodes, &current.cluster.nodes),
+                    removed: missing_from(&current.cluster.nodes, &config.cluster.nodes),
+                };
+                Some(Ok((config, change)))
+            }
+            Err(e) => {
+                // Wait for the next change rather than retrying on every poll
+                self.watch(current);
+                Some(Err(e.to_string()))
+            }
+        }
+    }
+
+    /// Records the files a configuration was read from and their current
+    /// modification times.
+    fn watch(&mut self, config: &Config) {
+        self.files = config
+            .files
+            .iter()
+            .map(|path| (path.clone(), modified_time(path)))
+            .collect();
+    }
+}
+
+/// Returns a file's modification time, or None if it can't be read.
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Returns the names of the nodes in `nodes` that aren't in `other`.
+fn missing_from(nodes: &[Node], other: &[Node]) -> Vec<String> {
+    nodes
+        .iter()
+        .filter(|node| {
+            !other
+                .iter()
+                .any(|o| o.host == node.host && o.port == node.port)
+        })
+        .map(|node| node.to_string())
+        .collect()
+}
@@ -0,0 +1,275 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Results as JSON, for `--output json`, and as test reports, for `--report`.
+//!
+//! Each node's result is printed as one JSON object on a line of its own as
+//! the node finishes, followed by an object summarising the run, so the
+//! output can be read line by line with `jq` or by automation. Objects have
+//! a `type` of `result` or `summary` to tell them apart. Everything else the
+//! run prints, such as progress and errors, goes to standard error.
+//!
+//! Once a run is over, the same results can be written as JUnit XML or TAP,
+//! with each node a test case, so health checks run in CI show up in its
+//! test views.
+
+use crate::output;
+use crate::summary::Summary;
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime};
+
+/// How a node's run ended.
+pub enum Status<'a> {
+    /// The command ran to completion with this exit status.
+    Exited(i32),
+    /// The node timed out connecting or running the command.
+    TimedOut(&'a str),
+    /// The node couldn't be run on.
+    Error(&'a str),
+    /// The node was cancelled part way through.
+    Cancelled,
+}
+
+/// What a node's result object reports.
+pub struct NodeResult<'a> {
+    pub host: &'a str,
+    /// The command as it was run on the node, with its placeholders filled in.
+    pub command: &'a str,
+    pub status: Status<'a>,
+    pub stdout: &'a str,
+    pub stderr: &'a str,
+    /// When the node's last attempt started.
+    pub started_at: Option<SystemTime>,
+    /// How long the command ran, or the last attempt took if it didn't finish.
+    pub duration: Duration,
+    pub attempts: u32,
+}
+
+/// Returns the JSON object reporting a node's result.
+pub fn node_json(result: &NodeResult) -> Value {
+    let (status, exit_code, error) = match &result.status {
+        Status::Exited(0) => ("succeeded", Some(0), None),
+        Status::Exited(code) => ("failed", Some(*code), None),
+        Status::TimedOut(e) => ("timed_out", None, Some(*e)),
+        Status::Error(e) => ("error", None, Some(*e)),
+        Status::Cancelled => ("cancelled", None, None),
+    };
+    json!({
+        "type": "result",
+        "host": result.host,
+        "command": result.command,
+        "status": status,
+        "exit_code": exit_code,
+        "stdout": result.stdout,
+        "stderr": result.stderr,
+        "started_at": result.started_at.map(output::timestamp),
+        "duration": result.duration.as_secs_f64(),
+        "attempts": result.attempts,
+        "error": error,
+    })
+}
+
+/// Prints the summary of a run as a JSON object on one line.
+pub fn print_summary(summary: &Summary) {
+    println!("{}", summary_json(summary));
+}
+
+/// Returns the JSON object summarising a run.
+pub fn summary_json(summary: &Summary) -> Value {
+    let failed: Vec<Value> = summary
+        .failed
+        .iter()
+        .map(|(host, exit_code)| json!({ "host": host, "exit_code": exit_code }))
+        .collect();
+    let errors: Vec<Value> = summary
+        .errored
+        .iter()
+        .map(|(host, error)| json!({ "host": host, "error": error }))
+        .collect();
+    let slowest = summary
+        .slowest
+        .as_ref()
+        .map(|(host, duration)| json!({ "host": host, "duration": duration.as_secs_f64() }));
+    json!({
+        "type": "summary",
+        "succeeded": summary.succeeded,
+        "failed": failed,
+        "timed_out": summary.timed_out,
+        "errors": errors,
+        "cancelled": summary.cancelled,
+        "not_started": summary.not_started,
+        "wall_time": summary.wall_time.as_secs_f64(),
+        "slowest": slowest,
+    })
+}
+
+/// The results of a run to write a report of for CI, with each node as a
+/// test case.
+pub struct Cases<'a> {
+    /// What was run, which names the test suite.
+    pub name: &'a str,
+    /// The nodes' result objects, as `node_json` returns them, in the order
+    /// of the node list.
+    pub results: &'a [Value],
+    /// The nodes the run never got to, which are reported as skipped.
+    pub not_started: &'a [String],
+    /// When the run started.
+    pub started_at: SystemTime,
+    pub wall_time: Duration,
+}
+
+/// Returns the results as JUnit XML, with a test case for each node that
+/// fails if the command did, has an error if the node couldn't be run on,
+/// and is skipped if the node was cancelled or never started.
+pub fn junit(cases: &Cases) -> String {
+    let count = |status: &str| {
+        cases
+            .results
+            .iter()
+            .filter(|result| result["status"] == status)
+            .count()
+    };
+    let tests = cases.results.len() + cases.not_started.len();
+    let failures = count("failed");
+    let errors = count("timed_out") + count("error");
+    let skipped = count("cancelled") + cases.not_started.len();
+    let totals = format!(
+        "tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\"",
+        tests,
+        failures,
+        errors,
+        skipped,
+        cases.wall_time.as_secs_f64()
+    );
+    let name = xml_escape(cases.name);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!("<testsuites name=\"cluster_run\" {}>\n", totals));
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" {} timestamp=\"{}\">\n",
+        name,
+        totals,
+        output::timestamp(cases.started_at)
+    ));
+    for result in cases.results {
+        let text = |field: &str| result[field].as_str().unwrap_or_default().to_string();
+        xml.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(&text("host")),
+            name,
+            result["duration"].as_f64().unwrap_or_default()
+        ));
+        let message = xml_escape(&text("error"));
+        match result["status"].as_str().unwrap_or_default() {
+            "failed" => xml.push_str(&format!(
+                "      <failure message=\"exited with status {}\" type=\"exit_code\"/>\n",
+                result["exit_code"]
+            )),
+            "cancelled" => xml.push_str("      <skipped message=\"cancelled\"/>\n"),
+            status @ ("timed_out" | "error") => xml.push_str(&format!(
+                "      <error message=\"{}\" type=\"{}\"/>\n",
+                message, status
+            )),
+            _ => {}
+        }
+        for (field, element) in [("stdout", "system-out"), ("stderr", "system-err")] {
+            let output = text(field);
+            if !output.is_empty() {
+                xml.push_str(&format!(
+                    "      <{}>{}</{}>\n",
+                    element,
+                    xml_escape(&output),
+                    element
+                ));
+            }
+        }
+        xml.push_str("    </testcase>\n");
+    }
+    for host in cases.not_started {
+        xml.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"0.000\">\n",
+            xml_escape(host),
+            name
+        ));
+        xml.push_str("      <skipped message=\"not started\"/>\n");
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// Returns the results in version 13 of the Test Anything Protocol, with a
+/// test for each node. A node that didn't succeed has the reason, and its
+/// output, in a YAML block after its line.
+pub fn tap(cases: &Cases) -> String {
+    let mut tap = String::from("TAP version 13\n");
+    tap.push_str(&format!(
+        "1..{}\n",
+        cases.results.len() + cases.not_started.len()
+    ));
+    let mut number = 0;
+    for result in cases.results {
+        number += 1;
+        let host = tap_escape(result["host"].as_str().unwrap_or_default());
+        let message = match result["status"].as_str().unwrap_or_default() {
+            "succeeded" => {
+                tap.push_str(&format!("ok {} - {}\n", number, host));
+                continue;
+            }
+            "cancelled" => {
+                tap.push_str(&format!("ok {} - {} # SKIP cancelled\n", number, host));
+                continue;
+            }
+            "failed" => format!("exited with status {}", result["exit_code"]),
+            _ => result["error"].as_str().unwrap_or_default().to_string(),
+        };
+        // JSON strings are also YAML ones, which copes with any output
+        tap.push_str(&format!("not ok {} - {}\n", number, host));
+        tap.push_str("  ---\n");
+        tap.push_str(&format!("  message: {}\n", Value::from(message)));
+        tap.push_str(&format!("  status: {}\n", result["status"]));
+        tap.push_str(&format!("  duration: {}\n", result["duration"]));
+        for field in ["stdout", "stderr"] {
+            if result[field]
+                .as_str()
+                .is_some_and(|output| !output.is_empty())
+            {
+                tap.push_str(&format!("  {}: {}\n", field, result[field]));
+            }
+        }
+        tap.push_str("  ...\n");
+    }
+    for host in cases.not_started {
+        number += 1;
+        tap.push_str(&format!(
+            "ok {} - {} # SKIP not started\n",
+            number,
+            tap_escape(host)
+        ));
+    }
+    tap
+}
+
+/// Escapes text for an XML attribute or element, leaving out the control
+/// characters XML 1.0 can't hold at all.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes a test's description for TAP, where a `#` starts a directive.
+fn tap_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('#', "\\#")
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Looking up nodes' addresses, all at once and once per run.
+//!
+//! Looking each node's name up as it's connected to adds up on large
+//! clusters, so every name is looked up concurrently when the nodes are
+//! loaded, and the addresses are kept for the rest of the run. Connections,
+//! including those to jump hosts and proxies, take their addresses from here.
+//! A failed lookup is tried again the next time the name is needed, since a
+//! retry may be what gets past a passing DNS failure.
+
+use crate::config::Node;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// The most lookups to run at once.
+const LOOKUP_THREADS: usize = 32;
+
+/// The outcome of looking a name up: its addresses, or why it didn't resolve.
+type Lookup = Result<Vec<IpAddr>, String>;
+
+/// The outcome of looking up each name, by name.
+static CACHE: OnceLock<Mutex<HashMap<String, Lookup>>> = OnceLock::new();
+
+/// Returns the socket addresses of a port on a host, looking the host up
+/// unless it's an IP address or has already been looked up.
+///
+/// # Returns
+///
+/// Returns the addresses, in the order the system prefers them, or an error
+/// of kind NotFound if the host doesn't resolve.
+pub fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let cached = cache().lock().unwrap().get(host).cloned();
+    let ips = match cached {
+        Some(Ok(ips)) => Ok(ips),
+        _ => resolve(host),
+    };
+    ips.map(|ips| {
+        ips.into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect()
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))
+}
+
+/// Looks up the names of every node connected to directly, in parallel.
+/// Nodes reached through jump hosts or a proxy are skipped, since their
+/// names are looked up at the other end.
+pub fn all(nodes: &[Node]) {
+    let mut hosts: Vec<&str> = nodes
+        .iter()
+        .filter(|node| is_direct(node))
+        .map(Node::address)
+        .filter(|host| host.parse::<IpAddr>().is_err())
+        .collect();
+    {
+        let cache = cache().lock().unwrap();
+        hosts.retain(|host| !matches!(cache.get(*host), Some(Ok(_))));
+    }
+    hosts.sort();
+    hosts.dedup();
+    if hosts.is_empty() {
+        return;
+    }
+    let chunk = hosts.len().div_ceil(LOOKUP_THREADS);
+    thread::scope(|scope| {
+        for chunk in hosts.chunks(chunk) {
+            scope.spawn(move || {
+                for host in chunk {
+                    let _ = resolve(host);
+                }
+            });
+        }
+    });
+}
+
+/// Lists the nodes whose names were looked up and didn't resolve.
+///
+/// # Returns
+///
+/// Returns each node with the lookup's error, in the nodes' order.
+fn unresolved(nodes: &[Node]) -> Vec<(&Node, String)> {
+    let cache = cache().lock().unwrap();
+    nodes
+        .iter()
+        .filter(|node| is_direct(node))
+        .filter_map(|node| match cache.get(node.address()) {
+            Some(Err(e)) => Some((node, e.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Prints the nodes whose names didn't resolve, before any of them are
+/// connected to.
+pub fn warn_unresolved(nodes: &[Node]) {
+    let unresolved = unresolved(nodes);
+    if unresolved.is_empty() {
+        return;
+    }
+    let listed: Vec<String> = unresolved
+        .iter()
+        .map(|(node, e)| format!("{} ({})", node, e))
+        .collect();
+    tracing::warn!(
+        "Unable to resolve {} nodes: {}",
+        unresolved.len(),
+        listed.join(", ")
+    );
+}
+
+/// Returns true if a node is connected to directly, so its name is looked
+/// up here.
+pub fn is_direct(node: &Node) -> bool {
+    node.jump.is_none() && node.proxy.is_none()
+}
+
+/// Looks a name up and records the outcome.
+fn resolve(host: &str) -> Lookup {
+    let ips = match (host, 0).to_socket_addrs() {
+        Ok(addrs) => {
+            let ips: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+            if ips.is_empty() {
+                Err(format!("{} did not resolve", host))
+            } else {
+                Ok(ips)
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    cache()
+        .lock()
+        .unwrap()
+        .insert(host.to_string(), ips.clone());
+    ips
+}
+
+fn cache() -> &'static Mutex<HashMap<String, Lookup>> {
+    CACHE.get_or_init(Default::default)
+}
@@ -0,0 +1,182 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Retrying transient failures with exponential backoff.
+//!
+//! SSH connections fail for all sorts of passing reasons, such as DNS blips or
+//! sshd restarting. Rather than failing a node on the first error, the whole
+//! connect-and-execute attempt is repeated after a delay that doubles each time.
+//!
+//! An attempt that fails after its command has started on the node, such as
+//! by timing out, may have done some or all of the command's work already,
+//! so it's only repeated if that's been allowed with `--retry-started`:
+//! running a migration or a package install twice can do more harm than
+//! failing the node.
+
+use crate::cancel::CancelToken;
+use std::cell::Cell;
+use std::time::Duration;
+
+/// The longest we'll ever wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// How many times to retry a failed attempt and how long to wait in between.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// The number of retries after the first attempt.
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each retry after it.
+    pub backoff: Duration,
+    /// Whether an attempt that failed after starting its command is retried.
+    pub started: bool,
+}
+
+/// Marks an attempt as having started its command on the node, so a failure
+/// after that point is only retried if the policy allows it.
+#[derive(Default)]
+pub struct Started(Cell<bool>);
+
+impl Started {
+    /// Notes that the attempt's command has started.
+    pub fn mark(&self) {
+        self.0.set(true);
+    }
+}
+
+impl RetryPolicy {
+    /// Returns how long to wait before the given retry, counting from one.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+
+    /// Runs `attempt` until it succeeds, the retries are used up, the run is
+    /// cancelled, or it fails after starting its command and the policy
+    /// doesn't allow retrying that.
+    ///
+    /// # Arguments
+    ///
+    /// * `cancel` - Stops any further attempts, including during the backoff delay.
+    /// * `attempt` - The work to perform, called with the attempt number starting
+    ///   at one, and what to mark once its command has started, if it has one.
+    /// * `on_retry` - Called with the failed attempt's number, its error, and the
+    ///   delay before the next attempt.
+    ///
+    /// # Returns
+    ///
+    /// Returns the result of the last attempt along with the number of attempts made.
+    pub async fn run<T, E>(
+        &self,
+        cancel: &CancelToken,
+        mut attempt: impl AsyncFnMut(u32, &Started) -> Result<T, E>,
+        mut on_retry: impl FnMut(u32, &E, Duration),
+    ) -> (Result<T, E>, u32) {
+        let mut number = 1;
+        loop {
+            let started = Started::default();
+            let result = attempt(number, &started).await;
+            let retryable = self.started || !started.0.get();
+            match result {
+                Err(e) if number <= self.retries && retryable && !cancel.is_cancelled() => {
+                    let delay = self.delay(number);
+                    on_retry(number, &e, delay);
+                    if !cancel.pause(delay).await {
+                        return (Err(e), number);
+                    }
+                    number += 1;
+                }
+                result => return (result, number),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(retries: u32, started: bool) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            backoff: Duration::ZERO,
+            started,
+        }
+    }
+
+    /// Runs attempts that fail until the last, marking each as started if
+    /// `start` says to, and returns the outcome and the retries made.
+    async fn attempts(
+        policy: RetryPolicy,
+        failures: u32,
+        start: bool,
+    ) -> (Result<u32, String>, u32, Vec<u32>) {
+        let mut retried = Vec::new();
+        let (result, number) = policy
+            .run(
+                &CancelToken::default(),
+                async |number, started: &Started| {
+                    if start {
+                        started.mark();
+                    }
+                    match number > failures {
+                        true => Ok(number),
+                        false => Err(format!("attempt {} failed", number)),
+                    }
+                },
+                |number, _, _| retried.push(number),
+            )
+            .await;
+        (result, number, retried)
+    }
+
+    #[test]
+    fn doubles_the_delay() {
+        let policy = RetryPolicy {
+            retries: 20,
+            backoff: Duration::from_secs(1),
+            started: false,
+        };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(20), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn retries_failures_before_the_command_starts() {
+        assert_eq!(
+            attempts(policy(3, false), 2, false).await,
+            (Ok(3), 3, vec![1, 2])
+        );
+        assert_eq!(
+            attempts(policy(1, false), 2, false).await,
+            (Err("attempt 2 failed".to_string()), 2, vec![1])
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_started_commands_only_if_allowed() {
+        assert_eq!(
+            attempts(policy(3, false), 2, true).await,
+            (Err("attempt 1 failed".to_string()), 1, vec![])
+        );
+        assert_eq!(
+            attempts(policy(3, true), 2, true).await,
+            (Ok(3), 3, vec![1, 2])
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_once_cancelled() {
+        let cancel = CancelToken::default();
+        cancel.cancel();
+        let (result, number) = policy(3, false)
+            .run(
+                &cancel,
+                async |_, _: &Started| Err::<(), _>("failed"),
+                |_, _, _| {},
+            )
+            .await;
+        assert_eq!((result, number), (Err("failed"), 1));
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Secrets kept in AWS Secrets Manager.
+//!
+//! A secret is written as `{ aws = "<name or ARN>" }`, with `field` naming
+//! the key to use when the secret is a JSON object, as secrets made in the
+//! AWS console's key/value editor are. It's read with the AWS CLI, so its
+//! usual credentials and profiles apply, and `region` and `profile` can be
+//! given as for the EC2 inventory.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::process::Command;
+
+/// A secret in AWS Secrets Manager.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reference {
+    /// The secret's name or ARN.
+    pub aws: String,
+    /// The key holding the value, if the secret is a JSON object.
+    pub field: Option<String>,
+    /// The region the secret is in, if not the CLI's default.
+    pub region: Option<String>,
+    /// The AWS CLI profile to use, if not the default.
+    pub profile: Option<String>,
+}
+
+/// Names the secret in error messages.
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AWS secret {}", self.aws)?;
+        if let Some(field) = &self.field {
+            write!(f, " field {}", field)?;
+        }
+        if let Some(region) = &self.region {
+            write!(f, " in {}", region)?;
+        }
+        if let Some(profile) = &self.profile {
+            write!(f, " with profile {}", profile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the current version of a secret from AWS Secrets Manager.
+///
+/// # Returns
+///
+/// Returns the secret's value, or the value of its field, or an error if
+/// the AWS CLI can't be run or fails, or the secret has no such field.
+pub fn fetch(reference: &Reference) -> Result<String, String> {
+    // Step 1: Ask the AWS CLI for the secret
+    let mut command = Command::new("aws");
+    command.args(["secretsmanager", "get-secret-value", "--output", "json"]);
+    command.arg("--secret-id").arg(&reference.aws);
+    if let Some(region) = &reference.region {
+        command.args(["--region", region]);
+    }
+    if let Some(profile) = &reference.profile {
+        command.args(["--profile", profile]);
+    }
+    let output = command
+        .output()
+        .map_err(|e| format!("couldn't run the AWS CLI: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "the AWS CLI failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("the AWS CLI printed invalid JSON: {}", e))?;
+    let secret = response["SecretString"]
+        .as_str()
+        .ok_or("the secret is binary rather than a string")?;
+
+    // Step 2: Pick out the field, if one is asked for
+    let Some(field) = &reference.field else {
+        return Ok(secret.to_string());
+    };
+    let fields: Value = serde_json::from_str(secret).map_err(|_| {
+        format!(
+            "the secret isn't a JSON object, so it has no field {}",
+            field
+        )
+    })?;
+    match fields.get(field) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(value) => Ok(value.to_string()),
+        None => Err(format!("the secret has no field {}", field)),
+    }
+}
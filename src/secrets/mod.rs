@@ -0,0 +1,218 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Secrets kept in the configuration file, such as sudo passwords and API
+//! tokens.
+//!
+//! A secret may be written in plain text, or encrypted with age as `age:`
+//! followed by the body of age's ASCII armor with its line breaks removed,
+//! which `cluster_run secret encrypt` prints. Encrypted secrets are only
+//! decrypted when they're used, by running `age` with the identity file in
+//! `CLUSTER_RUN_AGE_IDENTITY`, or `cluster_run/age-identity.txt` in the
+//! user's config directory. Leaving decryption to age keeps the identity in
+//! whatever form age supports, including passphrase-protected files and
+//! plugins.
+//!
+//! A secret can also be kept out of the config altogether, in a secrets
+//! manager it's fetched from when it's first used: HashiCorp Vault, written
+//! as `{ vault = "<path>" }`, or AWS Secrets Manager, written as
+//! `{ aws = "<name>" }`. Each is fetched once per run.
+
+mod aws;
+mod vault;
+
+use crate::redact;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+/// The prefix marking an encrypted secret.
+const PREFIX: &str = "age:";
+
+/// The environment variable naming the age identity file.
+const IDENTITY_VAR: &str = "CLUSTER_RUN_AGE_IDENTITY";
+
+/// The lines around the body of an ASCII-armored age file.
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+
+/// The length of each line in the body of the armor.
+const ARMOR_WIDTH: usize = 64;
+
+/// The secrets fetched from secrets managers, by where they're kept, so each
+/// is fetched once per run.
+static FETCHED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// A secret value from the configuration file.
+#[derive(Clone, Deserialize)]
+#[serde(
+    untagged,
+    expecting = "a string, or a table with `vault` or `aws` saying where the secret is kept"
+)]
+pub enum Secret {
+    /// The secret itself, in plain text or encrypted.
+    Value(String),
+    /// A secret kept in HashiCorp Vault.
+    Vault(vault::Reference),
+    /// A secret kept in AWS Secrets Manager.
+    Aws(aws::Reference),
+}
+
+impl Secret {
+    /// Returns the secret in plain text, decrypting it if it's encrypted, or
+    /// fetching it if it's kept in a secrets manager.
+    ///
+    /// The value is masked in the output from then on.
+    ///
+    /// # Returns
+    ///
+    /// Returns the plain text, or an error if decryption or fetching fails.
+    pub fn reveal(&self) -> Result<String, String> {
+        let value = match self {
+            Secret::Value(value) => match value.strip_prefix(PREFIX) {
+                Some(body) => decrypt(body),
+                None => Ok(value.clone()),
+            },
+            Secret::Vault(reference) => fetch_once(reference, vault::fetch),
+            Secret::Aws(reference) => fetch_once(reference, aws::fetch),
+        }?;
+        redact::use_secret(&value);
+        Ok(value)
+    }
+
+    /// Returns the secret if it's written in the config in plain text, for
+    /// checking it without revealing anything.
+    pub fn plain(&self) -> Option<&str> {
+        match self {
+            Secret::Value(value) if !value.starts_with(PREFIX) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Fetches a secret from a secrets manager the first time it's used, and
+/// returns the value fetched then every time after.
+fn fetch_once<R: fmt::Display>(
+    reference: &R,
+    fetch: fn(&R) -> Result<String, String>,
+) -> Result<String, String> {
+    let place = reference.to_string();
+    let fetched = FETCHED.get_or_init(Default::default);
+    if let Some(value) = fetched.lock().unwrap().get(&place) {
+        return Ok(value.clone());
+    }
+    let value = fetch(reference).map_err(|e| format!("unable to fetch the {}: {}", place, e))?;
+    fetched.lock().unwrap().insert(place, value.clone());
+    Ok(value)
+}
+
+/// Returns the path of the age identity file.
+pub fn identity_path() -> Result<PathBuf, String> {
+    if let Some(path) = env::var_os(IDENTITY_VAR) {
+        return Ok(PathBuf::from(path));
+    }
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").ok_or("Unable to determine home directory")?)
+            .join(".config"),
+    };
+    Ok(dir.join("cluster_run").join("age-identity.txt"))
+}
+
+/// Decrypts the body of an encrypted secret.
+fn decrypt(body: &str) -> Result<String, String> {
+    let identity = identity_path()?;
+    if !identity.exists() {
+        return Err(format!(
+            "the config has an encrypted secret, but the age identity file {} doesn't exist; set {} to use another",
+            identity.display(),
+            IDENTITY_VAR
+        ));
+    }
+
+    // age only accepts armor with its original line lengths
+    let mut armored = format!("{}\n", ARMOR_BEGIN);
+    for line in body.as_bytes().chunks(ARMOR_WIDTH) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push_str(ARMOR_END);
+    armored.push('\n');
+
+    let mut command = Command::new("age");
+    command.arg("--decrypt").arg("--identity").arg(&identity);
+    let plain = run_age(command, armored.as_bytes())
+        .map_err(|e| format!("unable to decrypt a secret: {}", e))?;
+    String::from_utf8(plain).map_err(|_| "a decrypted secret isn't valid UTF-8".to_string())
+}
+
+/// Encrypts a value for the config file.
+///
+/// # Arguments
+///
+/// * `value` - The plain text to encrypt.
+/// * `recipients` - The age recipients who can decrypt it. Empty means the
+///   public key of the identity file, so that this machine can decrypt it.
+///
+/// # Returns
+///
+/// Returns the secret as it should be written in the config file.
+pub fn encrypt(value: &str, recipients: &[String]) -> Result<String, String> {
+    let mut recipients = recipients.to_vec();
+    if recipients.is_empty() {
+        let identity = identity_path()?;
+        let mut keygen = Command::new("age-keygen");
+        keygen.arg("-y").arg(&identity);
+        let public = run_age(keygen, &[]).map_err(|e| {
+            format!(
+                "unable to read the public key of {}: {}",
+                identity.display(),
+                e
+            )
+        })?;
+        recipients.extend(String::from_utf8_lossy(&public).lines().map(str::to_string));
+    }
+
+    let mut command = Command::new("age");
+    command.arg("--encrypt").arg("--armor");
+    for recipient in &recipients {
+        command.arg("--recipient").arg(recipient);
+    }
+    let armored = run_age(command, value.as_bytes())?;
+    let body: String = String::from_utf8_lossy(&armored)
+        .lines()
+        .filter(|line| *line != ARMOR_BEGIN && *line != ARMOR_END)
+        .collect();
+    Ok(format!("{}{}", PREFIX, body))
+}
+
+/// Runs age or age-keygen with the given input, returning its output.
+fn run_age(mut command: Command, input: &[u8]) -> Result<Vec<u8>, String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run {}: {}", program, e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input)
+        .map_err(|e| e.to_string())?;
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Secrets kept in HashiCorp Vault's key/value store.
+//!
+//! A secret is written as `{ vault = "<path>" }`, with `field` naming which
+//! of the secret's fields to use when it has more than one. It's read with
+//! the Vault CLI, so `VAULT_ADDR`, `VAULT_TOKEN` or the token left by
+//! `vault login`, and `VAULT_NAMESPACE` apply as usual, and both versions of
+//! the key/value engine work.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::process::Command;
+
+/// A secret in Vault.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reference {
+    /// The secret's path, including the engine's mount, such as
+    /// `secret/cluster/sudo`.
+    pub vault: String,
+    /// The field holding the value, if the secret has more than one.
+    pub field: Option<String>,
+}
+
+/// Names the secret in error messages.
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Vault secret {}", self.vault)?;
+        if let Some(field) = &self.field {
+            write!(f, " field {}", field)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a secret from Vault.
+///
+/// # Returns
+///
+/// Returns the value of the secret's field, or an error if the Vault CLI
+/// can't be run or fails, or the field can't be told apart from the others.
+pub fn fetch(reference: &Reference) -> Result<String, String> {
+    // Step 1: Ask the Vault CLI for every field of the secret
+    let output = Command::new("vault")
+        .args(["kv", "get", "-format=json"])
+        .arg(&reference.vault)
+        .output()
+        .map_err(|e| format!("couldn't run the Vault CLI: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "the Vault CLI failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let response: Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("the Vault CLI printed invalid JSON: {}", e))?;
+
+    // Step 2: Pick out the field; version 2 of the engine nests the fields
+    // beside the secret's metadata
+    let data = &response["data"];
+    let fields = match (data.get("data"), data.get("metadata")) {
+        (Some(fields), Some(_)) => fields,
+        _ => data,
+    };
+    let fields = fields
+        .as_object()
+        .ok_or("the Vault CLI printed a secret without fields")?;
+    let value = match &reference.field {
+        Some(field) => fields
+            .get(field)
+            .ok_or_else(|| format!("the secret has no field {}", field))?,
+        None if fields.len() == 1 => fields.values().next().unwrap(),
+        None => {
+            let names: Vec<&str> = fields.keys().map(String::as_str).collect();
+            return Err(format!(
+                "the secret has the fields {}; set `field` to the one to use",
+                names.join(", ")
+            ));
+        }
+    };
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        value => Ok(value.to_string()),
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Narrowing a run down to a subset of the configured nodes.
+//!
+//! Nodes are selected with patterns, which are node names, ranges of names
+//! written as in the config, such as `node[001-064]`, or globs where `*`
+//! matches any run of characters, `?` matches any single one, and `[a-c]`
+//! or `[!a-c]` matches any one in or not in a class, and with group
+//! expressions. Brackets that hold a range are read as one rather than as a
+//! class, and patterns are separated by commas outside brackets. A group expression is a group name, or group
+//! names joined by space-separated operators applied from left to right:
+//! `a + b` is the nodes in either group, `a & b` those in both, and `a - b`
+//! those in `a` but not `b`. The group `all` holds every node unless the
+//! config defines its own.
+//!
+//! Nodes can also be selected by their labels, with conditions that must all
+//! hold: `key=value`, `key!=value`, `key` for a node that has the label and
+//! `!key` for one that doesn't. Values may be globs.
+
+use crate::config::{ClusterConfig, Node};
+use crate::inventory;
+
+/// The name of the group that holds every node.
+const ALL: &str = "all";
+
+/// Which of the configured nodes a run should cover.
+pub struct Selection<'a> {
+    /// A group expression the nodes must be in. None means every node.
+    pub group: Option<&'a str>,
+    /// Label conditions a node must meet all of.
+    pub selector: &'a [String],
+    /// Patterns a node must match at least one of. Empty means every node.
+    pub limit: &'a [String],
+    /// Patterns a node must match none of.
+    pub exclude: &'a [String],
+}
+
+impl Selection<'_> {
+    /// Filters the nodes down to the selected ones, keeping their order.
+    ///
+    /// # Returns
+    ///
+    /// Returns the selected nodes, or an error if a `--limit` pattern without
+    /// wildcards names no node that's configured, the group expression is
+    /// invalid, or nothing is selected.
+    pub fn apply(&self, cluster: &ClusterConfig) -> Result<Vec<Node>, String> {
+        let nodes = &cluster.nodes;
+        let in_group = match self.group {
+            Some(expression) => evaluate(expression, cluster)?,
+            None => vec![true; nodes.len()],
+        };
+        let conditions = self
+            .selector
+            .iter()
+            .map(|condition| Condition::parse(condition))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let limit = patterns(self.limit)?;
+        let exclude = patterns(self.exclude)?;
+
+        // A plain name that matches nothing is most likely a typo
+        for (pattern, names) in &limit {
+            let named = |name: &String| nodes.iter().any(|node| node.is_named(name));
+            if !names.iter().any(|name| is_glob(name) || named(name)) {
+                return Err(match names.len() {
+                    1 => format!("node {} is not in the cluster", pattern),
+                    _ => format!("none of the nodes {} are in the cluster", pattern),
+                });
+            }
+        }
+
+        // A node's address and alias are both names for it
+        let matches_any = |patterns: &[(String, Vec<String>)], node: &Node| {
+            patterns.iter().flat_map(|(_, names)| names).any(|p| {
+                glob_match(p, &node.host) || node.alias.as_deref().is_some_and(|a| glob_match(p, a))
+            })
+        };
+        let selected: Vec<Node> = nodes
+            .iter()
+            .zip(in_group)
+            .filter(|(_, in_group)| *in_group)
+            .map(|(node, _)| node)
+            .filter(|node| conditions.iter().all(|condition| condition.matches(node)))
+            .filter(|node| limit.is_empty() || matches_any(&limit, node))
+            .filter(|node| !matches_any(&exclude, node))
+            .cloned()
+            .collect();
+        if selected.is_empty() {
+            return Err("no nodes match the selection".to_string());
+        }
+        Ok(selected)
+    }
+}
+
+/// Works out which nodes a group expression covers.
+///
+/// # Returns
+///
+/// Returns whether each node, by position, is covered, or an error if the
+/// expression is malformed or names a group that doesn't exist.
+fn evaluate(expression: &str, cluster: &ClusterConfig) -> Result<Vec<bool>, String> {
+    let members = |name: &str| -> Result<Vec<bool>, String> {
+        if !cluster.groups.contains_key(name) {
+            if name == ALL {
+                return Ok(vec![true; cluster.nodes.len()]);
+            }
+            return Err(format!("group {} is not in the cluster", name));
+        }
+        Ok(cluster
+            .nodes
+            .iter()
+            .map(|node| node.groups.iter().any(|group| group == name))
+            .collect())
+    };
+
+    // Terms alternate between group names and operators
+    let malformed = || format!("invalid group expression '{}'", expression);
+    let mut terms = expression.split_whitespace();
+    let mut selected = members(terms.next().ok_or_else(malformed)?)?;
+    while let Some(operator) = terms.next() {
+        let operand = members(terms.next().ok_or_else(malformed)?)?;
+        let combine: fn(bool, bool) -> bool = match operator {
+            "+" => |a, b| a || b,
+            "&" => |a, b| a && b,
+            "-" => |a, b| a && !b,
+            _ => return Err(malformed()),
+        };
+        for (selected, operand) in selected.iter_mut().zip(operand) {
+            *selected = combine(*selected, operand);
+        }
+    }
+    Ok(selected)
+}
+
+/// A condition on a node's labels.
+enum Condition<'a> {
+    /// The label has a value matching the glob.
+    Equals(&'a str, &'a str),
+    /// The label is missing or has a value not matching the glob.
+    NotEquals(&'a str, &'a str),
+    /// The label is present.
+    Has(&'a str),
+    /// The label is missing.
+    Lacks(&'a str),
+}
+
+impl<'a> Condition<'a> {
+    /// Parses one of the comma-separated conditions given to `--selector`.
+    fn parse(condition: &'a str) -> Result<Self, String> {
+        let condition = condition.trim();
+        let parsed = if let Some((key, value)) = condition.split_once("!=") {
+            Condition::NotEquals(key.trim(), value.trim())
+        } else if let Some((key, value)) = condition.split_once('=') {
+            Condition::Equals(key.trim(), value.trim())
+        } else if let Some(key) = condition.strip_prefix('!') {
+            Condition::Lacks(key.trim())
+        } else {
+            Condition::Has(condition)
+        };
+        match parsed {
+            Condition::Equals("", _)
+            | Condition::NotEquals("", _)
+            | Condition::Has("")
+            | Condition::Lacks("") => Err(format!("invalid label condition '{}'", condition)),
+            parsed => Ok(parsed),
+        }
+    }
+
+    /// Returns true if the node's labels meet the condition.
+    fn matches(&self, node: &Node) -> bool {
+        let label = |key: &str| node.labels.get(key).map(String::as_str);
+        match *self {
+            Condition::Equals(key, value) => label(key).is_some_and(|v| glob_match(value, v)),
+            Condition::NotEquals(key, value) => !label(key).is_some_and(|v| glob_match(value, v)),
+            Condition::Has(key) => label(key).is_some(),
+            Condition::Lacks(key) => label(key).is_none(),
+        }
+    }
+}
+
+/// Splits the `--limit` or `--exclude` values into patterns at the commas
+/// outside brackets, and expands the ranges among them.
+///
+/// # Returns
+///
+/// Returns each pattern with the names or globs it stands for, which are
+/// just the pattern itself unless it's a range, or an error if a range
+/// expands to too many names.
+fn patterns(values: &[String]) -> Result<Vec<(String, Vec<String>)>, String> {
+    let mut patterns = Vec::new();
+    for value in values {
+        let mut depth = 0usize;
+        let mut start = 0;
+        for (at, c) in value.char_indices().chain([(value.len(), ',')]) {
+            match c {
+                '[' => depth += 1,
+                ']' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 || at == value.len() => {
+                    let pattern = &value[start..at];
+                    start = at + 1;
+                    if pattern.is_empty() {
+                        continue;
+                    }
+                    patterns.push((pattern.to_string(), expand(pattern)?));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(patterns)
+}
+
+/// Expands a pattern whose brackets all hold ranges of numbers, such as
+/// `node[001-064]`, into the names in them, leaving any other pattern as
+/// it is for glob matching.
+fn expand(pattern: &str) -> Result<Vec<String>, String> {
+    let mut bodies = pattern.split('[').skip(1).peekable();
+    if bodies.peek().is_none() {
+        return Ok(vec![pattern.to_string()]);
+    }
+    let is_range = bodies.all(|rest| match rest.split_once(']') {
+        Some((body, _)) => {
+            body.starts_with(|c: char| c.is_ascii_digit())
+                && body
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || c == '-' || c == ',')
+        }
+        None => false,
+    });
+    match is_range {
+        true => inventory::expand_range(pattern),
+        false => Ok(vec![pattern.to_string()]),
+    }
+}
+
+/// Returns true if the pattern contains wildcards.
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '['])
+}
+
+/// Returns true if `text` matches the glob `pattern` in its entirety.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Match greedily, backtracking to the most recent `*` on a mismatch
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&'*') {
+            backtrack = Some((p, t));
+            p += 1;
+            continue;
+        }
+        match match_char(&pattern[p..], text[t]) {
+            Some(width) => {
+                p += width;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches a character against what starts a pattern: a `?`, a class such
+/// as `[a-c]` or `[!0-9]`, or a character standing for itself. A `[`
+/// without a `]` to close it stands for itself.
+///
+/// # Returns
+///
+/// Returns how many characters of the pattern matched, or None if it didn't
+/// match.
+fn match_char(pattern: &[char], c: char) -> Option<usize> {
+    match *pattern.first()? {
+        '?' => Some(1),
+        '[' => {
+            // A `]` first in the class is one of its members
+            let negated = matches!(pattern.get(1), Some('!' | '^'));
+            let start = if negated { 2 } else { 1 };
+            let Some(end) = pattern
+                .get(start + 1..)
+                .and_then(|rest| rest.iter().position(|&c| c == ']'))
+                .map(|offset| start + 1 + offset)
+            else {
+                return (c == '[').then_some(1);
+            };
+            let members = &pattern[start..end];
+            let mut found = false;
+            let mut i = 0;
+            while i < members.len() {
+                if i + 2 < members.len() && members[i + 1] == '-' {
+                    found |= (members[i]..=members[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= members[i] == c;
+                    i += 1;
+                }
+            }
+            (found != negated).then_some(end + 1)
+        }
+        p => (p == c).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(glob_match("gpu-*", "gpu-01"));
+        assert!(glob_match("gpu-*", "gpu-"));
+        assert!(glob_match("*-01", "gpu-01"));
+        assert!(glob_match("*gpu*", "rack1-gpu-01"));
+        assert!(glob_match("g*u*1", "gpu-01"));
+        assert!(glob_match("**", ""));
+        assert!(!glob_match("gpu-*", "cpu-01"));
+        assert!(!glob_match("*-02", "gpu-01"));
+
+        assert!(glob_match("gpu-0?", "gpu-01"));
+        assert!(glob_match("???", "gpu"));
+        assert!(!glob_match("gpu-0?", "gpu-0"));
+        assert!(!glob_match("gpu-0?", "gpu-011"));
+        assert!(glob_match("gpu-?*", "gpu-011"));
+
+        // Backtracking past a near miss
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("a*b*c", "abbbc"));
+        assert!(!glob_match("a*b*c", "abbb"));
+    }
+
+    #[test]
+    fn matches_character_classes() {
+        assert!(glob_match("gpu-0[1-4]", "gpu-03"));
+        assert!(!glob_match("gpu-0[1-4]", "gpu-05"));
+        assert!(glob_match("gpu-0[157]", "gpu-05"));
+        assert!(!glob_match("gpu-0[157]", "gpu-06"));
+        assert!(glob_match("[a-cx]pu", "xpu"));
+        assert!(glob_match("gpu-0[!1-4]", "gpu-05"));
+        assert!(!glob_match("gpu-0[!1-4]", "gpu-02"));
+        assert!(glob_match("gpu-0[^1]", "gpu-02"));
+        assert!(glob_match("*[0-9]", "rack1-gpu-7"));
+        assert!(!glob_match("[0-9]*", "gpu-7"));
+
+        // A `]` first is a member, and a `[` left open is literal
+        assert!(glob_match("a[]]b", "a]b"));
+        assert!(glob_match("a[!]]b", "axb"));
+        assert!(glob_match("a[b", "a[b"));
+        assert!(!glob_match("a[b", "ab"));
+    }
+
+    #[test]
+    fn matches_empty_patterns() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "gpu-01"));
+        assert!(!glob_match("gpu-01", ""));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    /// Returns a cluster of four nodes, gpu-1 to gpu-4, with gpu-1 and gpu-2
+    /// in `a`, gpu-2 and gpu-3 in `b`, gpu-3 and gpu-4 in `c`, and gpu-4
+    /// labelled for an older rack.
+    fn cluster() -> ClusterConfig {
+        let groups: [(&str, &[&str]); 3] = [
+            ("a", &["gpu-1", "gpu-2"]),
+            ("b", &["gpu-2", "gpu-3"]),
+            ("c", &["gpu-3", "gpu-4"]),
+        ];
+        let mut cluster = ClusterConfig::default();
+        for number in 1..=4 {
+            let mut node = Node::from(format!("gpu-{}", number).as_str());
+            node.groups = groups
+                .iter()
+                .filter(|(_, members)| members.contains(&node.host.as_str()))
+                .map(|(group, _)| group.to_string())
+                .collect();
+            node.labels = BTreeMap::from([("rack".to_string(), format!("r{}", number % 2))]);
+            if number == 4 {
+                node.labels.insert("legacy".to_string(), "true".to_string());
+            }
+            cluster.nodes.push(node);
+        }
+        for (group, members) in groups {
+            let members = members.iter().map(|member| member.to_string()).collect();
+            cluster.groups.insert(group.to_string(), members);
+        }
+        cluster
+    }
+
+    /// Returns the nodes a group expression covers, by name.
+    fn covered(expression: &str) -> Vec<String> {
+        let cluster = cluster();
+        let covered = evaluate(expression, &cluster).unwrap();
+        cluster
+            .nodes
+            .iter()
+            .zip(covered)
+            .filter(|(_, covered)| *covered)
+            .map(|(node, _)| node.host.clone())
+            .collect()
+    }
+
+    #[test]
+    fn combines_groups() {
+        assert_eq!(covered("a"), ["gpu-1", "gpu-2"]);
+        assert_eq!(covered("a + c"), ["gpu-1", "gpu-2", "gpu-3", "gpu-4"]);
+        assert_eq!(covered("a & b"), ["gpu-2"]);
+        assert_eq!(covered("a - b"), ["gpu-1"]);
+        assert_eq!(covered("all - b"), ["gpu-1", "gpu-4"]);
+        assert_eq!(covered("a & c"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn applies_operators_from_left_to_right() {
+        // None binds tighter than another: a + b & c is (a + b) & c, not
+        // a + (b & c)
+        assert_eq!(covered("a + b & c"), ["gpu-3"]);
+        assert_eq!(covered("c & b + a"), ["gpu-1", "gpu-2", "gpu-3"]);
+        assert_eq!(covered("a + c - b"), ["gpu-1", "gpu-4"]);
+        assert_eq!(covered("a - b + c"), ["gpu-1", "gpu-3", "gpu-4"]);
+        assert_eq!(covered("all - a - c"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_malformed_group_expressions() {
+        let error = |expression: &str| evaluate(expression, &cluster()).err().unwrap();
+        assert_eq!(error(""), "invalid group expression ''");
+        assert_eq!(error("a +"), "invalid group expression 'a +'");
+        assert_eq!(error("a b"), "invalid group expression 'a b'");
+        assert_eq!(error("a | b"), "invalid group expression 'a | b'");
+        assert_eq!(error("a+b"), "group a+b is not in the cluster");
+        assert_eq!(error("a + d"), "group d is not in the cluster");
+    }
+
+    /// Returns the nodes a selection picks, by name.
+    fn selected(selection: Selection) -> Result<Vec<String>, String> {
+        let nodes = selection.apply(&cluster())?;
+        Ok(nodes.into_iter().map(|node| node.host).collect())
+    }
+
+    #[test]
+    fn selects_by_labels_limits_and_exclusions() {
+        let strings =
+            |items: &[&str]| -> Vec<String> { items.iter().map(|item| item.to_string()).collect() };
+        let selection = |selector: &[String], limit: &[String], exclude: &[String]| {
+            selected(Selection {
+                group: None,
+                selector,
+                limit,
+                exclude,
+            })
+        };
+        let none = &[];
+        assert_eq!(
+            selection(&strings(&["rack=r1"]), none, none).unwrap(),
+            ["gpu-1", "gpu-3"]
+        );
+        assert_eq!(
+            selection(&strings(&["rack!=r1", "!legacy"]), none, none).unwrap(),
+            ["gpu-2"]
+        );
+        assert_eq!(
+            selection(&strings(&["legacy", "rack=r*"]), none, none).unwrap(),
+            ["gpu-4"]
+        );
+        assert_eq!(
+            selection(
+                none,
+                &strings(&["gpu-[1-3]", "gpu-4"]),
+                &strings(&["gpu-2"])
+            )
+            .unwrap(),
+            ["gpu-1", "gpu-3", "gpu-4"]
+        );
+        assert_eq!(
+            selection(none, &strings(&["gpu-9"]), none).err().unwrap(),
+            "node gpu-9 is not in the cluster"
+        );
+        assert_eq!(
+            selection(none, &strings(&["cpu-*"]), none).err().unwrap(),
+            "no nodes match the selection"
+        );
+        assert_eq!(
+            selection(&strings(&["=r1"]), none, none).err().unwrap(),
+            "invalid label condition '=r1'"
+        );
+    }
+
+    #[test]
+    fn reads_brackets_with_numbers_as_ranges() {
+        let mut cluster = ClusterConfig::default();
+        for host in ["node0", "node1", "node001", "node002", "node003", "node010"] {
+            cluster.nodes.push(Node::from(host));
+        }
+        let limited = |limit: &str, exclude: &str| {
+            let limit = [limit.to_string()];
+            let exclude: Vec<String> = [exclude.to_string()]
+                .into_iter()
+                .filter(|e| !e.is_empty())
+                .collect();
+            let selection = Selection {
+                group: None,
+                selector: &[],
+                limit: &limit,
+                exclude: &exclude,
+            };
+            selection
+                .apply(&cluster)
+                .map(|nodes| nodes.into_iter().map(|node| node.host).collect::<Vec<_>>())
+        };
+
+        // Not node0 and node1, as a class would match
+        assert_eq!(
+            limited("node[001-064]", "").unwrap(),
+            ["node001", "node002", "node003", "node010"]
+        );
+        // Commas inside brackets are part of the range
+        assert_eq!(
+            limited("node[001-002,010],node0", "").unwrap(),
+            ["node0", "node001", "node002", "node010"]
+        );
+        assert_eq!(
+            limited("node*", "node[002-010]").unwrap(),
+            ["node0", "node1", "node001"]
+        );
+        // Other brackets are still classes
+        assert_eq!(limited("node[!0]*", "").unwrap(), ["node1"]);
+        assert_eq!(limited("node[01a]", "").unwrap(), ["node0", "node1"]);
+        // but only numbers makes a range, as in the config
+        assert_eq!(
+            limited("node[01]", "").err().unwrap(),
+            "node node[01] is not in the cluster"
+        );
+        assert_eq!(
+            limited("node[100-101]", "").err().unwrap(),
+            "none of the nodes node[100-101] are in the cluster"
+        );
+        assert_eq!(
+            limited("node[3-1]", "").err().unwrap(),
+            "invalid range [3-1] in node[3-1]"
+        );
+    }
+}
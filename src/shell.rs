@@ -0,0 +1,169 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Helpers for building remote shell command lines.
+
+/// Quotes a string so the remote shell treats it as a single literal word.
+///
+/// Strings made up only of characters the shell never interprets are returned
+/// as-is; anything else is wrapped in single quotes.
+pub fn quote(word: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_plain) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
+/// Prefixes a command so it runs in `cwd` with extra environment variables.
+///
+/// The command isn't run at all if the directory can't be entered. Variables
+/// are exported, so they apply to every part of a compound command.
+///
+/// # Arguments
+///
+/// * `command` - The command line to run.
+/// * `cwd` - The directory to run it in, or None for the login directory.
+/// * `env` - The variables to set, as names and values.
+pub fn with_env(command: &str, cwd: Option<&str>, env: &[(String, String)]) -> String {
+    let mut wrapped = String::new();
+    if let Some(cwd) = cwd {
+        wrapped.push_str(&format!("cd {} || exit 1; ", quote(cwd)));
+    }
+    if !env.is_empty() {
+        let assignments: Vec<String> = env
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, quote(value)))
+            .collect();
+        wrapped.push_str(&format!("export {}; ", assignments.join(" ")));
+    }
+    wrapped.push_str(command);
+    wrapped
+}
+
+/// How a command is elevated to root on the node.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Sudo {
+    /// Run the command as the login user.
+    Off,
+    /// Run the command with `sudo -n`, which fails rather than asking for a password.
+    Passwordless,
+    /// Run the command with `sudo -A`, given the password on the first line
+    /// of the command's input, which the command itself never sees.
+    Password,
+}
+
+impl Sudo {
+    /// Wraps a command so it runs as root.
+    ///
+    /// The command is run by a root shell, so compound commands and any
+    /// directory or environment prefix are elevated as a whole.
+    pub fn wrap(self, command: &str) -> String {
+        match self {
+            Sudo::Off => command.to_string(),
+            Sudo::Passwordless => format!("sudo -n -- sh -c {}", quote(command)),
+            Sudo::Password => format!(
+                "{} sh -c {}{}",
+                ASK_SUDO_PASSWORD,
+                quote(command),
+                AFTER_SUDO
+            ),
+        }
+    }
+}
+
+/// Reads the sudo password from the first line of input and runs `sudo -A`
+/// with a helper that answers with it, so the password is only given if
+/// sudo asks for it and the rest of the input is left for the command,
+/// which `sudo -S` would otherwise be handed whenever it doesn't ask.
+const ASK_SUDO_PASSWORD: &str = r#"IFS= read -r p || exit 255; a=$(mktemp "${TMPDIR:-/tmp}/cluster_run-askpass.XXXXXX") || exit 255; printf '#!/bin/sh\nprintf "%%s\\n" "$CLUSTER_RUN_SUDO_PASSWORD"\n' > "$a" && chmod 700 "$a" || { rm -f "$a"; exit 255; }; CLUSTER_RUN_SUDO_PASSWORD=$p SUDO_ASKPASS=$a sudo -A --"#;
+
+/// Removes the helper once sudo has exited, keeping the command's status.
+const AFTER_SUDO: &str = r#"; s=$?; rm -f "$a"; exit $s"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, Output, Stdio};
+
+    /// A stand-in for sudo that asks the askpass helper for the password
+    /// only if `ASKS` holds the one it expects, as sudo does without
+    /// NOPASSWD or cached credentials.
+    const SUDO: &str = r#"#!/bin/sh
+[ "$1" = -A ] && [ "$2" = -- ] || exit 90
+if [ -n "$ASKS" ]; then
+    [ "$("$SUDO_ASKPASS" "Password:")" = "$ASKS" ] || { echo "wrong password" >&2; exit 1; }
+fi
+shift 2
+exec "$@"
+"#;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cluster_run-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::create_dir(dir.join("tmp")).unwrap();
+        let sudo = dir.join("bin/sudo");
+        fs::write(&sudo, SUDO).unwrap();
+        fs::set_permissions(&sudo, fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    fn run(dir: &Path, asks: &str, command: &str, input: &str) -> Output {
+        let path = format!(
+            "{}:{}",
+            dir.join("bin").display(),
+            env::var("PATH").unwrap()
+        );
+        let mut child = Command::new("sh")
+            .args(["-c", &Sudo::Password.wrap(command)])
+            .env("PATH", path)
+            .env("TMPDIR", dir.join("tmp"))
+            .env("ASKS", asks)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    #[test]
+    fn keeps_the_sudo_password_from_the_command() {
+        let dir = scratch("nopasswd");
+
+        // With NOPASSWD sudo never asks, and the command gets only its input
+        let output = run(&dir, "", "cat", "it's a secret\nline one\nline two\n");
+        assert_eq!(output.stdout, b"line one\nline two\n");
+        assert_eq!(output.status.code(), Some(0));
+
+        // When sudo asks, the helper gives it the password
+        let output = run(
+            &dir,
+            "it's a secret",
+            "cat; exit 3",
+            "it's a secret\ninput\n",
+        );
+        assert_eq!(output.stdout, b"input\n");
+        assert_eq!(output.status.code(), Some(3));
+
+        let output = run(&dir, "other", "cat", "it's a secret\ninput\n");
+        assert_eq!(output.stderr, b"wrong password\n");
+        assert_eq!(output.status.code(), Some(1));
+
+        // The helper is removed however the command ends
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
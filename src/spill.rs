@@ -0,0 +1,214 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Keeping commands' output within bounds, for `--max-output`.
+//!
+//! Each stream of a command's output is kept in memory up to the limit, 10
+//! MiB by default, so a command that writes gigabytes can't exhaust it. Past
+//! the limit, the whole stream, already masked, is written to a file in a
+//! private temporary directory instead, and what's kept in memory is ended
+//! with a marker saying how much there was and which file has all of it. Streamed output and the
+//! `--log-dir` files still get everything as it arrives.
+
+use crate::output;
+use crate::ssh::OutputStream;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+/// How much of each stream is kept in memory without `--max-output`.
+pub const DEFAULT_MAX_OUTPUT: usize = 10 * 1024 * 1024;
+
+/// The limit on each stream kept in memory, from `--max-output`; zero keeps
+/// everything.
+static MAX_OUTPUT: OnceLock<usize> = OnceLock::new();
+
+/// The number of the last file output was spilled to, for naming the next.
+static SPILLED: AtomicUsize = AtomicUsize::new(0);
+
+/// The directory output is spilled to, once it's been created, or the error
+/// that stopped it being created.
+static DIR: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+/// Keeps at most `bytes` of each stream of output in memory from now on, or
+/// everything if zero.
+pub fn use_max_output(bytes: usize) {
+    let _ = MAX_OUTPUT.set(bytes);
+}
+
+/// Returns the limit on each stream kept in memory, if there is one.
+fn max_output() -> Option<usize> {
+    Some(*MAX_OUTPUT.get().unwrap_or(&DEFAULT_MAX_OUTPUT)).filter(|&bytes| bytes > 0)
+}
+
+/// One stream of a command's output, kept in memory up to the limit and
+/// spilled to a file past it.
+#[derive(Default)]
+pub struct Buffer {
+    kept: Vec<u8>,
+    /// How many bytes have been read, including those not kept.
+    len: usize,
+    /// Where the whole stream is written once it's passed the limit, or the
+    /// error that stopped it being written.
+    spill: Option<Result<(PathBuf, File), String>>,
+}
+
+impl Buffer {
+    /// Returns what's been kept of the stream.
+    pub fn bytes(&self) -> &[u8] {
+        &self.kept
+    }
+
+    /// Adds a chunk of the stream, spilling it to a file if the stream has
+    /// passed the limit.
+    pub fn push(&mut self, stream: OutputStream, chunk: &[u8]) {
+        self.len += chunk.len();
+        let Some(limit) = max_output() else {
+            self.kept.extend_from_slice(chunk);
+            return;
+        };
+        if self.spill.is_none() {
+            let room = limit.saturating_sub(self.kept.len());
+            if chunk.len() <= room {
+                self.kept.extend_from_slice(chunk);
+                return;
+            }
+            // The file gets the whole stream, starting with what was kept
+            let spill = create(stream).and_then(|(path, mut file)| {
+                file.write_all(&self.kept)
+                    .map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+                Ok((path, file))
+            });
+            if let Err(e) = &spill {
+                tracing::warn!(
+                    "Dropping output past {}: {}",
+                    output::format_bytes(limit as u64),
+                    e
+                );
+            }
+            self.spill = Some(spill);
+            self.kept.extend_from_slice(&chunk[..room]);
+        }
+        if let Some(Ok((path, file))) = &mut self.spill {
+            if let Err(e) = file.write_all(chunk) {
+                let e = format!("unable to write {}: {}", path.display(), e);
+                tracing::warn!("Dropping the rest of the output: {}", e);
+                self.spill = Some(Err(e));
+            }
+        }
+    }
+
+    /// Returns the stream as text, ending with a marker if it was cut short.
+    /// Bytes that aren't valid UTF-8, such as binary output, are shown as
+    /// replacement characters.
+    pub fn into_string(mut self) -> String {
+        let Some(spill) = self.spill.take() else {
+            return String::from_utf8_lossy(&self.kept).into_owned();
+        };
+        // A character cut in two at the limit is dropped whole
+        if let Err(e) = std::str::from_utf8(&self.kept) {
+            if e.error_len().is_none() {
+                self.kept.truncate(e.valid_up_to());
+            }
+        }
+        let mut text = String::from_utf8_lossy(&self.kept).into_owned();
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let limit = output::format_bytes(max_output().unwrap_or_default() as u64);
+        let total = output::format_bytes(self.len as u64);
+        text.push_str(&match spill {
+            Ok((path, _)) => format!(
+                "[output cut short at {} of {}; all of it is in {}]\n",
+                limit,
+                total,
+                path.display()
+            ),
+            Err(e) => format!("[output cut short at {} of {}; {}]\n", limit, total, e),
+        });
+        text
+    }
+}
+
+/// Creates the file to spill a stream to, in a directory of this process's
+/// own in the temporary directory. Only the user running cluster_run can
+/// read either of them.
+fn create(stream: OutputStream) -> Result<(PathBuf, File), String> {
+    let dir = dir()?;
+    let number = SPILLED.fetch_add(1, Ordering::Relaxed) + 1;
+    let extension = match stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    };
+    let path = dir.join(format!("{}.{}", number, extension));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| format!("unable to create {}: {}", path.display(), e))?;
+    Ok((path, file))
+}
+
+/// Returns the directory to spill output to, creating it the first time.
+/// It's made by mkdtemp(3), so its name can't be guessed ahead of time and
+/// it's only open to its owner.
+fn dir() -> Result<&'static Path, String> {
+    DIR.get_or_init(|| {
+        let template = env::temp_dir().join("cluster_run-XXXXXX");
+        let mut template = CString::new(template.into_os_string().into_vec())
+            .map_err(|e| format!("invalid temporary directory: {}", e))?
+            .into_bytes_with_nul();
+        // SAFETY: the template is a writable string ending in a nul, which
+        // mkdtemp fills in with the directory's name
+        if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+            return Err(format!(
+                "unable to create a directory in {}: {}",
+                env::temp_dir().display(),
+                io::Error::last_os_error()
+            ));
+        }
+        template.pop();
+        Ok(PathBuf::from(OsString::from_vec(template)))
+    })
+    .as_deref()
+    .map_err(|e| e.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    #[test]
+    fn spills_to_a_private_directory() {
+        let (path, _) = create(OutputStream::Stdout).unwrap();
+        let dir = path.parent().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert!(dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("cluster_run-"));
+        assert_ne!(
+            dir,
+            env::temp_dir().join(format!("cluster_run-{}", process::id()))
+        );
+
+        // Each file is new, never one left there before
+        let (other, _) = create(OutputStream::Stdout).unwrap();
+        assert_eq!(other.parent(), Some(dir));
+        assert_ne!(other, path);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The user's OpenSSH client configuration.
+//!
+//! Nodes are often known by the aliases in `~/.ssh/config`, so the `Host`
+//! sections matching a node supply its `HostName`, `User`, `Port`,
+//! `IdentityFile`, `ProxyJump` and `Compression` the way they would for
+//! `ssh <alias>`. As
+//! with ssh, the first value found for each option wins. A node's own
+//! settings in the cluster_run config take precedence over them, and
+//! `[defaults]` only fills in what neither sets. `Include` is followed, while
+//! `Match` sections are skipped, since their conditions can run commands and
+//! test things cluster_run doesn't know.
+
+use crate::config::{expand_home, Node};
+use crate::select::glob_match;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The environment variable naming the OpenSSH config file to read.
+const PATH_VAR: &str = "CLUSTER_RUN_SSH_CONFIG";
+
+/// How deeply `Include` may nest, as in ssh.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The file, read once per run.
+static CONFIG: OnceLock<Result<SshConfig, String>> = OnceLock::new();
+
+/// The options cluster_run uses from a host's sections.
+#[derive(Clone, Default)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// The identity files that exist, in the order they were given.
+    pub identity_files: Vec<PathBuf>,
+    pub proxy_jump: Option<String>,
+    pub compression: Option<bool>,
+}
+
+/// The sections of an OpenSSH config file, in order.
+#[derive(Default)]
+pub struct SshConfig {
+    sections: Vec<Section>,
+}
+
+/// A `Host` section, or the options before the first one.
+struct Section {
+    /// The host patterns, some negated with `!`; None for the options that
+    /// apply to every host.
+    patterns: Option<Vec<String>>,
+    /// The options, by lowercase keyword, each with its arguments.
+    options: Vec<(String, String)>,
+}
+
+/// Returns the options for a host from the user's OpenSSH config.
+///
+/// # Arguments
+///
+/// * `host` - The host as it would be given to ssh.
+///
+/// # Returns
+///
+/// Returns the options, which are all unset if there's no config, or an
+/// error if the config can't be read.
+pub fn lookup(host: &str) -> Result<HostConfig, String> {
+    let config = CONFIG.get_or_init(SshConfig::load);
+    config
+        .as_ref()
+        .map(|config| config.host(host))
+        .map_err(Clone::clone)
+}
+
+/// Returns the OpenSSH config file named by `CLUSTER_RUN_SSH_CONFIG`, if
+/// it's set.
+pub fn path_override() -> Option<PathBuf> {
+    env::var_os(PATH_VAR).map(PathBuf::from)
+}
+
+impl HostConfig {
+    /// Fills in a node's unset settings.
+    pub fn apply(self, node: &mut Node) {
+        if node.resolve.is_none() {
+            node.resolve = self.hostname.filter(|hostname| *hostname != node.host);
+        }
+        node.user = node.user.take().or(self.user);
+        node.port = node.port.or(self.port);
+        node.key = node.key.take().or(self.identity_files.into_iter().next());
+        node.jump = node.jump.take().or(self.proxy_jump);
+        node.compression = node.compression.or(self.compression);
+    }
+}
+
+impl SshConfig {
+    /// Reads the config from `CLUSTER_RUN_SSH_CONFIG`, or `~/.ssh/config`.
+    fn load() -> Result<SshConfig, String> {
+        let path = match (env::var_os(PATH_VAR), env::var_os("HOME")) {
+            (Some(path), _) => PathBuf::from(path),
+            (None, Some(home)) => PathBuf::from(home).join(".ssh").join("config"),
+            (None, None) => return Ok(SshConfig::default()),
+        };
+        let mut config = SshConfig::default();
+        config
+            .read(&path, 0)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    /// Adds the sections of a file and the files it includes.
+    fn read(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && depth == 0 => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.parse(&content, depth)
+    }
+
+    /// Adds the sections of a file's content, and of the files it includes.
+    fn parse(&mut self, content: &str, depth: usize) -> io::Result<()> {
+        // Options before the first Host apply to every host, or in an
+        // included file, to the hosts of the section it's included from
+        if depth == 0 {
+            self.sections.push(Section {
+                patterns: None,
+                options: Vec::new(),
+            });
+        }
+        for line in content.lines() {
+            let Some((keyword, args)) = split_line(line) else {
+                continue;
+            };
+            match keyword.as_str() {
+                "host" => self.sections.push(Section {
+                    patterns: Some(args.split_whitespace().map(str::to_string).collect()),
+                    options: Vec::new(),
+                }),
+                // A pattern that never matches skips the section
+                "match" => self.sections.push(Section {
+                    patterns: Some(Vec::new()),
+                    options: Vec::new(),
+                }),
+                "include" if depth < MAX_INCLUDE_DEPTH => {
+                    // The rest of the section continues after the included files
+                    let patterns = self.sections.last().and_then(|s| s.patterns.clone());
+                    for included in expand_include(&args) {
+                        self.read(&included, depth + 1)?;
+                    }
+                    self.sections.push(Section {
+                        patterns,
+                        options: Vec::new(),
+                    });
+                }
+                "include" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Include is nested too deeply",
+                    ))
+                }
+                _ => self
+                    .sections
+                    .last_mut()
+                    .expect("there is always a section")
+                    .options
+                    .push((keyword, args)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the options of every section that matches a host.
+    fn host(&self, host: &str) -> HostConfig {
+        let mut options: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut identity_files = Vec::new();
+        for section in self.sections.iter().filter(|s| s.matches(host)) {
+            for (keyword, args) in &section.options {
+                if keyword == "identityfile" {
+                    identity_files.push(args.as_str());
+                } else {
+                    options.entry(keyword).or_insert(args);
+                }
+            }
+        }
+
+        let hostname = options
+            .get("hostname")
+            .map(|name| expand_tokens(name, host, None));
+        let user = options.get("user").map(|user| user.to_string());
+        let identity_files = identity_files
+            .into_iter()
+            .map(|path| {
+                let path = expand_tokens(path, host, user.as_deref());
+                expand_home(Path::new(&path))
+            })
+            .filter(|path| path.exists())
+            .collect();
+        HostConfig {
+            hostname,
+            user,
+            port: options.get("port").and_then(|port| port.parse().ok()),
+            identity_files,
+            proxy_jump: options
+                .get("proxyjump")
+                .filter(|jump| !jump.eq_ignore_ascii_case("none"))
+                .map(|jump| jump.to_string()),
+            compression: options.get("compression").and_then(|on| {
+                match on.to_ascii_lowercase().as_str() {
+                    "yes" => Some(true),
+                    "no" => Some(false),
+                    _ => None,
+                }
+            }),
+        }
+    }
+}
+
+impl Section {
+    /// Returns true if the section's options apply to a host.
+    fn matches(&self, host: &str) -> bool {
+        let Some(patterns) = &self.patterns else {
+            return true;
+        };
+        let mut matched = false;
+        for pattern in patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) if glob_match(negated, host) => return false,
+                Some(_) => {}
+                None => matched |= glob_match(pattern, host),
+            }
+        }
+        matched
+    }
+}
+
+/// Splits a config line into its lowercase keyword and arguments, which are
+/// separated by spaces or an `=`.
+///
+/// # Returns
+///
+/// Returns None for blank lines and comments.
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == '=')
+        .unwrap_or(line.len());
+    let (keyword, args) = line.split_at(end);
+    let args = args.trim_start();
+    let args = args.strip_prefix('=').unwrap_or(args).trim();
+    let args = match args.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+        Some(unquoted) => unquoted,
+        None => args,
+    };
+    Some((keyword.to_lowercase(), args.to_string()))
+}
+
+/// Returns the files an `Include` names, which are relative to ~/.ssh and
+/// may have wildcards in their file names.
+fn expand_include(args: &str) -> Vec<PathBuf> {
+    let ssh_dir = env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh"))
+        .unwrap_or_default();
+    let mut files = Vec::new();
+    for arg in args.split_whitespace() {
+        let path = expand_home(Path::new(arg));
+        let path = ssh_dir.join(path);
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if !name.contains(['*', '?']) {
+            files.push(path);
+            continue;
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        let mut matched: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|file| {
+                file.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| glob_match(name, n))
+            })
+            .collect();
+        matched.sort();
+        files.extend(matched);
+    }
+    files
+}
+
+/// Replaces the `%h`, `%r` and `%%` tokens of an option with the host, the
+/// remote user and `%`.
+fn expand_tokens(value: &str, host: &str, user: Option<&str>) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('h')) => expanded.push_str(host),
+            ('%', Some('r')) => expanded.push_str(user.unwrap_or(crate::ssh::USER)),
+            ('%', Some('%')) => expanded.push('%'),
+            _ => {
+                expanded.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> SshConfig {
+        let mut config = SshConfig::default();
+        config.parse(content, 0).unwrap();
+        config
+    }
+
+    #[test]
+    fn matches_host_patterns() {
+        let config = parse(
+            "
+Host gpu-* !gpu-99
+    User ubuntu
+
+Host gpu-0?
+    Port 2222
+
+Host bastion
+    HostName 192.0.2.10
+",
+        );
+        let gpu = config.host("gpu-07");
+        assert_eq!(gpu.user.as_deref(), Some("ubuntu"));
+        assert_eq!(gpu.port, Some(2222));
+        assert_eq!(gpu.hostname, None);
+
+        let negated = config.host("gpu-99");
+        assert_eq!(negated.user, None);
+        assert_eq!(negated.port, None);
+
+        let bastion = config.host("bastion");
+        assert_eq!(bastion.hostname.as_deref(), Some("192.0.2.10"));
+        assert_eq!(bastion.user, None);
+        assert!(config.host("bastion.example.com").hostname.is_none());
+    }
+
+    #[test]
+    fn takes_the_first_value_of_each_option() {
+        let config = parse(
+            "
+User root
+Host gpu-01
+    User admin
+    Port 2200
+Host *
+    User ubuntu
+    Port=2201
+    ProxyJump \"bastion.example.com\"
+    Compression yes
+",
+        );
+        let gpu = config.host("gpu-01");
+        // Options before the first Host apply to every host, first
+        assert_eq!(gpu.user.as_deref(), Some("root"));
+        assert_eq!(gpu.port, Some(2200));
+        assert_eq!(gpu.proxy_jump.as_deref(), Some("bastion.example.com"));
+        assert_eq!(gpu.compression, Some(true));
+
+        let other = config.host("gpu-02");
+        assert_eq!(other.port, Some(2201));
+    }
+
+    #[test]
+    fn skips_match_sections() {
+        let config = parse(
+            "
+Match host gpu-01 exec \"true\"
+    User matched
+Host gpu-01
+    User ubuntu
+    ProxyJump none
+Match all
+    Port 2222
+",
+        );
+        let gpu = config.host("gpu-01");
+        assert_eq!(gpu.user.as_deref(), Some("ubuntu"));
+        assert_eq!(gpu.port, None);
+        assert_eq!(gpu.proxy_jump, None);
+    }
+
+    #[test]
+    fn expands_tokens_in_hostnames() {
+        let config = parse("Host gpu-*\n    HostName %h.internal.example.com\n");
+        assert_eq!(
+            config.host("gpu-01").hostname.as_deref(),
+            Some("gpu-01.internal.example.com")
+        );
+        assert_eq!(
+            expand_tokens("%r@%h:100%%", "gpu-01", Some("ops")),
+            "ops@gpu-01:100%"
+        );
+    }
+
+    #[test]
+    fn follows_includes() {
+        let dir = env::temp_dir().join(format!("cluster_run-ssh-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        fs::write(
+            dir.join("conf.d/10-gpu.conf"),
+            "Port 2210\nHost gpu-*\n    User gpu\n",
+        )
+        .unwrap();
+        fs::write(dir.join("conf.d/20-rest.conf"), "Host *\n    User other\n").unwrap();
+        fs::write(dir.join("conf.d/ignored.txt"), "Host *\n    Port 1\n").unwrap();
+        fs::write(
+            dir.join("loop"),
+            format!("Include {}/loop\n", dir.display()),
+        )
+        .unwrap();
+
+        let config = parse(&format!(
+            "
+Host gpu-01
+    Include {}/conf.d/*.conf
+    Compression no
+Host *
+    Port 22
+",
+            dir.display()
+        ));
+        // Options in an included file before its first Host belong to the
+        // section it's included from, which carries on after it
+        let gpu = config.host("gpu-01");
+        assert_eq!(gpu.port, Some(2210));
+        assert_eq!(gpu.user.as_deref(), Some("gpu"));
+        assert_eq!(gpu.compression, Some(false));
+        let other = config.host("gpu-02");
+        assert_eq!(other.port, Some(22));
+        assert_eq!(other.user.as_deref(), Some("gpu"));
+        assert_eq!(other.compression, None);
+
+        let mut looped = SshConfig::default();
+        let e = looped.read(&dir.join("loop"), 0).err().unwrap();
+        assert_eq!(e.to_string(), "Include is nested too deeply");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn splits_lines() {
+        assert_eq!(
+            split_line("  HostName = gpu.example.com "),
+            Some(("hostname".to_string(), "gpu.example.com".to_string()))
+        );
+        assert_eq!(
+            split_line("IdentityFile \"~/.ssh/my key\""),
+            Some(("identityfile".to_string(), "~/.ssh/my key".to_string()))
+        );
+        assert_eq!(split_line("   # a comment"), None);
+        assert_eq!(split_line(""), None);
+    }
+}
@@ -0,0 +1,23 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Files cluster_run keeps between runs.
+//!
+//! State lives under `cluster_run` in the user's state directory, which is
+//! `$XDG_STATE_HOME` or `~/.local/state`.
+
+use std::env;
+use std::path::PathBuf;
+
+/// Returns the path of the state file with the given name.
+///
+/// # Returns
+///
+/// Returns None if neither `XDG_STATE_HOME` nor `HOME` is set.
+pub fn path(name: &str) -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("cluster_run").join(name))
+}
@@ -0,0 +1,264 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The summary printed at the end of a run.
+//!
+//! Output from many nodes is interleaved while a run is in progress, so the
+//! summary collects what happened on each node and reports it in one place
+//! once every node has finished. When several nodes failed, the failures
+//! are grouped by exit code and by cause, so a failure shared by many nodes
+//! reads as one line rather than many to be compared by eye.
+
+use crate::inventory;
+use crate::output::{self, Color};
+use crate::ssh::OutputStream;
+use std::time::{Duration, SystemTime};
+
+/// What happened to each node in a run.
+#[derive(Default)]
+pub struct Summary {
+    /// The nodes whose command exited successfully.
+    pub succeeded: Vec<String>,
+    /// The nodes whose command exited non-zero, with the exit status.
+    pub failed: Vec<(String, i32)>,
+    /// The first line of stderr of each node whose command exited non-zero,
+    /// if it wrote any.
+    pub first_lines: Vec<(String, String)>,
+    /// The nodes that timed out connecting or running the command.
+    pub timed_out: Vec<String>,
+    /// The nodes that couldn't be run on, with the error.
+    pub errored: Vec<(String, String)>,
+    /// The nodes whose command was cancelled part way through.
+    pub cancelled: Vec<String>,
+    /// The number of nodes that were never started.
+    pub not_started: usize,
+    /// The slowest node that ran its command to completion, and how long it took.
+    pub slowest: Option<(String, Duration)>,
+    /// How long the whole run took.
+    pub wall_time: Duration,
+    /// How long each node took, for the nodes that were attempted.
+    pub timings: Vec<(String, Timing)>,
+}
+
+/// When a node's last attempt started, and how long its stages took.
+#[derive(Clone, Copy, Default)]
+pub struct Timing {
+    /// When the attempt started.
+    pub started: Option<SystemTime>,
+    /// The time taken to connect and authenticate, if it got that far.
+    pub connect: Option<Duration>,
+    /// The time taken to run the command, if it was connected.
+    pub command: Option<Duration>,
+}
+
+impl Timing {
+    /// Returns the time taken by all of the stages that completed.
+    pub fn total(&self) -> Duration {
+        self.connect.unwrap_or_default() + self.command.unwrap_or_default()
+    }
+}
+
+/// A node is flagged as slow when it takes this many times the median node.
+const SLOW_FACTOR: u32 = 2;
+
+impl Summary {
+    /// Records a command that ran to completion with the given exit status,
+    /// and what it wrote to stderr.
+    pub fn record_exit(&mut self, node: &str, exit_code: i32, duration: Duration, stderr: &str) {
+        if exit_code == 0 {
+            self.succeeded.push(node.to_string());
+        } else {
+            self.failed.push((node.to_string(), exit_code));
+            if let Some(line) = stderr.lines().map(str::trim).find(|line| !line.is_empty()) {
+                self.first_lines.push((node.to_string(), line.to_string()));
+            }
+        }
+        if self
+            .slowest
+            .as_ref()
+            .is_none_or(|(_, slowest)| duration > *slowest)
+        {
+            self.slowest = Some((node.to_string(), duration));
+        }
+    }
+
+    /// Records how long a node took.
+    pub fn record_timing(&mut self, node: &str, timing: Timing) {
+        if timing.connect.is_some() {
+            self.timings.push((node.to_string(), timing));
+        }
+    }
+
+    /// Returns the number of nodes that failed in any way other than being cancelled.
+    pub fn failures(&self) -> usize {
+        self.failed.len() + self.timed_out.len() + self.errored.len()
+    }
+
+    /// Prints the summary as a table, listing the nodes that didn't succeed.
+    pub fn print(&self) {
+        let failed: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(node, exit_code)| format!("{} (exit {})", node, exit_code))
+            .collect();
+        let errored: Vec<&str> = self.errored.iter().map(|(node, _)| node.as_str()).collect();
+
+        println!("Summary:");
+        print_row("Succeeded", self.succeeded.len(), "", Color::Green);
+        print_row("Failed", self.failed.len(), &failed.join(", "), Color::Red);
+        print_row(
+            "Timed out",
+            self.timed_out.len(),
+            &self.timed_out.join(", "),
+            Color::Red,
+        );
+        print_row(
+            "Errors",
+            self.errored.len(),
+            &errored.join(", "),
+            Color::Red,
+        );
+        if !self.cancelled.is_empty() {
+            print_row(
+                "Cancelled",
+                self.cancelled.len(),
+                &self.cancelled.join(", "),
+                Color::Yellow,
+            );
+        }
+        if self.not_started > 0 {
+            print_row("Not started", self.not_started, "", Color::Yellow);
+        }
+        println!("  {:<12} {:.2?}", "Wall time", self.wall_time);
+        if let Some((node, duration)) = &self.slowest {
+            println!("  {:<12} {} ({:.2?})", "Slowest", node, duration);
+        }
+        if self.failures() > 1 {
+            self.print_failures();
+        }
+    }
+
+    /// Prints the failures grouped by exit code, and by their cause: the
+    /// first line of stderr, the error, or timing out. The most common come
+    /// first.
+    fn print_failures(&self) {
+        let mut by_code: Vec<(i32, Vec<String>)> = Vec::new();
+        for (node, exit_code) in &self.failed {
+            add_to_group(&mut by_code, *exit_code, node);
+        }
+        let mut by_cause: Vec<(String, Vec<String>)> = Vec::new();
+        for (node, _) in &self.failed {
+            let cause = self
+                .first_lines
+                .iter()
+                .find(|(failed, _)| failed == node)
+                .map_or("(nothing on stderr)", |(_, line)| line.as_str());
+            add_to_group(&mut by_cause, cause.to_string(), node);
+        }
+        for node in &self.timed_out {
+            add_to_group(&mut by_cause, "(timed out)".to_string(), node);
+        }
+        for (node, error) in &self.errored {
+            add_to_group(&mut by_cause, error.clone(), node);
+        }
+        // Stable sorts keep groups of the same size in the order they appeared
+        by_code.sort_by_key(|(_, nodes)| std::cmp::Reverse(nodes.len()));
+        by_cause.sort_by_key(|(_, nodes)| std::cmp::Reverse(nodes.len()));
+
+        if !by_code.is_empty() {
+            println!("Failures by exit code:");
+            for (exit_code, nodes) in &by_code {
+                print_row(
+                    &format!("exit {}", exit_code),
+                    nodes.len(),
+                    &inventory::fold(nodes),
+                    Color::Red,
+                );
+            }
+        }
+        println!("Failures by cause:");
+        let width = by_cause
+            .iter()
+            .map(|(_, nodes)| nodes.len().to_string().len())
+            .max()
+            .unwrap_or(0);
+        for (cause, nodes) in &by_cause {
+            let count = format!("{:>width$}", nodes.len());
+            println!(
+                "  {} {}",
+                output::paint(OutputStream::Stdout, Color::Red, &count),
+                cause
+            );
+            println!("  {:width$} {}", "", inventory::fold(nodes));
+        }
+    }
+
+    /// Prints the latency report, with the slowest nodes first.
+    ///
+    /// Nodes that took more than twice as long as the median node are flagged,
+    /// since on a cluster of identical machines they often have hardware trouble.
+    pub fn print_timing(&self) {
+        let mut timings = self.timings.clone();
+        timings.sort_by_key(|(_, timing)| std::cmp::Reverse(timing.total()));
+        let median = match timings.len() {
+            0 => Duration::ZERO,
+            n => timings[n / 2].1.total(),
+        };
+
+        let width = timings
+            .iter()
+            .map(|(node, _)| node.len())
+            .max()
+            .unwrap_or(0)
+            .max(4);
+        let format = |duration: Option<Duration>| match duration {
+            Some(duration) => format!("{:.2?}", duration),
+            None => "-".to_string(),
+        };
+        println!("Timing, slowest first:");
+        println!(
+            "  {:<width$}  {:>10}  {:>10}  {:>10}",
+            "NODE", "CONNECT", "COMMAND", "TOTAL"
+        );
+        for (node, timing) in &timings {
+            let slow = timings.len() > 2 && timing.total() > median * SLOW_FACTOR;
+            println!(
+                "  {:<width$}  {:>10}  {:>10}  {:>10}{}",
+                node,
+                format(timing.connect),
+                format(timing.command),
+                format(Some(timing.total())),
+                match slow {
+                    true => format!(
+                        "  {}",
+                        output::paint(OutputStream::Stdout, Color::Yellow, "slow")
+                    ),
+                    false => String::new(),
+                }
+            );
+        }
+    }
+}
+
+/// Adds a node to the group with the given key, starting the group if it's
+/// the first node with that key.
+fn add_to_group<K: PartialEq>(groups: &mut Vec<(K, Vec<String>)>, key: K, node: &str) {
+    match groups.iter_mut().find(|(group, _)| *group == key) {
+        Some((_, nodes)) => nodes.push(node.to_string()),
+        None => groups.push((key, vec![node.to_string()])),
+    }
+}
+
+/// Prints one count in the summary, followed by the nodes it covers, with
+/// the label in the row's color unless the count is zero.
+fn print_row(label: &str, count: usize, nodes: &str, color: Color) {
+    let mut label = format!("{:<12}", label);
+    if count > 0 {
+        label = output::paint(OutputStream::Stdout, color, &label);
+    }
+    if nodes.is_empty() {
+        println!("  {} {}", label, count);
+    } else {
+        println!("  {} {}: {}", label, count, nodes);
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Mirroring a local directory onto the nodes, for the `sync` subcommand.
+//!
+//! The local tree is walked once, then each node's copy is listed over SFTP
+//! and compared with it. A file is sent if it's missing from the node or its
+//! size or modification time differ, or, with `--checksum`, its SHA-256
+//! digest does. Sent files keep their permission bits and modification time,
+//! so the next sync finds them up to date, and symlinks are recreated as
+//! symlinks. Anything on the node that's in the way, such as a file where the
+//! local tree has a directory, is replaced. With `--delete`, whatever isn't
+//! in the local tree is removed from the node, making its copy an exact
+//! mirror.
+
+use crate::shell;
+use crate::verify;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// What an entry in a tree is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Dir,
+    File,
+    /// A symlink, with the path it points to.
+    Symlink(PathBuf),
+    /// Anything else, such as a named pipe, which is never sent.
+    Other,
+}
+
+/// An entry in the local tree.
+#[derive(Clone)]
+pub struct Entry {
+    /// The entry's path from the top of the tree.
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub size: u64,
+    /// The modification time, in seconds since the epoch.
+    pub mtime: u64,
+    /// The permission bits.
+    pub mode: u32,
+    /// The file's SHA-256 digest in hex, if checksums are compared.
+    pub digest: Option<String>,
+}
+
+/// An entry in a node's copy of the tree, as listed over SFTP.
+pub struct Remote {
+    /// The entry's path from the top of the tree.
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub size: u64,
+    /// The modification time, in seconds since the epoch.
+    pub mtime: u64,
+}
+
+/// The local directory being mirrored.
+#[derive(Clone)]
+pub struct Tree {
+    /// The directory itself.
+    pub root: PathBuf,
+    /// Everything under it, with every directory before what's in it.
+    pub entries: Vec<Entry>,
+}
+
+impl Tree {
+    /// Walks a local directory, without following the symlinks in it.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory to mirror.
+    /// * `checksums` - Whether to work out the digest of every file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the tree, or an error if the directory can't be read.
+    pub fn walk(root: &Path, checksums: bool) -> Result<Self, String> {
+        if !fs::metadata(root).is_ok_and(|metadata| metadata.is_dir()) {
+            return Err(format!("{} isn't a directory", root.display()));
+        }
+        let mut tree = Tree {
+            root: root.to_path_buf(),
+            entries: Vec::new(),
+        };
+        tree.walk_dir(Path::new(""), checksums)?;
+        Ok(tree)
+    }
+
+    /// Adds the entries of a directory in the tree, in name order, and of
+    /// the directories in it.
+    fn walk_dir(&mut self, dir: &Path, checksums: bool) -> Result<(), String> {
+        let local = self.root.join(dir);
+        let error = |e: io::Error| format!("unable to read {}: {}", local.display(), e);
+        let mut names: Vec<_> = fs::read_dir(&local)
+            .map_err(error)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()
+            .map_err(error)?;
+        names.sort();
+        for name in names {
+            let path = dir.join(&name);
+            let local = self.root.join(&path);
+            let error = |e: io::Error| format!("unable to read {}: {}", local.display(), e);
+            let metadata = fs::symlink_metadata(&local).map_err(error)?;
+            let file_type = metadata.file_type();
+            let kind = if file_type.is_dir() {
+                Kind::Dir
+            } else if file_type.is_file() {
+                Kind::File
+            } else if file_type.is_symlink() {
+                Kind::Symlink(fs::read_link(&local).map_err(error)?)
+            } else {
+                tracing::warn!(
+                    "Not syncing {}, which isn't a regular file",
+                    local.display()
+                );
+                continue;
+            };
+            let digest = match kind {
+                Kind::File if checksums => Some(verify::digest_file(&local).map_err(error)?),
+                _ => None,
+            };
+            self.entries.push(Entry {
+                path: path.clone(),
+                kind: kind.clone(),
+                size: metadata.len(),
+                mtime: metadata.mtime().max(0) as u64,
+                mode: metadata.permissions().mode() & 0o7777,
+                digest,
+            });
+            if kind == Kind::Dir {
+                self.walk_dir(&path, checksums)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many files and symlinks are in the tree, and the bytes in
+    /// its files.
+    pub fn totals(&self) -> (usize, u64) {
+        let files = self.entries.iter().filter(|entry| entry.kind != Kind::Dir);
+        let bytes = files
+            .clone()
+            .filter(|entry| entry.kind == Kind::File)
+            .map(|entry| entry.size)
+            .sum();
+        (files.count(), bytes)
+    }
+}
+
+/// How a node's copy is brought in line with the tree.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Whether to remove what isn't in the local tree.
+    pub delete: bool,
+    /// The digests of the node's files from the top of its copy, if
+    /// checksums are compared.
+    pub digests: Option<HashMap<PathBuf, String>>,
+}
+
+/// A change to make to a node's copy, with paths from its top.
+pub enum Step {
+    /// Removes a file, symlink or, once what's in it has gone, directory.
+    Remove {
+        path: PathBuf,
+        dir: bool,
+    },
+    CreateDir {
+        path: PathBuf,
+        mode: u32,
+    },
+    /// Sends a file, giving it the local file's mode and modification time.
+    Upload {
+        path: PathBuf,
+        size: u64,
+        mode: u32,
+        mtime: u64,
+    },
+    Symlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+/// Totals for a completed sync.
+#[derive(Default)]
+pub struct Synced {
+    /// The number of files and symlinks sent.
+    pub files: u64,
+    /// The number of bytes sent.
+    pub bytes: u64,
+    /// The number of entries removed.
+    pub removed: u64,
+    /// The files sent, from the top of the node's copy.
+    pub sent: Vec<PathBuf>,
+}
+
+/// Works out the changes that bring a node's copy in line with the tree.
+///
+/// # Arguments
+///
+/// * `tree` - The local tree.
+/// * `remote` - Everything in the node's copy.
+/// * `options` - Whether to delete extra entries, and the node's digests.
+///
+/// # Returns
+///
+/// Returns the changes, with every removal first and deepest first, then
+/// everything to create with its directory before it.
+pub fn plan(tree: &Tree, remote: &[Remote], options: &Options) -> Vec<Step> {
+    let local: HashMap<&Path, &Entry> = tree
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+
+    // Whatever's where the local tree has something of another kind is in
+    // the way, and so is everything in a directory that's in the way
+    let in_way = |entry: &Remote| {
+        local
+            .get(entry.path.as_path())
+            .is_some_and(|local| local.kind != entry.kind)
+    };
+    let replaced: HashSet<&Path> = remote
+        .iter()
+        .filter(|entry| entry.kind == Kind::Dir && in_way(entry))
+        .map(|entry| entry.path.as_path())
+        .collect();
+    let mut removed: Vec<&Remote> = remote
+        .iter()
+        .filter(|entry| match local.get(entry.path.as_path()) {
+            Some(_) => in_way(entry),
+            None => {
+                options.delete
+                    || entry
+                        .path
+                        .ancestors()
+                        .any(|ancestor| replaced.contains(ancestor))
+            }
+        })
+        .collect();
+    removed.sort_by(|a, b| b.path.cmp(&a.path));
+    let gone: HashSet<&Path> = removed.iter().map(|entry| entry.path.as_path()).collect();
+    let present: HashMap<&Path, &Remote> = remote
+        .iter()
+        .filter(|entry| !gone.contains(entry.path.as_path()))
+        .map(|entry| (entry.path.as_path(), entry))
+        .collect();
+
+    let mut steps: Vec<Step> = removed
+        .iter()
+        .map(|entry| Step::Remove {
+            path: entry.path.clone(),
+            dir: entry.kind == Kind::Dir,
+        })
+        .collect();
+    for entry in &tree.entries {
+        let there = present.get(entry.path.as_path());
+        match &entry.kind {
+            Kind::Dir if there.is_none() => steps.push(Step::CreateDir {
+                path: entry.path.clone(),
+                mode: entry.mode,
+            }),
+            Kind::File if there.is_none_or(|there| changed(entry, there, options)) => {
+                steps.push(Step::Upload {
+                    path: entry.path.clone(),
+                    size: entry.size,
+                    mode: entry.mode,
+                    mtime: entry.mtime,
+                })
+            }
+            Kind::Symlink(target) if there.is_none() => steps.push(Step::Symlink {
+                path: entry.path.clone(),
+                target: target.clone(),
+            }),
+            _ => {}
+        }
+    }
+    steps
+}
+
+/// Returns the bytes the changes send.
+pub fn bytes_to_send(steps: &[Step]) -> u64 {
+    steps
+        .iter()
+        .map(|step| match step {
+            Step::Upload { size, .. } => *size,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Returns true if a file on the node differs from the local one: in size,
+/// then in digest if checksums are compared, or in modification time if not.
+fn changed(local: &Entry, remote: &Remote, options: &Options) -> bool {
+    if local.size != remote.size {
+        return true;
+    }
+    match &options.digests {
+        Some(digests) => digests.get(&local.path) != local.digest.as_ref(),
+        None => local.mtime != remote.mtime,
+    }
+}
+
+/// Returns the command that prints the digest of every file in a node's
+/// copy, as `sha256sum` does, or nothing if the copy doesn't exist yet.
+pub fn digest_command(remote: &Path) -> String {
+    format!(
+        "cd {} 2>/dev/null && find . -type f -exec sha256sum -- {{}} + || true",
+        shell::quote(&remote.to_string_lossy())
+    )
+}
+
+/// Parses the output of the digest command into the digest of each file,
+/// from the top of the node's copy.
+pub fn parse_digests(output: &str) -> HashMap<PathBuf, String> {
+    verify::parse(output)
+        .into_iter()
+        .map(|(path, digest)| match path.strip_prefix("./") {
+            Ok(path) => (path.to_path_buf(), digest),
+            Err(_) => (path, digest),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: Kind, mtime: u64) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            size: if kind == Kind::File { 10 } else { 0 },
+            kind,
+            mtime,
+            mode: 0o644,
+            digest: None,
+        }
+    }
+
+    fn remote(path: &str, kind: Kind, mtime: u64) -> Remote {
+        Remote {
+            path: PathBuf::from(path),
+            size: if kind == Kind::File { 10 } else { 0 },
+            kind,
+            mtime,
+        }
+    }
+
+    fn link(target: &str) -> Kind {
+        Kind::Symlink(PathBuf::from(target))
+    }
+
+    fn tree(entries: Vec<Entry>) -> Tree {
+        Tree {
+            root: PathBuf::from("/src"),
+            entries,
+        }
+    }
+
+    /// Returns the steps in a form that's easy to compare.
+    fn steps(tree: &Tree, remote: &[Remote], options: &Options) -> Vec<String> {
+        plan(tree, remote, options)
+            .iter()
+            .map(|step| match step {
+                Step::Remove { path, dir: true } => format!("rmdir {}", path.display()),
+                Step::Remove { path, dir: false } => format!("rm {}", path.display()),
+                Step::CreateDir { path, .. } => format!("mkdir {}", path.display()),
+                Step::Upload { path, .. } => format!("put {}", path.display()),
+                Step::Symlink { path, target } => {
+                    format!("ln {} -> {}", path.display(), target.display())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sends_only_what_changed() {
+        let local = tree(vec![
+            entry("a", Kind::File, 100),
+            entry("d", Kind::Dir, 100),
+            entry("d/b", Kind::File, 100),
+            entry("d/c", Kind::File, 100),
+            entry("l", link("a"), 100),
+        ]);
+        let same = [
+            remote("a", Kind::File, 100),
+            remote("d", Kind::Dir, 50),
+            remote("d/b", Kind::File, 100),
+            remote("d/c", Kind::File, 100),
+            remote("l", link("a"), 50),
+        ];
+        assert!(steps(&local, &same, &Options::default()).is_empty());
+
+        let differ = [
+            remote("a", Kind::File, 99),
+            remote("d", Kind::Dir, 100),
+            remote("d/b", Kind::File, 100),
+        ];
+        assert_eq!(
+            steps(&local, &differ, &Options::default()),
+            ["put a", "put d/c", "ln l -> a"]
+        );
+        assert_eq!(
+            steps(&local, &[], &Options::default()),
+            ["put a", "mkdir d", "put d/b", "put d/c", "ln l -> a"]
+        );
+    }
+
+    #[test]
+    fn compares_digests_rather_than_times() {
+        let mut file = entry("a", Kind::File, 100);
+        file.digest = Some("abc".to_string());
+        let local = tree(vec![file]);
+        let digests = |digest: &str| Options {
+            delete: false,
+            digests: Some(HashMap::from([(PathBuf::from("a"), digest.to_string())])),
+        };
+        let there = [remote("a", Kind::File, 1)];
+        assert!(steps(&local, &there, &digests("abc")).is_empty());
+        assert_eq!(steps(&local, &there, &digests("abd")), ["put a"]);
+    }
+
+    #[test]
+    fn removes_nothing_without_delete() {
+        let local = tree(vec![entry("a", Kind::File, 100)]);
+        let there = [
+            remote("a", Kind::File, 100),
+            remote("extra", Kind::Dir, 100),
+            remote("extra/x", Kind::File, 100),
+            remote("old", Kind::File, 100),
+        ];
+        assert!(steps(&local, &there, &Options::default()).is_empty());
+
+        let delete = Options {
+            delete: true,
+            digests: None,
+        };
+        assert_eq!(
+            steps(&local, &there, &delete),
+            ["rm old", "rm extra/x", "rmdir extra"]
+        );
+    }
+
+    #[test]
+    fn replaces_what_is_in_the_way() {
+        // A file where a directory was, with everything in the directory
+        // removed first, deepest first, even without --delete
+        let local = tree(vec![entry("a", Kind::File, 100)]);
+        let there = [
+            remote("a", Kind::Dir, 100),
+            remote("a/b", Kind::Dir, 100),
+            remote("a/b/c", Kind::File, 100),
+            remote("a/d", Kind::File, 100),
+        ];
+        assert_eq!(
+            steps(&local, &there, &Options::default()),
+            ["rm a/d", "rm a/b/c", "rmdir a/b", "rmdir a", "put a"]
+        );
+
+        // A directory where a file was
+        let local = tree(vec![
+            entry("a", Kind::Dir, 100),
+            entry("a/b", Kind::File, 100),
+        ]);
+        assert_eq!(
+            steps(&local, &[remote("a", Kind::File, 100)], &Options::default()),
+            ["rm a", "mkdir a", "put a/b"]
+        );
+
+        // A symlink pointing somewhere else, or a file where one was
+        let local = tree(vec![
+            entry("l", link("new"), 100),
+            entry("f", Kind::File, 100),
+        ]);
+        let there = [remote("l", link("old"), 100), remote("f", link("a"), 100)];
+        assert_eq!(
+            steps(&local, &there, &Options::default()),
+            ["rm l", "rm f", "ln l -> new", "put f"]
+        );
+    }
+
+    #[test]
+    fn parses_digests_from_the_top_of_the_copy() {
+        let digest = "a".repeat(64);
+        let digests = parse_digests(&format!("{0}  ./d/b\n{0}  c\n", digest));
+        assert_eq!(digests[Path::new("d/b")], digest);
+        assert_eq!(digests[Path::new("c")], digest);
+    }
+}
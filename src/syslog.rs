@@ -0,0 +1,248 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Mirroring runs to the local system log, for `--syslog`.
+//!
+//! When a run starts, as each node finishes and once the run is over, an
+//! entry is sent to journald, or to syslog where journald isn't running, so
+//! operations on the fleet reach a central log pipeline without anyone
+//! having to remember to copy them there. Each entry carries its details as
+//! structured fields: journald fields named `CLUSTER_RUN_EVENT`,
+//! `CLUSTER_RUN_HOST`, `CLUSTER_RUN_STATUS` and so on, or the same names in
+//! lower case as RFC 5424 structured data for syslog. The output of commands
+//! isn't sent, as it can be large; the `--log-dir` files are for that.
+
+use crate::output;
+use serde_json::Value;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Where journald listens for entries in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Where syslog listens for entries.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The identifier entries are logged under.
+const IDENTIFIER: &str = "cluster_run";
+
+/// The ID of the RFC 5424 structured data element holding the fields, under
+/// the example enterprise number RFC 5612 sets aside.
+const SD_ID: &str = "cluster_run@32473";
+
+/// How serious an entry is, as syslog numbers them.
+#[derive(Clone, Copy)]
+enum Priority {
+    Err = 3,
+    Notice = 5,
+    Info = 6,
+}
+
+/// The user-level facility, which syslog priorities are offset by.
+const FACILITY_USER: u8 = 1;
+
+/// The system log a run is mirrored to.
+pub struct Syslog {
+    socket: UnixDatagram,
+    journald: bool,
+    /// Whether sending has failed, so the failure is only warned of once.
+    failed: Mutex<bool>,
+}
+
+impl Syslog {
+    /// Connects to journald, or to syslog if journald isn't running.
+    ///
+    /// # Returns
+    ///
+    /// Returns the system log, or an error if neither is listening.
+    pub fn open() -> Result<Self, String> {
+        for (path, journald) in [(JOURNAL_SOCKET, true), (SYSLOG_SOCKET, false)] {
+            if !Path::new(path).exists() {
+                continue;
+            }
+            let socket = UnixDatagram::unbound()
+                .and_then(|socket| socket.connect(path).map(|_| socket))
+                .map_err(|e| format!("unable to connect to {}: {}", path, e))?;
+            return Ok(Syslog {
+                socket,
+                journald,
+                failed: Mutex::new(false),
+            });
+        }
+        Err(format!(
+            "--syslog needs journald or syslog to be running, but neither {} nor {} exists",
+            JOURNAL_SOCKET, SYSLOG_SOCKET
+        ))
+    }
+
+    /// Logs that a run has started.
+    pub fn run_started(&self, command: &str, nodes: usize) {
+        self.send(
+            Priority::Info,
+            &format!("Running `{}` on {} nodes", command, nodes),
+            &[
+                ("event", "run_started".to_string()),
+                ("command", command.to_string()),
+                ("nodes", nodes.to_string()),
+            ],
+        );
+    }
+
+    /// Logs a node's result, given its result object.
+    pub fn node_finished(&self, result: &Value) {
+        let field = |name: &str| field(result, name);
+        let (host, status) = (field("host"), field("status"));
+        let (priority, message) = match status.as_str() {
+            "succeeded" => (
+                Priority::Info,
+                format!(
+                    "{} succeeded in {:.2}s",
+                    host,
+                    result["duration"].as_f64().unwrap_or_default()
+                ),
+            ),
+            "failed" => (
+                Priority::Err,
+                format!("{} failed with exit status {}", host, field("exit_code")),
+            ),
+            "cancelled" => (Priority::Notice, format!("{} was cancelled", host)),
+            _ => (
+                Priority::Err,
+                format!("{} {}: {}", host, status.replace('_', " "), field("error")),
+            ),
+        };
+        let mut fields = vec![("event", "node_finished".to_string())];
+        for name in [
+            "host",
+            "command",
+            "status",
+            "exit_code",
+            "started_at",
+            "duration",
+            "attempts",
+            "error",
+        ] {
+            if !result[name].is_null() {
+                fields.push((name, field(name)));
+            }
+        }
+        self.send(priority, &message, &fields);
+    }
+
+    /// Logs the end of a run, given its summary object.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - What was run.
+    /// * `summary` - The run's summary object.
+    /// * `run` - The run's number in the history, if it was recorded.
+    pub fn run_finished(&self, command: &str, summary: &Value, run: Option<i64>) {
+        let count = |name: &str| summary[name].as_array().map_or(0, Vec::len);
+        let failed = count("failed") + count("timed_out") + count("errors");
+        let mut fields = vec![
+            ("event", "run_finished".to_string()),
+            ("command", command.to_string()),
+            ("succeeded", count("succeeded").to_string()),
+            ("failed", count("failed").to_string()),
+            ("timed_out", count("timed_out").to_string()),
+            ("errors", count("errors").to_string()),
+            ("cancelled", count("cancelled").to_string()),
+            ("not_started", field(summary, "not_started")),
+            ("wall_time", field(summary, "wall_time")),
+        ];
+        if let Some(run) = run {
+            fields.push(("history_id", run.to_string()));
+        }
+        let priority = if failed > 0 {
+            Priority::Err
+        } else {
+            Priority::Info
+        };
+        let message = format!(
+            "Finished `{}` in {:.2}s: {} succeeded, {} failed",
+            command,
+            summary["wall_time"].as_f64().unwrap_or_default(),
+            count("succeeded"),
+            failed
+        );
+        self.send(priority, &message, &fields);
+    }
+
+    /// Sends an entry, warning the first time it can't be sent.
+    fn send(&self, priority: Priority, message: &str, fields: &[(&str, String)]) {
+        let entry = if self.journald {
+            journal_entry(priority, message, fields)
+        } else {
+            syslog_entry(priority, message, fields)
+        };
+        if let Err(e) = self.socket.send(&entry) {
+            let mut failed = self.failed.lock().unwrap();
+            if !*failed {
+                tracing::warn!("Unable to write to the system log: {}", e);
+                *failed = true;
+            }
+        }
+    }
+}
+
+/// Returns a field of a JSON object as text, or empty if it's missing.
+fn field(object: &Value, name: &str) -> String {
+    match &object[name] {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Returns an entry in journald's native protocol, with the value of each
+/// field given as text or, if it spans lines, as a length and its bytes.
+fn journal_entry(priority: Priority, message: &str, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut entry = Vec::new();
+    let mut add = |name: &str, value: &str| {
+        entry.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    };
+    add("MESSAGE", message);
+    add("PRIORITY", &(priority as u8).to_string());
+    add("SYSLOG_IDENTIFIER", IDENTIFIER);
+    for (name, value) in fields {
+        add(&format!("CLUSTER_RUN_{}", name.to_ascii_uppercase()), value);
+    }
+    entry
+}
+
+/// Returns an RFC 5424 syslog entry, with the fields as structured data.
+fn syslog_entry(priority: Priority, message: &str, fields: &[(&str, String)]) -> Vec<u8> {
+    let params: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| {
+            // Quotes, backslashes and closing brackets are escaped in values
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!(
+        "<{}>1 {} - {} {} - [{} {}] {}",
+        FACILITY_USER * 8 + priority as u8,
+        output::timestamp(SystemTime::now()),
+        IDENTIFIER,
+        process::id(),
+        SD_ID,
+        params.join(" "),
+        message
+    )
+    .into_bytes()
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Per-node templating of commands and file paths.
+//!
+//! A template may contain placeholders such as `{host}` or `{index}`, which
+//! are replaced with values for the node being worked on. Only the names of
+//! known variables are treated as placeholders, so shell syntax like `${HOME}`
+//! or `{a,b}` passes through untouched. A literal placeholder is written by
+//! doubling its braces, so `{{host}}` becomes `{host}`.
+
+use crate::config::Node;
+use std::collections::BTreeMap;
+
+/// The values substituted into templates for a single node.
+pub struct Vars {
+    values: BTreeMap<String, String>,
+}
+
+impl Vars {
+    /// Creates the variables for a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The node's zero-based position in the node list, for `{index}`.
+    /// * `node` - The node, whose address is used for `{host}`, whose alias or
+    ///   address is used for `{name}` and whose first group is used for `{group}`.
+    pub fn for_node(index: usize, node: &Node) -> Self {
+        let mut vars = Vars {
+            values: BTreeMap::new(),
+        };
+        vars.set("host", &node.host);
+        vars.set("name", node.name());
+        vars.set("index", &index.to_string());
+        // A node in several groups gets the first, and one in none gets nothing
+        vars.set("group", node.groups.first().map_or("", String::as_str));
+        vars
+    }
+
+    /// Sets the value of a variable, replacing any existing value.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_string(), value.to_string());
+    }
+
+    /// Returns a copy of `template` with every placeholder replaced.
+    pub fn render(&self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            // A doubled placeholder is an escaped literal one
+            if let Some(name) = self.placeholder(&rest[1..]) {
+                if rest[1 + name.len() + 2..].starts_with('}') {
+                    rendered.push('{');
+                    rendered.push_str(name);
+                    rendered.push('}');
+                    rest = &rest[name.len() + 4..];
+                    continue;
+                }
+            }
+            match self.placeholder(rest) {
+                Some(name) => {
+                    rendered.push_str(&self.values[name]);
+                    rest = &rest[name.len() + 2..];
+                }
+                None => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+
+    /// Returns true if `template` contains a placeholder for any variable.
+    pub fn is_templated(&self, template: &str) -> bool {
+        self.render(template) != template
+    }
+
+    /// Returns the variable name if `text` starts with a placeholder for a known variable.
+    fn placeholder<'t>(&self, text: &'t str) -> Option<&'t str> {
+        let end = text.strip_prefix('{')?.find('}')?;
+        let name = &text[1..1 + end];
+        self.values.contains_key(name).then_some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        let mut node = Node::from("10.0.0.7");
+        node.alias = Some("gpu-07".to_string());
+        node.groups = vec!["gpu".to_string(), "rack1".to_string()];
+        Vars::for_node(6, &node)
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let vars = vars();
+        assert_eq!(
+            vars.render("ping {host} as {name}, node {index} of {group}"),
+            "ping 10.0.0.7 as gpu-07, node 6 of gpu"
+        );
+        assert_eq!(
+            vars.render("/logs/{name}/{name}.log"),
+            "/logs/gpu-07/gpu-07.log"
+        );
+        assert_eq!(vars.render("{host}{index}"), "10.0.0.76");
+        assert_eq!(vars.render(""), "");
+    }
+
+    #[test]
+    fn fills_in_a_node_without_an_alias_or_groups() {
+        let vars = Vars::for_node(0, &Node::from("gpu-01"));
+        assert_eq!(vars.render("{name}:{group}:{index}"), "gpu-01::0");
+    }
+
+    #[test]
+    fn leaves_unknown_placeholders_alone() {
+        let vars = vars();
+        for template in [
+            "echo ${HOME}",
+            "cp file.{a,b} /tmp",
+            "awk '{print $1}'",
+            "{hostname}",
+            "{ host }",
+            "{}",
+            "{host",
+            "}host{",
+        ] {
+            assert_eq!(vars.render(template), template);
+        }
+    }
+
+    #[test]
+    fn escapes_doubled_braces() {
+        let vars = vars();
+        assert_eq!(vars.render("{{host}} is {host}"), "{host} is 10.0.0.7");
+        assert_eq!(vars.render("{{{host}}}"), "{{host}}");
+        assert_eq!(vars.render("{{host}"), "{10.0.0.7");
+        // Doubled braces around other text aren't an escape
+        assert_eq!(vars.render("{{.Names}}"), "{{.Names}}");
+    }
+
+    #[test]
+    fn sets_extra_variables() {
+        let mut vars = vars();
+        vars.set("file", "app.tar");
+        vars.set("host", "gpu-07.example.com");
+        assert_eq!(vars.render("{host}:{file}"), "gpu-07.example.com:app.tar");
+        assert!(vars.is_templated("/srv/{file}"));
+        assert!(!vars.is_templated("/srv/${FILE}/{files}"));
+        // Shell syntax around a known name is still a placeholder
+        assert_eq!(vars.render("${file}"), "$app.tar");
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Spacing out new connections, so hundreds of nodes aren't all connected to
+//! in the same instant.
+//!
+//! sshd turns away connections beyond its `MaxStartups` that haven't logged
+//! in yet, and a bastion in front of the whole cluster sees every one of
+//! them. With `--connect-rate`, connections are started at most that many a
+//! second, each waiting for the next free turn, and with `--connect-jitter`
+//! each is also held back by a random part of that time. Only connecting is
+//! spaced out: once a node is logged into, its commands run alongside the
+//! others' as before, and sessions reused from the pool don't wait at all.
+
+use crate::cancel::{CancelToken, Cancelled};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The time between connections, from `--connect-rate`.
+static INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// The most a connection is held back at random, from `--connect-jitter`.
+static JITTER: OnceLock<Duration> = OnceLock::new();
+
+/// When the next connection may start.
+static NEXT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Starts at most `per_second` connections a second from now on.
+pub fn use_rate(per_second: f64) {
+    let _ = INTERVAL.set(Duration::from_secs_f64(1.0 / per_second));
+}
+
+/// Holds every connection back by a random time of up to `jitter`.
+pub fn use_jitter(jitter: Duration) {
+    let _ = JITTER.set(jitter);
+}
+
+/// Waits until a new connection may be started.
+///
+/// # Returns
+///
+/// Returns once it's this connection's turn, or an error if the run is
+/// cancelled first.
+pub async fn wait_turn(cancel: &CancelToken) -> Result<(), Cancelled> {
+    // Step 1: Take the next free turn, pushing the one after it back
+    let now = Instant::now();
+    let mut start = now;
+    if let Some(interval) = INTERVAL.get() {
+        let mut next = NEXT.lock().unwrap();
+        start = next.map_or(now, |next| next.max(now));
+        *next = Some(start + *interval);
+    }
+
+    // Step 2: Add the jitter; RandomState is seeded randomly for every instance
+    if let Some(jitter) = JITTER.get() {
+        let fraction = RandomState::new().hash_one(0) as f64 / u64::MAX as f64;
+        start += jitter.mul_f64(fraction);
+    }
+
+    if start > now && !cancel.pause(start - now).await {
+        return Err(Cancelled);
+    }
+    Ok(())
+}
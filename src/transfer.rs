@@ -0,0 +1,464 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! File transfers to and from cluster nodes over SFTP.
+//!
+//! Transfers run over the same authenticated sessions used for executing
+//! commands, so no separate scp setup is needed on the nodes. Files are copied
+//! in chunks, and a cancelled transfer stops at the next chunk.
+
+use crate::bandwidth::Pace;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::sync::{self, Kind, Options, Remote, Step, Synced, Tree};
+use ssh2::{ErrorCode, FileStat, OpenFlags, OpenType, Session, Sftp};
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The SFTP status for a path that doesn't exist.
+const NO_SUCH_FILE: i32 = 2;
+
+/// The size of the chunks files are copied in.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Uploads a local file to a path on the node.
+///
+/// The remote file is created or truncated, and its permissions are set to
+/// `mode` whether or not it already existed.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `local` - The file to upload.
+/// * `remote` - Where to write the file on the node.
+/// * `mode` - The permission bits to give the remote file.
+/// * `cancel` - Stops the upload part way through when cancelled.
+/// * `on_progress` - Called with the bytes sent so far and the total after each chunk.
+///
+/// # Returns
+///
+/// Returns the number of bytes uploaded, or an error if the transfer fails.
+pub fn upload(
+    sess: &Session,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let sftp = sess.sftp()?;
+    let mut pace = Pace::default();
+    send_file(
+        &sftp,
+        local,
+        remote,
+        mode,
+        None,
+        0,
+        &mut pace,
+        cancel,
+        on_progress,
+    )
+}
+
+/// Sends a local file over an SFTP session, creating or truncating the
+/// remote file and giving it `mode`, and the modification time if one is
+/// given, at the pace the bandwidth limits allow.
+///
+/// With an `offset`, the remote file's first `offset` bytes are taken to be
+/// the local file's and kept, and the rest is sent after them.
+///
+/// # Returns
+///
+/// Returns the number of bytes sent, or an error if the transfer fails.
+#[allow(clippy::too_many_arguments)]
+fn send_file(
+    sftp: &Sftp,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    mtime: Option<u64>,
+    offset: u64,
+    pace: &mut Pace,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(local)?;
+    let total = file.metadata()?.len();
+
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if offset == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote_file = sftp.open_mode(remote, flags, mode as i32, OpenType::File)?;
+    file.seek(SeekFrom::Start(offset))?;
+    remote_file.seek(SeekFrom::Start(offset))?;
+
+    // Copy the file across in chunks, reporting progress as we go
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        remote_file.write_all(&buf[..n])?;
+        pace.pause(n);
+        sent += n as u64;
+        on_progress(offset + sent, total);
+    }
+    remote_file.close()?;
+
+    // The mode given when opening only applies to newly created files
+    sftp.setstat(
+        remote,
+        FileStat {
+            atime: mtime,
+            mtime,
+            ..with_mode(mode)
+        },
+    )?;
+
+    Ok(sent)
+}
+
+/// Totals for a completed resumable upload.
+#[derive(Default)]
+pub struct Resumed {
+    /// The bytes kept from an earlier upload that was interrupted.
+    pub kept: u64,
+    /// The bytes sent.
+    pub sent: u64,
+}
+
+/// Uploads a local file to a path on the node by way of a partial file
+/// beside it, picking up from where an earlier upload left off.
+///
+/// The partial file is named after the local file's size and modification
+/// time, so an upload is only resumed from one of the same local file, and
+/// whatever it already holds is kept. Once the rest is sent, the partial
+/// file is given `mode` and moved into place, replacing what was there. An
+/// upload that's interrupted leaves the partial file for the next to resume.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `local` - The file to upload.
+/// * `remote` - Where to put the file on the node.
+/// * `mode` - The permission bits to give the remote file.
+/// * `cancel` - Stops the upload part way through when cancelled.
+/// * `on_progress` - Called with the bytes on the node so far and the total after each chunk.
+///
+/// # Returns
+///
+/// Returns the bytes kept and sent, or an error if the transfer fails.
+pub fn resume_upload(
+    sess: &Session,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<Resumed, Box<dyn std::error::Error + Send + Sync>> {
+    let metadata = fs::metadata(local)?;
+    let partial = partial_path(remote, &metadata);
+    let sftp = sess.sftp()?;
+
+    // Step 1: Keep what an earlier upload got through
+    let kept = match sftp.stat(&partial) {
+        Ok(stat) => stat
+            .size
+            .filter(|&size| size <= metadata.len())
+            .unwrap_or(0),
+        Err(e) if e.code() == ErrorCode::SFTP(NO_SUCH_FILE) => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    // Step 2: Send the rest
+    let mut pace = Pace::default();
+    let sent = send_file(
+        &sftp,
+        local,
+        &partial,
+        mode,
+        None,
+        kept,
+        &mut pace,
+        cancel,
+        on_progress,
+    )?;
+
+    // Step 3: Move it into place; renaming over an existing file isn't
+    // allowed by every server, so that's removed first
+    match sftp.unlink(remote) {
+        Ok(()) => {}
+        Err(e) if e.code() == ErrorCode::SFTP(NO_SUCH_FILE) => {}
+        Err(e) => return Err(e.into()),
+    }
+    sftp.rename(&partial, remote, None)?;
+    Ok(Resumed { kept, sent })
+}
+
+/// Returns the partial file beside a remote path that a resumable upload of
+/// a local file writes to, named after the local file's size and
+/// modification time.
+pub fn partial_path(remote: &Path, local: &fs::Metadata) -> PathBuf {
+    let name = remote.file_name().unwrap_or_default().to_string_lossy();
+    remote.with_file_name(format!(
+        ".{}.{}-{}.partial",
+        name,
+        local.len(),
+        local.mtime()
+    ))
+}
+
+/// Returns file attributes that only set the permission bits.
+fn with_mode(mode: u32) -> FileStat {
+    FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode),
+        atime: None,
+        mtime: None,
+    }
+}
+
+/// Totals for a completed download.
+#[derive(Default)]
+pub struct Downloaded {
+    /// The number of files written locally, including symlinks.
+    pub files: u64,
+    /// The number of bytes written locally.
+    pub bytes: u64,
+}
+
+/// Downloads a file, or a directory recursively, from the node.
+///
+/// Directories are recreated locally, files keep their permission bits, and
+/// symlinks are recreated as symlinks rather than followed.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `remote` - The file or directory to download.
+/// * `local` - The local path to write it to.
+/// * `cancel` - Stops the download part way through when cancelled.
+///
+/// # Returns
+///
+/// Returns the totals of what was downloaded, or an error if any part of
+/// the transfer fails.
+pub fn download(
+    sess: &Session,
+    remote: &Path,
+    local: &Path,
+    cancel: &CancelToken,
+) -> Result<Downloaded, Box<dyn std::error::Error + Send + Sync>> {
+    let sftp = sess.sftp()?;
+    let stat = sftp.lstat(remote)?;
+    if let Some(parent) = local.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut totals = Downloaded::default();
+    let mut pace = Pace::default();
+    download_entry(&sftp, remote, &stat, local, &mut pace, cancel, &mut totals)?;
+    Ok(totals)
+}
+
+/// Downloads a single remote entry, recursing into directories.
+fn download_entry(
+    sftp: &Sftp,
+    remote: &Path,
+    stat: &FileStat,
+    local: &Path,
+    pace: &mut Pace,
+    cancel: &CancelToken,
+    totals: &mut Downloaded,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if cancel.is_cancelled() {
+        return Err(Cancelled.into());
+    }
+    let file_type = stat.file_type();
+    if file_type.is_dir() {
+        fs::create_dir_all(local)?;
+        for (path, stat) in sftp.readdir(remote)? {
+            let name = path.file_name().ok_or("Remote entry has no file name")?;
+            download_entry(sftp, &path, &stat, &local.join(name), pace, cancel, totals)?;
+        }
+    } else if file_type.is_symlink() {
+        let target = sftp.readlink(remote)?;
+        if local.symlink_metadata().is_ok() {
+            fs::remove_file(local)?;
+        }
+        symlink(target, local)?;
+        totals.files += 1;
+    } else {
+        let mut remote_file = sftp.open(remote)?;
+        let mut file = File::create(local)?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            if cancel.is_cancelled() {
+                return Err(Cancelled.into());
+            }
+            let n = remote_file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n])?;
+            pace.pause(n);
+            totals.bytes += n as u64;
+        }
+        if let Some(perm) = stat.perm {
+            fs::set_permissions(local, fs::Permissions::from_mode(perm & 0o7777))?;
+        }
+        totals.files += 1;
+    }
+    Ok(())
+}
+
+/// Brings a directory on the node in line with a local tree, as the `sync`
+/// module describes, creating the directory if it doesn't exist yet.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `tree` - The local tree to mirror.
+/// * `remote` - The directory on the node to mirror it to.
+/// * `options` - Whether to delete extra entries, and the node's digests.
+/// * `cancel` - Stops the sync part way through when cancelled.
+/// * `on_progress` - Called with the bytes sent so far and the total to send after each chunk.
+///
+/// # Returns
+///
+/// Returns the totals of what was changed, or an error if any change fails.
+pub fn sync(
+    sess: &Session,
+    tree: &Tree,
+    remote: &Path,
+    options: &Options,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<Synced, Box<dyn std::error::Error + Send + Sync>> {
+    let sftp = sess.sftp()?;
+    let listing = match sftp.lstat(remote) {
+        Ok(stat) if stat.is_dir() => list(&sftp, remote, cancel)?,
+        Ok(_) => return Err(format!("{} isn't a directory", remote.display()).into()),
+        Err(e) if e.code() == ErrorCode::SFTP(NO_SUCH_FILE) => {
+            create_dirs(&sftp, remote)?;
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let steps = sync::plan(tree, &listing, options);
+    let total = sync::bytes_to_send(&steps);
+    let mut synced = Synced::default();
+    let mut pace = Pace::default();
+    for step in steps {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        match step {
+            Step::Remove { path, dir } => {
+                if dir {
+                    sftp.rmdir(&remote.join(path))?;
+                } else {
+                    sftp.unlink(&remote.join(path))?;
+                }
+                synced.removed += 1;
+            }
+            Step::CreateDir { path, mode } => {
+                let path = remote.join(path);
+                sftp.mkdir(&path, mode as i32)?;
+                sftp.setstat(&path, with_mode(mode))?;
+            }
+            Step::Upload {
+                path, mode, mtime, ..
+            } => {
+                let sent_before = synced.bytes;
+                synced.bytes += send_file(
+                    &sftp,
+                    &tree.root.join(&path),
+                    &remote.join(&path),
+                    mode,
+                    Some(mtime),
+                    0,
+                    &mut pace,
+                    cancel,
+                    &mut |sent, _| on_progress(sent_before + sent, total),
+                )?;
+                synced.files += 1;
+                synced.sent.push(path);
+            }
+            Step::Symlink { path, target } => {
+                // OpenSSH's server takes the target and the link the other
+                // way round from the SFTP draft
+                sftp.symlink(&target, &remote.join(path))?;
+                synced.files += 1;
+            }
+        }
+    }
+    Ok(synced)
+}
+
+/// Lists everything in a directory on the node, with every directory before
+/// what's in it.
+fn list(
+    sftp: &Sftp,
+    remote: &Path,
+    cancel: &CancelToken,
+) -> Result<Vec<Remote>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut listing = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        for (path, stat) in sftp.readdir(&remote.join(&dir))? {
+            let name = path.file_name().ok_or("Remote entry has no file name")?;
+            let path = dir.join(name);
+            let file_type = stat.file_type();
+            let kind = if file_type.is_dir() {
+                dirs.push(path.clone());
+                Kind::Dir
+            } else if file_type.is_file() {
+                Kind::File
+            } else if file_type.is_symlink() {
+                Kind::Symlink(sftp.readlink(&remote.join(&path))?)
+            } else {
+                Kind::Other
+            };
+            listing.push(Remote {
+                path,
+                kind,
+                size: stat.size.unwrap_or_default(),
+                mtime: stat.mtime.unwrap_or_default(),
+            });
+        }
+    }
+    Ok(listing)
+}
+
+/// Creates a directory on the node, and any of its parents that are missing.
+fn create_dirs(sftp: &Sftp, remote: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let missing: Vec<&Path> = remote
+        .ancestors()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .take_while(|dir| sftp.lstat(dir).is_err())
+        .collect();
+    for dir in missing.into_iter().rev() {
+        // Something else may have created it in the meantime
+        if let Err(e) = sftp.mkdir(dir, 0o755) {
+            if !sftp.lstat(dir).is_ok_and(|stat| stat.is_dir()) {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,246 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The transport built on libssh2, through the `ssh2` crate.
+//!
+//! libssh2's calls block, so each one runs on the runtime's pool of blocking
+//! threads, with output and progress sent back to the future awaiting it, so
+//! callbacks still run on the thread driving the nodes.
+
+use super::{Session, Transport};
+use crate::cancel::CancelToken;
+use crate::config::Node;
+use crate::ssh::{self, CommandResult, Connection, Latency, OutputStream, Reconnect};
+use crate::sync::{Options, Synced, Tree};
+use crate::transfer::{self, Downloaded, Resumed};
+use futures::future::LocalBoxFuture;
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinError};
+
+/// Opens sessions with libssh2.
+pub struct Libssh2;
+
+/// A libssh2 session, shared with the blocking calls made on it.
+struct Libssh2Session(Arc<Connection>);
+
+impl Transport for Libssh2 {
+    fn connect<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Box<dyn Session>, Box<dyn std::error::Error + Send + Sync>>>
+    {
+        let node = node.clone();
+        Box::pin(async move {
+            let conn = blocking(move || ssh::open(&node, timeout)).await?;
+            Ok(Box::new(Libssh2Session(Arc::new(conn))) as Box<dyn Session>)
+        })
+    }
+
+    fn probe<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Latency, Box<dyn std::error::Error + Send + Sync>>> {
+        let node = node.clone();
+        Box::pin(blocking(move || ssh::probe(&node, timeout)))
+    }
+}
+
+impl Session for Libssh2Session {
+    fn exec<'a>(
+        &'a self,
+        command: &'a str,
+        stdin: Option<&'a [u8]>,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let command = command.to_string();
+        let stdin = stdin.map(<[u8]>::to_vec);
+        let cancel = cancel.clone();
+        Box::pin(reporting(
+            move |report| {
+                ssh::exec(
+                    &conn.sess,
+                    &command,
+                    stdin.as_deref(),
+                    timeout,
+                    &cancel,
+                    &mut |stream, chunk| report((stream, chunk.to_vec())),
+                )
+            },
+            |(stream, chunk)| on_output(stream, &chunk),
+        ))
+    }
+
+    fn exec_resumable<'a>(
+        &'a self,
+        command: &'a str,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        reconnect: &'a Reconnect<'a>,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let command = command.to_string();
+        let cancel = cancel.clone();
+        let node = reconnect.node.clone();
+        let (reconnect_timeout, attempts) = (reconnect.timeout, reconnect.attempts);
+        Box::pin(reporting(
+            move |report| {
+                let reconnect = Reconnect {
+                    node: &node,
+                    timeout: reconnect_timeout,
+                    attempts,
+                };
+                ssh::exec_resumable(
+                    &conn.sess,
+                    &command,
+                    timeout,
+                    &cancel,
+                    &reconnect,
+                    &mut |stream, chunk| report((stream, chunk.to_vec())),
+                )
+            },
+            |(stream, chunk)| on_output(stream, &chunk),
+        ))
+    }
+
+    fn upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<u64, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let (local, remote) = (local.to_path_buf(), remote.to_path_buf());
+        let cancel = cancel.clone();
+        Box::pin(reporting(
+            move |report| {
+                transfer::upload(
+                    &conn.sess,
+                    &local,
+                    &remote,
+                    mode,
+                    &cancel,
+                    &mut |sent, total| report((sent, total)),
+                )
+            },
+            |(sent, total)| on_progress(sent, total),
+        ))
+    }
+
+    fn resume_upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Resumed, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let (local, remote) = (local.to_path_buf(), remote.to_path_buf());
+        let cancel = cancel.clone();
+        Box::pin(reporting(
+            move |report| {
+                transfer::resume_upload(
+                    &conn.sess,
+                    &local,
+                    &remote,
+                    mode,
+                    &cancel,
+                    &mut |sent, total| report((sent, total)),
+                )
+            },
+            |(sent, total)| on_progress(sent, total),
+        ))
+    }
+
+    fn download<'a>(
+        &'a self,
+        remote: &'a Path,
+        local: &'a Path,
+        cancel: &'a CancelToken,
+    ) -> LocalBoxFuture<'a, Result<Downloaded, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let (remote, local) = (remote.to_path_buf(), local.to_path_buf());
+        let cancel = cancel.clone();
+        Box::pin(blocking(move || {
+            transfer::download(&conn.sess, &remote, &local, &cancel)
+        }))
+    }
+
+    fn sync<'a>(
+        &'a self,
+        tree: &'a Tree,
+        remote: &'a Path,
+        options: &'a Options,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Synced, Box<dyn std::error::Error + Send + Sync>>> {
+        let conn = self.0.clone();
+        let (tree, remote, options) = (tree.clone(), remote.to_path_buf(), options.clone());
+        let cancel = cancel.clone();
+        Box::pin(reporting(
+            move |report| {
+                transfer::sync(
+                    &conn.sess,
+                    &tree,
+                    &remote,
+                    &options,
+                    &cancel,
+                    &mut |sent, total| report((sent, total)),
+                )
+            },
+            |(sent, total)| on_progress(sent, total),
+        ))
+    }
+
+    fn is_open(&self) -> bool {
+        self.0.is_open()
+    }
+}
+
+/// Runs blocking work on the runtime's pool of blocking threads.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    joined(task::spawn_blocking(work).await)
+}
+
+/// Runs blocking work as `blocking` does, passing each event it reports to
+/// `on_event` as it's reported.
+async fn reporting<E, T>(
+    work: impl FnOnce(&mut dyn FnMut(E)) -> T + Send + 'static,
+    mut on_event: impl FnMut(E),
+) -> T
+where
+    E: Send + 'static,
+    T: Send + 'static,
+{
+    let (events, mut reported) = mpsc::unbounded_channel();
+    let handle = task::spawn_blocking(move || {
+        work(&mut |event| {
+            let _ = events.send(event);
+        })
+    });
+    // The channel closes once the work returns and drops its sender
+    while let Some(event) = reported.recv().await {
+        on_event(event);
+    }
+    joined(handle.await)
+}
+
+/// Returns what blocking work returned, carrying on any panic it raised.
+fn joined<T>(result: Result<T, JoinError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The transports sessions with nodes are opened over, and what's done over
+//! a session: running commands, copying files and mirroring directories.
+//!
+//! Everything that logs into nodes goes through the `Transport` chosen with
+//! `--transport`, and the `Session`s it opens. Their methods return futures,
+//! which the executor drives concurrently on the calling thread, so a node
+//! that's waiting on the network doesn't hold a thread of its own, however
+//! many nodes are being run on at once.
+//!
+//! Three transports are built in:
+//! - `russh`, the default, which speaks SSH natively on the runtime, so
+//!   every session is a task on a few threads.
+//! - `libssh2`, which runs libssh2's blocking calls on the runtime's pool of
+//!   threads, taking a thread per node while it's busy.
+//! - `openssh`, which runs the system's ssh, so logins work however the
+//!   user's OpenSSH config and ssh-agent set them up.
+//!
+//! All of them kill abandoned commands in the same way. The first two also
+//! log in and check host keys in the same way, while `openssh` leaves both
+//! to ssh.
+
+pub mod libssh2;
+pub mod openssh;
+pub mod russh;
+pub mod sftp;
+
+use crate::cancel::CancelToken;
+use crate::config::Node;
+use crate::ssh::{CommandResult, Latency, OutputStream, Reconnect, TimedOut};
+use crate::sync::{Options, Synced, Tree};
+use crate::transfer::{Downloaded, Resumed};
+use clap::ValueEnum;
+use futures::future::LocalBoxFuture;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+/// The most blocking calls run at once, which for libssh2 is the most nodes
+/// that can be busy at once.
+const BLOCKING_THREADS: usize = 4096;
+
+/// The transport chosen with `--transport`, set once per run.
+static KIND: OnceLock<Kind> = OnceLock::new();
+
+/// The runtime every transport runs on, started when it's first needed.
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The ways sessions with nodes can be opened.
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    /// russh, with every session a task on a few threads
+    #[default]
+    Russh,
+    /// libssh2, with its blocking calls run on a pool of threads
+    Libssh2,
+    /// The system's ssh, with its own config, keys and ControlMaster
+    Openssh,
+}
+
+/// Opens sessions with nodes.
+pub trait Transport: Sync {
+    /// Opens an authenticated session with a node.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The node to connect to, with the user, port and key to use.
+    /// * `timeout` - The limit on connecting, handshaking and authenticating.
+    ///
+    /// # Returns
+    ///
+    /// Returns the session, or an error if the node can't be reached or
+    /// logged into.
+    fn connect<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Box<dyn Session>, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Checks that a node accepts TCP connections and completes an SSH
+    /// handshake, without logging in.
+    ///
+    /// # Returns
+    ///
+    /// Returns how long each stage took, or an error if the node can't be
+    /// reached.
+    fn probe<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Latency, Box<dyn std::error::Error + Send + Sync>>>;
+}
+
+/// An authenticated session with a node.
+pub trait Session: Send {
+    /// Executes a command, as `ssh::exec` describes.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command to execute.
+    /// * `stdin` - Input to give the command. Without it the command's input is left open.
+    /// * `timeout` - The limit on the command running to completion.
+    /// * `cancel` - Abandons the command when the run is cancelled.
+    /// * `on_output` - Called with each chunk of output as it arrives.
+    ///
+    /// # Returns
+    ///
+    /// Returns the command's output and exit status, or a TimedOut or
+    /// Cancelled error if it was killed, or any other error that stopped it.
+    fn exec<'a>(
+        &'a self,
+        command: &'a str,
+        stdin: Option<&'a [u8]>,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Executes a command that outlives the connection it was started over,
+    /// as `ssh::exec_resumable` describes.
+    fn exec_resumable<'a>(
+        &'a self,
+        command: &'a str,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        reconnect: &'a Reconnect<'a>,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Uploads a local file to a path on the node, as `transfer::upload`
+    /// describes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of bytes uploaded, or an error if the transfer fails.
+    fn upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<u64, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Uploads a local file to a path on the node by way of a partial file,
+    /// picking up from where an earlier upload left off, as
+    /// `transfer::resume_upload` describes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the bytes kept and sent, or an error if the transfer fails.
+    fn resume_upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Resumed, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Downloads a file, or a directory recursively, from the node, as
+    /// `transfer::download` describes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the totals of what was downloaded, or an error if any part of
+    /// the transfer fails.
+    fn download<'a>(
+        &'a self,
+        remote: &'a Path,
+        local: &'a Path,
+        cancel: &'a CancelToken,
+    ) -> LocalBoxFuture<'a, Result<Downloaded, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Brings a directory on the node in line with a local tree, as
+    /// `transfer::sync` describes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the totals of what was changed, or an error if any change
+    /// fails.
+    fn sync<'a>(
+        &'a self,
+        tree: &'a Tree,
+        remote: &'a Path,
+        options: &'a Options,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Synced, Box<dyn std::error::Error + Send + Sync>>>;
+
+    /// Returns true if the connection is still open, so the session can be
+    /// used again. This never waits.
+    fn is_open(&self) -> bool;
+}
+
+/// Opens sessions over the given transport for the rest of the run.
+pub fn use_transport(kind: Kind) {
+    let _ = KIND.set(kind);
+}
+
+/// Returns the transport sessions are opened over.
+pub fn current() -> &'static dyn Transport {
+    match KIND.get().copied().unwrap_or_default() {
+        Kind::Russh => &self::russh::Russh,
+        Kind::Libssh2 => &libssh2::Libssh2,
+        Kind::Openssh => &openssh::Openssh,
+    }
+}
+
+/// Runs a future to completion on the calling thread, with the runtime's
+/// threads running the transports' work.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
+
+/// Bounds connecting to a node by `timeout`, turning a connection error
+/// caused by the timeout into a TimedOut error.
+pub async fn within<T>(
+    timeout: Option<Duration>,
+    work: impl Future<Output = Result<T, Box<dyn std::error::Error + Send + Sync>>>,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(after) = timeout else {
+        return work.await;
+    };
+    let timed_out = || -> Box<dyn std::error::Error + Send + Sync> {
+        Box::new(TimedOut {
+            stage: "connection",
+            after,
+        })
+    };
+    match tokio::time::timeout(after, work).await {
+        Ok(Err(e))
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut) =>
+        {
+            Err(timed_out())
+        }
+        Ok(result) => result,
+        Err(_) => Err(timed_out()),
+    }
+}
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .max_blocking_threads(BLOCKING_THREADS)
+            .build()
+            .expect("unable to start the runtime")
+    })
+}
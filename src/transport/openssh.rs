@@ -0,0 +1,909 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The transport that runs the system's `ssh`, for logins that only
+//! OpenSSH's client can do, such as with PKCS#11 tokens, GSSAPI, or
+//! `ProxyCommand` and `Match` rules in `~/.ssh/config`.
+//!
+//! Nodes are given to ssh by the name they have in the cluster config, so
+//! everything the user's OpenSSH config says about them applies, and ssh
+//! asks for any PIN or password it needs itself. Each session is a
+//! ControlMaster that the node is logged into once, with its commands and
+//! transfers run as ssh processes sharing its connection, and that closes
+//! itself once it's been idle for a while, including after cluster_run
+//! exits. A node the user's config already multiplexes, with a
+//! `ControlPath`, uses the user's masters instead. Transfers run the sftp
+//! subsystem over an ssh process's pipes.
+//!
+//! The nodes behind the same jump hosts share a master for the last of
+//! them, which they connect through with `ssh -W` as their `ProxyCommand`
+//! rather than each logging into the jump hosts with `-J`.
+//!
+//! Host keys are checked by ssh against its own known hosts files, as
+//! `--strict-host-key-checking` says, so fingerprints pinned in the cluster
+//! config can't be checked, and nodes can't be reached through `proxy`
+//! URLs; a `ProxyCommand` in the OpenSSH config does that instead.
+
+use super::{sftp, within, Session, Transport};
+use crate::agent;
+use crate::bandwidth::Pace;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::config::Node;
+use crate::dial;
+use crate::host_key::{self, Checking};
+use crate::jump;
+use crate::shell;
+use crate::ssh::{self, Capture, CommandResult, Job, Latency, OutputStream, Reconnect, TimedOut};
+use crate::ssh_config;
+use crate::sync::{Options, Synced, Tree};
+use crate::transfer::{Downloaded, Resumed, CHUNK_SIZE};
+use futures::future::{self, LocalBoxFuture};
+use russh_sftp::client::SftpSession;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+
+/// The OpenSSH client.
+const PROGRAM: &str = "ssh";
+
+/// The exit status ssh reports when it fails itself, or when the command
+/// closed without one.
+const NO_STATUS: i32 = 255;
+
+/// How long a session's master is kept open with no commands running over
+/// it, after which it closes itself.
+const MASTER_IDLE: Duration = Duration::from_secs(30);
+
+/// How often to check whether a master has closed.
+const MASTER_POLL: Duration = Duration::from_millis(20);
+
+/// The number of the next master started, which names its socket.
+static NEXT_MASTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The sockets of the masters for each chain of jump hosts.
+static JUMPS: jump::Logins<PathBuf> = jump::Logins::new();
+
+/// Opens sessions by running ssh.
+pub struct Openssh;
+
+/// A session with a node through ssh.
+struct OpensshSession {
+    node: Node,
+    /// The limit on connecting, for commands that connect afresh because
+    /// the master closed.
+    timeout: Option<Duration>,
+    /// The socket of the master the session started, or None if the user's
+    /// OpenSSH config multiplexes the node.
+    master: Option<PathBuf>,
+    /// The socket of the master for the node's jump hosts, if it has any.
+    via: Option<PathBuf>,
+}
+
+impl Transport for Openssh {
+    fn connect<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Box<dyn Session>, Box<dyn std::error::Error + Send + Sync>>>
+    {
+        Box::pin(async move {
+            let sess = log_in(node, timeout).await?;
+            Ok(Box::new(sess) as Box<dyn Session>)
+        })
+    }
+
+    fn probe<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Latency, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let via = jump_master(node, timeout).await?;
+            within(timeout, probe(node, via.as_deref(), timeout)).await
+        })
+    }
+}
+
+/// Logs into a node through the master for its jump hosts, if it has any,
+/// with logging into the node bounded by `timeout`.
+async fn log_in(
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<OpensshSession, Box<dyn std::error::Error + Send + Sync>> {
+    let via = jump_master(node, timeout).await?;
+    within(timeout, open(node, via, timeout)).await
+}
+
+/// Returns the socket of the master for a node's jump hosts, starting it
+/// unless another node behind them has. Logging in isn't bounded by the
+/// node's timeout, as ssh may ask for a one-time code, but connecting is
+/// by ssh's `ConnectTimeout`.
+///
+/// # Returns
+///
+/// Returns None if the node has no jump hosts, or an error if the master
+/// couldn't log in.
+async fn jump_master(
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<Option<PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(jump) = &node.jump else {
+        return Ok(None);
+    };
+    // A master removes its socket when it closes
+    let usable = |path: &PathBuf| path.exists();
+    let log_in = || start_jump_master(jump, timeout);
+    JUMPS.get(jump, node, usable, log_in).await.map(Some)
+}
+
+/// Starts a master for the last of a chain of jump hosts, reached through
+/// the others with `-J`.
+async fn start_jump_master(
+    jump: &str,
+    timeout: Option<Duration>,
+) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let path = env::temp_dir().join(format!(
+        "cluster_run-{}-{}",
+        std::process::id(),
+        NEXT_MASTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (before, last) = split_jump(jump);
+    let mut cmd = client(timeout);
+    cmd.args(["-o", "LogLevel=ERROR", "-N", "-M", "-a", "-S"])
+        .arg(&path)
+        .arg("-o")
+        .arg(format!("ControlPersist={}", MASTER_IDLE.as_secs()));
+    if let Some(before) = before {
+        cmd.arg("-J").arg(before);
+    }
+    let output = cmd
+        .arg("--")
+        .arg(destination(last))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(format!(
+            "unable to reach jump host {}: {}",
+            last,
+            failure(output.status, &output.stderr)
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+/// Splits a node's jump hosts into those before the last, if any, and the
+/// last.
+fn split_jump(jump: &str) -> (Option<&str>, &str) {
+    match jump.rsplit_once(',') {
+        Some((before, last)) => (Some(before), last.trim()),
+        None => (None, jump.trim()),
+    }
+}
+
+/// Returns a jump host, written as `[user@]host[:port]`, as an `ssh://`
+/// destination, which unlike a plain destination can have a port.
+fn destination(hop: &str) -> String {
+    let (user, address) = match hop.rsplit_once('@') {
+        Some((user, address)) => (format!("{}@", user), address),
+        None => (String::new(), hop),
+    };
+    // A bare IPv6 address needs brackets to be told from its port
+    match address.matches(':').count() > 1 && !address.starts_with('[') {
+        true => format!("ssh://{}[{}]", user, address),
+        false => format!("ssh://{}{}", user, address),
+    }
+}
+
+/// Logs into a node, starting a master for the session unless the user's
+/// OpenSSH config multiplexes the node.
+async fn open(
+    node: &Node,
+    via: Option<PathBuf>,
+    timeout: Option<Duration>,
+) -> Result<OpensshSession, Box<dyn std::error::Error + Send + Sync>> {
+    let master = match multiplexed(node, via.as_deref()).await? {
+        true => None,
+        false => Some(env::temp_dir().join(format!(
+            "cluster_run-{}-{}",
+            std::process::id(),
+            NEXT_MASTER.fetch_add(1, Ordering::Relaxed)
+        ))),
+    };
+
+    // A master goes into the background once it's logged in, and otherwise
+    // logging in with the user's master starts or reuses it
+    let mut cmd = ssh(node, via.as_deref(), timeout)?;
+    cmd.args(["-o", "LogLevel=ERROR"]);
+    match &master {
+        Some(path) => {
+            // The master decides whether its sessions may forward the agent
+            cmd.args(["-N", "-M", forward_agent(), "-S"])
+                .arg(path)
+                .arg("-o")
+                .arg(format!("ControlPersist={}", MASTER_IDLE.as_secs()));
+            cmd.arg("--").arg(&node.host);
+        }
+        None => {
+            cmd.args(["-T", "-a", "--"]).arg(&node.host).arg("exit");
+        }
+    }
+    let output = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(failure(output.status, &output.stderr).into());
+    }
+    Ok(OpensshSession {
+        node: node.clone(),
+        timeout,
+        master,
+        via,
+    })
+}
+
+/// Returns true if the user's OpenSSH config gives the node a
+/// `ControlPath`, so its masters should be used rather than one of ours.
+async fn multiplexed(
+    node: &Node,
+    via: Option<&Path>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let output = ssh(node, via, None)?
+        .arg("-G")
+        .arg("--")
+        .arg(&node.host)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(failure(output.status, &output.stderr).into());
+    }
+    let config = String::from_utf8_lossy(&output.stdout);
+    Ok(config.lines().any(|line| line.starts_with("controlpath ")))
+}
+
+/// Checks that a node completes an SSH handshake by trying to log in
+/// without asking for anything, which either logging in or being turned
+/// down shows.
+///
+/// # Returns
+///
+/// Returns how long it took to connect, and then to be logged in or turned
+/// down, or an error if the node can't be reached.
+async fn probe(
+    node: &Node,
+    via: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<Latency, Box<dyn std::error::Error + Send + Sync>> {
+    let mut cmd = ssh(node, via, timeout)?;
+    cmd.args([
+        "-v",
+        "-T",
+        "-a",
+        "-o",
+        "BatchMode=yes",
+        "-o",
+        "ControlPath=none",
+    ])
+    .arg("--")
+    .arg(&node.host)
+    .arg("exit")
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::piped());
+    let started = Instant::now();
+    let mut child = cmd.spawn().map_err(spawn_error)?;
+
+    // ssh says when it's connected, or through a proxy command when the
+    // node's sshd first answers
+    let mut lines = BufReader::new(child.stderr.take().expect("stderr is piped")).lines();
+    let mut connected = None;
+    let mut messages = Vec::new();
+    let mut refused = false;
+    while let Some(line) = lines.next_line().await? {
+        if connected.is_none()
+            && (line.contains("Connection established") || line.contains("Remote protocol version"))
+        {
+            connected = Some(Instant::now());
+        }
+        if !line.starts_with("debug") {
+            refused |= line.contains("Permission denied");
+            messages.push(line);
+        }
+    }
+    let status = child.wait().await?;
+    match connected {
+        Some(connected) if status.success() || refused => Ok(Latency {
+            connect: connected - started,
+            handshake: connected.elapsed(),
+        }),
+        _ => Err(failure(status, messages.join("\n").as_bytes()).into()),
+    }
+}
+
+/// Returns an ssh command for a node, with the options that come from the
+/// cluster config and cluster_run's flags, reaching it through the master
+/// for its jump hosts at `via` if it has one. The caller adds its own
+/// options, then `--`, the node's name and the command to run.
+fn ssh(node: &Node, via: Option<&Path>, timeout: Option<Duration>) -> Result<Command, String> {
+    if node.proxy.is_some() {
+        return Err(format!(
+            "{} has a proxy, which the openssh transport can't connect through; set a ProxyCommand for it in the OpenSSH config instead",
+            node
+        ));
+    }
+    let checking = host_key::checking();
+    if node.host_key.is_some() && checking != Checking::No {
+        return Err(format!(
+            "{} pins its host key, which the openssh transport can't check; list the key in the known hosts file instead",
+            node
+        ));
+    }
+
+    let mut cmd = client(timeout);
+    cmd.arg("-l").arg(node.user());
+    if let Some(port) = node.port {
+        cmd.arg("-p").arg(port.to_string());
+    }
+    if let Some(address) = &node.resolve {
+        cmd.arg("-o").arg(format!("HostName={}", address));
+    }
+    if let Some(key) = &node.key {
+        cmd.arg("-i").arg(key);
+    }
+    match (via, &node.jump) {
+        (Some(master), Some(jump)) => {
+            cmd.arg("-o").arg(format!(
+                "ProxyCommand={}",
+                proxy_command(master, jump, timeout)
+            ));
+        }
+        (None, Some(jump)) => {
+            cmd.arg("-J").arg(jump);
+        }
+        _ => {}
+    }
+    if let Some(compression) = node.compression {
+        let value = if compression { "yes" } else { "no" };
+        cmd.arg("-o").arg(format!("Compression={}", value));
+    }
+    Ok(cmd)
+}
+
+/// Returns the `ProxyCommand` that reaches a node over the master for its
+/// jump hosts, which connects to them itself if the master has closed.
+fn proxy_command(master: &Path, jump: &str, timeout: Option<Duration>) -> String {
+    let (before, last) = split_jump(jump);
+    let mut cmd = client(timeout);
+    cmd.args(["-o", "LogLevel=ERROR", "-a", "-o", "ControlMaster=no", "-S"])
+        .arg(master);
+    if let Some(before) = before {
+        cmd.arg("-J").arg(before);
+    }
+    cmd.args(["-W", "%h:%p", "--"]).arg(destination(last));
+    let mut words = vec![PROGRAM.to_string()];
+    words.extend(
+        cmd.as_std()
+            .get_args()
+            .map(|arg| shell::quote(&arg.to_string_lossy())),
+    );
+    words.join(" ")
+}
+
+/// Returns an ssh command with the options that come from cluster_run's
+/// flags and the `[transport]` section.
+fn client(timeout: Option<Duration>) -> Command {
+    let mut cmd = Command::new(PROGRAM);
+    cmd.kill_on_drop(true);
+    if let Some(path) = ssh_config::path_override() {
+        cmd.arg("-F").arg(path);
+    }
+    let checking = host_key::checking();
+
+    // Step 1: Check host keys as the other transports do
+    cmd.args(checking.ssh_options());
+    if let (Some(path), false) = (host_key::path_override(), checking == Checking::No) {
+        cmd.arg("-o")
+            .arg(format!("UserKnownHostsFile={}", path.display()));
+    }
+
+    // Step 2: Bound connecting, and send keepalives if they're on
+    let settings = dial::settings();
+    let connect_timeout = settings.connect_timeout.map(Duration::from_secs);
+    if let Some(timeout) = timeout.into_iter().chain(connect_timeout).min() {
+        let secs = timeout.as_secs_f64().ceil().max(1.0);
+        cmd.arg("-o").arg(format!("ConnectTimeout={}", secs));
+    }
+    if let Some(interval) = ssh::keepalive() {
+        cmd.arg("-o")
+            .arg(format!("ServerAliveInterval={}", interval.as_secs().max(1)));
+        cmd.arg("-o")
+            .arg(format!("ServerAliveCountMax={}", ssh::KEEPALIVE_COUNT_MAX));
+    }
+
+    // Step 3: Open the connection with the `[transport]` settings ssh has
+    // options for; it has none for TCP_NODELAY, and sends TCP keepalives as
+    // often as the system's settings say
+    if settings.tcp_keepalive.is_some() {
+        cmd.args(["-o", "TCPKeepAlive=yes"]);
+    }
+    if let Some(interface) = &settings.bind_interface {
+        cmd.arg("-o").arg(format!("BindInterface={}", interface));
+    }
+    if let Some(source) = settings.source_address {
+        cmd.arg("-o").arg(format!("BindAddress={}", source));
+    }
+    cmd
+}
+
+/// Explains a failure to run ssh, which is usually that it isn't installed.
+fn spawn_error(e: io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => format!(
+            "the openssh transport runs {}, which isn't installed or isn't on the PATH",
+            PROGRAM
+        ),
+        _ => format!("unable to run {}: {}", PROGRAM, e),
+    }
+}
+
+/// Returns ssh's flag for forwarding the agent if it's forwarded, or for
+/// not forwarding it otherwise.
+fn forward_agent() -> &'static str {
+    match agent::is_forwarded() {
+        true => "-A",
+        false => "-a",
+    }
+}
+
+/// Returns what ssh printed about why it failed, or its exit status if it
+/// printed nothing.
+fn failure(status: ExitStatus, stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let message: Vec<&str> = stderr
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    match message.is_empty() {
+        true => format!("{} exited with {}", PROGRAM, status),
+        false => message.join("; "),
+    }
+}
+
+impl Session for OpensshSession {
+    fn exec<'a>(
+        &'a self,
+        command: &'a str,
+        stdin: Option<&'a [u8]>,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let deadline = timeout.map(|timeout| started + timeout);
+            let remote = format!("echo $$; {}", command);
+            let mut capture = Capture::default();
+            let pumped = self
+                .pump(&remote, stdin, deadline, cancel, &mut capture, on_output)
+                .await;
+            match pumped {
+                Ok(exit_code) => Ok(capture.into_result(exit_code, started)),
+                Err(e) => {
+                    let pid = capture.header.and_then(|header| header.parse().ok());
+                    Err(self.abandon(pid, e, timeout).await)
+                }
+            }
+        })
+    }
+
+    fn exec_resumable<'a>(
+        &'a self,
+        command: &'a str,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        reconnect: &'a Reconnect<'a>,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let deadline = timeout.map(|timeout| started + timeout);
+            let mut remote = ssh::start_job(command);
+
+            let mut capture = Capture::default();
+            let mut reconnected: Option<OpensshSession> = None;
+            let mut attempts = 0;
+            let exit_code = loop {
+                let current = reconnected.as_ref().unwrap_or(self);
+                let pumped = current
+                    .pump(&remote, None, deadline, cancel, &mut capture, on_output)
+                    .await;
+                let e = match pumped {
+                    Ok(exit_code) => break exit_code,
+                    Err(e) => e,
+                };
+                let job = capture.header.as_deref().and_then(Job::parse);
+                if ssh::is_abandoned(&e) {
+                    // Stop the command and drop its output files
+                    let pid = job.as_ref().map(|job| job.pid);
+                    let e = current.abandon(pid, e, timeout).await;
+                    if let Some(job) = job {
+                        let remove = format!("rm -rf {}", shell::quote(&job.dir));
+                        let _ = tokio::time::timeout(
+                            ssh::CLEANUP_TIMEOUT,
+                            current.run_quietly(&remove),
+                        )
+                        .await;
+                    }
+                    return Err(e);
+                }
+                let Some(job) = job else {
+                    // Lost before the command started
+                    return Err(e.into());
+                };
+
+                // Step 1: Reconnect, waiting a little longer before each try
+                let node = reconnect.node;
+                let sess = loop {
+                    if attempts == reconnect.attempts {
+                        return Err(format!(
+                            "lost the connection while the command ran, and gave up after reconnecting {} times: {}",
+                            attempts, e
+                        )
+                        .into());
+                    }
+                    attempts += 1;
+                    tracing::warn!(
+                        "[{}] Connection lost while the command ran: {}; reconnecting ({} of {})",
+                        node,
+                        e,
+                        attempts,
+                        reconnect.attempts
+                    );
+                    if !cancel.pause(ssh::RECONNECT_DELAY * attempts).await {
+                        return Err(Box::new(Cancelled));
+                    }
+                    match log_in(node, reconnect.timeout).await {
+                        Ok(sess) => break sess,
+                        Err(e) => tracing::warn!("[{}] Unable to reconnect: {}", node, e),
+                    }
+                };
+
+                // Step 2: Follow the output again from where it stopped
+                remote = ssh::follow_job(
+                    &job,
+                    capture.read(OutputStream::Stdout),
+                    capture.read(OutputStream::Stderr),
+                );
+                tracing::info!(
+                    "[{}] Reconnected; resuming the output after {} bytes of stdout and {} bytes of stderr",
+                    node,
+                    capture.read(OutputStream::Stdout),
+                    capture.read(OutputStream::Stderr)
+                );
+                capture.header = None;
+                reconnected = Some(sess);
+            };
+            if capture.header.as_deref() == Some(ssh::JOB_GONE) {
+                return Err(
+                    "the command's output is gone from the node, which may have restarted while it ran"
+                        .into(),
+                );
+            }
+            Ok(capture.into_result(exit_code, started))
+        })
+    }
+
+    fn upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<u64, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let (sftp, _ssh) = self.sftp().await?;
+            sftp::upload(&sftp, local, remote, mode, cancel, on_progress).await
+        })
+    }
+
+    fn resume_upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Resumed, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let (sftp, _ssh) = self.sftp().await?;
+            sftp::resume_upload(&sftp, local, remote, mode, cancel, on_progress).await
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        remote: &'a Path,
+        local: &'a Path,
+        cancel: &'a CancelToken,
+    ) -> LocalBoxFuture<'a, Result<Downloaded, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let (sftp, _ssh) = self.sftp().await?;
+            sftp::download(&sftp, remote, local, cancel).await
+        })
+    }
+
+    fn sync<'a>(
+        &'a self,
+        tree: &'a Tree,
+        remote: &'a Path,
+        options: &'a Options,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Synced, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let (sftp, _ssh) = self.sftp().await?;
+            sftp::sync(&sftp, tree, remote, options, cancel, on_progress).await
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        // A master removes its socket when it closes
+        match &self.master {
+            Some(path) => path.exists(),
+            None => true,
+        }
+    }
+}
+
+impl OpensshSession {
+    /// Returns an ssh command that runs over the session's master, falling
+    /// back to connecting afresh if it has closed. The caller adds its own
+    /// options, then `--`, the node's name and the command to run.
+    fn command(&self) -> Result<Command, String> {
+        let mut cmd = ssh(&self.node, self.via.as_deref(), self.timeout)?;
+        cmd.args(["-o", "LogLevel=ERROR"]);
+        if let Some(path) = &self.master {
+            cmd.arg("-S").arg(path).args(["-o", "ControlMaster=no"]);
+        }
+        Ok(cmd)
+    }
+
+    /// Starts an ssh process running the sftp subsystem, and an SFTP session
+    /// over its pipes.
+    ///
+    /// # Returns
+    ///
+    /// Returns the session, with the process it runs over, which is killed
+    /// when dropped.
+    async fn sftp(&self) -> Result<(SftpSession, Child), Box<dyn std::error::Error + Send + Sync>> {
+        let mut child = self
+            .command()?
+            .args(["-a", "-s", "--"])
+            .arg(&self.node.host)
+            .arg("sftp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_error)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        match SftpSession::new(tokio::io::join(stdout, stdin)).await {
+            Ok(sftp) => Ok((sftp, child)),
+            Err(e) => {
+                let mut stderr = Vec::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    let _ = pipe.read_to_end(&mut stderr).await;
+                }
+                match child.wait().await {
+                    Ok(status) if !stderr.is_empty() => Err(failure(status, &stderr).into()),
+                    _ => Err(Box::new(e)),
+                }
+            }
+        }
+    }
+
+    /// Runs a command, ignoring its output, until it exits.
+    async fn run_quietly(
+        &self,
+        remote: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.command()?
+            .args(["-T", "-a", "--"])
+            .arg(&self.node.host)
+            .arg(remote)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await?;
+        Ok(())
+    }
+
+    /// Runs a command with ssh, writing stdin to it and reading its output
+    /// until it exits, as the other transports pump their channels.
+    ///
+    /// # Returns
+    ///
+    /// Returns the command's exit status, with the output added to
+    /// `capture`, or an error of kind TimedOut if the deadline passes first,
+    /// of kind Interrupted if the run is cancelled, or of kind
+    /// ConnectionAborted if the connection was lost.
+    async fn pump(
+        &self,
+        remote: &str,
+        stdin: Option<&[u8]>,
+        deadline: Option<Instant>,
+        cancel: &CancelToken,
+        capture: &mut Capture,
+        on_output: &mut dyn FnMut(OutputStream, &[u8]),
+    ) -> io::Result<i32> {
+        let mut child = self
+            .command()
+            .map_err(io::Error::other)?
+            .args(["-T", forward_agent(), "--"])
+            .arg(&self.node.host)
+            .arg(remote)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::other(spawn_error(e)))?;
+        let pipe = child.stdin.take();
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        let mut header = capture.header.is_none().then(Vec::new);
+        let output = async {
+            let (mut out, mut err) = (vec![0; 8192], vec![0; 8192]);
+            let (mut out_open, mut err_open) = (true, true);
+            while out_open || err_open {
+                tokio::select! {
+                    n = stdout.read(&mut out), if out_open => match n? {
+                        0 => out_open = false,
+                        n => capture.push(&mut header, OutputStream::Stdout, &out[..n], on_output),
+                    },
+                    n = stderr.read(&mut err), if err_open => match n? {
+                        0 => err_open = false,
+                        n => capture.push(&mut header, OutputStream::Stderr, &err[..n], on_output),
+                    },
+                }
+            }
+            let status = child.wait().await?;
+            Ok::<_, io::Error>(status.code().unwrap_or(NO_STATUS))
+        };
+        let input = async move {
+            // The command may exit or close its input without reading
+            // everything, and without input it's left open
+            let Some(mut pipe) = pipe else {
+                return future::pending().await;
+            };
+            if let Some(input) = stdin {
+                let mut pace = Pace::default();
+                let mut written = true;
+                for chunk in input.chunks(CHUNK_SIZE) {
+                    if pipe.write_all(chunk).await.is_err() {
+                        written = false;
+                        break;
+                    }
+                    pace.wait(chunk.len()).await;
+                }
+                if written {
+                    drop(pipe);
+                    return future::pending().await;
+                }
+            }
+            let _held = pipe;
+            future::pending().await
+        };
+        let expired = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => future::pending().await,
+            }
+        };
+        let exit_code = tokio::select! {
+            result = output => result,
+            result = input => result,
+            () = expired => Err(io::ErrorKind::TimedOut.into()),
+            () = cancel.cancelled() => Err(io::ErrorKind::Interrupted.into()),
+        }?;
+
+        // ssh fails with the same status a command can exit with, so it's
+        // only taken as the connection failing if the command never started
+        // or the master is gone
+        if exit_code == NO_STATUS {
+            if capture.header.is_none() {
+                let stderr = String::from_utf8_lossy(capture.stderr.bytes());
+                return Err(io::Error::other(format!(
+                    "ssh couldn't run the command: {}",
+                    stderr.trim()
+                )));
+            }
+            if self.lost().await {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the connection was lost",
+                ));
+            }
+        }
+        Ok(exit_code)
+    }
+
+    /// Returns true if the connection a command ran over has closed, which
+    /// a master does soon after losing it.
+    async fn lost(&self) -> bool {
+        match &self.master {
+            Some(path) => {
+                let closed = async {
+                    while path.exists() {
+                        tokio::time::sleep(MASTER_POLL).await;
+                    }
+                };
+                tokio::time::timeout(ssh::CLEANUP_TIMEOUT, closed)
+                    .await
+                    .is_ok()
+            }
+            None => {
+                let check = self.command().map(|mut cmd| {
+                    cmd.args(["-O", "check", "--"])
+                        .arg(&self.node.host)
+                        .stdin(Stdio::null())
+                        .stdout(Stdio::null())
+                        .stderr(Stdio::null())
+                        .status()
+                });
+                match check {
+                    Ok(check) => !matches!(check.await, Ok(status) if status.success()),
+                    Err(_) => true,
+                }
+            }
+        }
+    }
+
+    /// Turns a pump error into the error to return, killing the remote
+    /// command first if it was given up on because of the timeout or
+    /// cancellation.
+    async fn abandon(
+        &self,
+        pid: Option<u32>,
+        e: io::Error,
+        timeout: Option<Duration>,
+    ) -> Box<dyn std::error::Error + Send + Sync> {
+        if !ssh::is_abandoned(&e) {
+            return e.into();
+        }
+
+        // Kill the remote command rather than waiting on it any longer; the
+        // local ssh was killed when the pump dropped it
+        if let Some(pid) = pid {
+            let kill = ssh::kill_command(pid);
+            let _ = tokio::time::timeout(ssh::CLEANUP_TIMEOUT, self.run_quietly(&kill)).await;
+        }
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Box::new(Cancelled);
+        }
+        Box::new(TimedOut {
+            stage: "command",
+            after: timeout.unwrap_or_default(),
+        })
+    }
+}
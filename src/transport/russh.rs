@@ -0,0 +1,1035 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! The transport built on russh, which speaks SSH natively on the runtime.
+//!
+//! Every session is a task on the runtime's few threads rather than a
+//! thread of its own, and nodes behind jump hosts are reached over channels
+//! of the hops' sessions directly, with no relay threads. Nodes are logged
+//! into the way the libssh2 transport logs into them, with their host keys
+//! checked against the same pins and known hosts files, and commands that
+//! are given up on are killed with the same commands.
+
+use super::{sftp, within, Session, Transport};
+use crate::agent;
+use crate::bandwidth::Pace;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::certificate::{self, Certificate};
+use crate::config::{AuthMethod, Node};
+use crate::dial;
+use crate::host_key;
+use crate::jump;
+use crate::prompt;
+use crate::proxy;
+use crate::shell;
+use crate::ssh::{
+    self, Capture, CommandResult, Job, KeyPaths, Latency, OutputStream, Reconnect, TimedOut,
+};
+use crate::sync::{Options, Synced, Tree};
+use crate::transfer::{Downloaded, Resumed, CHUNK_SIZE};
+use futures::future::{self, LocalBoxFuture};
+use russh::client::{self, AuthResult, Handle, KeyboardInteractiveAuthResponse, Msg};
+use russh::keys::agent::client::AgentClient;
+use russh::keys::agent::AgentIdentity;
+use russh::keys::{self, Algorithm, HashAlg, PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+use russh::{
+    compression, Channel, ChannelMsg, ChannelReadHalf, ChannelWriteHalf, MethodKind, Preferred,
+};
+use russh_sftp::client::SftpSession;
+use std::borrow::Cow;
+use std::env;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::task;
+
+/// The exit status reported for a command that closed without one, such as
+/// one killed by a signal, as OpenSSH's client reports it.
+const NO_STATUS: i32 = 255;
+
+/// The compression methods asked for when a node's session is compressed,
+/// in OpenSSH's order.
+const COMPRESSED: &[compression::Name] = &[
+    compression::ZLIB_LEGACY,
+    compression::ZLIB,
+    compression::NONE,
+];
+
+/// Opens sessions with russh.
+pub struct Russh;
+
+/// A russh session, with the sessions of the jump hosts it runs through,
+/// which are shared with the other nodes behind them and closed after the
+/// last of their sessions.
+struct RusshSession {
+    handle: Handle<Handler>,
+    _hops: Option<Arc<Hops>>,
+}
+
+/// A connection to a node that has completed the handshake, with how long
+/// it took.
+struct Started {
+    handle: Handle<Handler>,
+    hops: Option<Arc<Hops>>,
+    latency: Latency,
+}
+
+/// The logged in sessions with a node's jump hosts, in order.
+type Hops = Vec<Handle<Handler>>;
+
+/// The sessions with each chain of jump hosts, so the nodes behind a
+/// bastion share one login to it rather than each asking for a one-time
+/// code.
+static JUMPS: jump::Logins<Arc<Hops>> = jump::Logins::new();
+
+/// A connection a session runs over: a socket, or a channel through a jump
+/// host.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Answers a session's requests: checking the node's host key, and opening
+/// channels to the local agent when it's forwarded.
+struct Handler {
+    node: Node,
+    /// Why the host key was refused, since russh only says that it was.
+    refused: Arc<Mutex<Option<String>>>,
+}
+
+impl client::Handler for Handler {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let checked = match key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => {
+                let key_type = host_key::key_type_of(key.algorithm().as_str());
+                match (key_type, key.to_bytes()) {
+                    (Some(key_type), Ok(bytes)) => {
+                        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+                        host_key::check(&self.node, key_type, &bytes, &fingerprint)
+                    }
+                    _ => Err(format!(
+                        "{} sent a type of host key that can't be checked",
+                        self.node.address()
+                    )),
+                }
+            }
+            PublicKeyOrCertificate::Certificate(_) => Err(format!(
+                "{} sent a host certificate, which can't be checked",
+                self.node.address()
+            )),
+        };
+        match checked {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                *self.refused.lock().unwrap() = Some(e);
+                Ok(false)
+            }
+        }
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: client::ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        // Dropping the reply turns the channel down
+        if !agent::is_forwarded() {
+            return Ok(());
+        }
+        let Some(path) = env::var_os("SSH_AUTH_SOCK") else {
+            return Ok(());
+        };
+        if let Ok(mut agent) = UnixStream::connect(path).await {
+            reply.accept().await;
+            task::spawn(async move {
+                let mut channel = channel.into_stream();
+                let _ = tokio::io::copy_bidirectional(&mut channel, &mut agent).await;
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Transport for Russh {
+    fn connect<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Box<dyn Session>, Box<dyn std::error::Error + Send + Sync>>>
+    {
+        Box::pin(async move {
+            let sess = open(node, timeout).await?;
+            Ok(Box::new(sess) as Box<dyn Session>)
+        })
+    }
+
+    fn probe<'a>(
+        &'a self,
+        node: &'a Node,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'a, Result<Latency, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let started = start(node, timeout).await?;
+            Ok(started.latency)
+        })
+    }
+}
+
+/// Opens an authenticated session with a node, with every step bounded by
+/// `timeout`, save for answering the node's questions on the terminal.
+async fn open(
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<RusshSession, Box<dyn std::error::Error + Send + Sync>> {
+    let started = login(node, timeout).await?;
+    Ok(RusshSession {
+        handle: started.handle,
+        _hops: started.hops,
+    })
+}
+
+/// Connects, handshakes and authenticates.
+async fn login(
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<Started, Box<dyn std::error::Error + Send + Sync>> {
+    let mut started = start(node, timeout).await?;
+    authenticate(&mut started.handle, node, timeout).await?;
+    Ok(started)
+}
+
+/// Connects to a node, through its jump hosts or proxy if it has any, and
+/// handshakes.
+///
+/// # Returns
+///
+/// Returns the session, with the sessions of the jump hosts it runs
+/// through, and how long each stage took.
+async fn start(
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<Started, Box<dyn std::error::Error + Send + Sync>> {
+    let started = Instant::now();
+    let (stream, hops): (Box<dyn Stream>, _) = match (&node.jump, &node.proxy) {
+        (Some(jump), _) => tunnel(jump, node, timeout).await?,
+        (None, proxy) => {
+            let dialed = async {
+                let tcp = match proxy {
+                    Some(url) => {
+                        let (url, host, port) =
+                            (url.clone(), node.address().to_string(), node.port());
+                        let tcp = task::spawn_blocking(move || {
+                            proxy::connect(&url, &host, port, timeout)
+                        })
+                        .await??;
+                        tcp.set_nonblocking(true)?;
+                        TcpStream::from_std(tcp)?
+                    }
+                    None => dial::connect_async(node.address(), node.port(), timeout).await?,
+                };
+                ssh::limit_unacknowledged(&tcp)?;
+                Ok(tcp)
+            };
+            (Box::new(within(timeout, dialed).await?), None)
+        }
+    };
+    let connected = Instant::now();
+    let handle = within(timeout, handshake(stream, node)).await?;
+    let latency = Latency {
+        connect: connected - started,
+        handshake: connected.elapsed(),
+    };
+    tracing::debug!(
+        "[{}] Connected in {:.2?} and handshook in {:.2?}",
+        node,
+        latency.connect,
+        latency.handshake
+    );
+    Ok(Started {
+        handle,
+        hops,
+        latency,
+    })
+}
+
+/// Opens a connection to a node's sshd through its jump hosts, over the
+/// sessions with them that the nodes behind them share, logging into them
+/// if no other node has yet or their sessions have closed.
+///
+/// # Returns
+///
+/// Returns the connection, with the sessions of the jump hosts, or an error
+/// if a hop can't be reached or won't open a channel to the next.
+async fn tunnel(
+    jump: &str,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<(Box<dyn Stream>, Option<Arc<Hops>>), Box<dyn std::error::Error + Send + Sync>> {
+    let hops = jump::hops(jump)?;
+    let usable = |handles: &Arc<Hops>| !handles.last().unwrap().is_closed();
+    let log_in = || log_into_hops(hops.clone(), node, timeout);
+    let handles = JUMPS.get(jump, node, usable, log_in).await?;
+    let stream = within(
+        timeout,
+        forward(handles.last().unwrap(), hops.last().unwrap(), node),
+    )
+    .await?;
+    Ok((stream, Some(handles)))
+}
+
+/// Logs into a chain of jump hosts, the first through the node's proxy.
+async fn log_into_hops(
+    mut hops: Vec<Node>,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<Arc<Hops>, Box<dyn std::error::Error + Send + Sync>> {
+    hops[0].proxy.clone_from(&node.proxy);
+    let first = Box::pin(login(&hops[0], timeout))
+        .await
+        .map_err(|e| format!("unable to reach jump host {}: {}", hops[0], e))?;
+    let mut handles = Vec::new();
+    handles.push(first.handle);
+    for (hop, next) in hops.iter().zip(&hops[1..]) {
+        let stream = within(timeout, forward(handles.last().unwrap(), hop, next)).await?;
+        let mut handle = within(timeout, handshake(stream, next))
+            .await
+            .map_err(|e| format!("unable to reach jump host {}: {}", next, e))?;
+        authenticate(&mut handle, next, timeout)
+            .await
+            .map_err(|e| format!("unable to reach jump host {}: {}", next, e))?;
+        handles.push(handle);
+    }
+    Ok(Arc::new(handles))
+}
+
+/// Opens a channel from a hop to the next host.
+async fn forward(
+    sess: &Handle<Handler>,
+    hop: &Node,
+    next: &Node,
+) -> Result<Box<dyn Stream>, Box<dyn std::error::Error + Send + Sync>> {
+    let channel = sess
+        .channel_open_direct_tcpip(next.address(), u32::from(next.port()), "127.0.0.1", 0)
+        .await
+        .map_err(|e| {
+            format!(
+                "jump host {} can't connect to {} port {}: {}",
+                hop,
+                next.address(),
+                next.port(),
+                e
+            )
+        })?;
+    Ok(Box::new(channel.into_stream()))
+}
+
+/// Starts a session over a connected stream and checks the node's host key.
+async fn handshake(
+    stream: Box<dyn Stream>,
+    node: &Node,
+) -> Result<Handle<Handler>, Box<dyn std::error::Error + Send + Sync>> {
+    let refused = Arc::new(Mutex::new(None));
+    let handler = Handler {
+        node: node.clone(),
+        refused: refused.clone(),
+    };
+    match client::connect_stream(config(node)?, stream, handler).await {
+        Ok(handle) => Ok(handle),
+        Err(e) => match refused.lock().unwrap().take() {
+            Some(reason) => Err(reason.into()),
+            None => Err(Box::new(e)),
+        },
+    }
+}
+
+/// Returns the settings for a session with a node, asking for the types of
+/// host key it's listed with first, compressing if the node asks for it, as
+/// OpenSSH's `-C` does, and sending keepalives if they're on.
+fn config(node: &Node) -> Result<Arc<client::Config>, String> {
+    let listed = host_key::listed_types(node)?;
+    let (mut keys, others): (Vec<Algorithm>, Vec<Algorithm>) = Preferred::DEFAULT
+        .key
+        .iter()
+        .cloned()
+        .partition(|algorithm| {
+            host_key::key_type_of(algorithm.as_str()).is_some_and(|t| listed.contains(&t))
+        });
+    keys.extend(others);
+    let compression = match node.compresses() {
+        true => Cow::Borrowed(COMPRESSED),
+        false => Preferred::DEFAULT.compression,
+    };
+    Ok(Arc::new(client::Config {
+        preferred: Preferred {
+            key: keys.into(),
+            compression,
+            ..Preferred::DEFAULT
+        },
+        keepalive_interval: ssh::keepalive(),
+        keepalive_max: ssh::KEEPALIVE_COUNT_MAX as usize,
+        ..Default::default()
+    }))
+}
+
+/// Authenticates with each of the node's ways of logging in in turn, as the
+/// libssh2 transport does, with each bounded by `timeout` save for the time
+/// spent answering the node's questions.
+async fn authenticate(
+    handle: &mut Handle<Handler>,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut failures = Vec::new();
+    let mut skipped = Vec::new();
+    let mut cert_error: Option<String> = None;
+    for method in node.auth() {
+        let result = match method {
+            AuthMethod::Agent if !ssh::agent_available() => {
+                skipped.push("no ssh-agent is running");
+                continue;
+            }
+            AuthMethod::Agent => within(timeout, authenticate_agent(handle, node))
+                .await
+                .map(|()| "the ssh-agent's keys".to_string())
+                .map_err(|e| match e.is::<TimedOut>() {
+                    true => e,
+                    false => format!("the ssh-agent's keys failed ({})", e).into(),
+                }),
+            AuthMethod::Key | AuthMethod::DefaultKeys => match ssh::method_keys(node, method) {
+                Ok(keys) => {
+                    let keys = authenticate_keys(handle, node, keys, &mut cert_error);
+                    within(timeout, keys).await
+                }
+                Err(e) => Err(e),
+            },
+            AuthMethod::Password => match ssh::password() {
+                Some(password) => within(timeout, authenticate_password(handle, node, password))
+                    .await
+                    .map(|()| "a password".to_string()),
+                None => {
+                    skipped.push("no password was given with --ask-pass");
+                    continue;
+                }
+            },
+            AuthMethod::KeyboardInteractive => answer_challenge(handle, node, timeout)
+                .await
+                .map(|()| "answers to its questions".to_string()),
+        };
+        match result {
+            Ok(used) => {
+                ssh::logged_in(node, &used);
+                return Ok(());
+            }
+            // The session can't be used for another way after a timeout
+            Err(e) if e.is::<TimedOut>() => return Err(e),
+            Err(e) => {
+                tracing::debug!("[{}] Not logged in: {}", node, e);
+                failures.push(e)
+            }
+        }
+    }
+    Err(ssh::login_failed(node, failures, &skipped, cert_error))
+}
+
+/// Authenticates with each of the keys the running ssh-agent holds.
+async fn authenticate_agent(
+    handle: &mut Handle<Handler>,
+    node: &Node,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut agent = AgentClient::connect_env().await?;
+    let identities = agent.request_identities().await?;
+    if identities.is_empty() {
+        return Err("it holds no keys".into());
+    }
+    for identity in identities {
+        let result = match identity {
+            AgentIdentity::PublicKey { key, .. } => {
+                let hash = rsa_hash(handle, key.algorithm()).await?;
+                handle
+                    .authenticate_publickey_with(node.user(), key, hash, &mut agent)
+                    .await?
+            }
+            AgentIdentity::Certificate { certificate, .. } => {
+                let hash = rsa_hash(handle, certificate.algorithm()).await?;
+                handle
+                    .authenticate_certificate_with(node.user(), certificate, hash, &mut agent)
+                    .await?
+            }
+        };
+        if result.success() {
+            return Ok(());
+        }
+    }
+    Err(format!("{} turned down every key", node.address()).into())
+}
+
+/// Authenticates using each SSH key in turn, as the libssh2 transport does.
+///
+/// # Returns
+///
+/// Returns a description of the key that worked, or the last key's error.
+async fn authenticate_keys(
+    handle: &mut Handle<Handler>,
+    node: &Node,
+    keys: Vec<KeyPaths>,
+    cert_error: &mut Option<String>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut last_error: Option<Box<dyn std::error::Error + Send + Sync>> = None;
+    for (pubkey, privkey) in keys {
+        let cert = match pubkey.as_deref().filter(|p| certificate::is_certificate(p)) {
+            Some(path) => {
+                match Certificate::read(path).and_then(|c| c.check(node.user()).map(|()| c)) {
+                    Ok(cert) => Some(cert),
+                    Err(e) => {
+                        cert_error.get_or_insert(e);
+                        continue;
+                    }
+                }
+            }
+            None => None,
+        };
+        let passphrase = ssh::passphrase(&privkey)?;
+        let key = match keys::load_secret_key(&privkey, passphrase.as_deref()) {
+            Ok(key) => Arc::new(key),
+            Err(e) if passphrase.is_some() => {
+                last_error =
+                    Some(format!("{}; check the passphrase of {}", e, privkey.display()).into());
+                continue;
+            }
+            Err(e) => {
+                last_error = Some(format!("unable to load {}: {}", privkey.display(), e).into());
+                continue;
+            }
+        };
+        let result = match &cert {
+            Some(cert) => {
+                let openssh = keys::load_openssh_certificate(&cert.path)?;
+                handle
+                    .authenticate_openssh_cert(node.user(), key, openssh)
+                    .await?
+            }
+            None => {
+                let hash = rsa_hash(handle, key.algorithm()).await?;
+                let key = PrivateKeyWithHashAlg::new(key, hash);
+                handle.authenticate_publickey(node.user(), key).await?
+            }
+        };
+        if result.success() {
+            return Ok(match &cert {
+                Some(cert) => format!(
+                    "key {} and certificate {}",
+                    privkey.display(),
+                    cert.path.display()
+                ),
+                None => format!("key {}", privkey.display()),
+            });
+        }
+        if let Some(cert) = &cert {
+            cert_error.get_or_insert(cert.turned_down(node.address()));
+        }
+        last_error =
+            Some(format!("{} turned down key {}", node.address(), privkey.display()).into());
+    }
+    Err(last_error.unwrap_or_else(|| {
+        cert_error
+            .clone()
+            .unwrap_or_else(|| "no key could be used".to_string())
+            .into()
+    }))
+}
+
+/// Returns the hash to sign with a key of the given algorithm, which for
+/// RSA keys is the strongest the node accepts.
+async fn rsa_hash(
+    handle: &Handle<Handler>,
+    algorithm: Algorithm,
+) -> Result<Option<HashAlg>, russh::Error> {
+    if !algorithm.is_rsa() {
+        return Ok(None);
+    }
+    Ok(handle.best_supported_rsa_hash().await?.flatten())
+}
+
+/// Authenticates with a password, as the libssh2 transport does.
+async fn authenticate_password(
+    handle: &mut Handle<Handler>,
+    node: &Node,
+    password: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let methods = match handle.authenticate_none(node.user()).await? {
+        AuthResult::Success => return Ok(()),
+        AuthResult::Failure {
+            remaining_methods, ..
+        } => remaining_methods,
+    };
+    let accepted = if methods.contains(&MethodKind::Password) {
+        handle
+            .authenticate_password(node.user(), password)
+            .await?
+            .success()
+    } else if methods.contains(&MethodKind::KeyboardInteractive) {
+        keyboard_interactive(handle, node.user(), password).await?
+    } else {
+        let methods: Vec<&str> = methods.iter().map(<&str>::from).collect();
+        return Err(format!(
+            "{} doesn't accept passwords, only {}",
+            node.address(),
+            methods.join(", ")
+        )
+        .into());
+    };
+    if !accepted {
+        return Err(format!(
+            "{} turned down the password; check the password",
+            node.address()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Authenticates by keyboard-interactive authentication, asking the node's
+/// questions, such as for a one-time code, on the terminal. Only the
+/// exchanges with the node are bounded by `timeout`, not the answering.
+async fn answer_challenge(
+    handle: &mut Handle<Handler>,
+    node: &Node,
+    timeout: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let start = handle.authenticate_keyboard_interactive_start(node.user(), None::<String>);
+    let mut response = within(timeout, async { Ok(start.await?) }).await?;
+    loop {
+        let (name, instructions, prompts) = match response {
+            KeyboardInteractiveAuthResponse::Success => return Ok(()),
+            KeyboardInteractiveAuthResponse::Failure { .. } => {
+                return Err(format!(
+                    "{} turned down the answers to its questions; check the answers",
+                    node.address()
+                )
+                .into());
+            }
+            KeyboardInteractiveAuthResponse::InfoRequest {
+                name,
+                instructions,
+                prompts,
+            } => (name, instructions, prompts),
+        };
+        let questions = prompts
+            .into_iter()
+            .map(|prompt| (prompt.prompt, prompt.echo))
+            .collect::<Vec<_>>();
+        let server = node.to_string();
+        let answers = task::spawn_blocking(move || {
+            prompt::challenge(&server, &name, &instructions, &questions)
+        })
+        .await?
+        .map_err(|e| format!("unable to answer {}'s questions: {}", node, e))?;
+        let respond = handle.authenticate_keyboard_interactive_respond(answers);
+        response = within(timeout, async { Ok(respond.await?) }).await?;
+    }
+}
+
+/// Authenticates by keyboard-interactive authentication, answering each of
+/// the node's hidden prompts with the password.
+///
+/// # Returns
+///
+/// Returns whether the node accepted the answers.
+async fn keyboard_interactive(
+    handle: &mut Handle<Handler>,
+    user: &str,
+    password: &str,
+) -> Result<bool, russh::Error> {
+    let mut response = handle
+        .authenticate_keyboard_interactive_start(user, None::<String>)
+        .await?;
+    loop {
+        let prompts = match response {
+            KeyboardInteractiveAuthResponse::Success => return Ok(true),
+            KeyboardInteractiveAuthResponse::Failure { .. } => return Ok(false),
+            KeyboardInteractiveAuthResponse::InfoRequest { prompts, .. } => prompts,
+        };
+        // Prompts that echo ask for something other than the password
+        let answers = prompts
+            .iter()
+            .map(|prompt| match prompt.echo {
+                false => password.to_string(),
+                true => String::new(),
+            })
+            .collect();
+        response = handle
+            .authenticate_keyboard_interactive_respond(answers)
+            .await?;
+    }
+}
+
+impl Session for RusshSession {
+    fn exec<'a>(
+        &'a self,
+        command: &'a str,
+        stdin: Option<&'a [u8]>,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let deadline = timeout.map(|timeout| started + timeout);
+            let channel = self.channel().await?;
+            channel.exec(true, format!("echo $$; {}", command)).await?;
+            let (mut reader, writer) = channel.split();
+
+            let mut capture = Capture::default();
+            let pumped = pump(
+                &mut reader,
+                &writer,
+                stdin,
+                deadline,
+                cancel,
+                &mut capture,
+                on_output,
+            )
+            .await;
+            match pumped {
+                Ok(exit_code) => Ok(capture.into_result(exit_code, started)),
+                Err(e) => {
+                    let pid = capture.header.and_then(|header| header.parse().ok());
+                    Err(self.abandon(&writer, pid, e, timeout).await)
+                }
+            }
+        })
+    }
+
+    fn exec_resumable<'a>(
+        &'a self,
+        command: &'a str,
+        timeout: Option<Duration>,
+        cancel: &'a CancelToken,
+        reconnect: &'a Reconnect<'a>,
+        on_output: &'a mut dyn FnMut(OutputStream, &[u8]),
+    ) -> LocalBoxFuture<'a, Result<CommandResult, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let deadline = timeout.map(|timeout| started + timeout);
+            let channel = self.channel().await?;
+            channel.exec(true, ssh::start_job(command)).await?;
+            let (mut reader, mut writer) = channel.split();
+
+            let mut capture = Capture::default();
+            let mut reconnected: Option<RusshSession> = None;
+            let mut attempts = 0;
+            let exit_code = loop {
+                let current = reconnected.as_ref().unwrap_or(self);
+                let pumped = pump(
+                    &mut reader,
+                    &writer,
+                    None,
+                    deadline,
+                    cancel,
+                    &mut capture,
+                    on_output,
+                )
+                .await;
+                let e = match pumped {
+                    Ok(exit_code) => break exit_code,
+                    Err(e) => e,
+                };
+                let job = capture.header.as_deref().and_then(Job::parse);
+                if ssh::is_abandoned(&e) {
+                    // Stop the command and drop its output files
+                    let pid = job.as_ref().map(|job| job.pid);
+                    let e = current.abandon(&writer, pid, e, timeout).await;
+                    if let Some(job) = job {
+                        let remove = format!("rm -rf {}", shell::quote(&job.dir));
+                        let _ = tokio::time::timeout(
+                            ssh::CLEANUP_TIMEOUT,
+                            current.run_quietly(&remove),
+                        )
+                        .await;
+                    }
+                    return Err(e);
+                }
+                let Some(job) = job else {
+                    // Lost before the command started
+                    return Err(e.into());
+                };
+
+                // Step 1: Reconnect, waiting a little longer before each try
+                let node = reconnect.node;
+                let sess = loop {
+                    if attempts == reconnect.attempts {
+                        return Err(format!(
+                            "lost the connection while the command ran, and gave up after reconnecting {} times: {}",
+                            attempts, e
+                        )
+                        .into());
+                    }
+                    attempts += 1;
+                    tracing::warn!(
+                        "[{}] Connection lost while the command ran: {}; reconnecting ({} of {})",
+                        node,
+                        e,
+                        attempts,
+                        reconnect.attempts
+                    );
+                    if !cancel.pause(ssh::RECONNECT_DELAY * attempts).await {
+                        return Err(Box::new(Cancelled));
+                    }
+                    match open(node, reconnect.timeout).await {
+                        Ok(sess) => break sess,
+                        Err(e) => tracing::warn!("[{}] Unable to reconnect: {}", node, e),
+                    }
+                };
+
+                // Step 2: Follow the output again from where it stopped
+                let channel = sess.channel().await?;
+                let follow = ssh::follow_job(
+                    &job,
+                    capture.read(OutputStream::Stdout),
+                    capture.read(OutputStream::Stderr),
+                );
+                channel.exec(true, follow).await?;
+                (reader, writer) = channel.split();
+                tracing::info!(
+                    "[{}] Reconnected; resuming the output after {} bytes of stdout and {} bytes of stderr",
+                    node,
+                    capture.read(OutputStream::Stdout),
+                    capture.read(OutputStream::Stderr)
+                );
+                capture.header = None;
+                reconnected = Some(sess);
+            };
+            if capture.header.as_deref() == Some(ssh::JOB_GONE) {
+                return Err(
+                    "the command's output is gone from the node, which may have restarted while it ran"
+                        .into(),
+                );
+            }
+            Ok(capture.into_result(exit_code, started))
+        })
+    }
+
+    fn upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<u64, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            sftp::upload(
+                &self.sftp().await?,
+                local,
+                remote,
+                mode,
+                cancel,
+                on_progress,
+            )
+            .await
+        })
+    }
+
+    fn resume_upload<'a>(
+        &'a self,
+        local: &'a Path,
+        remote: &'a Path,
+        mode: u32,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Resumed, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            sftp::resume_upload(
+                &self.sftp().await?,
+                local,
+                remote,
+                mode,
+                cancel,
+                on_progress,
+            )
+            .await
+        })
+    }
+
+    fn download<'a>(
+        &'a self,
+        remote: &'a Path,
+        local: &'a Path,
+        cancel: &'a CancelToken,
+    ) -> LocalBoxFuture<'a, Result<Downloaded, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move { sftp::download(&self.sftp().await?, remote, local, cancel).await })
+    }
+
+    fn sync<'a>(
+        &'a self,
+        tree: &'a Tree,
+        remote: &'a Path,
+        options: &'a Options,
+        cancel: &'a CancelToken,
+        on_progress: &'a mut dyn FnMut(u64, u64),
+    ) -> LocalBoxFuture<'a, Result<Synced, Box<dyn std::error::Error + Send + Sync>>> {
+        Box::pin(async move {
+            sftp::sync(
+                &self.sftp().await?,
+                tree,
+                remote,
+                options,
+                cancel,
+                on_progress,
+            )
+            .await
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        !self.handle.is_closed()
+    }
+}
+
+impl RusshSession {
+    /// Opens a channel to run a command on, asking for the agent to be
+    /// forwarded over it if it is.
+    async fn channel(&self) -> Result<Channel<Msg>, russh::Error> {
+        let channel = self.handle.channel_open_session().await?;
+        if agent::is_forwarded() {
+            channel.agent_forward(false).await?;
+        }
+        Ok(channel)
+    }
+
+    /// Opens an SFTP session over a channel.
+    async fn sftp(&self) -> Result<SftpSession, Box<dyn std::error::Error + Send + Sync>> {
+        let channel = self.handle.channel_open_session().await?;
+        channel.request_subsystem(true, "sftp").await?;
+        Ok(SftpSession::new(channel.into_stream()).await?)
+    }
+
+    /// Runs a command, ignoring its output, until its channel closes.
+    async fn run_quietly(&self, command: &str) -> Result<(), russh::Error> {
+        let mut channel = self.handle.channel_open_session().await?;
+        channel.exec(true, command).await?;
+        while let Some(msg) = channel.wait().await {
+            if matches!(msg, ChannelMsg::Close) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turns a pump error into the error to return, killing the remote
+    /// command first if it was given up on because of the timeout or
+    /// cancellation.
+    async fn abandon(
+        &self,
+        writer: &ChannelWriteHalf<Msg>,
+        pid: Option<u32>,
+        e: io::Error,
+        timeout: Option<Duration>,
+    ) -> Box<dyn std::error::Error + Send + Sync> {
+        if !ssh::is_abandoned(&e) {
+            return e.into();
+        }
+
+        // Kill the remote command rather than waiting on it any longer
+        let cleanup = async {
+            if let Some(pid) = pid {
+                let _ = self.run_quietly(&ssh::kill_command(pid)).await;
+            }
+            let _ = writer.close().await;
+        };
+        let _ = tokio::time::timeout(ssh::CLEANUP_TIMEOUT, cleanup).await;
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Box::new(Cancelled);
+        }
+        Box::new(TimedOut {
+            stage: "command",
+            after: timeout.unwrap_or_default(),
+        })
+    }
+}
+
+/// Writes stdin to and reads stdout and stderr from a channel until it
+/// closes, as the libssh2 transport's pump does, with input written while
+/// output is read.
+///
+/// # Returns
+///
+/// Returns the command's exit status once the channel closes, with the
+/// output added to `capture`, or an error of kind TimedOut if the deadline
+/// passes first, or of kind Interrupted if the run is cancelled.
+async fn pump(
+    reader: &mut ChannelReadHalf,
+    writer: &ChannelWriteHalf<Msg>,
+    stdin: Option<&[u8]>,
+    deadline: Option<Instant>,
+    cancel: &CancelToken,
+    capture: &mut Capture,
+    on_output: &mut dyn FnMut(OutputStream, &[u8]),
+) -> io::Result<i32> {
+    let mut header = capture.header.is_none().then(Vec::new);
+    let output = async {
+        let mut exit_code = None;
+        loop {
+            match reader.wait().await {
+                Some(ChannelMsg::Data { data }) => {
+                    capture.push(&mut header, OutputStream::Stdout, &data, on_output)
+                }
+                Some(ChannelMsg::ExtendedData { data, ext: 1 }) => {
+                    capture.push(&mut header, OutputStream::Stderr, &data, on_output)
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => {
+                    exit_code = Some(exit_status as i32)
+                }
+                Some(ChannelMsg::Failure) => {
+                    return Err(io::Error::other("the node wouldn't run the command"))
+                }
+                Some(ChannelMsg::Close) => return Ok(exit_code.unwrap_or(NO_STATUS)),
+                Some(_) => {}
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "the connection was lost",
+                    ))
+                }
+            }
+        }
+    };
+    let input = async {
+        // The command may exit or close its input without reading everything
+        if let Some(input) = stdin {
+            let mut pace = Pace::default();
+            let mut written = true;
+            for chunk in input.chunks(CHUNK_SIZE) {
+                if writer.data(chunk).await.is_err() {
+                    written = false;
+                    break;
+                }
+                pace.wait(chunk.len()).await;
+            }
+            if written {
+                let _ = writer.eof().await;
+            }
+        }
+        future::pending().await
+    };
+    let expired = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::select! {
+        result = output => result,
+        result = input => result,
+        () = expired => Err(io::ErrorKind::TimedOut.into()),
+        () = cancel.cancelled() => Err(io::ErrorKind::Interrupted.into()),
+    }
+}
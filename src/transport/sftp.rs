@@ -0,0 +1,417 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! File transfers over an async SFTP session, for the transports that don't
+//! use libssh2.
+//!
+//! Files are copied as `transfer` copies them over libssh2: in chunks, with
+//! a cancelled transfer stopping at the next chunk, and with permissions and
+//! symlinks kept. The SFTP session can run over anything that carries the
+//! subsystem, such as a russh channel or an ssh process's pipes.
+
+use crate::bandwidth::Pace;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::sync::{self, Kind, Options, Remote, Step, Synced, Tree};
+use crate::transfer::{self, Downloaded, Resumed, CHUNK_SIZE};
+use futures::future::LocalBoxFuture;
+use russh_sftp::client::error::Error;
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{FileAttributes, OpenFlags, StatusCode};
+use std::io::SeekFrom;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Uploads a local file to a path on the node, as `transfer::upload` does,
+/// and closes the SFTP session.
+///
+/// # Returns
+///
+/// Returns the number of bytes uploaded, or an error if the transfer fails.
+pub async fn upload(
+    sftp: &SftpSession,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut pace = Pace::default();
+    let sent = send_file(
+        sftp,
+        local,
+        remote,
+        mode,
+        None,
+        0,
+        &mut pace,
+        cancel,
+        on_progress,
+    )
+    .await?;
+    let _ = sftp.close().await;
+    Ok(sent)
+}
+
+/// Uploads a local file to a path on the node by way of a partial file
+/// beside it, as `transfer::resume_upload` does, and closes the SFTP session.
+///
+/// # Returns
+///
+/// Returns the bytes kept and sent, or an error if the transfer fails.
+pub async fn resume_upload(
+    sftp: &SftpSession,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<Resumed, Box<dyn std::error::Error + Send + Sync>> {
+    let metadata = fs::metadata(local).await?;
+    let partial = transfer::partial_path(remote, &metadata);
+    let partial = partial.to_string_lossy().into_owned();
+
+    // Step 1: Keep what an earlier upload got through
+    let kept = match sftp.metadata(&partial).await {
+        Ok(stat) => stat
+            .size
+            .filter(|&size| size <= metadata.len())
+            .unwrap_or(0),
+        Err(Error::Status(status)) if status.status_code == StatusCode::NoSuchFile => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    // Step 2: Send the rest
+    let mut pace = Pace::default();
+    let sent = send_file(
+        sftp,
+        local,
+        Path::new(&partial),
+        mode,
+        None,
+        kept,
+        &mut pace,
+        cancel,
+        on_progress,
+    )
+    .await?;
+
+    // Step 3: Move it into place; renaming over an existing file isn't
+    // allowed by every server, so that's removed first
+    let remote = remote.to_string_lossy();
+    match sftp.remove_file(remote.as_ref()).await {
+        Ok(()) => {}
+        Err(Error::Status(status)) if status.status_code == StatusCode::NoSuchFile => {}
+        Err(e) => return Err(e.into()),
+    }
+    sftp.rename(partial, remote).await?;
+    let _ = sftp.close().await;
+    Ok(Resumed { kept, sent })
+}
+
+/// Sends a local file over an SFTP session, creating or truncating the
+/// remote file and giving it `mode`, and the modification time if one is
+/// given, at the pace the bandwidth limits allow.
+///
+/// With an `offset`, the remote file's first `offset` bytes are taken to be
+/// the local file's and kept, and the rest is sent after them.
+///
+/// # Returns
+///
+/// Returns the number of bytes sent, or an error if the transfer fails.
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    sftp: &SftpSession,
+    local: &Path,
+    remote: &Path,
+    mode: u32,
+    mtime: Option<u64>,
+    offset: u64,
+    pace: &mut Pace,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(local).await?;
+    let total = file.metadata().await?.len();
+
+    let remote = remote.to_string_lossy().into_owned();
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if offset == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote_file = sftp
+        .open_with_flags_and_attributes(&remote, flags, with_mode(mode))
+        .await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    remote_file.seek(SeekFrom::Start(offset)).await?;
+
+    // Copy the file across in chunks, reporting progress as we go
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        remote_file.write_all(&buf[..n]).await?;
+        pace.wait(n).await;
+        sent += n as u64;
+        on_progress(offset + sent, total);
+    }
+    remote_file.shutdown().await?;
+
+    // The mode given when opening only applies to newly created files
+    let attributes = FileAttributes {
+        atime: mtime.map(|mtime| mtime as u32),
+        mtime: mtime.map(|mtime| mtime as u32),
+        ..with_mode(mode)
+    };
+    sftp.set_metadata(&remote, attributes).await?;
+    Ok(sent)
+}
+
+/// Downloads a file, or a directory recursively, from the node, as
+/// `transfer::download` does, and closes the SFTP session.
+///
+/// # Returns
+///
+/// Returns the totals of what was downloaded, or an error if any part of
+/// the transfer fails.
+pub async fn download(
+    sftp: &SftpSession,
+    remote: &Path,
+    local: &Path,
+    cancel: &CancelToken,
+) -> Result<Downloaded, Box<dyn std::error::Error + Send + Sync>> {
+    let stat = sftp.symlink_metadata(remote.to_string_lossy()).await?;
+    if let Some(parent) = local.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut totals = Downloaded::default();
+    let mut pace = Pace::default();
+    download_entry(sftp, remote, &stat, local, &mut pace, cancel, &mut totals).await?;
+    let _ = sftp.close().await;
+    Ok(totals)
+}
+
+/// Brings a directory on the node in line with a local tree, as
+/// `transfer::sync` does, and closes the SFTP session.
+///
+/// # Returns
+///
+/// Returns the totals of what was changed, or an error if any change fails.
+pub async fn sync(
+    sftp: &SftpSession,
+    tree: &Tree,
+    remote: &Path,
+    options: &Options,
+    cancel: &CancelToken,
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> Result<Synced, Box<dyn std::error::Error + Send + Sync>> {
+    let listing = match sftp.symlink_metadata(remote.to_string_lossy()).await {
+        Ok(stat) if stat.is_dir() => list(sftp, remote, cancel).await?,
+        Ok(_) => return Err(format!("{} isn't a directory", remote.display()).into()),
+        Err(Error::Status(status)) if status.status_code == StatusCode::NoSuchFile => {
+            create_dirs(sftp, remote).await?;
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let steps = sync::plan(tree, &listing, options);
+    let total = sync::bytes_to_send(&steps);
+    let mut synced = Synced::default();
+    let mut pace = Pace::default();
+    for step in steps {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        match step {
+            Step::Remove { path, dir } => {
+                let path = remote.join(path).to_string_lossy().into_owned();
+                if dir {
+                    sftp.remove_dir(path).await?;
+                } else {
+                    sftp.remove_file(path).await?;
+                }
+                synced.removed += 1;
+            }
+            Step::CreateDir { path, mode } => {
+                let path = remote.join(path).to_string_lossy().into_owned();
+                sftp.create_dir(&path).await?;
+                sftp.set_metadata(path, with_mode(mode)).await?;
+            }
+            Step::Upload {
+                path, mode, mtime, ..
+            } => {
+                let sent_before = synced.bytes;
+                synced.bytes += send_file(
+                    sftp,
+                    &tree.root.join(&path),
+                    &remote.join(&path),
+                    mode,
+                    Some(mtime),
+                    0,
+                    &mut pace,
+                    cancel,
+                    &mut |sent, _| on_progress(sent_before + sent, total),
+                )
+                .await?;
+                synced.files += 1;
+                synced.sent.push(path);
+            }
+            Step::Symlink { path, target } => {
+                // OpenSSH's server takes the target and the link the other
+                // way round from the SFTP draft
+                sftp.symlink(
+                    target.to_string_lossy(),
+                    remote.join(path).to_string_lossy(),
+                )
+                .await?;
+                synced.files += 1;
+            }
+        }
+    }
+    let _ = sftp.close().await;
+    Ok(synced)
+}
+
+/// Lists everything in a directory on the node, with every directory before
+/// what's in it.
+async fn list(
+    sftp: &SftpSession,
+    remote: &Path,
+    cancel: &CancelToken,
+) -> Result<Vec<Remote>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut listing = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        for entry in sftp.read_dir(remote.join(&dir).to_string_lossy()).await? {
+            let path = dir.join(entry.file_name());
+            let stat = entry.metadata();
+            let file_type = stat.file_type();
+            let kind = if file_type.is_dir() {
+                dirs.push(path.clone());
+                Kind::Dir
+            } else if file_type.is_file() {
+                Kind::File
+            } else if file_type.is_symlink() {
+                let target = sftp.read_link(remote.join(&path).to_string_lossy()).await?;
+                Kind::Symlink(PathBuf::from(target))
+            } else {
+                Kind::Other
+            };
+            listing.push(Remote {
+                path,
+                kind,
+                size: stat.size.unwrap_or_default(),
+                mtime: stat.mtime.unwrap_or_default().into(),
+            });
+        }
+    }
+    Ok(listing)
+}
+
+/// Creates a directory on the node, and any of its parents that are missing.
+async fn create_dirs(
+    sftp: &SftpSession,
+    remote: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut missing = Vec::new();
+    for dir in remote.ancestors() {
+        if dir.as_os_str().is_empty() || sftp.try_exists(dir.to_string_lossy()).await? {
+            break;
+        }
+        missing.push(dir);
+    }
+    for dir in missing.into_iter().rev() {
+        // Something else may have created it in the meantime
+        if let Err(e) = sftp.create_dir(dir.to_string_lossy()).await {
+            match sftp.symlink_metadata(dir.to_string_lossy()).await {
+                Ok(stat) if stat.is_dir() => {}
+                _ => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns file attributes that only set the permission bits.
+fn with_mode(mode: u32) -> FileAttributes {
+    FileAttributes {
+        permissions: Some(mode),
+        ..FileAttributes::empty()
+    }
+}
+
+/// Downloads a single remote entry, recursing into directories.
+fn download_entry<'a>(
+    sftp: &'a SftpSession,
+    remote: &'a Path,
+    stat: &'a FileAttributes,
+    local: &'a Path,
+    pace: &'a mut Pace,
+    cancel: &'a CancelToken,
+    totals: &'a mut Downloaded,
+) -> LocalBoxFuture<'a, Result<(), Box<dyn std::error::Error + Send + Sync>>> {
+    Box::pin(async move {
+        if cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let file_type = stat.file_type();
+        if file_type.is_dir() {
+            fs::create_dir_all(local).await?;
+            for entry in sftp.read_dir(remote.to_string_lossy()).await? {
+                let name = entry.file_name();
+                let path: PathBuf = remote.join(&name);
+                download_entry(
+                    sftp,
+                    &path,
+                    &entry.metadata(),
+                    &local.join(name),
+                    pace,
+                    cancel,
+                    totals,
+                )
+                .await?;
+            }
+        } else if file_type.is_symlink() {
+            let target = sftp.read_link(remote.to_string_lossy()).await?;
+            if fs::symlink_metadata(local).await.is_ok() {
+                fs::remove_file(local).await?;
+            }
+            symlink(target, local)?;
+            totals.files += 1;
+        } else {
+            let mut remote_file = sftp.open(remote.to_string_lossy()).await?;
+            let mut file = File::create(local).await?;
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                if cancel.is_cancelled() {
+                    return Err(Cancelled.into());
+                }
+                let n = remote_file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buf[..n]).await?;
+                pace.wait(n).await;
+                totals.bytes += n as u64;
+            }
+            file.flush().await?;
+            if let Some(perm) = stat.permissions {
+                let permissions = std::fs::Permissions::from_mode(perm & 0o7777);
+                fs::set_permissions(local, permissions).await?;
+            }
+            totals.files += 1;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,157 @@
+// Copyright (c) 2024 San Francisco Compute Company
+// All rights reserved.
+
+//! Checking that files arrived intact, for `--verify`.
+//!
+//! Once `push` or `sync` has sent its files to a node, their digests are
+//! worked out on the node and compared with those of the local files. A node
+//! where any file differs fails, naming the files, so corruption over a flaky
+//! link is caught when it happens rather than when the files are next used.
+
+use crate::cancel::CancelToken;
+use crate::cli::ChecksumAlgorithm;
+use crate::transfer::CHUNK_SIZE;
+use crate::transport::Session;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The most differing files named in an error.
+const MAX_NAMED: usize = 10;
+
+/// Returns a local file's SHA-256 digest in hex.
+pub fn digest_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Checks that files on the node have the digests of the local files they
+/// were sent from.
+///
+/// # Arguments
+///
+/// * `sess` - The authenticated session to the node.
+/// * `algorithm` - The digest to compare.
+/// * `files` - Each file's path on the node, with the local file's digest.
+/// * `timeout` - The limit on working out the digests.
+/// * `cancel` - Abandons the check when the run is cancelled.
+///
+/// # Returns
+///
+/// Returns an error naming the files that differ, or if the digests
+/// couldn't be worked out.
+pub async fn check(
+    sess: &dyn Session,
+    algorithm: ChecksumAlgorithm,
+    files: &[(PathBuf, String)],
+    timeout: Option<Duration>,
+    cancel: &CancelToken,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    // The paths are given on the command's input, so there can be any
+    // number of them
+    let program = match algorithm {
+        ChecksumAlgorithm::Sha256 => "sha256sum",
+    };
+    let mut input = Vec::new();
+    for (path, _) in files {
+        input.extend_from_slice(path.as_os_str().as_bytes());
+        input.push(0);
+    }
+    let command = format!("xargs -0 {} --", program);
+    let result = sess
+        .exec(&command, Some(&input), timeout, cancel, &mut |_, _| {})
+        .await?;
+    let digests = parse(&result.stdout);
+    if digests.is_empty() && result.exit_code != 0 {
+        return Err(format!(
+            "unable to check the files sent with {}: {}",
+            program,
+            result.stderr.trim()
+        )
+        .into());
+    }
+
+    let differ: Vec<String> = files
+        .iter()
+        .filter(|(path, digest)| digests.get(path) != Some(digest))
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    if differ.is_empty() {
+        return Ok(());
+    }
+    if files.len() == 1 {
+        return Err(format!("{} differs from the local file", differ[0]).into());
+    }
+    let mut named = differ[..differ.len().min(MAX_NAMED)].join(", ");
+    if differ.len() > MAX_NAMED {
+        named.push_str(&format!(" and {} more", differ.len() - MAX_NAMED));
+    }
+    Err(format!(
+        "{} of the {} files sent differ from the local ones: {}",
+        differ.len(),
+        files.len(),
+        named
+    )
+    .into())
+}
+
+/// Parses the output of `sha256sum` into the digest of each file it names,
+/// undoing the escaping of names with backslashes or line breaks in them.
+pub fn parse(output: &str) -> HashMap<PathBuf, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (escaped, line) = match line.strip_prefix('\\') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (digest, path) = line.split_once("  ")?;
+            let path = if escaped {
+                unescape(path)
+            } else {
+                path.to_string()
+            };
+            Some((PathBuf::from(path), digest.to_string()))
+        })
+        .collect()
+}
+
+/// Undoes `sha256sum`'s escaping of a file name.
+fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}